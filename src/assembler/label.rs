@@ -4,46 +4,201 @@
  * License, v. 2.0. If a copy of the MPL was not distributed with this
  * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
 
-use std::collections::hash_map::Iter as HMIter;
-use std::collections::HashMap;
+use crate::parse::{SymbolType, Visibility};
+use indexmap::map::Iter as IMIter;
+use indexmap::IndexMap;
+
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub enum Binding {
+    #[default]
+    Local,
+    Global,
+    Weak,
+}
+
+/// Where the size given by `.size` ends. Offsets into the section move along when jumps are relaxed.
+#[derive(Clone, Debug)]
+pub enum SizeEnd {
+    Offset(usize),
+    Label(String),
+}
+
+/// An offset into the section stored in the label map, which has to move when the section's contents do
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Position {
+    Address(String),
+    SizeEnd(String),
+}
+
+#[derive(Clone, Debug)]
+pub enum SizeExpression {
+    Constant(u64),
+    /// The distance from the start label to the end
+    Difference(SizeEnd, String),
+}
+
+/// Everything the symbol directives can say about a symbol
+#[derive(Clone, Debug, Default)]
+pub struct Attributes {
+    pub binding: Binding,
+    pub visibility: Visibility,
+    pub typ: SymbolType,
+    pub size: Option<u64>,
+}
+
+impl Attributes {
+    /// Combines the declarations of a symbol from two sections
+    pub fn merge(&mut self, other: &Attributes) {
+        if other.binding != Binding::Local {
+            self.binding = other.binding;
+        }
+        if other.visibility != Visibility::Default {
+            self.visibility = other.visibility;
+        }
+        if other.typ != SymbolType::NoType {
+            self.typ = other.typ;
+        }
+        self.size = self.size.or(other.size);
+    }
+}
 
 pub struct Label {
     pub name: String,
     pub at: usize,
-    pub global: bool
 }
 
+#[derive(Default)]
 struct UnnamedLabel {
     at: Option<usize>,
-    global: bool,
+    binding: Binding,
+    visibility: Visibility,
+    typ: SymbolType,
+    size: Option<SizeExpression>,
 }
 
+/// The symbols mentioned in a section, in the order they were first mentioned
 pub struct LabelMap {
-    map: HashMap<String, UnnamedLabel>,
+    map: IndexMap<String, UnnamedLabel>,
 }
 
 pub struct Iter<'a> {
-    inner: HMIter<'a, String, UnnamedLabel>
+    inner: IMIter<'a, String, UnnamedLabel>
 }
 
 impl LabelMap {
     pub fn new() -> Self {
-        LabelMap { map: HashMap::new() }
+        LabelMap { map: IndexMap::new() }
     }
 
     pub fn insert_label(&mut self, name: String, at: usize) {
-        self.map.insert(name, UnnamedLabel { at: Some(at), global: false });
+        // Keep the attributes of preceding directives, like .globl
+        self.map.entry(name).or_default().at = Some(at);
+    }
+
+    pub fn set_binding(&mut self, name: String, binding: Binding) {
+        let label = self.map.entry(name).or_default();
+
+        // A weak symbol stays weak, even if it's also declared .globl
+        if !(label.binding == Binding::Weak && binding == Binding::Global) {
+            label.binding = binding;
+        }
+    }
+
+    pub fn set_visibility(&mut self, name: String, visibility: Visibility) {
+        self.map.entry(name).or_default().visibility = visibility;
+    }
+
+    pub fn set_type(&mut self, name: String, typ: SymbolType) {
+        self.map.entry(name).or_default().typ = typ;
+    }
+
+    pub fn set_size(&mut self, name: String, size: SizeExpression) {
+        self.map.entry(name).or_default().size = Some(size);
+    }
+
+    /// All offsets stored for symbols that are exactly at `at`
+    pub fn positions_at(&self, at: usize) -> Vec<Position> {
+        let mut positions = Vec::new();
+
+        for (name, label) in &self.map {
+            if label.at == Some(at) {
+                positions.push(Position::Address(name.clone()));
+            }
+            if let Some(SizeExpression::Difference(SizeEnd::Offset(end), _)) = label.size {
+                if end == at {
+                    positions.push(Position::SizeEnd(name.clone()));
+                }
+            }
+        }
+
+        positions
     }
 
-    pub fn make_global(&mut self, name: String) {
-        if self.map.contains_key(&*name) {
-            self.map.get_mut(name.as_str()).unwrap().global = true;
-        } else {
-            self.map.insert(name, UnnamedLabel { at: None, global: true });
+    /// Moves all labels at or behind `from` by `by` bytes, except for the positions in `keep`
+    pub fn shift(&mut self, from: usize, by: isize, keep: &[Position]) {
+        let shift = |at: &mut usize, position: Position| {
+            if *at >= from && !keep.contains(&position) {
+                *at = (*at as isize + by) as usize;
+            }
+        };
+
+        for (name, label) in &mut self.map {
+            if let Some(at) = &mut label.at {
+                shift(at, Position::Address(name.clone()));
+            }
+            if let Some(SizeExpression::Difference(SizeEnd::Offset(end), _)) = &mut label.size {
+                shift(end, Position::SizeEnd(name.clone()));
+            }
         }
     }
 
-    pub fn iter(&self) -> Iter {
+    /// Takes over the labels of a subsection that's appended at `offset`, combining the declarations of symbols
+    /// mentioned in both
+    pub fn append(&mut self, mut other: LabelMap, offset: usize) {
+        other.shift(0, offset as isize, &[]);
+
+        for (name, other) in other.map {
+            let label = self.map.entry(name).or_default();
+
+            label.at = label.at.or(other.at);
+            if other.binding != Binding::Local && label.binding != Binding::Weak {
+                label.binding = other.binding;
+            }
+            if other.visibility != Visibility::Default {
+                label.visibility = other.visibility;
+            }
+            if other.typ != SymbolType::NoType {
+                label.typ = other.typ;
+            }
+            label.size = label.size.take().or(other.size);
+        }
+    }
+
+    pub fn get(&self, name: &str) -> Option<Label> {
+        let label = self.map.get(name)?;
+
+        label.at.map(|at| Label { name: name.to_string(), at })
+    }
+
+    /// The attributes of all symbols mentioned in this section, whether they are defined here or not
+    pub fn attributes(&self) -> impl Iterator<Item = (&String, Attributes)> {
+        self.map.iter().map(|(name, label)| {
+            let size = label.size.as_ref().and_then(|size| match size {
+                SizeExpression::Constant(size) => Some(*size),
+                SizeExpression::Difference(end, start) => {
+                    let end = match end {
+                        SizeEnd::Offset(end) => *end,
+                        SizeEnd::Label(end) => self.get(end)?.at,
+                    };
+                    Some(end.checked_sub(self.get(start)?.at)? as u64)
+                }
+            });
+
+            (name, Attributes { binding: label.binding, visibility: label.visibility, typ: label.typ, size })
+        })
+    }
+
+    pub fn iter(&self) -> Iter<'_> {
         Iter {
             inner: self.map.iter()
         }
@@ -54,21 +209,9 @@ impl<'a> Iterator for Iter<'a> {
     type Item = Label;
 
     fn next(&mut self) -> Option<Self::Item> {
-        let i = self.inner.next();
-
-        if let Some(i) = i {
-            if i.1.at.is_some() {
-                Some(Label {
-                    name: i.0.clone(),
-                    at: i.1.at.unwrap(),
-                    global: i.1.global
-                })
-            } else {
-                None
-            }
-        } else {
-            None
-        }
+        // Skip symbols that are only declared, but not defined in this section
+        self.inner
+            .by_ref()
+            .find_map(|(name, label)| label.at.map(|at| Label { name: name.clone(), at }))
     }
 }
-
@@ -6,24 +6,76 @@
 
 mod label;
 mod section;
+#[cfg(test)]
+mod tests;
 
+use crate::assembler::label::{Attributes, Binding, SizeEnd, SizeExpression};
 use crate::assembler::section::Section;
-use crate::parse::helpers::Relativity;
-use crate::parse::{Directive, Line};
-use object::write::{Mangling, Relocation, StandardSection, Symbol, SymbolSection};
+use crate::instructions::Reference;
+use crate::parse::helpers::{ImmediateType, Relativity, Specifier};
+use crate::parse::{
+    Directive, ElfFlags, ElfSectionType, Line, MachOAttribute, MachOSectionType, SectionOptions, SymbolSize,
+    SymbolType, Visibility,
+};
+use indexmap::IndexMap;
+use object::read::elf::FileHeader;
+use object::write::{Comdat, Mangling, Relocation, SectionId, StandardSection, Symbol, SymbolId, SymbolSection};
 use object::{
-    write, Architecture, BinaryFormat, Endianness, RelocationEncoding, RelocationKind, SectionKind, SymbolFlags,
-    SymbolKind, SymbolScope,
+    elf, macho, write, Architecture, BinaryFormat, ComdatKind, Endianness, RelocationEncoding, RelocationKind,
+    SectionFlags, SectionKind, SymbolFlags, SymbolKind, SymbolScope,
 };
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::error::Error;
+use std::fmt::{Display, Formatter};
 use std::fs::File;
+use std::io::Write;
 use std::path::Path;
+use std::str::FromStr;
 
 pub struct Module {
-    sections: HashMap<String, Section>,
+    sections: IndexMap<String, Section>,
+    /// Values assigned by `.set` and its siblings, the last assignment wins
+    constants: IndexMap<String, i64>,
+    /// Size and alignment of common symbols, the largest of each wins
+    commons: IndexMap<String, (u64, u64)>,
+}
+
+#[derive(Debug)]
+pub enum AssemblerError {
+    BranchOutOfRange(String),
+    UnresolvedDifference(String, String),
+    DifferenceOutOfRange(String, String),
+    UnsupportedRelocation(String, Relativity, u8, ObjectFileType),
+    InitializedData(String),
+    EmptySectionStack,
 }
 
+impl Display for AssemblerError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AssemblerError::BranchOutOfRange(to) => write!(f, "Branch target '{}' is out of range", to),
+            AssemblerError::UnresolvedDifference(end, start) => {
+                write!(f, "'{}' and '{}' have to be defined in the same section to subtract them", end, start)
+            }
+            AssemblerError::DifferenceOutOfRange(end, start) => {
+                write!(f, "Distance from '{}' to '{}' doesn't fit into its field", start, end)
+            }
+            AssemblerError::UnsupportedRelocation(to, rel, size, object_type) => {
+                let rel = if *rel == Relativity::Absolute { "absolute" } else { "PC-relative" };
+                let object_type: &str = (*object_type).into();
+                write!(f, "{}-bit {} reference to '{}' can't be expressed in {} files", size, rel, to, object_type)
+            }
+            AssemblerError::InitializedData(section) => {
+                write!(f, "Section '{}' is uninitialized, so it can only hold zeros", section)
+            }
+            AssemblerError::EmptySectionStack => write!(f, ".popsection without a matching .pushsection"),
+        }
+    }
+}
+
+impl Error for AssemblerError {}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
 pub enum ObjectFileType {
     Elf,
     MachO,
@@ -39,38 +91,470 @@ impl Default for ObjectFileType {
     }
 }
 
-impl Into<&'static str> for ObjectFileType {
-    fn into(self) -> &'static str {
+impl From<ObjectFileType> for &'static str {
+    fn from(object_type: ObjectFileType) -> Self {
         use ObjectFileType::*;
 
-        match self {
+        match object_type {
             Elf => "elf",
             MachO => "macho",
         }
     }
 }
 
-impl Module {
-    pub fn from_lines(lines: Vec<Line>) -> Self {
-        let mut sections = HashMap::new();
-        sections.insert("__TEXT,__text".to_string(), Section::new());
+impl FromStr for ObjectFileType {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        use ObjectFileType::*;
+
+        match s {
+            "elf" => Ok(Elf),
+            "macho" => Ok(MachO),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Maps the ELF and Mach-O spellings of the standard sections onto one name, so e.g. `.text` and `__TEXT,__text`
+/// end up in the same section, no matter which format we're writing
+fn canonical_section_name(name: String) -> String {
+    match name.as_str() {
+        ".text" | "__TEXT,__text" => "text",
+        ".data" | "__DATA,__data" => "data",
+        ".rodata" | "__TEXT,__const" | "__DATA,__const" | "__TEXT,__literal4" => "rodata",
+        "__TEXT,__cstring" => "rodata.str",
+        ".bss" | "__DATA,__bss" => "bss",
+        _ => return name,
+    }
+    .to_string()
+}
+
+/// Guesses the kind of a non-standard ELF section from its name, e.g. `.text.startup` or `.rodata.cst8`
+fn elf_section_kind(name: &str) -> SectionKind {
+    if name.starts_with(".text") {
+        SectionKind::Text
+    } else if name.starts_with(".data") {
+        SectionKind::Data
+    } else if name.starts_with(".rodata") {
+        SectionKind::ReadOnlyData
+    } else if name.starts_with(".bss") {
+        SectionKind::UninitializedData
+    } else if name.starts_with(".note") {
+        SectionKind::Note
+    } else {
+        SectionKind::Other
+    }
+}
+
+/// Whether padding in a section should be NOPs, instead of zeros
+fn is_code_section(name: &str) -> bool {
+    name == "text" || elf_section_kind(name) == SectionKind::Text
+}
+
+fn is_uninitialized_section(name: &str) -> bool {
+    name == "bss" || elf_section_kind(name) == SectionKind::UninitializedData
+}
+
+fn new_section(name: &str, options: &SectionOptions) -> Section {
+    let (code, uninitialized) = match options {
+        SectionOptions::None => (is_code_section(name), is_uninitialized_section(name)),
+        SectionOptions::Elf { flags, typ: None, .. } => (flags.exec, is_uninitialized_section(name)),
+        SectionOptions::Elf { flags, typ: Some(typ), .. } => (flags.exec, *typ == ElfSectionType::NoBits),
+        SectionOptions::MachO { typ, attributes } => (
+            attributes.contains(&MachOAttribute::PureInstructions),
+            matches!(typ, MachOSectionType::Zerofill | MachOSectionType::ThreadLocalZerofill),
+        ),
+    };
+
+    let mut section = if uninitialized { Section::uninitialized() } else { Section::new(code) };
+    section.options = options.clone();
+    section
+}
+
+/// Creates the subsection `key` unless it exists, like the other subsections of its section if there are any
+fn enter_section(subsections: &mut IndexMap<(String, usize), Section>, key: &(String, usize), options: SectionOptions) {
+    if !subsections.contains_key(key) {
+        let sibling = subsections.iter().find(|((name, _), _)| *name == key.0);
+        let section = match sibling {
+            Some((_, sibling)) => sibling.subsection(),
+            None => new_section(&key.0, &options),
+        };
+        subsections.insert(key.clone(), section);
+    }
+
+    // Sections are usually only described the first time they're named
+    let section = subsections.get_mut(key).unwrap();
+    if section.options == SectionOptions::None {
+        section.options = options;
+    }
+}
+
+/// Not in the object crate yet, keeps the linker from garbage collecting a section
+const SHF_GNU_RETAIN: u32 = 1 << 21;
+
+/// Picks the kind and flags of an ELF section from the flags and type given to `.section`
+fn elf_section(
+    name: &str,
+    flags: &ElfFlags,
+    typ: Option<ElfSectionType>,
+    entity_size: Option<u64>,
+) -> (SectionKind, u64) {
+    let kind = match typ {
+        Some(ElfSectionType::NoBits) if flags.tls => SectionKind::UninitializedTls,
+        Some(ElfSectionType::NoBits) => SectionKind::UninitializedData,
+        Some(ElfSectionType::Note) => SectionKind::Note,
+        Some(ElfSectionType::InitArray) => SectionKind::Elf(elf::SHT_INIT_ARRAY),
+        Some(ElfSectionType::FiniArray) => SectionKind::Elf(elf::SHT_FINI_ARRAY),
+        Some(ElfSectionType::PreinitArray) => SectionKind::Elf(elf::SHT_PREINIT_ARRAY),
+        Some(ElfSectionType::Unwind) => SectionKind::Elf(elf::SHT_X86_64_UNWIND),
+        // Without a type, GNU as goes by the name
+        None if matches!(elf_section_kind(name), SectionKind::UninitializedData | SectionKind::Note) => {
+            elf_section_kind(name)
+        }
+        _ if flags.merge && flags.strings && entity_size == Some(1) && flags.alloc => SectionKind::ReadOnlyString,
+        _ if flags.merge && flags.strings && entity_size == Some(1) => SectionKind::OtherString,
+        _ if flags.exec => SectionKind::Text,
+        _ if flags.tls => SectionKind::Tls,
+        _ if flags.write => SectionKind::Data,
+        _ if flags.alloc => SectionKind::ReadOnlyData,
+        _ => SectionKind::Other,
+    };
+
+    let mut sh_flags = 0;
+    for (set, flag) in [
+        (flags.alloc, elf::SHF_ALLOC),
+        (flags.write, elf::SHF_WRITE),
+        (flags.exec, elf::SHF_EXECINSTR),
+        (flags.tls, elf::SHF_TLS),
+        (flags.retain, SHF_GNU_RETAIN),
+        (flags.exclude, elf::SHF_EXCLUDE),
+        (flags.merge, elf::SHF_MERGE),
+        (flags.strings, elf::SHF_STRINGS),
+    ] {
+        if set {
+            sh_flags |= flag;
+        }
+    }
+
+    (kind, sh_flags as u64)
+}
+
+/// Writes the entity sizes of merged sections into the headers of an ELF file, since the object crate only writes one
+/// for strings of single bytes
+fn set_entity_sizes(data: &mut [u8], entity_sizes: &HashMap<String, u64>) -> Result<(), Box<dyn Error>> {
+    let header = elf::FileHeader64::<Endianness>::parse(&*data)?;
+    let endian = header.endian()?;
+    let offset = header.e_shoff(endian) as usize;
+
+    let table = header.sections(endian, &*data)?;
+    let mut sizes = Vec::with_capacity(table.len());
+    for section in table.iter() {
+        let name = String::from_utf8_lossy(table.section_name(endian, section)?);
+        sizes.push(entity_sizes.get(name.as_ref()).copied());
+    }
+
+    let (headers, _) =
+        object::slice_from_bytes_mut::<elf::SectionHeader64<Endianness>>(&mut data[offset..], sizes.len())
+            .map_err(|_| "Invalid section headers")?;
+    for (header, size) in headers.iter_mut().zip(sizes) {
+        if let Some(size) = size {
+            header.sh_entsize.set(endian, size);
+        }
+    }
 
-        let mut current_section = sections.get_mut("__TEXT,__text").unwrap();
+    Ok(())
+}
+
+/// Picks the kind and flags of a Mach-O section from the type and attributes given to `.section`
+fn macho_section(typ: MachOSectionType, attributes: &[MachOAttribute]) -> (SectionKind, u32) {
+    let (kind, mut flags) = match typ {
+        MachOSectionType::Regular => (SectionKind::Other, macho::S_REGULAR),
+        MachOSectionType::Zerofill => (SectionKind::UninitializedData, macho::S_ZEROFILL),
+        MachOSectionType::CStringLiterals => (SectionKind::ReadOnlyString, macho::S_CSTRING_LITERALS),
+        MachOSectionType::FourByteLiterals => (SectionKind::ReadOnlyData, macho::S_4BYTE_LITERALS),
+        MachOSectionType::EightByteLiterals => (SectionKind::ReadOnlyData, macho::S_8BYTE_LITERALS),
+        MachOSectionType::SixteenByteLiterals => (SectionKind::ReadOnlyData, macho::S_16BYTE_LITERALS),
+        MachOSectionType::LiteralPointers => (SectionKind::Data, macho::S_LITERAL_POINTERS),
+        MachOSectionType::NonLazySymbolPointers => (SectionKind::Data, macho::S_NON_LAZY_SYMBOL_POINTERS),
+        MachOSectionType::LazySymbolPointers => (SectionKind::Data, macho::S_LAZY_SYMBOL_POINTERS),
+        MachOSectionType::ModInitFuncs => (SectionKind::Data, macho::S_MOD_INIT_FUNC_POINTERS),
+        MachOSectionType::ModTermFuncs => (SectionKind::Data, macho::S_MOD_TERM_FUNC_POINTERS),
+        MachOSectionType::Coalesced => (SectionKind::Other, macho::S_COALESCED),
+        MachOSectionType::Interposing => (SectionKind::Data, macho::S_INTERPOSING),
+        MachOSectionType::ThreadLocalRegular => (SectionKind::Tls, macho::S_THREAD_LOCAL_REGULAR),
+        MachOSectionType::ThreadLocalZerofill => (SectionKind::UninitializedTls, macho::S_THREAD_LOCAL_ZEROFILL),
+        MachOSectionType::ThreadLocalVariables => (SectionKind::Data, macho::S_THREAD_LOCAL_VARIABLES),
+        MachOSectionType::ThreadLocalVariablePointers => (SectionKind::Data, macho::S_THREAD_LOCAL_VARIABLE_POINTERS),
+        MachOSectionType::ThreadLocalInitFunctionPointers => {
+            (SectionKind::Data, macho::S_THREAD_LOCAL_INIT_FUNCTION_POINTERS)
+        }
+    };
+
+    for attribute in attributes {
+        flags |= match attribute {
+            // Like other assemblers, mark the section as holding instructions, since that's what it's for
+            MachOAttribute::PureInstructions => macho::S_ATTR_PURE_INSTRUCTIONS | macho::S_ATTR_SOME_INSTRUCTIONS,
+            MachOAttribute::NoToc => macho::S_ATTR_NO_TOC,
+            MachOAttribute::StripStaticSyms => macho::S_ATTR_STRIP_STATIC_SYMS,
+            MachOAttribute::NoDeadStrip => macho::S_ATTR_NO_DEAD_STRIP,
+            MachOAttribute::LiveSupport => macho::S_ATTR_LIVE_SUPPORT,
+            MachOAttribute::SelfModifyingCode => macho::S_ATTR_SELF_MODIFYING_CODE,
+            MachOAttribute::Debug => macho::S_ATTR_DEBUG,
+        };
+    }
+
+    let kind = match kind {
+        SectionKind::Other if attributes.contains(&MachOAttribute::PureInstructions) => SectionKind::Text,
+        SectionKind::Other if attributes.contains(&MachOAttribute::Debug) => SectionKind::Debug,
+        kind => kind,
+    };
+
+    (kind, flags)
+}
+
+/// Whether a line puts anything but zeros into its section
+fn writes_data(line: &Line) -> bool {
+    match line {
+        Line::Instruction(_) => true,
+        Line::Directive(Directive::Ascii(string)) => !string.is_empty(),
+        Line::Directive(Directive::Asciz(_)) => true,
+        Line::Directive(Directive::Data(values)) => {
+            values.iter().any(|value| !matches!(value.typ, ImmediateType::Integer(0)))
+        }
+        Line::Directive(Directive::Fill { value, .. }) => *value != 0,
+        _ => false,
+    }
+}
+
+/// The kind of a symbol with the given `.type`, symbols without one are `untyped`
+fn symbol_kind(typ: SymbolType, untyped: SymbolKind) -> SymbolKind {
+    match typ {
+        SymbolType::Function => SymbolKind::Text,
+        SymbolType::Object => SymbolKind::Data,
+        SymbolType::Tls => SymbolKind::Tls,
+        SymbolType::NoType => untyped,
+    }
+}
+
+fn symbol_scope(attributes: &Attributes) -> SymbolScope {
+    match (attributes.binding, attributes.visibility) {
+        (Binding::Local, _) => SymbolScope::Compilation,
+        (_, Visibility::Hidden | Visibility::Internal) => SymbolScope::Linkage,
+        _ => SymbolScope::Dynamic,
+    }
+}
+
+/// The object crate only knows default and hidden visibility, so protected and internal symbols need their ELF
+/// flags spelled out
+fn elf_symbol_flags(kind: SymbolKind, attributes: &Attributes) -> SymbolFlags<SectionId> {
+    let st_other = match attributes.visibility {
+        Visibility::Protected => elf::STV_PROTECTED,
+        Visibility::Internal => elf::STV_INTERNAL,
+        _ => return SymbolFlags::None,
+    };
+    let st_bind = match attributes.binding {
+        Binding::Local => elf::STB_LOCAL,
+        Binding::Global => elf::STB_GLOBAL,
+        Binding::Weak => elf::STB_WEAK,
+    };
+    let st_type = match kind {
+        SymbolKind::Text => elf::STT_FUNC,
+        SymbolKind::Data => elf::STT_OBJECT,
+        SymbolKind::Tls => elf::STT_TLS,
+        _ => elf::STT_NOTYPE,
+    };
+
+    SymbolFlags::Elf { st_info: st_bind << 4 | st_type, st_other }
+}
+
+/// Picks the relocation for a reference, or None if the object file format can't express it. `data` is the section's
+/// contents, which tell GOT loads apart from other GOT references on Mach-O.
+fn relocation_kind(
+    reference: &Reference,
+    object_type: ObjectFileType,
+    data: &[u8],
+) -> Option<(RelocationKind, RelocationEncoding, i64)> {
+    let size = reference.size.bytes() as u8 * 8;
+    let pc_relative = reference.rel != Relativity::Absolute;
+
+    // PC-relative addends already include the distance to the end of the instruction. The object crate moves them
+    // into the section data for Mach-O.
+    let (kind, encoding) = match (reference.specifier, reference.rel, object_type, size) {
+        // R_X86_64_32S for values the CPU sign-extends, R_X86_64_32 for zero-extended ones
+        (None, Relativity::Absolute, ObjectFileType::Elf, 32) if reference.sign_extended => {
+            (RelocationKind::Absolute, RelocationEncoding::X86Signed)
+        }
+        (None, Relativity::Absolute, ObjectFileType::Elf, _) => (RelocationKind::Absolute, RelocationEncoding::Generic),
+        (None | Some(Specifier::Plt), Relativity::Relative, ObjectFileType::Elf, 32) => {
+            (RelocationKind::PltRelative, RelocationEncoding::X86Branch)
+        }
+        (None, Relativity::Relative, ObjectFileType::Elf, _) => (RelocationKind::Relative, RelocationEncoding::Generic),
+        // Mach-O only has 32- and 64-bit absolute and 32-bit PC-relative relocations
+        (None, Relativity::Absolute, ObjectFileType::MachO, 32 | 64) => {
+            (RelocationKind::Absolute, RelocationEncoding::Generic)
+        }
+        (None, Relativity::Relative, ObjectFileType::MachO, 32) => {
+            (RelocationKind::Relative, RelocationEncoding::X86Branch)
+        }
+        (None, Relativity::RipRelative, _, 32) => (RelocationKind::Relative, RelocationEncoding::X86RipRelative),
+        (Some(Specifier::Plt), Relativity::RipRelative, ObjectFileType::Elf, 32) => {
+            (RelocationKind::PltRelative, RelocationEncoding::Generic)
+        }
+        (Some(Specifier::GotPcRel), _, ObjectFileType::Elf, 32) if pc_relative => {
+            (RelocationKind::GotRelative, RelocationEncoding::Generic)
+        }
+        (Some(specifier), _, ObjectFileType::Elf, _) => {
+            let r_type = match (specifier, pc_relative, size) {
+                (Specifier::GotOff, false, 64) => elf::R_X86_64_GOTOFF64,
+                (Specifier::TpOff, false, 32) => elf::R_X86_64_TPOFF32,
+                (Specifier::TpOff, false, 64) => elf::R_X86_64_TPOFF64,
+                (Specifier::GotTpOff, true, 32) => elf::R_X86_64_GOTTPOFF,
+                (Specifier::TlsGd, true, 32) => elf::R_X86_64_TLSGD,
+                (Specifier::TlsLd, true, 32) => elf::R_X86_64_TLSLD,
+                (Specifier::DtpOff, false, 32) => elf::R_X86_64_DTPOFF32,
+                (Specifier::DtpOff, false, 64) => elf::R_X86_64_DTPOFF64,
+                _ => return None,
+            };
+            (RelocationKind::Elf(r_type), RelocationEncoding::Generic)
+        }
+        (Some(Specifier::GotPcRel), _, ObjectFileType::MachO, 32) if pc_relative => {
+            // The linker may turn `mov r64, [rip + _foo@GOTPCREL]` into a lea, if it knows that's what it is
+            let at = reference.at;
+            let load = at >= 3 && data[at - 3] & 0xF8 == 0x48 && data[at - 2] == 0x8B;
+            let encoding = if load { RelocationEncoding::X86RipRelativeMovq } else { RelocationEncoding::Generic };
+            (RelocationKind::GotRelative, encoding)
+        }
+        (Some(Specifier::Tlvp), _, ObjectFileType::MachO, 32) if pc_relative => {
+            // Unlike its own PC-relative kinds, the object crate moves the whole addend of raw Mach-O relocations
+            // into the section data, which is relative to the end of the field there
+            let kind = RelocationKind::MachO { value: macho::X86_64_RELOC_TLV, relative: true };
+            return Some((kind, RelocationEncoding::Generic, reference.addend + 4));
+        }
+        _ => return None,
+    };
+
+    Some((kind, encoding, reference.addend))
+}
+
+/// The attributes of every symbol, combined from all sections. Symbol directives don't have to be in the section the
+/// symbol is defined in.
+fn merged_attributes(sections: &IndexMap<String, Section>) -> HashMap<String, Attributes> {
+    let mut attributes: HashMap<String, Attributes> = HashMap::new();
+    for sec in sections.values() {
+        for (name, attr) in sec.label_map.attributes() {
+            attributes.entry(name.clone()).or_default().merge(&attr);
+        }
+    }
+
+    attributes
+}
+
+impl Module {
+    pub fn from_lines(lines: Vec<Line>) -> Result<Self, AssemblerError> {
+        // Subsections are only concatenated at the end, so they're kept apart until then
+        let mut subsections = IndexMap::new();
+        subsections.insert(("text".to_string(), 0), Section::new(true));
+
+        let mut current = ("text".to_string(), 0);
+        let mut previous = current.clone();
+        let mut stack = Vec::new();
+        let mut constants = IndexMap::new();
+        let mut commons: IndexMap<String, (u64, u64)> = IndexMap::new();
+        let mut locals = HashSet::new();
 
         for line in lines {
+            let current_section = subsections.get_mut(&current).unwrap();
+            if current_section.uninitialized && writes_data(&line) {
+                return Err(AssemblerError::InitializedData(current.0));
+            }
+
             match line {
                 Line::Directive(dir) => match dir {
+                    Directive::Align { alignment, fill, max_skip } => {
+                        current_section.align(alignment, fill, max_skip);
+                    }
+                    Directive::Ascii(string) => {
+                        current_section.write_ascii(&string);
+                    }
                     Directive::Asciz(string) => {
-                        current_section.write_string(string);
+                        current_section.write_string(&string);
+                    }
+                    Directive::Data(values) => {
+                        for value in &values {
+                            current_section.write_data(value);
+                        }
+                    }
+                    Directive::Fill { repeat, size, value } => {
+                        current_section.write_fill(repeat, size, value);
                     }
                     Directive::Global(name) => {
-                        current_section.label_map.make_global(name);
+                        current_section.label_map.set_binding(name, Binding::Global);
+                    }
+                    Directive::Local(name) => {
+                        current_section.label_map.set_binding(name.clone(), Binding::Local);
+                        locals.insert(name);
+                    }
+                    Directive::Weak(name) => {
+                        current_section.label_map.set_binding(name, Binding::Weak);
+                    }
+                    Directive::PrivateExtern(name) => {
+                        current_section.label_map.set_binding(name.clone(), Binding::Global);
+                        current_section.label_map.set_visibility(name, Visibility::Hidden);
                     }
-                    Directive::Section(name) => {
-                        if !sections.contains_key(name.as_str()) {
-                            sections.insert(name.clone(), Section::new());
+                    Directive::Visibility(name, visibility) => {
+                        current_section.label_map.set_visibility(name, visibility);
+                    }
+                    Directive::Type(name, typ) => {
+                        current_section.label_map.set_type(name, typ);
+                    }
+                    Directive::Size(name, size) => {
+                        let size = match size {
+                            SymbolSize::Constant(size) => SizeExpression::Constant(size),
+                            SymbolSize::Difference(None, start) => {
+                                SizeExpression::Difference(SizeEnd::Offset(current_section.at()), start)
+                            }
+                            SymbolSize::Difference(Some(end), start) => {
+                                SizeExpression::Difference(SizeEnd::Label(end), start)
+                            }
+                        };
+                        current_section.label_map.set_size(name, size);
+                    }
+                    Directive::Set(name, value) => {
+                        constants.insert(name, value);
+                    }
+                    Directive::Common { name, size, alignment } => {
+                        let common = commons.entry(name).or_default();
+                        *common = (common.0.max(size), common.1.max(alignment));
+                    }
+                    Directive::Section { name, options, subsection } => {
+                        previous = std::mem::replace(&mut current, (canonical_section_name(name), subsection));
+                        enter_section(&mut subsections, &current, options);
+                    }
+                    Directive::PushSection { name, options, subsection } => {
+                        let next = (canonical_section_name(name), subsection);
+                        stack.push((std::mem::replace(&mut current, next), previous.clone()));
+                        enter_section(&mut subsections, &current, options);
+                    }
+                    Directive::PopSection => {
+                        (current, previous) = stack.pop().ok_or(AssemblerError::EmptySectionStack)?;
+                    }
+                    Directive::Previous => {
+                        std::mem::swap(&mut current, &mut previous);
+                    }
+                    Directive::Subsection(subsection) => {
+                        let next = (current.0.clone(), subsection);
+                        previous = std::mem::replace(&mut current, next);
+                        enter_section(&mut subsections, &current, SectionOptions::None);
+                    }
+                    // Unlike .section, this doesn't switch to the section
+                    Directive::Zerofill { section, name, size, alignment } => {
+                        let key = (canonical_section_name(section), 0);
+                        let zerofill = subsections.entry(key).or_insert_with(Section::uninitialized);
+
+                        if let Some(name) = name {
+                            zerofill.allocate(name, size, alignment);
                         }
-                        current_section = sections.get_mut(name.as_str()).unwrap()
                     }
                     _ => {}
                 },
@@ -81,12 +565,78 @@ impl Module {
             }
         }
 
-        Module { sections }
+        // Local common symbols can't be merged with anything, so they're allocated in .bss like .lcomm does
+        let local_commons: Vec<_> = commons.keys().filter(|name| locals.contains(*name)).cloned().collect();
+        for name in local_commons {
+            let (size, alignment) = commons.shift_remove(&name).unwrap();
+            let bss = subsections.entry(("bss".to_string(), 0)).or_insert_with(Section::uninitialized);
+            bss.allocate(name, size as usize, alignment as usize);
+        }
+
+        // Sections stay in the order they were first used in, so that the output is the same every time
+        let mut grouped: IndexMap<String, Vec<(usize, Section)>> = IndexMap::new();
+        for ((name, number), subsection) in subsections {
+            grouped.entry(name).or_default().push((number, subsection));
+        }
+
+        let mut sections = IndexMap::new();
+        for (name, mut subsections) in grouped {
+            subsections.sort_by_key(|(number, _)| *number);
+
+            let mut subsections = subsections.into_iter().map(|(_, subsection)| subsection);
+            let mut section = subsections.next().unwrap();
+            subsections.for_each(|subsection| section.append(subsection));
+            sections.insert(name, section);
+        }
+
+        // A symbol might be declared global in another section than the one it's defined in
+        let attributes = merged_attributes(&sections);
+        for section in sections.values_mut() {
+            section.relax(&attributes)?;
+        }
+
+        // Differences don't have to be in the section their labels are in, so they're resolved once all are laid out
+        let mut labels = HashMap::new();
+        for (index, section) in sections.values().enumerate() {
+            labels.extend(section.label_map.iter().map(|label| (label.name, (index, label.at))));
+        }
+        for section in sections.values_mut() {
+            section.resolve_references(&attributes, &labels)?;
+        }
+
+        Ok(Module { sections, constants, commons })
     }
 
     pub fn write_to_file<P: AsRef<Path>>(self, name: P, object_type: ObjectFileType) -> Result<(), Box<dyn Error>> {
-        let file = File::options().create(true).write(true).open(name.as_ref())?;
+        let mut file = File::options().create(true).write(true).truncate(true).open(name.as_ref())?;
+
+        file.write_all(&self.write(object_type)?)?;
+
+        Ok(())
+    }
+
+    /// Writes the object file, with everything the object crate can't express filled in afterwards
+    pub fn write(self, object_type: ObjectFileType) -> Result<Vec<u8>, Box<dyn Error>> {
+        let mut entity_sizes = HashMap::new();
+        if object_type == ObjectFileType::Elf {
+            for (name, section) in &self.sections {
+                if let SectionOptions::Elf { flags, entity_size: Some(size), .. } = &section.options {
+                    if flags.merge {
+                        entity_sizes.insert(name.clone(), *size);
+                    }
+                }
+            }
+        }
 
+        let mut data = self.into_object(object_type)?.write()?;
+        if !entity_sizes.is_empty() {
+            set_entity_sizes(&mut data, &entity_sizes)?;
+        }
+
+        Ok(data)
+    }
+
+    pub fn into_object(self, object_type: ObjectFileType) -> Result<write::Object<'static>, Box<dyn Error>> {
         let binary_format = match object_type {
             ObjectFileType::Elf => BinaryFormat::Elf,
             ObjectFileType::MachO => BinaryFormat::MachO,
@@ -97,101 +647,268 @@ impl Module {
         object.mangling = Mangling::None;
 
         let mut relocations = Vec::new();
+        // The object crate only looks up symbols of some kinds by name, so all of them are kept track of here
+        let mut symbol_ids: HashMap<String, SymbolId> = HashMap::new();
+        // ELF temporaries like .L1 and where they are, they're only added to the symbol table if relocations need them
+        let mut temporaries: HashMap<String, (SectionId, u64)> = HashMap::new();
+
+        let attributes = merged_attributes(&self.sections);
+        // The sections of each COMDAT group, by its signature
+        let mut groups: IndexMap<String, Vec<SectionId>> = IndexMap::new();
 
         for (name, sec) in self.sections {
-            let mut code = false;
+            let standard = match &*name {
+                "text" => Some(StandardSection::Text),
+                "data" => Some(StandardSection::Data),
+                "rodata" => Some(StandardSection::ReadOnlyData),
+                "rodata.str" => Some(StandardSection::ReadOnlyString),
+                "bss" => Some(StandardSection::UninitializedData),
+                // Todo: do the other standard sections
+                _ => None,
+            };
 
-            let section = match &*name {
-                "text" | "__TEXT,__text" => {
-                    code = true;
-                    object.section_id(StandardSection::Text)
+            // Options for the other format are ignored, so the same code can be assembled into both
+            let (options_kind, flags) = match (&sec.options, object_type) {
+                (SectionOptions::Elf { flags, typ, entity_size, group }, ObjectFileType::Elf) => {
+                    let (kind, mut sh_flags) = elf_section(&name, flags, *typ, *entity_size);
+                    if group.is_some() {
+                        sh_flags |= elf::SHF_GROUP as u64;
+                    }
+                    (Some(kind), SectionFlags::Elf { sh_flags })
                 }
-                "data" | "__DATA,__data" => object.section_id(StandardSection::Data),
-                "rodata" | "__TEXT,__const" | "__DATA,__const" | "__TEXT,__literal4" => {
-                    object.section_id(StandardSection::ReadOnlyData)
+                (SectionOptions::MachO { typ, attributes }, ObjectFileType::MachO) => {
+                    let (kind, flags) = macho_section(*typ, attributes);
+                    (Some(kind), SectionFlags::MachO { flags })
                 }
-                "rodata.str" | "__TEXT,__cstring" => object.section_id(StandardSection::ReadOnlyString),
-                "bss" | "__DATA,__bss" => object.section_id(StandardSection::UninitializedData),
-                // Todo: do the other standard sections
-                _ => {
-                    let mut s = name.split(',');
-                    let segment = s.next().unwrap().as_bytes().to_vec();
-                    let section = s.next().unwrap().as_bytes().to_vec();
+                _ => (None, SectionFlags::None),
+            };
 
-                    object.add_section(segment, section, SectionKind::Unknown)
-                }
+            let (section, kind) = if let Some(standard) = standard {
+                (object.section_id(standard), standard.kind())
+            } else {
+                let (segment, section_name, kind) = match object_type {
+                    ObjectFileType::Elf => ("", name.as_str(), elf_section_kind(&name)),
+                    ObjectFileType::MachO => {
+                        let (segment, section) = name.split_once(',').unwrap_or(("", &name));
+                        let kind = if sec.uninitialized { SectionKind::UninitializedData } else { SectionKind::Other };
+                        (segment, section, kind)
+                    }
+                };
+                let kind = options_kind.unwrap_or(kind);
+
+                (object.add_section(segment.as_bytes().to_vec(), section_name.as_bytes().to_vec(), kind), kind)
             };
+            if flags != SectionFlags::None {
+                object.section_mut(section).flags = flags;
+            }
+            if let (SectionOptions::Elf { group: Some(group), .. }, ObjectFileType::Elf) = (&sec.options, object_type) {
+                groups.entry(group.clone()).or_default().push(section);
+            }
+            let code = kind == SectionKind::Text;
 
-            object.append_section_data(section, &*sec.bytes, 4 /*todo: read align from directives*/);
+            // The section might already hold data, if two names map to the same output section
+            let base = if sec.uninitialized {
+                object.append_section_bss(section, sec.at() as u64, sec.alignment as u64)
+            } else {
+                object.append_section_data(section, &sec.bytes, sec.alignment as u64)
+            };
 
             for label in sec.label_map.iter() {
+                let attributes = &attributes[&label.name];
+                let temporary = label.name.starts_with(".L") && attributes.binding == Binding::Local;
+                if object_type == ObjectFileType::Elf && temporary {
+                    temporaries.insert(label.name, (section, base + label.at as u64));
+                    continue;
+                }
+
+                // Like GNU as, ELF symbols without a .type are STT_NOTYPE. Mach-O doesn't store a type, but the object
+                // crate still wants one, so they get the kind of their section there.
+                let untyped = match object_type {
+                    ObjectFileType::Elf => SymbolKind::Label,
+                    ObjectFileType::MachO if code => SymbolKind::Text,
+                    ObjectFileType::MachO => SymbolKind::Data,
+                };
+                let kind = symbol_kind(attributes.typ, untyped);
+
                 let symbol = Symbol {
-                    name: label.name.into_bytes(),
-                    value: label.at as u64,
+                    name: label.name.clone().into_bytes(),
+                    value: 0,
                     size: 0,
-                    kind: if code { SymbolKind::Text } else { SymbolKind::Data },
-                    scope: if label.global { SymbolScope::Dynamic } else { SymbolScope::Linkage },
-                    weak: false,
-                    section: SymbolSection::Absolute,
-                    flags: SymbolFlags::None,
+                    kind,
+                    scope: symbol_scope(attributes),
+                    weak: attributes.binding == Binding::Weak,
+                    section: SymbolSection::Undefined,
+                    flags: match object_type {
+                        ObjectFileType::Elf => elf_symbol_flags(kind, attributes),
+                        ObjectFileType::MachO => SymbolFlags::None,
+                    },
                 };
 
                 let symbol_id = object.add_symbol(symbol);
-                object.set_symbol_data(symbol_id, section, label.at as u64, 0);
+                symbol_ids.insert(label.name, symbol_id);
+                object.set_symbol_data(symbol_id, section, base + label.at as u64, attributes.size.unwrap_or(0));
             }
 
-            for rel in sec.references {
+            for mut rel in sec.references {
+                rel.at += base as usize;
                 relocations.push((section, rel));
             }
         }
 
+        // Constants were already folded into the code, but global ones are still visible to the linker
+        for (name, value) in self.constants {
+            let attributes = attributes.get(&name).cloned().unwrap_or_default();
+            if attributes.binding == Binding::Local {
+                continue;
+            }
+
+            let symbol = Symbol {
+                name: name.clone().into_bytes(),
+                value: value as u64,
+                size: attributes.size.unwrap_or(0),
+                // Untyped constants are STT_NOTYPE on ELF, Mach-O doesn't store a type
+                kind: match object_type {
+                    ObjectFileType::Elf => symbol_kind(attributes.typ, SymbolKind::Label),
+                    ObjectFileType::MachO => symbol_kind(attributes.typ, SymbolKind::Data),
+                },
+                scope: symbol_scope(&attributes),
+                weak: attributes.binding == Binding::Weak,
+                section: SymbolSection::Absolute,
+                flags: SymbolFlags::None,
+            };
+
+            symbol_ids.insert(name, object.add_symbol(symbol));
+        }
+
+        for (name, (size, alignment)) in self.commons {
+            // A definition of the symbol overrides the common one
+            if symbol_ids.contains_key(&name) {
+                continue;
+            }
+
+            // Common symbols are global, unless they're declared .local, which made them into .bss symbols
+            let attributes =
+                Attributes { binding: Binding::Global, ..attributes.get(&name).cloned().unwrap_or_default() };
+            // Common symbols are objects, even without a .type
+            let kind = symbol_kind(attributes.typ, SymbolKind::Data);
+            let mut symbol = Symbol {
+                name: name.clone().into_bytes(),
+                value: 0,
+                size: 0,
+                kind,
+                scope: symbol_scope(&attributes),
+                weak: false,
+                section: SymbolSection::Undefined,
+                flags: SymbolFlags::None,
+            };
+
+            match object_type {
+                // ELF stores a common symbol's alignment as its value
+                ObjectFileType::Elf => {
+                    symbol.section = SymbolSection::Common;
+                    symbol.value = alignment;
+                    symbol.size = size;
+                    symbol.flags = elf_symbol_flags(kind, &attributes);
+                }
+                // Mach-O's are undefined symbols with a size as their value, and the alignment's log2 in n_desc
+                ObjectFileType::MachO => {
+                    symbol.value = size;
+                    symbol.flags = SymbolFlags::MachO { n_desc: (alignment.trailing_zeros() as u16) << 8 };
+                }
+            }
+
+            symbol_ids.insert(name, object.add_symbol(symbol));
+        }
+
+        for (signature, sections) in groups {
+            // Like GNU as, make up a local symbol for signatures that aren't defined anywhere
+            let symbol = match symbol_ids.get(&signature) {
+                Some(symbol) => *symbol,
+                None => object.add_symbol(Symbol {
+                    name: signature.clone().into_bytes(),
+                    value: 0,
+                    size: 0,
+                    kind: SymbolKind::Label,
+                    scope: SymbolScope::Compilation,
+                    weak: false,
+                    section: SymbolSection::Section(sections[0]),
+                    flags: SymbolFlags::None,
+                }),
+            };
+            symbol_ids.insert(signature, symbol);
+
+            object.add_comdat(Comdat { kind: ComdatKind::Any, symbol, sections });
+        }
+
         for rel in relocations {
-            let to_op = object.symbol_id(rel.1.to.as_bytes());
-            let to = if let Some(to_op) = to_op {
-                to_op
+            // Like GNU as, refer to temporaries through their section, unless the relocation needs the symbol itself,
+            // like GOT and PLT relocations do
+            let mut offset = 0;
+            let to = if let (Some(&(section, at)), None) = (temporaries.get(&rel.1.to), rel.1.specifier) {
+                offset = at as i64;
+                object.section_symbol(section)
+            } else if let Some(to) = symbol_ids.get(&rel.1.to) {
+                *to
+            } else if let Some(&(section, at)) = temporaries.get(&rel.1.to) {
+                let symbol = Symbol {
+                    name: rel.1.to.as_bytes().to_vec(),
+                    value: at,
+                    size: 0,
+                    kind: SymbolKind::Label,
+                    scope: SymbolScope::Compilation,
+                    weak: false,
+                    section: SymbolSection::Section(section),
+                    flags: SymbolFlags::None,
+                };
+
+                let to = object.add_symbol(symbol);
+                symbol_ids.insert(rel.1.to.clone(), to);
+                to
             } else {
+                let attributes = attributes.get(&rel.1.to).cloned().unwrap_or_default();
                 let symbol = Symbol {
-                    name: rel.1.to.into_bytes(),
+                    name: rel.1.to.as_bytes().to_vec(),
                     value: 0,
                     size: 0,
-                    kind: SymbolKind::Unknown,
-                    scope: SymbolScope::Unknown,
-                    weak: false,
+                    // Linkers insist on TLS relocations going to TLS symbols
+                    kind: match rel.1.specifier {
+                        Some(specifier) if specifier.is_tls() => SymbolKind::Tls,
+                        _ => SymbolKind::Unknown,
+                    },
+                    scope: match attributes.visibility {
+                        Visibility::Hidden | Visibility::Internal => SymbolScope::Linkage,
+                        _ => SymbolScope::Unknown,
+                    },
+                    weak: attributes.binding == Binding::Weak,
                     section: SymbolSection::Undefined,
                     flags: SymbolFlags::None,
                 };
 
-                object.add_symbol(symbol)
-            };
-
-            let kind = match rel.1.rel {
-                Relativity::Absolute => RelocationKind::Absolute,
-                Relativity::Relative | Relativity::RipRelative => RelocationKind::Relative,
+                let to = object.add_symbol(symbol);
+                symbol_ids.insert(rel.1.to.clone(), to);
+                to
             };
 
-            let encoding = match rel.1.rel {
-                Relativity::Absolute => RelocationEncoding::X86Signed,
-                Relativity::Relative => RelocationEncoding::X86Branch,
-                Relativity::RipRelative => RelocationEncoding::X86RipRelative,
+            let size = rel.1.size.bytes() as u8 * 8;
+
+            let data = object.section(rel.0).data();
+            let (kind, encoding, addend) = match relocation_kind(&rel.1, object_type, data) {
+                Some(kind) => kind,
+                None => {
+                    let to = match rel.1.specifier {
+                        Some(specifier) => format!("{}@{}", rel.1.to, <&str>::from(specifier)),
+                        None => rel.1.to,
+                    };
+                    return Err(AssemblerError::UnsupportedRelocation(to, rel.1.rel, size, object_type).into());
+                }
             };
 
-            let relocation = Relocation {
-                offset: rel.1.at as u64,
-                size: 32, // Todo: This is hardcoded atm
-                kind,
-                encoding,
-                symbol: to,
-                addend: match object_type {
-                    ObjectFileType::Elf => 0,
-                    ObjectFileType::MachO => -4,
-                },
-            };
+            let relocation =
+                Relocation { offset: rel.1.at as u64, size, kind, encoding, symbol: to, addend: addend + offset };
 
             object.add_relocation(rel.0, relocation)?;
         }
 
-        object.write_stream(file)?;
-
-        Ok(())
+        Ok(object)
     }
 }
@@ -4,23 +4,152 @@
  * License, v. 2.0. If a copy of the MPL was not distributed with this
  * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
 
+use std::collections::HashMap;
 use std::io::Write;
-use crate::assembler::label::LabelMap;
-use crate::instructions::{Instruction, Reference};
+use crate::assembler::label::{Attributes, Binding, Label, LabelMap, Position};
+use crate::assembler::AssemblerError;
+use crate::instructions::{Instruction, Reference, Size};
+use crate::number::Number;
+use crate::parse::helpers::{Immediate, ImmediateType, Relativity};
+use crate::parse::SectionOptions;
+
+/// A jump that was written in its short form, and might still have to be relaxed
+struct Branch {
+    at: usize,
+    len: usize,
+    to: String,
+    /// Added to the target's address, like the 2 in `jmp .L1 + 2`
+    addend: i64,
+    relaxed: Option<Instruction>,
+}
+
+/// Padding inserted by an alignment directive, which has to be recomputed when instructions in front of it grow
+struct Alignment {
+    at: usize,
+    len: usize,
+    alignment: usize,
+    fill: Option<u8>,
+    max_skip: Option<usize>,
+    /// Symbols at `at` that were defined in front of the directive. While the padding is empty they can't be told
+    /// apart from those behind it, but they have to stay in front of it once it grows.
+    before: Vec<Position>,
+}
+
+/// The recommended multi-byte NOPs, indexed by their length - 1
+const NOPS: [&[u8]; 9] = [
+    &[0x90],
+    &[0x66, 0x90],
+    &[0x0F, 0x1F, 0x00],
+    &[0x0F, 0x1F, 0x40, 0x00],
+    &[0x0F, 0x1F, 0x44, 0x00, 0x00],
+    &[0x66, 0x0F, 0x1F, 0x44, 0x00, 0x00],
+    &[0x0F, 0x1F, 0x80, 0x00, 0x00, 0x00, 0x00],
+    &[0x0F, 0x1F, 0x84, 0x00, 0x00, 0x00, 0x00, 0x00],
+    &[0x66, 0x0F, 0x1F, 0x84, 0x00, 0x00, 0x00, 0x00, 0x00],
+];
+
+impl Alignment {
+    fn padding(&self, code: bool) -> Vec<u8> {
+        let len = (self.alignment - self.at % self.alignment) % self.alignment;
+        if self.max_skip.is_some_and(|max_skip| len > max_skip) {
+            return Vec::new();
+        }
+
+        match self.fill {
+            // Filling code with single byte NOPs would only waste cycles
+            Some(0x90) | None if code => {
+                let mut padding = Vec::with_capacity(len);
+                while padding.len() < len {
+                    padding.extend(NOPS[(len - padding.len()).min(NOPS.len()) - 1]);
+                }
+                padding
+            }
+            fill => vec![fill.unwrap_or(0); len],
+        }
+    }
+}
 
 pub struct Section {
     pub bytes: Vec<u8>,
     pub references: Vec<Reference>,
     pub label_map: LabelMap,
+    /// The largest alignment requested by any directive in this section
+    pub alignment: usize,
+    /// What `.section` said about the section, if anything
+    pub options: SectionOptions,
+    /// Set for sections like .bss, which only hold zeros and therefore don't take up space in the file
+    pub uninitialized: bool,
+    /// The size of an uninitialized section, which doesn't have any bytes
+    reserved: usize,
+    code: bool,
+    branches: Vec<Branch>,
+    alignments: Vec<Alignment>,
 }
 
 impl Section {
-    pub fn new() -> Self {
-        Section { bytes: Vec::new(), references: Vec::new(), label_map: LabelMap::new() }
+    pub fn new(code: bool) -> Self {
+        Section {
+            bytes: Vec::new(),
+            references: Vec::new(),
+            label_map: LabelMap::new(),
+            alignment: 1,
+            options: SectionOptions::None,
+            uninitialized: false,
+            reserved: 0,
+            code,
+            branches: Vec::new(),
+            alignments: Vec::new(),
+        }
+    }
+
+    pub fn uninitialized() -> Self {
+        Section { uninitialized: true, ..Section::new(false) }
+    }
+
+    /// An empty subsection of this section, which will later be appended to it
+    pub fn subsection(&self) -> Self {
+        Section { options: self.options.clone(), uninitialized: self.uninitialized, ..Section::new(self.code) }
+    }
+
+    /// Appends a subsection, moving everything in it behind the contents of this one
+    pub fn append(&mut self, other: Section) {
+        // Uninitialized sections don't remember their padding, so it can't be fixed up by realign later
+        if self.uninitialized {
+            self.align(other.alignment, None, None);
+        }
+
+        let offset = self.at();
+        self.bytes.extend(other.bytes);
+        self.reserved += other.reserved;
+        self.alignment = self.alignment.max(other.alignment);
+        if self.options == SectionOptions::None {
+            self.options = other.options;
+        }
+
+        self.references.extend(other.references.into_iter().map(|r| Reference { at: r.at + offset, ..r }));
+        self.branches.extend(other.branches.into_iter().map(|b| Branch { at: b.at + offset, ..b }));
+        self.alignments.extend(other.alignments.into_iter().map(|a| Alignment { at: a.at + offset, ..a }));
+        self.label_map.append(other.label_map, offset);
     }
 
     pub fn at(&self) -> usize {
-        self.bytes.len()
+        self.bytes.len() + self.reserved
+    }
+
+    /// Defines a symbol for `size` zeros at the next multiple of `alignment`, like `.lcomm` does
+    pub fn allocate(&mut self, name: String, size: usize, alignment: usize) {
+        self.align(alignment, None, None);
+        self.label_map.insert_label(name, self.at());
+        self.reserve(size);
+    }
+
+    /// Appends `len` zeros, which uninitialized sections only count
+    pub fn reserve(&mut self, len: usize) {
+        if self.uninitialized {
+            self.reserved += len;
+        } else {
+            self.bytes.resize(self.bytes.len() + len, 0);
+        }
     }
 
     pub fn write_instruction(&mut self, instruction: &Instruction) {
@@ -29,13 +158,240 @@ impl Section {
         let encoded = instruction.encode();
         self.bytes.extend(encoded.get_bytes());
         for r in encoded.get_refs() {
-            let new_r = Reference { to: r.to.clone(), at: r.at + reloc_offset, rel: r.rel };
-            self.references.push(new_r);
+            if r.rel == Relativity::Relative && r.size == Size::Byte {
+                let len = encoded.get_bytes().len();
+                self.branches.push(Branch {
+                    at: reloc_offset,
+                    len,
+                    to: r.to.clone(),
+                    // The reference's addend is relative to its field, not to the end of the jump
+                    addend: r.addend + (len - r.at) as i64,
+                    relaxed: instruction.relaxed(),
+                });
+            }
+
+            self.references.push(Reference { at: r.at + reloc_offset, ..r.clone() });
+        }
+    }
+
+    /// Pads the section up to the next multiple of `alignment`, unless that takes more than `max_skip` bytes
+    pub fn align(&mut self, alignment: usize, fill: Option<u8>, max_skip: Option<usize>) {
+        self.alignment = self.alignment.max(alignment);
+
+        let before = self.label_map.positions_at(self.at());
+        let alignment = Alignment { at: self.at(), len: 0, alignment, fill, max_skip, before };
+        let padding = alignment.padding(self.code);
+
+        // Nothing in uninitialized sections can grow, so their padding never has to be recomputed
+        if self.uninitialized {
+            self.reserve(padding.len());
+            return;
+        }
+
+        self.bytes.extend(&padding);
+        self.alignments.push(Alignment { len: padding.len(), ..alignment });
+    }
+
+    /// Replaces the `len` bytes at `at` with `bytes`, moving references, branches and labels behind them, except for
+    /// the positions in `keep`. Returns by how much everything was moved.
+    fn splice(&mut self, at: usize, len: usize, bytes: &[u8], keep: &[Position]) -> isize {
+        let moved = bytes.len() as isize - len as isize;
+        let shift = |pos: &mut usize| {
+            if *pos >= at + len {
+                *pos = (*pos as isize + moved) as usize;
+            }
+        };
+
+        self.bytes.splice(at..at + len, bytes.iter().copied());
+        self.references.retain(|r| r.at < at || r.at >= at + len);
+
+        self.references.iter_mut().for_each(|r| shift(&mut r.at));
+        self.branches.iter_mut().for_each(|b| shift(&mut b.at));
+        self.label_map.shift(at + len, moved, keep);
+
+        moved
+    }
+
+    /// Replaces the `len` bytes long instruction at `at` with another one, moving everything behind it
+    fn replace_instruction(&mut self, at: usize, len: usize, instruction: &Instruction) {
+        let encoded = instruction.encode();
+        let moved = self.splice(at, len, encoded.get_bytes(), &[]);
+
+        for alignment in &mut self.alignments {
+            if alignment.at >= at + len {
+                alignment.at = (alignment.at as isize + moved) as usize;
+            }
+        }
+
+        for r in encoded.get_refs() {
+            self.references.push(Reference { at: r.at + at, ..r.clone() });
+        }
+    }
+
+    /// Recomputes the padding of all alignment directives, returns whether any of it changed
+    fn realign(&mut self) -> bool {
+        let mut changed = false;
+
+        for i in 0..self.alignments.len() {
+            let alignment = &self.alignments[i];
+            let (at, len) = (alignment.at, alignment.len);
+            let padding = alignment.padding(self.code);
+
+            if padding.len() != len {
+                let before = alignment.before.clone();
+                let moved = self.splice(at, len, &padding, &before);
+
+                self.alignments[i].len = padding.len();
+                for alignment in &mut self.alignments[i + 1..] {
+                    alignment.at = (alignment.at as isize + moved) as usize;
+                }
+                changed = true;
+            }
+        }
+
+        changed
+    }
+
+    /// A label defined in this section that no section declared global or weak, so references to it can be resolved
+    /// in place
+    fn local_label(&self, name: &str, attributes: &HashMap<String, Attributes>) -> Option<Label> {
+        let binding = attributes.get(name).map_or(Binding::Local, |attributes| attributes.binding);
+
+        self.label_map.get(name).filter(|_| binding == Binding::Local)
+    }
+
+    /// Grows short jumps, whose target is too far away or not known in this section, into their rel32 forms. Since
+    /// that moves labels, this repeats until no more jumps have to grow. `attributes` are those of the whole module.
+    pub fn relax(&mut self, attributes: &HashMap<String, Attributes>) -> Result<(), AssemblerError> {
+        loop {
+            let mut grown = false;
+            let mut i = 0;
+
+            while i < self.branches.len() {
+                let branch = &self.branches[i];
+                let end = branch.at + branch.len;
+
+                let label = self.local_label(&branch.to, attributes);
+                if label.is_some_and(|label| i8::try_from(label.at as i64 + branch.addend - end as i64).is_ok()) {
+                    i += 1;
+                    continue;
+                }
+
+                let branch = self.branches.remove(i);
+                let relaxed = branch.relaxed.ok_or(AssemblerError::BranchOutOfRange(branch.to))?;
+                self.replace_instruction(branch.at, branch.len, &relaxed);
+                grown = true;
+            }
+
+            // Grown jumps move alignment directives, whose padding might then move other jumps' targets
+            if !self.realign() && !grown {
+                return Ok(());
+            }
         }
     }
 
-    pub fn write_string(&mut self, string: String) {
-        self.bytes.write(string.as_bytes()).unwrap();
-        self.bytes.write(&[0]).unwrap();
+    pub fn write_ascii(&mut self, string: &[u8]) {
+        self.bytes.write_all(string).unwrap();
+    }
+
+    pub fn write_string(&mut self, string: &[u8]) {
+        self.write_ascii(string);
+        self.bytes.write_all(&[0]).unwrap();
+    }
+
+    /// Writes a value from a data directive, symbols are left as absolute references
+    pub fn write_data(&mut self, value: &Immediate) {
+        let (to, from, addend) = match &value.typ {
+            ImmediateType::Integer(_) if self.uninitialized => {
+                self.reserve(value.size.bytes());
+                return;
+            }
+            ImmediateType::Integer(i) => {
+                self.bytes.write_all(&i.to_bytes(false)[..value.size.bytes()]).unwrap();
+                return;
+            }
+            ImmediateType::Reference(to, addend) => (to.clone(), None, *addend),
+            ImmediateType::Difference(end, start, offset) => (end.clone(), Some(start.clone()), *offset),
+        };
+
+        let at = self.at();
+        self.references.push(Reference {
+            to,
+            from,
+            at,
+            rel: value.rel,
+            size: value.size,
+            sign_extended: false,
+            specifier: value.specifier,
+            addend,
+        });
+        self.bytes.write_all(&vec![0; value.size.bytes()]).unwrap();
+    }
+
+    /// Writes the lowest `size` bytes of `value`, `repeat` times
+    pub fn write_fill(&mut self, repeat: usize, size: usize, value: i64) {
+        if self.uninitialized {
+            self.reserve(repeat * size);
+            return;
+        }
+
+        for _ in 0..repeat {
+            self.bytes.write_all(&value.to_bytes(false)[..size]).unwrap();
+        }
+    }
+
+    /// Overwrites the `size` bytes at `at` with `value`, if it fits. Unless `signed`, the field may also hold the
+    /// value as an unsigned number.
+    fn patch(&mut self, at: usize, size: Size, value: i64, signed: bool) -> bool {
+        let bits = size.bytes() as u32 * 8;
+        let min = -(1i128 << (bits - 1));
+        let max = if signed { (1i128 << (bits - 1)) - 1 } else { (1i128 << bits) - 1 };
+        let fits = (min..=max).contains(&(value as i128));
+
+        if fits {
+            self.bytes[at..at + size.bytes()].copy_from_slice(&value.to_bytes(false)[..size.bytes()]);
+        }
+        fits
+    }
+
+    /// Patches relative references to labels defined in this section and differences directly into the section's
+    /// bytes, so that only references to external symbols or other sections are left over as relocations. `attributes`
+    /// are those of the whole module, `labels` holds the index of the section each label is in and its offset there.
+    pub fn resolve_references(
+        &mut self,
+        attributes: &HashMap<String, Attributes>,
+        labels: &HashMap<String, (usize, usize)>,
+    ) -> Result<(), AssemblerError> {
+        let references = std::mem::take(&mut self.references);
+
+        for reference in references {
+            // Both labels have to be in the same section, but that doesn't have to be this one
+            if let Some(from) = reference.from {
+                let (end, start) = match (labels.get(&reference.to), labels.get(&from)) {
+                    (Some((section, end)), Some((other, start))) if section == other => (*end, *start),
+                    _ => return Err(AssemblerError::UnresolvedDifference(reference.to, from)),
+                };
+
+                let distance = end as i64 - start as i64 + reference.addend;
+                if !self.patch(reference.at, reference.size, distance, false) {
+                    return Err(AssemblerError::DifferenceOutOfRange(reference.to, from));
+                }
+                continue;
+            }
+
+            // Local targets of calls through the PLT don't need it either
+            if reference.rel == Relativity::Relative {
+                if let Some(label) = self.local_label(&reference.to, attributes) {
+                    let distance = label.at as i64 - reference.at as i64 + reference.addend;
+                    if self.patch(reference.at, reference.size, distance, true) {
+                        continue;
+                    }
+                }
+            }
+
+            self.references.push(reference);
+        }
+
+        Ok(())
     }
 }
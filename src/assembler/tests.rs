@@ -0,0 +1,1306 @@
+// aron (c) Nikolas Wipper 2022
+
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+
+use crate::assembler::{AssemblerError, Module, ObjectFileType};
+use crate::parse::parser::parse_lines;
+use object::read::elf::FileHeader;
+use object::read::File;
+use object::{
+    elf, macho, BinaryFormat, ComdatKind, Endianness, Object, ObjectComdat, ObjectSection, ObjectSymbol,
+    RelocationEncoding, RelocationKind, RelocationTarget, SectionFlags, SectionKind, SymbolFlags, SymbolIndex,
+    SymbolKind, SymbolScope, SymbolSection,
+};
+
+fn assemble(code: &str, object_type: ObjectFileType) -> Vec<u8> {
+    let lines = parse_lines("test.s".to_string(), code.to_string()).unwrap();
+
+    Module::from_lines(lines).unwrap().write(object_type).unwrap()
+}
+
+/// The name of a symbol, section symbols are named after their section
+fn symbol_name(file: &File, index: SymbolIndex) -> String {
+    let symbol = file.symbol_by_index(index).unwrap();
+
+    match symbol.section_index() {
+        Some(section) if symbol.kind() == SymbolKind::Section => {
+            file.section_by_index(section).unwrap().name().unwrap().to_string()
+        }
+        _ => symbol.name().unwrap().to_string(),
+    }
+}
+
+const HELLO_ELF: &str = "\
+    .section .text
+    .globl main
+main:
+    push rbp
+    lea rdi, [rip + .L.str]
+    call printf
+    pop rbp
+    ret
+    .section .rodata
+.L.str:
+    .asciz \"Hello\"
+";
+
+#[test]
+fn test_object_file_type() {
+    assert_eq!("elf".parse::<ObjectFileType>(), Ok(ObjectFileType::Elf));
+    assert_eq!("macho".parse::<ObjectFileType>(), Ok(ObjectFileType::MachO));
+    assert!("coff".parse::<ObjectFileType>().is_err());
+
+    let name: &str = ObjectFileType::Elf.into();
+    assert_eq!(name, "elf");
+}
+
+#[test]
+fn test_elf_sections() {
+    let bytes = assemble(HELLO_ELF, ObjectFileType::Elf);
+    let file = File::parse(&*bytes).unwrap();
+
+    assert_eq!(file.format(), BinaryFormat::Elf);
+
+    let text = file.section_by_name(".text").unwrap();
+    assert_eq!(text.kind(), SectionKind::Text);
+    assert_eq!(text.data().unwrap(), &[0x55, 0x48, 0x8D, 0x3D, 0, 0, 0, 0, 0xE8, 0, 0, 0, 0, 0x5D, 0xC3]);
+
+    let rodata = file.section_by_name(".rodata").unwrap();
+    assert_eq!(rodata.kind(), SectionKind::ReadOnlyData);
+    assert_eq!(rodata.data().unwrap(), b"Hello\0");
+
+    assert!(file.section_by_name("__text").is_none());
+}
+
+#[test]
+fn test_elf_symbols() {
+    let bytes = assemble(HELLO_ELF, ObjectFileType::Elf);
+    let file = File::parse(&*bytes).unwrap();
+    let text = file.section_by_name(".text").unwrap();
+    let rodata = file.section_by_name(".rodata").unwrap();
+
+    let main = file.symbols().find(|s| s.name() == Ok("main")).unwrap();
+    assert!(main.is_global());
+    assert_eq!(main.section_index(), Some(text.index()));
+    assert_eq!(main.address(), 0);
+
+    // Temporaries stay out of the symbol table
+    assert!(file.symbols().all(|s| s.name() != Ok(".L.str")));
+    let string = file.symbols().find(|s| s.kind() == SymbolKind::Section && s.section_index() == Some(rodata.index()));
+    assert!(string.is_some());
+
+    let printf = file.symbols().find(|s| s.name() == Ok("printf")).unwrap();
+    assert!(printf.is_undefined());
+    assert!(printf.is_global());
+}
+
+#[test]
+fn test_elf_relocations() {
+    let bytes = assemble(HELLO_ELF, ObjectFileType::Elf);
+    let file = File::parse(&*bytes).unwrap();
+    let text = file.section_by_name(".text").unwrap();
+
+    let relocations = text.relocations().collect::<Vec<_>>();
+    assert_eq!(relocations.len(), 2);
+
+    let (offset, lea) = relocations.iter().find(|r| r.0 == 4).unwrap();
+    assert_eq!(*offset, 4);
+    assert_eq!(lea.kind(), RelocationKind::Relative);
+    assert_eq!(lea.size(), 32);
+    assert_eq!(lea.addend(), -4);
+    assert!(matches!(lea.target(), RelocationTarget::Symbol(s) if symbol_name(&file, s) == ".rodata"));
+
+    let (_, call) = relocations.iter().find(|r| r.0 == 9).unwrap();
+    assert_eq!(call.kind(), RelocationKind::PltRelative);
+    assert_eq!(call.size(), 32);
+    assert_eq!(call.addend(), -4);
+    assert!(matches!(call.target(), RelocationTarget::Symbol(s) if symbol_name(&file, s) == "printf"));
+}
+
+#[test]
+fn test_repeated_references() {
+    let code = "\
+    call foo
+    call foo@PLT
+    mov rax, [rip + foo@GOTPCREL]
+    .data
+    .quad foo
+";
+    let bytes = assemble(code, ObjectFileType::Elf);
+    let file = File::parse(&*bytes).unwrap();
+
+    assert_eq!(file.symbols().filter(|s| s.name() == Ok("foo")).count(), 1);
+
+    let data = file.section_by_name(".data").unwrap();
+    let text = file.section_by_name(".text").unwrap();
+    let relocations = text.relocations().chain(data.relocations()).collect::<Vec<_>>();
+    assert_eq!(relocations.len(), 4);
+    for (_, relocation) in relocations {
+        assert!(matches!(relocation.target(), RelocationTarget::Symbol(s) if symbol_name(&file, s) == "foo"));
+    }
+}
+
+#[test]
+fn test_elf_custom_section() {
+    let bytes = assemble(".section .text.startup\nret\n.section .comment\n.asciz \"aron\"\n", ObjectFileType::Elf);
+    let file = File::parse(&*bytes).unwrap();
+
+    assert_eq!(file.section_by_name(".text.startup").unwrap().kind(), SectionKind::Text);
+    assert_eq!(file.section_by_name(".comment").unwrap().data().unwrap(), b"aron\0");
+}
+
+#[test]
+fn test_macho_sections() {
+    let code = "\
+    .section __TEXT,__text
+    .globl _main
+_main:
+    lea rdi, [rip + L_.str]
+    call _printf
+    ret
+    .section __TEXT,__cstring
+L_.str:
+    .asciz \"Hello\"
+";
+    let bytes = assemble(code, ObjectFileType::MachO);
+    let file = File::parse(&*bytes).unwrap();
+
+    assert_eq!(file.format(), BinaryFormat::MachO);
+
+    let text = file.section_by_name("__text").unwrap();
+    assert_eq!(text.segment_name().unwrap(), Some("__TEXT"));
+    assert_eq!(text.kind(), SectionKind::Text);
+
+    let cstring = file.section_by_name("__cstring").unwrap();
+    assert_eq!(cstring.data().unwrap(), b"Hello\0");
+
+    let main = file.symbols().find(|s| s.name() == Ok("_main")).unwrap();
+    assert!(main.is_global());
+
+    let relocations = text.relocations().collect::<Vec<_>>();
+    assert_eq!(relocations.len(), 2);
+    for (_, relocation) in relocations {
+        assert_eq!(relocation.kind(), RelocationKind::Relative);
+        assert_eq!(relocation.size(), 32);
+    }
+}
+
+
+#[test]
+fn test_local_references_resolved() {
+    let code = "\
+    .globl f
+f:
+    call .Lhelper
+.Lloop:
+    sub ecx, 1
+    jne .Lloop
+    ret
+.Lhelper:
+    ret
+";
+    let bytes = assemble(code, ObjectFileType::Elf);
+    let file = File::parse(&*bytes).unwrap();
+    let text = file.section_by_name(".text").unwrap();
+
+    assert_eq!(
+        text.data().unwrap(),
+        &[0xE8, 0x06, 0, 0, 0, 0x83, 0xE9, 0x01, 0x75, 0xFB, 0xC3, 0xC3]
+    );
+    assert_eq!(text.relocations().count(), 0);
+}
+
+#[test]
+fn test_external_references_kept() {
+    let code = "\
+    .globl f
+f:
+    call f
+    call g
+    lea rdi, [rip + .Ldata]
+    .section .data
+.Ldata:
+    .asciz \"x\"
+";
+    let bytes = assemble(code, ObjectFileType::Elf);
+    let file = File::parse(&*bytes).unwrap();
+    let text = file.section_by_name(".text").unwrap();
+
+    assert_eq!(text.data().unwrap()[..10], [0xE8, 0, 0, 0, 0, 0xE8, 0, 0, 0, 0]);
+
+    let targets = text
+        .relocations()
+        .map(|(_, r)| match r.target() {
+            RelocationTarget::Symbol(s) => symbol_name(&file, s),
+            _ => panic!("relocation against non-symbol"),
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(targets, vec!["f", "g", ".data"]);
+}
+
+fn text_bytes(code: &str) -> Vec<u8> {
+    let bytes = assemble(code, ObjectFileType::Elf);
+    let file = File::parse(&*bytes).unwrap();
+    let text = file.section_by_name(".text").unwrap();
+
+    text.data().unwrap().to_vec()
+}
+
+#[test]
+fn test_short_jumps() {
+    let code = "\
+.Lstart:
+    jmp .Lend
+    loop .Lstart
+    jrcxz .Lstart
+    jle .Lstart
+.Lend:
+    ret
+";
+
+    assert_eq!(text_bytes(code), &[0xEB, 0x06, 0xE2, 0xFC, 0xE3, 0xFA, 0x7E, 0xF8, 0xC3]);
+}
+
+#[test]
+fn test_jump_relaxation() {
+    let code = format!(
+        "    je .Lend\n{}    jmp .Lfar\n.Lend:\n{}.Lfar:\n    ret\n",
+        "    xor eax, eax\n".repeat(62),
+        "    xor eax, eax\n".repeat(65)
+    );
+    let bytes = text_bytes(&code);
+
+    // The jmp has to grow, which pushes .Lend out of the je's reach as well
+    assert_eq!(bytes.len(), 266);
+    assert_eq!(bytes[..6], [0x0F, 0x84, 0x81, 0, 0, 0]);
+    assert_eq!(bytes[130..135], [0xE9, 0x82, 0, 0, 0]);
+}
+
+#[test]
+fn test_external_jump_relaxation() {
+    let bytes = assemble("    jmp exit\n", ObjectFileType::Elf);
+    let file = File::parse(&*bytes).unwrap();
+    let text = file.section_by_name(".text").unwrap();
+
+    assert_eq!(text.data().unwrap(), &[0xE9, 0, 0, 0, 0]);
+    assert_eq!(text.relocations().count(), 1);
+}
+
+#[test]
+fn test_short_only_out_of_range() {
+    let code = format!(".Lstart:\n{}    loop .Lstart\n", "    xor eax, eax\n".repeat(64));
+    let lines = parse_lines("test.s".to_string(), code).unwrap();
+    assert!(matches!(Module::from_lines(lines), Err(AssemblerError::BranchOutOfRange(to)) if to == ".Lstart"));
+
+    let lines = parse_lines("test.s".to_string(), "    jrcxz exit\n".to_string()).unwrap();
+    assert!(matches!(Module::from_lines(lines), Err(AssemblerError::BranchOutOfRange(to)) if to == "exit"));
+}
+
+fn section_bytes(code: &str, name: &str) -> Vec<u8> {
+    let bytes = assemble(code, ObjectFileType::Elf);
+    let file = File::parse(&*bytes).unwrap();
+    let section = file.section_by_name(name).unwrap();
+
+    section.data().unwrap().to_vec()
+}
+
+#[test]
+fn test_data_directives() {
+    let code = "\
+    .section .data
+    .byte 1, 0xFF, -1, 'a'
+    .short 0x1234, -2
+    .word 0x5678
+    .long 0xDEADBEEF
+    .int -1
+    .quad 0x0102030405060708
+";
+
+    assert_eq!(
+        section_bytes(code, ".data"),
+        &[
+            0x01, 0xFF, 0xFF, 0x61, 0x34, 0x12, 0xFE, 0xFF, 0x78, 0x56, 0xEF, 0xBE, 0xAD, 0xDE, 0xFF, 0xFF, 0xFF, 0xFF,
+            0x08, 0x07, 0x06, 0x05, 0x04, 0x03, 0x02, 0x01
+        ]
+    );
+}
+
+#[test]
+fn test_string_directives() {
+    let code = "\
+    .section .data
+    .ascii \"ab\", \"c\\n\"
+    .string \"d\"
+    .asciz \"e\", \"f\"
+";
+
+    assert_eq!(section_bytes(code, ".data"), b"abc\nd\0e\0f\0");
+
+    // Octal and hexadecimal escapes only keep their lowest byte
+    let code = "\
+    .section .data
+    .ascii \"\\0\\123\\1234\\400\\377\"
+    .ascii \"\\x41\\x4142\\X1ff\"
+    .ascii \"\\\\\\\"\\b\\f\\n\\r\\t\\v\"
+";
+
+    assert_eq!(section_bytes(code, ".data"), b"\0SS4\0\xFFAB\xFF\\\"\x08\x0C\n\r\t\x0B");
+}
+
+#[test]
+fn test_fill_directives() {
+    let code = "\
+    .section .data
+    .zero 3
+    .space 2, 0x90
+    .skip 1
+    .fill 2, 4, 0x11223344
+    .fill 3
+";
+
+    assert_eq!(
+        section_bytes(code, ".data"),
+        &[0, 0, 0, 0x90, 0x90, 0, 0x44, 0x33, 0x22, 0x11, 0x44, 0x33, 0x22, 0x11, 0, 0, 0]
+    );
+}
+
+#[test]
+fn test_data_relocations() {
+    let code = "\
+    .section .data
+table:
+    .quad f, 1
+    .long g
+    .short h
+    .byte i
+";
+    let bytes = assemble(code, ObjectFileType::Elf);
+    let file = File::parse(&*bytes).unwrap();
+    let data = file.section_by_name(".data").unwrap();
+
+    assert_eq!(data.data().unwrap(), &[0, 0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+
+    let relocations = data
+        .relocations()
+        .map(|(at, r)| match r.target() {
+            RelocationTarget::Symbol(s) => (at, r.kind(), r.size(), symbol_name(&file, s)),
+            _ => panic!("relocation against non-symbol"),
+        })
+        .collect::<Vec<_>>();
+
+    assert_eq!(
+        relocations,
+        vec![
+            (0, RelocationKind::Absolute, 64, "f".to_string()),
+            (16, RelocationKind::Absolute, 32, "g".to_string()),
+            (20, RelocationKind::Absolute, 16, "h".to_string()),
+            (22, RelocationKind::Absolute, 8, "i".to_string()),
+        ]
+    );
+}
+
+#[test]
+fn test_invalid_data_directives() {
+    assert!(parse_lines("test.s".to_string(), ".byte 256\n".to_string()).is_err());
+    assert!(parse_lines("test.s".to_string(), ".long 1 2\n".to_string()).is_err());
+    assert!(parse_lines("test.s".to_string(), ".fill 1, 9\n".to_string()).is_err());
+    assert!(parse_lines("test.s".to_string(), ".zero sym\n".to_string()).is_err());
+    assert!(parse_lines("test.s".to_string(), ".ascii \"\\q\"\n".to_string()).is_err());
+    assert!(parse_lines("test.s".to_string(), ".asciz \"\\xg\"\n".to_string()).is_err());
+    assert!(parse_lines("test.s".to_string(), ".string \"\\8\"\n".to_string()).is_err());
+}
+
+#[test]
+fn test_code_alignment() {
+    let bytes = text_bytes("    ret\n    .p2align 4, 0x90\nf:\n    ret\n    .balign 8\n    ret\n    .align 4, 0xCC\n");
+
+    assert_eq!(
+        bytes,
+        &[
+            0xC3, 0x66, 0x0F, 0x1F, 0x84, 0x00, 0x00, 0x00, 0x00, 0x00, 0x66, 0x0F, 0x1F, 0x44, 0x00, 0x00, 0xC3, 0x0F,
+            0x1F, 0x80, 0x00, 0x00, 0x00, 0x00, 0xC3, 0xCC, 0xCC, 0xCC
+        ]
+    );
+}
+
+#[test]
+fn test_data_alignment() {
+    let code = "\
+    .section .data
+    .byte 1
+    .balign 4
+    .byte 2
+    .p2align 3, 0xFF
+    .byte 3
+    .balign 16, 0, 3
+    .byte 4
+    .p2align 4,,14
+    .byte 5
+";
+    let bytes = assemble(code, ObjectFileType::Elf);
+    let file = File::parse(&*bytes).unwrap();
+    let data = file.section_by_name(".data").unwrap();
+
+    assert_eq!(data.align(), 16);
+    assert_eq!(
+        data.data().unwrap(),
+        &[1, 0, 0, 0, 2, 0xFF, 0xFF, 0xFF, 3, 4, 0, 0, 0, 0, 0, 0, 5]
+    );
+}
+
+#[test]
+fn test_alignment_after_relaxation() {
+    // The external jmp grows by three bytes, so the padding in front of .Lend has to shrink from four bytes to one
+    let code = format!(
+        "    jmp .Lend\n    jmp exit\n{}    .p2align 4\n.Lend:\n    ret\n",
+        "    xor eax, eax\n".repeat(60)
+    );
+    let bytes = text_bytes(&code);
+
+    assert_eq!(bytes.len(), 129);
+    assert_eq!(bytes[..4], [0xEB, 0x7E, 0xE9, 0x00]);
+    assert_eq!(bytes[127..], [0x90, 0xC3]);
+}
+
+#[test]
+fn test_labels_in_front_of_alignment() {
+    // The padding is empty until the jmp grows, but before and the end of f still have to stay in front of it
+    let code = format!(
+        "f:\n    jmp exit\n{}    .size f, .-f\nbefore:\n    .p2align 4\nafter:\n    ret\n",
+        "    xor eax, eax\n".repeat(7)
+    );
+    let bytes = assemble(&code, ObjectFileType::Elf);
+    let file = File::parse(&*bytes).unwrap();
+
+    assert_eq!(find_symbol(&file, "f").size(), 19);
+    assert_eq!(find_symbol(&file, "before").address(), 19);
+    assert_eq!(find_symbol(&file, "after").address(), 32);
+}
+
+#[test]
+fn test_invalid_alignment() {
+    assert!(parse_lines("test.s".to_string(), ".balign 3\n".to_string()).is_err());
+    assert!(parse_lines("test.s".to_string(), ".p2align 40\n".to_string()).is_err());
+    assert!(parse_lines("test.s".to_string(), ".balign 4, 256\n".to_string()).is_err());
+}
+
+fn find_symbol<'a>(file: &'a File, name: &str) -> object::Symbol<'a, 'a> {
+    file.symbols().find(|s| s.name() == Ok(name)).unwrap()
+}
+
+#[test]
+fn test_symbol_attributes() {
+    let code = "\
+    .globl f
+    .type f, @function
+f:
+    jmp exit
+    ret
+    .size f, .-f
+    .weak g
+    .hidden g
+g:
+    ret
+    .section .data
+    .globl table
+    .protected table
+    .type table, %object
+table:
+    .quad 1, 2
+.Ltable_end:
+    .size table, .Ltable_end-table
+counter:
+    .long 0
+    .size counter, 4
+    .local counter
+    .section .text
+    .internal h
+    .globl h
+h:
+    ret
+";
+    let bytes = assemble(code, ObjectFileType::Elf);
+    let file = File::parse(&*bytes).unwrap();
+
+    // The size follows the relaxed jmp
+    let f = find_symbol(&file, "f");
+    assert!(f.is_global() && !f.is_weak());
+    assert_eq!(f.kind(), SymbolKind::Text);
+    assert_eq!(f.size(), 6);
+    assert_eq!(f.scope(), SymbolScope::Dynamic);
+
+    let g = find_symbol(&file, "g");
+    assert!(g.is_weak());
+    assert_eq!(g.scope(), SymbolScope::Linkage);
+
+    let table = find_symbol(&file, "table");
+    assert_eq!(table.kind(), SymbolKind::Data);
+    assert_eq!(table.size(), 16);
+    assert!(matches!(table.flags(), SymbolFlags::Elf { st_other, .. } if st_other == elf::STV_PROTECTED));
+
+    let counter = find_symbol(&file, "counter");
+    assert!(counter.is_local());
+    assert_eq!(counter.size(), 4);
+
+    let h = find_symbol(&file, "h");
+    assert!(h.is_global());
+    assert!(matches!(h.flags(), SymbolFlags::Elf { st_other, .. } if st_other == elf::STV_INTERNAL));
+}
+
+#[test]
+fn test_untyped_symbols() {
+    let code = "\
+main:
+.Lloop:
+    jmp .Lloop
+    mov rax, [rip + .Lconst@GOTPCREL]
+    lea rax, [rip + .Lconst]
+    .data
+value:
+.Lconst:
+    .quad 0
+";
+    let bytes = assemble(code, ObjectFileType::Elf);
+    let file = File::parse(&*bytes).unwrap();
+
+    // Like GNU as, symbols without a .type are STT_NOTYPE, whatever section they're in
+    for name in ["main", "value"] {
+        match find_symbol(&file, name).flags() {
+            SymbolFlags::Elf { st_info, .. } => assert_eq!(st_info & 0xF, elf::STT_NOTYPE),
+            flags => panic!("Unexpected flags {:?}", flags),
+        }
+    }
+
+    // Temporaries only show up if a relocation needs the symbol itself
+    assert!(file.symbols().all(|s| s.name() != Ok(".Lloop")));
+    let text = file.section_by_name(".text").unwrap();
+    let targets = text.relocations().map(|(_, r)| match r.target() {
+        RelocationTarget::Symbol(s) => (symbol_name(&file, s), r.addend()),
+        _ => panic!("relocation against non-symbol"),
+    });
+    assert_eq!(targets.collect::<Vec<_>>(), [(".Lconst".to_string(), -4), (".data".to_string(), -4)]);
+}
+
+#[test]
+fn test_weak_references() {
+    let code = "\
+    .weak hook
+    .extern exit
+    call hook
+    call exit
+";
+    let bytes = assemble(code, ObjectFileType::Elf);
+    let file = File::parse(&*bytes).unwrap();
+
+    let hook = find_symbol(&file, "hook");
+    assert!(hook.is_undefined() && hook.is_weak());
+
+    let exit = find_symbol(&file, "exit");
+    assert!(exit.is_undefined() && !exit.is_weak());
+}
+
+#[test]
+fn test_macho_symbol_attributes() {
+    let code = "\
+    .private_extern _helper
+_helper:
+    ret
+    .globl _main
+    .weak_definition _main
+_main:
+    ret
+";
+    let bytes = assemble(code, ObjectFileType::MachO);
+    let file = File::parse(&*bytes).unwrap();
+
+    let helper = find_symbol(&file, "_helper");
+    assert!(helper.is_global());
+    assert_eq!(helper.scope(), SymbolScope::Linkage);
+
+    let main = find_symbol(&file, "_main");
+    assert!(main.is_global() && main.is_weak());
+}
+
+#[test]
+fn test_invalid_symbol_directives() {
+    assert!(parse_lines("test.s".to_string(), ".type f, @banana\n".to_string()).is_err());
+    assert!(parse_lines("test.s".to_string(), ".size f\n".to_string()).is_err());
+    assert!(parse_lines("test.s".to_string(), ".globl\n".to_string()).is_err());
+}
+
+#[test]
+fn test_constant_assignments() {
+    let code = "\
+    .set COUNT, 4
+    .equ SIZE, COUNT
+    WIDTH = 0x10
+    .equiv HEIGHT, 2
+    mov eax, COUNT
+    mov rax, [rbx + WIDTH]
+    .set COUNT, 5
+    mov ecx, COUNT
+    .section .data
+    .long SIZE, WIDTH
+    .zero HEIGHT
+";
+
+    assert_eq!(
+        section_bytes(code, ".text"),
+        [0xB8, 4, 0, 0, 0, 0x48, 0x8B, 0x43, 0x10, 0xB9, 5, 0, 0, 0]
+    );
+    assert_eq!(section_bytes(code, ".data"), [4, 0, 0, 0, 0x10, 0, 0, 0, 0, 0]);
+
+    let bytes = assemble(code, ObjectFileType::Elf);
+    let file = File::parse(&*bytes).unwrap();
+    assert_eq!(file.section_by_name(".text").unwrap().relocations().count(), 0);
+}
+
+#[test]
+fn test_global_constants() {
+    let code = "\
+    .globl ANSWER
+    .set ANSWER, 42
+    .set PRIVATE, 1
+    ret
+";
+    let bytes = assemble(code, ObjectFileType::Elf);
+    let file = File::parse(&*bytes).unwrap();
+
+    let answer = find_symbol(&file, "ANSWER");
+    assert!(answer.is_global());
+    assert_eq!(answer.section(), SymbolSection::Absolute);
+    assert_eq!(answer.address(), 42);
+
+    assert!(file.symbols().all(|s| s.name() != Ok("PRIVATE")));
+
+    let bytes = assemble(&code.replace("ANSWER", "_answer"), ObjectFileType::MachO);
+    let file = File::parse(&*bytes).unwrap();
+    assert_eq!(find_symbol(&file, "_answer").address(), 42);
+}
+
+#[test]
+fn test_invalid_assignments() {
+    assert!(parse_lines("test.s".to_string(), ".equiv A, 1\n.equiv A, 2\n".to_string()).is_err());
+    assert!(parse_lines("test.s".to_string(), ".set A, 1\n.equiv A, 2\n".to_string()).is_err());
+    assert!(parse_lines("test.s".to_string(), ".set A, 1\n.set A, 2\nA = 3\n".to_string()).is_ok());
+    assert!(parse_lines("test.s".to_string(), ".set 5, 1\n".to_string()).is_err());
+    assert!(parse_lines("test.s".to_string(), ".equ A\n".to_string()).is_err());
+}
+
+#[test]
+fn test_symbol_differences() {
+    // The jmp is relaxed to five bytes, which has to be reflected in the distance
+    let code = "\
+start:
+    jmp exit
+    mov eax, end - start
+end:
+    .section .data
+table:
+    .quad 1, 2, 3
+table_end:
+    .long table_end - table
+    .byte table_end - table
+";
+    let text = section_bytes(code, ".text");
+    assert_eq!(text[5..], [0xB8, 10, 0, 0, 0]);
+
+    let data = section_bytes(code, ".data");
+    assert_eq!(data[24..], [24, 0, 0, 0, 24]);
+
+    // Like in .eh_frame, the labels can be in another section, which is laid out first
+    let code = "\
+main:
+    jmp exit
+    ret
+.Lend:
+    .section .data
+    .long .Lend - main
+";
+    assert_eq!(section_bytes(code, ".data")[..4], [6, 0, 0, 0]);
+}
+
+#[test]
+fn test_invalid_differences() {
+    let assemble = |code: &str| {
+        let lines = parse_lines("test.s".to_string(), code.to_string()).unwrap();
+        Module::from_lines(lines).map(|_| ())
+    };
+
+    assert!(matches!(
+        assemble("a:\n    .section .data\nb:\n    .long b - a\n"),
+        Err(AssemblerError::UnresolvedDifference(..))
+    ));
+    assert!(matches!(assemble("a:\n    .long b - a\n"), Err(AssemblerError::UnresolvedDifference(..))));
+    assert!(matches!(
+        assemble("a:\n    .zero 300\nb:\n    .byte b - a\n"),
+        Err(AssemblerError::DifferenceOutOfRange(..))
+    ));
+}
+
+#[test]
+fn test_relocation_addends() {
+    let code = "\
+    lea rdi, [rip + msg + 8]
+    mov dword ptr [rip + counter], 5
+    mov byte ptr [rip + counter - 1], 1
+    call handler + 2
+    .section .data
+    .quad table + 16
+    .long table - 4
+";
+    let bytes = assemble(code, ObjectFileType::Elf);
+    let file = File::parse(&*bytes).unwrap();
+
+    let addends = |name: &str| {
+        let section = file.section_by_name(name).unwrap();
+        section.relocations().map(|(offset, r)| (offset, r.addend())).collect::<Vec<_>>()
+    };
+
+    // PC-relative addends are measured from the end of the instruction, which might still hold an immediate
+    assert_eq!(addends(".text"), [(3, 4), (9, -8), (19, -6), (25, -2)]);
+    assert_eq!(addends(".data"), [(0, 16), (8, -4)]);
+}
+
+#[test]
+fn test_sse_relocation_addends() {
+    let code = "\
+    movsd xmm0, qword ptr [rip + c]
+    pshufd xmm1, xmmword ptr [rip + c], 3
+    cmpsd xmm8, qword ptr [rip + c + 8], 1
+";
+    let bytes = assemble(code, ObjectFileType::Elf);
+    let file = File::parse(&*bytes).unwrap();
+
+    let section = file.section_by_name(".text").unwrap();
+    let addends = section.relocations().map(|(offset, r)| (offset, r.addend())).collect::<Vec<_>>();
+
+    assert_eq!(addends, [(4, -4), (12, -5), (22, 3)]);
+}
+
+#[test]
+fn test_macho_relocation_addends() {
+    let code = "\
+    mov dword ptr [rip + _counter], 5
+    .section __DATA,__data
+    .quad _table + 16
+";
+    let bytes = assemble(code, ObjectFileType::MachO);
+    let file = File::parse(&*bytes).unwrap();
+
+    // Mach-O keeps the addend in the section data, relative to the end of the 32-bit field
+    let text = file.section_by_name("__text").unwrap();
+    assert_eq!(text.data().unwrap()[2..6], [0xFC, 0xFF, 0xFF, 0xFF]);
+
+    let data = file.section_by_name("__data").unwrap();
+    assert_eq!(data.data().unwrap(), [16, 0, 0, 0, 0, 0, 0, 0]);
+}
+
+#[test]
+fn test_local_addends() {
+    // Jump targets are resolved within the section, including their offsets
+    let code = "\
+    jmp .Lnext + 1
+    call .Lnext - 1
+.Lnext:
+    ret
+    ret
+";
+
+    assert_eq!(text_bytes(code)[..7], [0xEB, 0x06, 0xE8, 0xFF, 0xFF, 0xFF, 0xFF]);
+}
+
+#[test]
+fn test_relocation_widths() {
+    let code = "\
+    mov eax, sym
+    mov rax, sym
+    movabs rax, sym
+    mov al, sym
+    add rax, sym
+    mov rcx, [sym]
+    .section .data
+    .long sym
+    .short sym
+    .byte sym
+    .quad sym
+";
+    let bytes = assemble(code, ObjectFileType::Elf);
+    let file = File::parse(&*bytes).unwrap();
+
+    let relocations = |name: &str| {
+        let section = file.section_by_name(name).unwrap();
+        section.relocations().map(|(at, r)| (at, r.size(), r.encoding())).collect::<Vec<_>>()
+    };
+
+    use RelocationEncoding::{Generic, X86Signed};
+    assert_eq!(
+        relocations(".text"),
+        [
+            (1, 32, Generic),
+            (8, 32, X86Signed),
+            (14, 64, Generic),
+            (23, 8, Generic),
+            (26, 32, X86Signed),
+            (34, 32, X86Signed)
+        ]
+    );
+    assert_eq!(relocations(".data"), [(0, 32, Generic), (4, 16, Generic), (6, 8, Generic), (7, 64, Generic)]);
+
+    // Short sign-extended immediates can't hold a symbol
+    assert_eq!(text_bytes("push sym\n")[0], 0x68);
+}
+
+#[test]
+fn test_unsupported_relocations() {
+    let lines = parse_lines("test.s".to_string(), ".short _sym\n".to_string()).unwrap();
+    let module = Module::from_lines(lines).unwrap();
+
+    assert!(module.into_object(ObjectFileType::MachO).is_err());
+}
+
+#[test]
+fn test_relocation_specifiers() {
+    let code = "\
+    local:
+    call local@PLT
+    call foo@PLT
+    mov rax, [rip + foo@GOTPCREL]
+    mov rax, [rip + x@GOTTPOFF]
+    lea rdi, [rip + x@TLSGD]
+    lea rdi, [rip + y@TLSLD]
+    mov eax, [rax + y@DTPOFF]
+    mov rax, x@TPOFF
+    movabs rax, foo@GOTOFF
+    .section .data
+    .quad y@DTPOFF
+";
+    let bytes = assemble(code, ObjectFileType::Elf);
+    let file = File::parse(&*bytes).unwrap();
+
+    let relocations = |name: &str| {
+        let section = file.section_by_name(name).unwrap();
+        section
+            .relocations()
+            .map(|(at, r)| match r.target() {
+                RelocationTarget::Symbol(s) => (at, r.kind(), r.addend(), symbol_name(&file, s)),
+                _ => panic!("Relocation against a section"),
+            })
+            .collect::<Vec<_>>()
+    };
+    let reloc = |at, kind, addend, symbol: &str| (at, kind, addend, symbol.to_string());
+    assert_eq!(
+        relocations(".text"),
+        [
+            reloc(6, RelocationKind::PltRelative, -4, "foo"),
+            reloc(13, RelocationKind::GotRelative, -4, "foo"),
+            reloc(20, RelocationKind::Elf(elf::R_X86_64_GOTTPOFF), -4, "x"),
+            reloc(27, RelocationKind::Elf(elf::R_X86_64_TLSGD), -4, "x"),
+            reloc(34, RelocationKind::Elf(elf::R_X86_64_TLSLD), -4, "y"),
+            reloc(40, RelocationKind::Elf(elf::R_X86_64_DTPOFF32), 0, "y"),
+            reloc(47, RelocationKind::Elf(elf::R_X86_64_TPOFF32), 0, "x"),
+            reloc(53, RelocationKind::Elf(elf::R_X86_64_GOTOFF64), 0, "foo"),
+        ]
+    );
+    assert_eq!(relocations(".data"), [reloc(0, RelocationKind::Elf(elf::R_X86_64_DTPOFF64), 0, "y")]);
+
+    // Calls to local labels don't need the PLT
+    assert_eq!(file.section_by_name(".text").unwrap().data().unwrap()[..5], [0xE8, 0xFB, 0xFF, 0xFF, 0xFF]);
+
+    assert_eq!(find_symbol(&file, "x").kind(), SymbolKind::Tls);
+    assert_eq!(find_symbol(&file, "foo").kind(), SymbolKind::Unknown);
+}
+
+#[test]
+fn test_macho_relocation_specifiers() {
+    let code = "\
+    mov rax, [rip + _foo@GOTPCREL]
+    add rax, [rip + _bar@GOTPCREL + 8]
+    mov rdi, [rip + _x@TLVP]
+";
+    let bytes = assemble(code, ObjectFileType::MachO);
+    let file = File::parse(&*bytes).unwrap();
+    let text = file.section_by_name("__text").unwrap();
+
+    let relocations = text.relocations().map(|(at, r)| (at, r.kind(), r.encoding())).collect::<Vec<_>>();
+    let tlv = RelocationKind::MachO { value: macho::X86_64_RELOC_TLV, relative: true };
+    assert_eq!(
+        relocations,
+        [
+            (3, RelocationKind::GotRelative, RelocationEncoding::X86RipRelativeMovq),
+            (10, RelocationKind::GotRelative, RelocationEncoding::Generic),
+            (17, tlv, RelocationEncoding::Generic),
+        ]
+    );
+
+    // Addends beyond the end of the instruction end up in the section data
+    assert_eq!(text.data().unwrap()[10..14], [8, 0, 0, 0]);
+    assert_eq!(text.data().unwrap()[17..21], [0, 0, 0, 0]);
+}
+
+#[test]
+fn test_invalid_relocation_specifiers() {
+    assert!(parse_lines("test.s".to_string(), "call foo@GOTPCREL\n".to_string()).is_err());
+    assert!(parse_lines("test.s".to_string(), ".quad foo@PLT - bar\n".to_string()).is_err());
+
+    let object = |code: &str, object_type| {
+        let lines = parse_lines("test.s".to_string(), code.to_string()).unwrap();
+        Module::from_lines(lines).unwrap().into_object(object_type)
+    };
+
+    assert!(object(".long foo@GOTOFF\n", ObjectFileType::Elf).is_err());
+    assert!(object("mov rax, [rip + x@TPOFF]\n", ObjectFileType::Elf).is_err());
+    assert!(object("mov eax, _x@TPOFF\n", ObjectFileType::MachO).is_err());
+    assert!(object("mov rax, [rip + x@TLVP]\n", ObjectFileType::Elf).is_err());
+}
+
+#[test]
+fn test_common_symbols() {
+    let code = "\
+    .comm buf, 64, 32
+    .comm small, 2
+    .comm buf, 16
+    .local private
+    .comm private, 8, 8
+    .lcomm counter, 4, 4
+    .bss
+    .zero 16
+    after:
+    .skip 8
+    .long 0
+";
+    let bytes = assemble(code, ObjectFileType::Elf);
+    let file = File::parse(&*bytes).unwrap();
+
+    let buf = find_symbol(&file, "buf");
+    assert!(buf.is_common());
+    assert!(buf.is_global());
+    assert_eq!((buf.size(), buf.address()), (64, 32));
+
+    let small = find_symbol(&file, "small");
+    assert_eq!((small.size(), small.address()), (2, 2));
+
+    let bss = file.section_by_name(".bss").unwrap();
+    assert_eq!(bss.kind(), SectionKind::UninitializedData);
+    assert_eq!(bss.file_range(), None);
+    assert_eq!(bss.size(), 40);
+    assert_eq!(bss.align(), 8);
+
+    for (name, address) in [("counter", 0), ("after", 20), ("private", 32)] {
+        let symbol = find_symbol(&file, name);
+        assert!(symbol.is_local());
+        assert_eq!(symbol.section_index(), Some(bss.index()));
+        assert_eq!(symbol.address(), address);
+    }
+}
+
+#[test]
+fn test_macho_zerofill() {
+    let code = "\
+    .zerofill __DATA,__bss,_buf,64,4
+    .zerofill __DATA,__huge,_big,4096,12
+    .zerofill __DATA,__empty
+    .comm _shared,16,16
+    lea rax, [rip + _buf]
+";
+    let bytes = assemble(code, ObjectFileType::MachO);
+    let file = File::parse(&*bytes).unwrap();
+
+    for (name, size, align) in [("__bss", 64, 16), ("__huge", 4096, 4096), ("__empty", 0, 1)] {
+        let section = file.section_by_name(name).unwrap();
+        // The reader only knows __bss by name, but zerofill sections don't have any data in the file
+        assert_eq!(section.file_range(), None);
+        assert_eq!((section.size(), section.align()), (size, align));
+    }
+
+    // Mach-O common symbols are undefined symbols with their size as the value, and a log2 alignment
+    let shared = find_symbol(&file, "_shared");
+    assert!(shared.is_undefined() && shared.is_global());
+    assert_eq!(shared.address(), 16);
+    assert_eq!(shared.flags(), SymbolFlags::MachO { n_desc: 4 << 8 });
+
+    // The instruction ends up in __text, not in the last zerofill section
+    assert_eq!(file.section_by_name("__text").unwrap().size(), 7);
+}
+
+#[test]
+fn test_uninitialized_data() {
+    let module = |code: &str| Module::from_lines(parse_lines("test.s".to_string(), code.to_string()).unwrap());
+
+    assert!(matches!(module(".bss\n.long 1\n"), Err(AssemblerError::InitializedData(name)) if name == "bss"));
+    assert!(module(".section .bss.big\n.byte 0, 1\n").is_err());
+    assert!(module(".bss\nret\n").is_err());
+    assert!(module(".bss\n.asciz \"\"\n").is_err());
+    assert!(module(".bss\n.long 0\n.space 4, 0\n.p2align 4\n").is_ok());
+
+    assert!(parse_lines("test.s".to_string(), ".comm x, 4, 3\n".to_string()).is_err());
+    assert!(parse_lines("test.s".to_string(), ".zerofill __DATA,__bss,_x,4,32\n".to_string()).is_err());
+}
+
+#[test]
+fn test_section_stack() {
+    let code = "\
+    .text
+    std
+    .data
+    .byte 1
+    .pushsection .rodata, 1
+    .byte 0x11
+    .pushsection .text
+    cld
+    .popsection
+    .byte 0x12
+    .subsection 0
+    .byte 0x10
+    .popsection
+    .byte 2
+    .previous
+    ret
+    .previous
+    .byte 3
+    .text 1
+after:
+    hlt
+    .text
+    jmp after
+    .bss 1
+    .p2align 3
+big:
+    .quad 0
+    .bss
+    .long 0
+";
+    let bytes = assemble(code, ObjectFileType::Elf);
+    let file = File::parse(&*bytes).unwrap();
+
+    // Subsections are concatenated in order, so the jump from subsection 0 goes forward
+    let text = file.section_by_name(".text").unwrap();
+    assert_eq!(text.data().unwrap(), &[0xFD, 0xFC, 0xC3, 0xEB, 0x00, 0xF4]);
+    assert_eq!(find_symbol(&file, "after").address(), 5);
+
+    assert_eq!(file.section_by_name(".data").unwrap().data().unwrap(), &[1, 2, 3]);
+    assert_eq!(file.section_by_name(".rodata").unwrap().data().unwrap(), &[0x10, 0x11, 0x12]);
+
+    let bss = file.section_by_name(".bss").unwrap();
+    assert_eq!((bss.size(), bss.align()), (16, 8));
+    assert_eq!(find_symbol(&file, "big").address(), 8);
+
+    let module = |code: &str| Module::from_lines(parse_lines("test.s".to_string(), code.to_string()).unwrap());
+    assert!(matches!(module(".pushsection .data\n.popsection\n.popsection\n"), Err(AssemblerError::EmptySectionStack)));
+    assert!(parse_lines("test.s".to_string(), ".previous 1\n".to_string()).is_err());
+    assert!(parse_lines("test.s".to_string(), ".subsection\n".to_string()).is_err());
+}
+
+#[test]
+fn test_reproducible_output() {
+    let code = "\
+    .section .data.zeta
+zeta:
+    .quad alpha
+    .section .data.alpha
+    .globl omega
+omega:
+    .long 1
+alpha:
+    .long 2
+    .text
+    call external
+    .section .data.mid
+mid:
+    .comm common_b, 8
+    .comm common_a, 8
+    .set const_b, 2
+    .set const_a, 1
+";
+    for object_type in [ObjectFileType::Elf, ObjectFileType::MachO] {
+        let first = assemble(code, object_type);
+        for _ in 0..8 {
+            assert_eq!(assemble(code, object_type), first);
+        }
+    }
+
+    let bytes = assemble(code, ObjectFileType::Elf);
+    let file = File::parse(&*bytes).unwrap();
+
+    // Sections are in the order they were first used, symbols in the order they were mentioned
+    let sections: Vec<_> = file.sections().map(|section| section.name().unwrap().to_string()).collect();
+    let sections: Vec<_> = sections.iter().filter(|name| name.starts_with(".data") || *name == ".text").collect();
+    assert_eq!(sections, [".text", ".data.zeta", ".data.alpha", ".data.mid"]);
+
+    let symbols: Vec<_> =
+        file.symbols().filter_map(|symbol| symbol.name().ok().filter(|name| !name.is_empty())).collect();
+    assert_eq!(symbols, ["zeta", "alpha", "mid", "omega", "common_b", "common_a", "external"]);
+}
+
+#[test]
+fn test_elf_section_flags() {
+    let code = "\
+    .section .rodata.str1.1,\"aMS\",@progbits,1
+    .asciz \"hi\"
+    .section .rodata.cst8,\"aM\",@progbits,8
+    .quad 1
+    .section .rodata.str4.4,\"aMS\",@progbits,4
+    .long 104, 0
+    .section .init_array,\"aw\",%init_array
+    .quad 0
+    .section .note.GNU-stack,\"\",@progbits
+    .section .note.custom,\"a\",@note
+    .long 0
+    .section .tbss,\"awT\",@nobits
+    .zero 8
+    .section .kept,\"awR\"
+    .long 0
+    .section .my_code,\"ax\",@progbits
+    ret
+    .p2align 2
+";
+    let bytes = assemble(code, ObjectFileType::Elf);
+    let file = File::parse(&*bytes).unwrap();
+
+    let section = |name: &str| {
+        let section = file.section_by_name(name).unwrap();
+        let flags = match section.flags() {
+            SectionFlags::Elf { sh_flags } => sh_flags as u32,
+            flags => panic!("Unexpected flags {:?}", flags),
+        };
+        (section.kind(), flags)
+    };
+
+    let (alloc, write, exec) = (elf::SHF_ALLOC, elf::SHF_WRITE, elf::SHF_EXECINSTR);
+    let strings = elf::SHF_MERGE | elf::SHF_STRINGS;
+    assert_eq!(section(".rodata.str1.1"), (SectionKind::ReadOnlyString, alloc | strings));
+    assert_eq!(section(".rodata.cst8"), (SectionKind::ReadOnlyData, alloc | elf::SHF_MERGE));
+    assert_eq!(section(".rodata.str4.4"), (SectionKind::ReadOnlyString, alloc | strings));
+    assert_eq!(section(".init_array"), (SectionKind::Elf(elf::SHT_INIT_ARRAY), alloc | write));
+    assert_eq!(section(".note.GNU-stack"), (SectionKind::Other, 0));
+    assert_eq!(section(".note.custom"), (SectionKind::Note, alloc));
+    assert_eq!(section(".tbss"), (SectionKind::UninitializedTls, alloc | write | elf::SHF_TLS));
+    assert_eq!(section(".kept"), (SectionKind::Data, alloc | write | 1 << 21));
+    assert_eq!(section(".my_code"), (SectionKind::Text, alloc | exec));
+
+    let header = elf::FileHeader64::<Endianness>::parse(&*bytes).unwrap();
+    let sections = header.sections(Endianness::Little, &*bytes).unwrap();
+    let entity_size = |name: &str| {
+        let (_, section) = sections.section_by_name(Endianness::Little, name.as_bytes()).unwrap();
+        section.sh_entsize.get(Endianness::Little)
+    };
+    assert_eq!(entity_size(".rodata.str1.1"), 1);
+    assert_eq!(entity_size(".rodata.cst8"), 8);
+    assert_eq!(entity_size(".rodata.str4.4"), 4);
+    assert_eq!(entity_size(".init_array"), 0);
+
+    assert_eq!(file.section_by_name(".tbss").unwrap().file_range(), None);
+    // Custom code sections are padded with NOPs
+    assert_eq!(file.section_by_name(".my_code").unwrap().data().unwrap(), [0xC3, 0x0F, 0x1F, 0x00]);
+}
+
+#[test]
+fn test_comdat_groups() {
+    let code = "\
+    .section .text.f,\"axG\",@progbits,f,comdat
+    .globl f
+f:
+    ret
+    .section .data.g,\"awG\",@progbits,sig,comdat
+    .long 1
+    .section .rodata.m,\"aMG\",@progbits,4,sig,comdat
+    .long 2
+";
+    let bytes = assemble(code, ObjectFileType::Elf);
+    let file = File::parse(&*bytes).unwrap();
+
+    let groups = file
+        .comdats()
+        .map(|comdat| {
+            assert_eq!(comdat.kind(), ComdatKind::Any);
+            let sections = comdat
+                .sections()
+                .map(|index| file.section_by_index(index).unwrap().name().unwrap().to_string())
+                .collect::<Vec<_>>();
+            format!("{}: {}", symbol_name(&file, comdat.symbol()), sections.join(" "))
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(groups, ["f: .text.f", "sig: .data.g .rodata.m"]);
+
+    let flags = match file.section_by_name(".data.g").unwrap().flags() {
+        SectionFlags::Elf { sh_flags } => sh_flags as u32,
+        flags => panic!("Unexpected flags {:?}", flags),
+    };
+    assert_eq!(flags, elf::SHF_ALLOC | elf::SHF_WRITE | elf::SHF_GROUP);
+
+    // Signatures that aren't defined anywhere get a local symbol
+    assert!(find_symbol(&file, "f").is_global());
+    assert!(find_symbol(&file, "sig").is_local());
+}
+
+#[test]
+fn test_macho_section_flags() {
+    let code = "\
+    .section __TEXT,__text,regular,pure_instructions
+    ret
+    .section __TEXT,__patchable,regular,pure_instructions+self_modifying_code
+    ret
+    .section __DATA,__mod_init_func,mod_init_funcs
+    .quad 0
+    .section __DATA,__thread_bss,thread_local_zerofill
+    .zero 8
+    .section __TEXT,__literal8,8byte_literals
+    .quad 1
+    .section __TEXT,__cstring,cstring_literals
+    .asciz \"hi\"
+";
+    let bytes = assemble(code, ObjectFileType::MachO);
+    let file = File::parse(&*bytes).unwrap();
+
+    let flags = |name: &str| match file.section_by_name(name).unwrap().flags() {
+        SectionFlags::MachO { flags } => flags,
+        flags => panic!("Unexpected flags {:?}", flags),
+    };
+
+    let instructions = macho::S_ATTR_PURE_INSTRUCTIONS | macho::S_ATTR_SOME_INSTRUCTIONS;
+    assert_eq!(flags("__text"), instructions);
+    assert_eq!(flags("__patchable"), instructions | macho::S_ATTR_SELF_MODIFYING_CODE);
+    assert_eq!(flags("__mod_init_func"), macho::S_MOD_INIT_FUNC_POINTERS);
+    assert_eq!(flags("__thread_bss"), macho::S_THREAD_LOCAL_ZEROFILL);
+    assert_eq!(flags("__literal8"), macho::S_8BYTE_LITERALS);
+    assert_eq!(flags("__cstring"), macho::S_CSTRING_LITERALS);
+
+    assert_eq!(file.section_by_name("__thread_bss").unwrap().file_range(), None);
+}
+
+#[test]
+fn test_invalid_section_flags() {
+    let parse = |code: &str| parse_lines("test.s".to_string(), code.to_string());
+
+    // Only COMDAT groups are supported, linked sections aren't
+    assert!(parse(".section .text.f,\"axG\",@progbits,f\n").is_err());
+    assert!(parse(".section .text.f,\"axG\",@progbits,f,bogus\n").is_err());
+    assert!(parse(".section .text.f,\"axo\",@progbits,.text\n").is_err());
+    assert!(parse(".section .text.f,\"ax?\",@progbits\n").is_err());
+    assert!(parse(".section .rodata.cst4,\"aM\",@progbits\n").is_err());
+    assert!(parse(".section .foo,\"a\",@bogus\n").is_err());
+    assert!(parse(".section __TEXT,__foo,bogus\n").is_err());
+    assert!(parse(".section __TEXT,__foo,regular,pure_instructions+bogus\n").is_err());
+}
+
+#[test]
+fn test_global_declared_in_other_section() {
+    let code = "\
+    .data
+    .globl f
+    .text
+f:
+    jmp f
+    call f
+";
+    let bytes = assemble(code, ObjectFileType::Elf);
+    let file = File::parse(&*bytes).unwrap();
+    let text = file.section_by_name(".text").unwrap();
+
+    // f may be preempted, so neither reference can be resolved in place
+    assert_eq!(text.data().unwrap(), &[0xE9, 0, 0, 0, 0, 0xE8, 0, 0, 0, 0]);
+    assert_eq!(text.relocations().count(), 2);
+    assert!(find_symbol(&file, "f").is_global());
+}
@@ -6,6 +6,7 @@
 
 use clap::{command, Arg, App, PossibleValue};
 
+use crate::parse::symbols::UndefinedSymbols;
 use crate::ObjectFileType;
 
 #[derive(Default)]
@@ -13,6 +14,7 @@ pub struct CommandLine {
     pub format: ObjectFileType,
     pub input: String,
     pub output: String,
+    pub undefined: UndefinedSymbols,
 }
 
 pub fn get_app() -> App<'static> {
@@ -36,6 +38,18 @@ pub fn get_app() -> App<'static> {
         .help("The output filename")
         .default_value("a.out");
 
+    let arg_undefined = Arg::new("undefined")
+        .long("undefined")
+        .takes_value(true)
+        .value_name("action")
+        .help("What to do about references to symbols that aren't defined in the file")
+        .value_parser([
+            PossibleValue::new("allow"),
+            PossibleValue::new("warn"),
+            PossibleValue::new("error"),
+        ])
+        .default_value(UndefinedSymbols::default().into());
+
     let arg_input_file = Arg::new("input file")
         .takes_value(true)
         .value_name("filename")
@@ -44,5 +58,17 @@ pub fn get_app() -> App<'static> {
     command!()
         .arg(arg_format)
         .arg(arg_output)
+        .arg(arg_undefined)
         .arg(arg_input_file)
 }
+
+pub fn get_command_line() -> CommandLine {
+    let matches = get_app().get_matches();
+
+    CommandLine {
+        format: matches.get_one::<String>("format").unwrap().parse().unwrap(),
+        input: matches.get_one::<String>("input file").unwrap().clone(),
+        output: matches.get_one::<String>("output file").unwrap().clone(),
+        undefined: matches.get_one::<String>("undefined").unwrap().parse().unwrap(),
+    }
+}
@@ -5,7 +5,11 @@
  * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
 
 use crate::number::Number;
-use crate::parse::helpers::{Immediate, ImmediateType, Relativity};
+use crate::parse::helpers::{
+    Immediate, ImmediateType, Memory, Relativity, Rm, Specifier, REGS_MASK, REGS_XMM, REGS_XMM_EVEX, REGS_YMM,
+    REGS_YMM_EVEX, REGS_ZMM,
+};
+use crate::parse::ParseError;
 use std::fmt::{Debug, Formatter};
 use std::io::Write;
 use std::str::FromStr;
@@ -29,9 +33,137 @@ pub enum Register {
     R13,
     R14,
     R15,
+    /// ah, ch, dh and bh take the place of spl, bpl, sil and dil without a REX prefix, so they can't be used with one
+    Ah = 0x14,
+    Ch,
+    Dh,
+    Bh,
+    /// spl, bpl, sil and dil, which need a REX prefix, even if it's empty
+    Spl = 0x24,
+    Bpl,
+    Sil,
+    Dil,
+    /// The SSE registers, which are encoded like the general purpose ones. Bit 4 is only encoded by EVEX.
+    Xmm0 = 0x40,
+    Xmm1,
+    Xmm2,
+    Xmm3,
+    Xmm4,
+    Xmm5,
+    Xmm6,
+    Xmm7,
+    Xmm8,
+    Xmm9,
+    Xmm10,
+    Xmm11,
+    Xmm12,
+    Xmm13,
+    Xmm14,
+    Xmm15,
+    Xmm16,
+    Xmm17,
+    Xmm18,
+    Xmm19,
+    Xmm20,
+    Xmm21,
+    Xmm22,
+    Xmm23,
+    Xmm24,
+    Xmm25,
+    Xmm26,
+    Xmm27,
+    Xmm28,
+    Xmm29,
+    Xmm30,
+    Xmm31,
+    /// The AVX registers, whose lower halves are the SSE registers
+    Ymm0 = 0x60,
+    Ymm1,
+    Ymm2,
+    Ymm3,
+    Ymm4,
+    Ymm5,
+    Ymm6,
+    Ymm7,
+    Ymm8,
+    Ymm9,
+    Ymm10,
+    Ymm11,
+    Ymm12,
+    Ymm13,
+    Ymm14,
+    Ymm15,
+    Ymm16,
+    Ymm17,
+    Ymm18,
+    Ymm19,
+    Ymm20,
+    Ymm21,
+    Ymm22,
+    Ymm23,
+    Ymm24,
+    Ymm25,
+    Ymm26,
+    Ymm27,
+    Ymm28,
+    Ymm29,
+    Ymm30,
+    Ymm31,
+    /// The AVX-512 registers, whose lower halves are the AVX registers
+    Zmm0 = 0x80,
+    Zmm1,
+    Zmm2,
+    Zmm3,
+    Zmm4,
+    Zmm5,
+    Zmm6,
+    Zmm7,
+    Zmm8,
+    Zmm9,
+    Zmm10,
+    Zmm11,
+    Zmm12,
+    Zmm13,
+    Zmm14,
+    Zmm15,
+    Zmm16,
+    Zmm17,
+    Zmm18,
+    Zmm19,
+    Zmm20,
+    Zmm21,
+    Zmm22,
+    Zmm23,
+    Zmm24,
+    Zmm25,
+    Zmm26,
+    Zmm27,
+    Zmm28,
+    Zmm29,
+    Zmm30,
+    Zmm31,
+    /// The opmask registers, which are only used by AVX-512
+    K0 = 0xA0,
+    K1,
+    K2,
+    K3,
+    K4,
+    K5,
+    K6,
+    K7,
     Rip = 0xFF,
 }
 
+impl Register {
+    fn is_high_byte(self) -> bool {
+        matches!(self, Ah | Ch | Dh | Bh)
+    }
+
+    fn needs_rex(self) -> bool {
+        matches!(self, Spl | Bpl | Sil | Dil)
+    }
+}
+
 impl TryFrom<i32> for Register {
     type Error = ();
 
@@ -46,13 +178,126 @@ impl TryFrom<i32> for Register {
             6 => Ok(Si),
             7 => Ok(Di),
             8 => Ok(R8),
-            10 => Ok(R9),
-            11 => Ok(R10),
-            12 => Ok(R11),
-            13 => Ok(R12),
-            14 => Ok(R13),
-            15 => Ok(R14),
-            16 => Ok(R15),
+            9 => Ok(R9),
+            10 => Ok(R10),
+            11 => Ok(R11),
+            12 => Ok(R12),
+            13 => Ok(R13),
+            14 => Ok(R14),
+            15 => Ok(R15),
+            0x14 => Ok(Ah),
+            0x15 => Ok(Ch),
+            0x16 => Ok(Dh),
+            0x17 => Ok(Bh),
+            0x24 => Ok(Spl),
+            0x25 => Ok(Bpl),
+            0x26 => Ok(Sil),
+            0x27 => Ok(Dil),
+            0x40 => Ok(Xmm0),
+            0x41 => Ok(Xmm1),
+            0x42 => Ok(Xmm2),
+            0x43 => Ok(Xmm3),
+            0x44 => Ok(Xmm4),
+            0x45 => Ok(Xmm5),
+            0x46 => Ok(Xmm6),
+            0x47 => Ok(Xmm7),
+            0x48 => Ok(Xmm8),
+            0x49 => Ok(Xmm9),
+            0x4A => Ok(Xmm10),
+            0x4B => Ok(Xmm11),
+            0x4C => Ok(Xmm12),
+            0x4D => Ok(Xmm13),
+            0x4E => Ok(Xmm14),
+            0x4F => Ok(Xmm15),
+            0x50 => Ok(Xmm16),
+            0x51 => Ok(Xmm17),
+            0x52 => Ok(Xmm18),
+            0x53 => Ok(Xmm19),
+            0x54 => Ok(Xmm20),
+            0x55 => Ok(Xmm21),
+            0x56 => Ok(Xmm22),
+            0x57 => Ok(Xmm23),
+            0x58 => Ok(Xmm24),
+            0x59 => Ok(Xmm25),
+            0x5A => Ok(Xmm26),
+            0x5B => Ok(Xmm27),
+            0x5C => Ok(Xmm28),
+            0x5D => Ok(Xmm29),
+            0x5E => Ok(Xmm30),
+            0x5F => Ok(Xmm31),
+            0x60 => Ok(Ymm0),
+            0x61 => Ok(Ymm1),
+            0x62 => Ok(Ymm2),
+            0x63 => Ok(Ymm3),
+            0x64 => Ok(Ymm4),
+            0x65 => Ok(Ymm5),
+            0x66 => Ok(Ymm6),
+            0x67 => Ok(Ymm7),
+            0x68 => Ok(Ymm8),
+            0x69 => Ok(Ymm9),
+            0x6A => Ok(Ymm10),
+            0x6B => Ok(Ymm11),
+            0x6C => Ok(Ymm12),
+            0x6D => Ok(Ymm13),
+            0x6E => Ok(Ymm14),
+            0x6F => Ok(Ymm15),
+            0x70 => Ok(Ymm16),
+            0x71 => Ok(Ymm17),
+            0x72 => Ok(Ymm18),
+            0x73 => Ok(Ymm19),
+            0x74 => Ok(Ymm20),
+            0x75 => Ok(Ymm21),
+            0x76 => Ok(Ymm22),
+            0x77 => Ok(Ymm23),
+            0x78 => Ok(Ymm24),
+            0x79 => Ok(Ymm25),
+            0x7A => Ok(Ymm26),
+            0x7B => Ok(Ymm27),
+            0x7C => Ok(Ymm28),
+            0x7D => Ok(Ymm29),
+            0x7E => Ok(Ymm30),
+            0x7F => Ok(Ymm31),
+            0x80 => Ok(Zmm0),
+            0x81 => Ok(Zmm1),
+            0x82 => Ok(Zmm2),
+            0x83 => Ok(Zmm3),
+            0x84 => Ok(Zmm4),
+            0x85 => Ok(Zmm5),
+            0x86 => Ok(Zmm6),
+            0x87 => Ok(Zmm7),
+            0x88 => Ok(Zmm8),
+            0x89 => Ok(Zmm9),
+            0x8A => Ok(Zmm10),
+            0x8B => Ok(Zmm11),
+            0x8C => Ok(Zmm12),
+            0x8D => Ok(Zmm13),
+            0x8E => Ok(Zmm14),
+            0x8F => Ok(Zmm15),
+            0x90 => Ok(Zmm16),
+            0x91 => Ok(Zmm17),
+            0x92 => Ok(Zmm18),
+            0x93 => Ok(Zmm19),
+            0x94 => Ok(Zmm20),
+            0x95 => Ok(Zmm21),
+            0x96 => Ok(Zmm22),
+            0x97 => Ok(Zmm23),
+            0x98 => Ok(Zmm24),
+            0x99 => Ok(Zmm25),
+            0x9A => Ok(Zmm26),
+            0x9B => Ok(Zmm27),
+            0x9C => Ok(Zmm28),
+            0x9D => Ok(Zmm29),
+            0x9E => Ok(Zmm30),
+            0x9F => Ok(Zmm31),
+            0xA0 => Ok(K0),
+            0xA1 => Ok(K1),
+            0xA2 => Ok(K2),
+            0xA3 => Ok(K3),
+            0xA4 => Ok(K4),
+            0xA5 => Ok(K5),
+            0xA6 => Ok(K6),
+            0xA7 => Ok(K7),
+            0xFF => Ok(Rip),
             _ => Err(()),
         }
     }
@@ -62,22 +307,38 @@ impl FromStr for Register {
     type Err = ();
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        if ["ah", "al", "ax", "eax", "rax"].contains(&s) {
+        if ["al", "ax", "eax", "rax"].contains(&s) {
             Ok(Ax)
-        } else if ["bh", "bl", "bx", "ebx", "rbx"].contains(&s) {
+        } else if ["bl", "bx", "ebx", "rbx"].contains(&s) {
             Ok(Bx)
-        } else if ["ch", "cl", "cx", "ecx", "rcx"].contains(&s) {
+        } else if ["cl", "cx", "ecx", "rcx"].contains(&s) {
             Ok(Cx)
-        } else if ["dh", "dl", "dx", "edx", "rdx"].contains(&s) {
+        } else if ["dl", "dx", "edx", "rdx"].contains(&s) {
             Ok(Dx)
-        } else if ["sil", "si", "esi", "rsi"].contains(&s) {
+        } else if ["si", "esi", "rsi"].contains(&s) {
             Ok(Si)
-        } else if ["dil", "di", "edi", "rdi"].contains(&s) {
+        } else if ["di", "edi", "rdi"].contains(&s) {
             Ok(Di)
-        } else if ["spl", "sp", "esp", "rsp"].contains(&s) {
+        } else if ["sp", "esp", "rsp"].contains(&s) {
             Ok(Sp)
-        } else if ["bpl", "bp", "ebp", "rbp"].contains(&s) {
+        } else if ["bp", "ebp", "rbp"].contains(&s) {
             Ok(Bp)
+        } else if s == "ah" {
+            Ok(Ah)
+        } else if s == "ch" {
+            Ok(Ch)
+        } else if s == "dh" {
+            Ok(Dh)
+        } else if s == "bh" {
+            Ok(Bh)
+        } else if s == "spl" {
+            Ok(Spl)
+        } else if s == "bpl" {
+            Ok(Bpl)
+        } else if s == "sil" {
+            Ok(Sil)
+        } else if s == "dil" {
+            Ok(Dil)
         } else if ["r8b", "r8w", "r8d", "r8"].contains(&s) {
             Ok(R8)
         } else if ["r9b", "r9w", "r9d", "r9"].contains(&s) {
@@ -94,6 +355,14 @@ impl FromStr for Register {
             Ok(R14)
         } else if ["r15b", "r15w", "r15d", "r15"].contains(&s) {
             Ok(R15)
+        } else if let Some(number) = REGS_XMM.iter().chain(&REGS_XMM_EVEX).position(|name| *name == s) {
+            Register::try_from(Xmm0 as i32 + number as i32)
+        } else if let Some(number) = REGS_YMM.iter().chain(&REGS_YMM_EVEX).position(|name| *name == s) {
+            Register::try_from(Ymm0 as i32 + number as i32)
+        } else if let Some(number) = REGS_ZMM.iter().position(|name| *name == s) {
+            Register::try_from(Zmm0 as i32 + number as i32)
+        } else if let Some(number) = REGS_MASK.iter().position(|name| *name == s) {
+            Register::try_from(K0 as i32 + number as i32)
         } else if ["rip", "eip", "ip"].contains(&s) {
             Ok(Rip)
         } else {
@@ -102,6 +371,134 @@ impl FromStr for Register {
     }
 }
 
+/// A segment register, which can override the segment of a memory operand, like in `mov rax, fs:[0x28]`
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub enum Segment {
+    Es,
+    Cs,
+    Ss,
+    Ds,
+    Fs,
+    Gs,
+}
+
+impl Segment {
+    fn prefix(self) -> u8 {
+        match self {
+            Segment::Es => 0x26,
+            Segment::Cs => 0x2E,
+            Segment::Ss => 0x36,
+            Segment::Ds => 0x3E,
+            Segment::Fs => 0x64,
+            Segment::Gs => 0x65,
+        }
+    }
+}
+
+impl FromStr for Segment {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "es" => Ok(Segment::Es),
+            "cs" => Ok(Segment::Cs),
+            "ss" => Ok(Segment::Ss),
+            "ds" => Ok(Segment::Ds),
+            "fs" => Ok(Segment::Fs),
+            "gs" => Ok(Segment::Gs),
+            _ => Err(()),
+        }
+    }
+}
+
+/// A prefix written in front of the mnemonic, like `lock` in `lock cmpxchg [rdi], rsi`
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub enum Prefix {
+    Lock,
+    Rep,
+    Repe,
+    Repne,
+    Xacquire,
+    Xrelease,
+    Notrack,
+    Bnd,
+}
+
+impl Prefix {
+    fn byte(self) -> u8 {
+        match self {
+            Prefix::Lock => 0xF0,
+            Prefix::Rep | Prefix::Repe | Prefix::Xrelease => 0xF3,
+            Prefix::Repne | Prefix::Xacquire | Prefix::Bnd => 0xF2,
+            Prefix::Notrack => 0x3E,
+        }
+    }
+
+    /// Only one prefix of each group can be used, and the groups are written in this order
+    fn group(self) -> usize {
+        match self {
+            Prefix::Notrack => 0,
+            Prefix::Rep | Prefix::Repe | Prefix::Repne | Prefix::Xacquire | Prefix::Xrelease | Prefix::Bnd => 1,
+            Prefix::Lock => 2,
+        }
+    }
+}
+
+impl FromStr for Prefix {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "lock" => Ok(Prefix::Lock),
+            "rep" => Ok(Prefix::Rep),
+            "repe" | "repz" => Ok(Prefix::Repe),
+            "repne" | "repnz" => Ok(Prefix::Repne),
+            "xacquire" => Ok(Prefix::Xacquire),
+            "xrelease" => Ok(Prefix::Xrelease),
+            "notrack" => Ok(Prefix::Notrack),
+            "bnd" => Ok(Prefix::Bnd),
+            _ => Err(()),
+        }
+    }
+}
+
+/// A rounding mode decoration, like `{rn-sae}`, or `{sae}`, which only suppresses exceptions
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub enum Rounding {
+    Nearest,
+    Down,
+    Up,
+    Zero,
+    Sae,
+}
+
+impl Rounding {
+    /// The value of EVEX.L'L, which holds the rounding mode if EVEX.b is set for an operation on registers
+    fn bits(self) -> u8 {
+        match self {
+            Rounding::Nearest | Rounding::Sae => 0b00,
+            Rounding::Down => 0b01,
+            Rounding::Up => 0b10,
+            Rounding::Zero => 0b11,
+        }
+    }
+}
+
+impl FromStr for Rounding {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "rn-sae" => Ok(Rounding::Nearest),
+            "rd-sae" => Ok(Rounding::Down),
+            "ru-sae" => Ok(Rounding::Up),
+            "rz-sae" => Ok(Rounding::Zero),
+            "sae" => Ok(Rounding::Sae),
+            _ => Err(()),
+        }
+    }
+}
+
 #[derive(Copy, Clone, Debug, PartialEq, Eq)]
 pub enum Mod {
     NoOffset = 0b00,
@@ -110,7 +507,6 @@ pub enum Mod {
     NoDereference = 0b11,
 }
 
-use crate::parse::helpers::Relativity::RipRelative;
 use Mod::*;
 
 #[derive(Copy, Clone, Debug, Eq, PartialOrd, PartialEq)]
@@ -121,6 +517,19 @@ pub enum Size {
     QWord,
 }
 
+impl Size {
+    pub fn bytes(&self) -> usize {
+        use Size::*;
+
+        match self {
+            Byte => 1,
+            Word => 2,
+            DWord => 4,
+            QWord => 8,
+        }
+    }
+}
+
 impl TryFrom<usize> for Size {
     type Error = ();
 
@@ -153,90 +562,394 @@ impl TryFrom<String> for Size {
     }
 }
 
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub enum Opcode {
     Byte(u8),
-    Rex { wide: bool },
+    Rex {
+        wide: bool,
+    },
+    /// An opcode byte with the low bits of a register added to it, like `B8+rd`
+    AddRegister(u8, Register),
+    /// A VEX prefix, which replaces the REX prefix, the mandatory prefix and the escape bytes, like
+    /// `VEX.256.66.0F38.W0`
+    Vex {
+        /// Set for 256-bit operations
+        long: bool,
+        /// The implied mandatory prefix (0x66, 0xF3 or 0xF2), or 0 for none
+        prefix: u8,
+        /// The last implied escape byte, 0x0F for `0F`, 0x38 for `0F 38` or 0x3A for `0F 3A`
+        map: u8,
+        wide: bool,
+        /// The extra source register encoded in VEX.vvvv
+        register: Option<Register>,
+    },
+    /// An EVEX prefix, which extends VEX with 32 registers, 512-bit operations and operand decorations
+    Evex(Evex),
     // ModPart(u8),
 }
 
-#[derive(Debug)]
+/// The fields of an EVEX prefix, like `EVEX.512.66.0F.W0`, and the decorations of the operands
+#[derive(Clone, Debug)]
+pub struct Evex {
+    /// 0 for 128-bit, 1 for 256-bit and 2 for 512-bit operations
+    pub length: u8,
+    /// The implied mandatory prefix (0x66, 0xF3 or 0xF2), or 0 for none
+    pub prefix: u8,
+    /// The last implied escape byte, 0x0F for `0F`, 0x38 for `0F 38` or 0x3A for `0F 3A`
+    pub map: u8,
+    pub wide: bool,
+    /// The extra source register encoded in EVEX.vvvv and EVEX.V'
+    pub register: Option<Register>,
+    /// The opmask register of `{k1}`
+    pub mask: Option<Register>,
+    /// Set by `{z}`, which zeroes the masked elements instead of leaving them alone
+    pub zeroing: bool,
+    /// Set by `{1to16}` and friends, which repeat a single element from memory
+    pub broadcast: bool,
+    pub rounding: Option<Rounding>,
+    /// The size of the memory access, which 8-bit displacements are multiplied by
+    pub scale: u8,
+}
+
+#[derive(Clone, Debug)]
 pub struct Instruction {
     name: String,
-    r#mod: Mod,
     opcode: Vec<Opcode>,
-    offset: Option<Immediate>,
     reg: Option<Register>,
-    rm: Option<Register>,
+    rm: Option<Rm>,
     imm1: Option<Immediate>,
     imm2: Option<Immediate>,
+    /// The size of the operation, if it differs from the default of 32 bits without asking for REX.W
+    operand_size: Option<Size>,
+    /// The size of implicit addresses, like the count register of `jecxz`
+    address_size: Option<Size>,
+    prefixes: Vec<Prefix>,
 }
 
+#[derive(Clone)]
 pub struct Reference {
     pub to: String,
+    /// Set for differences like `end - start`, which are resolved within the section instead of by the linker
+    pub from: Option<String>,
     pub at: usize,
     pub rel: Relativity,
+    pub size: Size,
+    pub sign_extended: bool,
+    pub specifier: Option<Specifier>,
+    /// Added to the symbol's address. PC-relative references are relative to the start of their field, so this
+    /// includes the distance from there to the end of the instruction.
+    pub addend: i64,
 }
 
 pub struct EncodedInstruction {
     bytes: Vec<u8>,
+    #[allow(dead_code)]
     name: String,
     refs: Vec<Reference>,
 }
 
-fn to_byte(reg: &Option<Register>) -> u8 {
-    if reg == &Some(Rip) {
-        0b101
-    } else {
-        reg.unwrap_or(Ax) as u8
-    }
-}
+/// Legacy prefixes that have to come before a REX prefix
+const LEGACY_PREFIXES: [u8; 3] = [0x66, 0xF2, 0xF3];
+
+/// Switches an operation from 32 to 16 bits
+const OPERAND_SIZE_PREFIX: u8 = 0x66;
+/// Switches addresses from 64 to 32 bits
+const ADDRESS_SIZE_PREFIX: u8 = 0x67;
+
+/// Instructions that can be locked, if they write to memory
+const LOCKABLE: [&str; 19] = [
+    "adc",
+    "add",
+    "and",
+    "btc",
+    "btr",
+    "bts",
+    "cmpxchg",
+    "cmpxchg8b",
+    "cmpxchg16b",
+    "dec",
+    "inc",
+    "neg",
+    "not",
+    "or",
+    "sbb",
+    "sub",
+    "xadd",
+    "xchg",
+    "xor",
+];
+
+/// String instructions, which can be repeated
+const STRING_INSTRUCTIONS: [&str; 26] = [
+    "cmpsb", "cmpsw", "cmpsd", "cmpsq", "insb", "insw", "insd", "lodsb", "lodsw", "lodsd", "lodsq", "movsb", "movsw",
+    "movsd", "movsq", "outsb", "outsw", "outsd", "scasb", "scasw", "scasd", "scasq", "stosb", "stosw", "stosd",
+    "stosq",
+];
 
 impl Instruction {
     pub fn new(
         name: String,
-        r#mod: Mod,
         opcode: Vec<Opcode>,
-        offset: Option<Immediate>,
         reg: Option<Register>,
-        rm: Option<Register>,
+        rm: Option<Rm>,
         imm1: Option<Immediate>,
         imm2: Option<Immediate>,
     ) -> Self {
-        Instruction { name, r#mod, opcode, offset, reg, rm, imm1, imm2 }
+        Instruction { name, opcode, reg, rm, imm1, imm2, operand_size: None, address_size: None, prefixes: Vec::new() }
+    }
+
+    pub fn with_operand_size(self, size: Size) -> Self {
+        Instruction { operand_size: Some(size), ..self }
+    }
+
+    pub fn with_address_size(self, size: Size) -> Self {
+        Instruction { address_size: Some(size), ..self }
+    }
+
+    /// Returns the rel32 form of a short jump, or None if there is none (loop, jrcxz, ...)
+    pub fn relaxed(&self) -> Option<Instruction> {
+        let opcode = match self.opcode.as_slice() {
+            [Opcode::Byte(0xEB)] => vec![Opcode::Byte(0xE9)],
+            [Opcode::Byte(cc @ 0x70..=0x7F)] => vec![Opcode::Byte(0x0F), Opcode::Byte(cc + 0x10)],
+            _ => return None,
+        };
+
+        let mut rel = self.imm1.clone()?;
+        rel.size = Size::DWord;
+
+        // Prefixes like bnd still apply to the longer jump
+        Some(Instruction { opcode, imm1: Some(rel), ..self.clone() })
+    }
+
+    /// The symbols the operands refer to
+    pub fn symbols(&self) -> Vec<String> {
+        let displacement = match &self.rm {
+            Some(Rm::Memory(memory)) => memory.displacement.as_ref(),
+            _ => None,
+        };
+
+        [displacement, self.imm1.as_ref(), self.imm2.as_ref()]
+            .into_iter()
+            .flatten()
+            .flat_map(Immediate::symbols)
+            .collect()
+    }
+
+    /// Adds the prefixes written in front of the mnemonic, if the instruction allows them. Fails with the index of
+    /// the first prefix that doesn't work.
+    pub fn with_prefixes(self, prefixes: Vec<Prefix>) -> Result<Self, (usize, ParseError)> {
+        let name = self.name.as_str();
+        let memory = match &self.rm {
+            Some(Rm::Memory(memory)) => Some(memory),
+            _ => None,
+        };
+        let primary = self.opcode.iter().rev().find_map(|part| match part {
+            Opcode::Byte(byte) => Some(*byte),
+            _ => None,
+        });
+
+        // The arithmetic instructions also have forms that load from memory into a register, like `add rax, [rbx]`
+        let loads = primary.is_some_and(|opcode| opcode < 0x40 && opcode & 0b111 >= 2);
+        let lockable = LOCKABLE.contains(&name) && memory.is_some() && !loads;
+        // xchg with memory is always locked, and xrelease can also be used on the store that releases a lock
+        let elidable = name == "xchg" && memory.is_some();
+        let stores = name == "mov"
+            && memory.is_some()
+            && primary.is_some_and(|opcode| [0x88, 0x89, 0xC6, 0xC7].contains(&opcode));
+        let branch = name == "call" || name == "jmp";
+        let conditional = name.starts_with('j') && name != "jmp" && !name.ends_with("cxz");
+
+        for (i, prefix) in prefixes.iter().enumerate() {
+            // notrack shares its byte with the ds segment override
+            let overridden = *prefix == Prefix::Notrack && memory.is_some_and(|memory| memory.segment.is_some());
+            if overridden || prefixes[..i].iter().any(|other| other.group() == prefix.group()) {
+                return Err((i, ParseError::DuplicatePrefix));
+            }
+
+            let allowed = match prefix {
+                Prefix::Lock => lockable,
+                Prefix::Rep | Prefix::Repe | Prefix::Repne => {
+                    (STRING_INSTRUCTIONS.contains(&name) && self.rm.is_none()) || name == "ret"
+                }
+                Prefix::Xacquire => elidable || (lockable && prefixes.contains(&Prefix::Lock)),
+                Prefix::Xrelease => elidable || stores || (lockable && prefixes.contains(&Prefix::Lock)),
+                Prefix::Notrack => branch && self.rm.is_some(),
+                Prefix::Bnd => branch || conditional || name == "ret",
+            };
+            if !allowed {
+                return Err((i, ParseError::InvalidPrefix));
+            }
+        }
+
+        Ok(Instruction { prefixes, ..self })
+    }
+
+    /// The registers of the instruction, apart from the ones addressing memory
+    fn registers(&self) -> impl Iterator<Item = Register> + '_ {
+        let rm = match &self.rm {
+            Some(Rm::Register(rm)) => Some(*rm),
+            _ => None,
+        };
+        let added = self.opcode.iter().filter_map(|part| match part {
+            Opcode::AddRegister(_, reg) => Some(*reg),
+            Opcode::Vex { register, .. } => *register,
+            Opcode::Evex(evex) => evex.register,
+            _ => None,
+        });
+
+        self.reg.into_iter().chain(rm).chain(added)
+    }
+
+    /// Checks that the instruction can be encoded, which it can't if it uses ah, ch, dh or bh, but needs a REX prefix
+    pub fn check_registers(&self) -> Result<(), ParseError> {
+        let has_rex = self.rex_bits() != 0
+            || self.registers().any(Register::needs_rex)
+            || self.opcode.iter().any(|part| matches!(part, Opcode::Rex { .. }));
+
+        if has_rex && self.registers().any(Register::is_high_byte) {
+            Err(ParseError::HighByteRegister)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Whether the instruction needs an EVEX prefix. Other assemblers only use it if there's no other encoding.
+    pub fn is_evex(&self) -> bool {
+        self.opcode.iter().any(|part| matches!(part, Opcode::Evex(_)))
+    }
+
+    /// The R', X and B bits of the EVEX prefix, like [Instruction::rex_bits]. Registers 16 to 31 keep their highest
+    /// bit in R' and, as there is no index register then, in X.
+    fn evex_bits(&self) -> u8 {
+        let higher = |reg: Register| (reg as u8 >> 4) & 1;
+
+        let r = self.reg.map_or(0, higher);
+        let x = match &self.rm {
+            Some(Rm::Register(rm)) => higher(*rm),
+            _ => 0,
+        };
+
+        r << 3 | x << 1 | self.rex_bits()
+    }
+
+    /// The R, X and B bits of the REX prefix
+    fn rex_bits(&self) -> u8 {
+        let high = |reg: Register| (reg as u8 >> 3) & 1;
+
+        let r = self.reg.map_or(0, high);
+        let (x, b) = match &self.rm {
+            Some(Rm::Register(rm)) => (0, high(*rm)),
+            Some(Rm::Memory(memory)) => {
+                (memory.index.map_or(0, high), memory.base.filter(|base| base != &Rip).map_or(0, high))
+            }
+            None => {
+                let added = self.opcode.iter().find_map(|part| match part {
+                    Opcode::AddRegister(_, reg) => Some(*reg),
+                    _ => None,
+                });
+                (0, added.map_or(0, high))
+            }
+        };
+
+        r << 2 | x << 1 | b
+    }
+
+    /// The legacy prefixes the instruction needs, which are written in front of everything
+    fn legacy_prefixes(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        let mut prefixes = self.prefixes.clone();
+        prefixes.sort_by_key(|prefix| prefix.group());
+
+        // Same order as GNU as, so the output can be compared byte by byte
+        if let Some(Rm::Memory(Memory { segment: Some(segment), base, .. })) = &self.rm {
+            // Overriding a segment with the one that's used anyway does nothing
+            let default = if matches!(base, Some(Sp | Bp)) { Segment::Ss } else { Segment::Ds };
+            if *segment != default {
+                bytes.push(segment.prefix());
+            }
+        }
+        if prefixes.first() == Some(&Prefix::Notrack) {
+            bytes.push(prefixes.remove(0).byte());
+        }
+
+        let address_size = match &self.rm {
+            Some(Rm::Memory(memory)) => Some(memory.address_size),
+            _ => self.address_size,
+        };
+        if address_size == Some(Size::DWord) {
+            bytes.push(ADDRESS_SIZE_PREFIX);
+        }
+
+        if self.operand_size == Some(Size::Word) {
+            bytes.push(OPERAND_SIZE_PREFIX);
+        }
+
+        bytes.extend(prefixes.iter().map(|prefix| prefix.byte()));
+        bytes
     }
 
     pub fn encode(&self) -> EncodedInstruction {
         let mut encoded = EncodedInstruction::new(self.name.clone());
 
-        for part in &self.opcode {
+        for prefix in self.legacy_prefixes() {
+            encoded.write_byte(prefix);
+        }
+
+        let rex = self.rex_bits();
+        // Extended registers need a REX prefix, even if the opcode doesn't ask for one. A VEX prefix contains its bits.
+        let needs_rex = (rex != 0 || self.registers().any(Register::needs_rex))
+            && !self
+                .opcode
+                .iter()
+                .any(|part| matches!(part, Opcode::Rex { .. } | Opcode::Vex { .. } | Opcode::Evex(_)));
+        let rex_at = self
+            .opcode
+            .iter()
+            .take_while(|part| matches!(part, Opcode::Byte(b) if LEGACY_PREFIXES.contains(b)))
+            .count();
+
+        for (i, part) in self.opcode.iter().enumerate() {
+            if needs_rex && i == rex_at {
+                encoded.write_byte(0b01000000 | rex);
+            }
+
             match part {
                 Opcode::Byte(byte) => encoded.write_byte(*byte),
-                Opcode::Rex { wide } => {
-                    let rm_bw: u8 = (to_byte(&self.rm) & 0b1000) >> 1;
-                    let reg_bw: u8 = (to_byte(&self.reg) & 0b1000) >> 3;
-                    let rex: u8 = 0b01000000 | (*wide as u8) << 3 | rm_bw | reg_bw;
-                    encoded.write_byte(rex);
-                } //Opcode::ModPart(_) => {}
+                Opcode::Rex { wide } => encoded.write_byte(0b01000000 | (*wide as u8) << 3 | rex),
+                Opcode::AddRegister(byte, reg) => encoded.write_byte(byte + (*reg as u8 & 0b111)),
+                Opcode::Vex { long, prefix, map, wide, register } => {
+                    encoded.write_vex(rex, *long, *prefix, *map, *wide, *register)
+                }
+                Opcode::Evex(evex) => encoded.write_evex(evex, self.evex_bits()),
             }
         }
 
-        if self.rm.is_some() || self.reg.is_some() {
-            encoded.write_mod(self.r#mod, to_byte(&self.rm), to_byte(&self.reg));
+        let scale = self.opcode.iter().find_map(|part| match part {
+            Opcode::Evex(evex) => Some(evex.scale as i64),
+            _ => None,
+        });
+
+        let reg = self.reg.map_or(0, |reg| reg as u8);
+        match &self.rm {
+            Some(Rm::Register(rm)) => encoded.write_mod(NoDereference, *rm as u8, reg),
+            Some(Rm::Memory(memory)) => encoded.write_memory(memory, reg, scale.unwrap_or(1)),
+            None => {}
         }
 
-        if self.offset.is_some() {
-            if self.r#mod == Offset32Bit || self.offset.as_ref().unwrap().rel == RipRelative {
-                encoded.write_imm::<i32, [u8; 4]>(self.offset.as_ref().unwrap());
-            } else if self.r#mod == Offset8Bit {
-                encoded.write_imm::<i8, [u8; 1]>(self.offset.as_ref().unwrap());
-            }
+        if let Some(imm1) = &self.imm1 {
+            encoded.write_immediate(imm1);
         }
-        if self.imm1.is_some() {
-            encoded.write_immediate(self.imm1.as_ref().unwrap());
+        if let Some(imm2) = &self.imm2 {
+            encoded.write_immediate(imm2);
         }
-        if self.imm2.is_some() {
-            encoded.write_immediate(self.imm2.as_ref().unwrap());
+
+        // The CPU adds PC-relative fields to the address of the next instruction, e.g. an imm32 might still follow a
+        // rip-relative displacement
+        let len = encoded.bytes.len();
+        for r in &mut encoded.refs {
+            if r.rel != Relativity::Absolute {
+                r.addend -= (len - r.at) as i64;
+            }
         }
 
         encoded
@@ -267,21 +980,35 @@ impl EncodedInstruction {
     }
 
     pub fn write_num<I: Number<Output = O>, O: AsRef<[u8]>>(&mut self, value: I) {
-        self.bytes.write(value.to_bytes(false).as_ref()).unwrap();
+        self.bytes.write_all(value.to_bytes(false).as_ref()).unwrap();
     }
 
-    pub fn write_imm<'a, I: Number<Output = O> + From<i8> + TryFrom<i32>, O: AsRef<[u8]>>(&mut self, imm: &Immediate) {
+    pub fn write_imm<I: Number<Output = O> + From<i8> + TryFrom<i64>, O: AsRef<[u8]>>(&mut self, imm: &Immediate) {
         let at = self.bytes.len();
-        match &imm.typ {
+        let (to, from, addend) = match &imm.typ {
             ImmediateType::Integer(i) => {
-                // The unsafe block helps avoid needing Debug for I's TryInto error
-                self.write_num::<I, O>(unsafe { (*i).try_into().unwrap_unchecked() });
+                // Immediates are range checked while parsing, so this can only fail if an encoding is broken
+                let num = I::try_from(*i)
+                    .unwrap_or_else(|_| panic!("Immediate {} doesn't fit into {} bytes", i, std::mem::size_of::<I>()));
+                self.write_num::<I, O>(num);
+                return;
             }
-            ImmediateType::Reference(r) => {
-                self.write_num::<I, O>(0.into());
-                self.refs.push(Reference { to: r.to_string(), at, rel: imm.rel });
-            }
-        }
+            ImmediateType::Reference(to, addend) => (to.clone(), None, *addend),
+            ImmediateType::Difference(end, start, offset) => (end.clone(), Some(start.clone()), *offset),
+        };
+
+        self.write_num::<I, O>(0.into());
+        let size = Size::try_from(std::mem::size_of::<I>() * 8).unwrap();
+        self.refs.push(Reference {
+            to,
+            from,
+            at,
+            rel: imm.rel,
+            size,
+            sign_extended: imm.sign_extended,
+            specifier: imm.specifier,
+            addend,
+        });
     }
 
     pub fn write_immediate(&mut self, imm: &Immediate) {
@@ -293,8 +1020,119 @@ impl EncodedInstruction {
         };
     }
 
+    /// Writes the four byte EVEX prefix. R, X, B, R', vvvv and V' are stored inverted.
+    fn write_evex(&mut self, evex: &Evex, bits: u8) {
+        let pp = match evex.prefix {
+            0x66 => 0b01,
+            0xF3 => 0b10,
+            0xF2 => 0b11,
+            _ => 0b00,
+        };
+        let mm = match evex.map {
+            0x38 => 0b10,
+            0x3A => 0b11,
+            _ => 0b01,
+        };
+        let register = evex.register.map_or(0, |register| register as u8);
+        let vvvv = !register & 0b1111;
+        let v = !register >> 4 & 1;
+        // Rounding replaces the vector length, and shares EVEX.b with broadcasting
+        let length = evex.rounding.map_or(evex.length, Rounding::bits);
+        let b = evex.broadcast || evex.rounding.is_some();
+        let aaa = evex.mask.map_or(0, |mask| mask as u8 & 0b111);
+
+        // R, X and B come first, then R'
+        let rxb = !bits & 0b0111;
+        let r = !bits >> 3 & 1;
+        self.write_byte(0x62);
+        self.write_byte(rxb << 5 | r << 4 | mm);
+        self.write_byte((evex.wide as u8) << 7 | vvvv << 3 | 0b100 | pp);
+        self.write_byte((evex.zeroing as u8) << 7 | length << 5 | (b as u8) << 4 | v << 3 | aaa);
+    }
+
+    /// Writes the two byte form of a VEX prefix if possible, and the three byte form otherwise. R, X, B and vvvv are
+    /// stored inverted.
+    fn write_vex(&mut self, rex: u8, long: bool, prefix: u8, map: u8, wide: bool, register: Option<Register>) {
+        let pp = match prefix {
+            0x66 => 0b01,
+            0xF3 => 0b10,
+            0xF2 => 0b11,
+            _ => 0b00,
+        };
+        let mmmmm = match map {
+            0x38 => 0b00010,
+            0x3A => 0b00011,
+            _ => 0b00001,
+        };
+        let vvvv = !register.map_or(0, |register| register as u8) & 0b1111;
+        let last = (vvvv << 3) | (long as u8) << 2 | pp;
+
+        // The two byte form only has room for R, and implies W0 and the 0F map
+        if rex & 0b011 == 0 && !wide && mmmmm == 0b00001 {
+            self.write_byte(0xC5);
+            self.write_byte((!rex & 0b100) << 5 | last);
+        } else {
+            self.write_byte(0xC4);
+            self.write_byte((!rex & 0b111) << 5 | mmmmm);
+            self.write_byte((wide as u8) << 7 | last);
+        }
+    }
+
     fn write_mod(&mut self, r#mod: Mod, rm: u8, reg: u8) {
         let mod_rm = (r#mod as u8) << 6 | ((reg & 0b111) << 3) | (rm & 0b111);
         self.write_byte(mod_rm);
     }
+
+    /// Writes ModRM, SIB and the displacement. With EVEX, 8-bit displacements are multiplied by `disp8_scale`, so
+    /// only multiples of it can be that short.
+    fn write_memory(&mut self, memory: &Memory, reg: u8, disp8_scale: i64) {
+        let base = memory.base.map(|base| base as u8 & 0b111);
+
+        if memory.base == Some(Rip) {
+            self.write_mod(NoOffset, 0b101, reg);
+            self.write_imm::<i32, [u8; 4]>(memory.displacement.as_ref().unwrap());
+            return;
+        }
+
+        let r#mod = match (&memory.displacement, base) {
+            // Without a base, there always is a 32-bit displacement
+            (_, None) => NoOffset,
+            // rbp and r13 as base can only be encoded with a displacement
+            (None, Some(0b101)) => Offset8Bit,
+            (None, Some(_)) => NoOffset,
+            (Some(Immediate { typ: ImmediateType::Integer(i), .. }), Some(_)) => {
+                if *i == 0 && base != Some(0b101) {
+                    NoOffset
+                } else if *i % disp8_scale == 0 && i8::try_from(*i / disp8_scale).is_ok() {
+                    Offset8Bit
+                } else {
+                    Offset32Bit
+                }
+            }
+            (Some(_), Some(_)) => Offset32Bit,
+        };
+
+        // rsp and r12 as base, an index or no base at all need a SIB byte
+        match base {
+            Some(base) if memory.index.is_none() && base != 0b100 => self.write_mod(r#mod, base, reg),
+            _ => {
+                self.write_mod(r#mod, 0b100, reg);
+
+                let scale = memory.scale.trailing_zeros() as u8;
+                let index = memory.index.map_or(0b100, |index| index as u8 & 0b111);
+                self.write_byte(scale << 6 | index << 3 | base.unwrap_or(0b101));
+            }
+        }
+
+        match (r#mod, &memory.displacement) {
+            (NoOffset, Some(displacement)) if base.is_none() => self.write_imm::<i32, [u8; 4]>(displacement),
+            (NoOffset, None) if base.is_none() => self.write_num(0i32),
+            (Offset8Bit, Some(Immediate { typ: ImmediateType::Integer(i), .. })) => {
+                self.write_num((*i / disp8_scale) as i8)
+            }
+            (Offset8Bit, None) => self.write_num(0i8),
+            (Offset32Bit, Some(displacement)) => self.write_imm::<i32, [u8; 4]>(displacement),
+            _ => {}
+        }
+    }
 }
@@ -7,10 +7,10 @@
 extern crate custom_derive;
 extern crate enum_derive;
 
-use cli::get_app;
+use cli::get_command_line;
 
 use crate::assembler::{Module, ObjectFileType};
-use crate::parse::parser::parse_lines;
+use crate::parse::parser::parse_file;
 use std::ffi::OsStr;
 use std::fs::File;
 use std::io::Read;
@@ -24,27 +24,27 @@ mod number;
 mod parse;
 
 fn main() {
-    let app = get_app();
-    let matches = app.get_matches();
+    let command_line = get_command_line();
 
-    let arg_input_file = matches.get_one::<String>("input file").unwrap();
-    let arg_output_file = matches.get_one::<String>("output file").unwrap();
-    let path = Path::new(arg_input_file);
+    let path = Path::new(&command_line.input);
     if path.extension().unwrap() == OsStr::new("o") {
-        eprintln!("Skipping {}, has .o extension", arg_input_file);
+        eprintln!("Skipping {}, has .o extension", command_line.input);
         exit(0);
     }
 
-    let mut file = File::open(&path).unwrap();
+    let mut file = File::open(path).unwrap();
 
     let mut code = String::new();
     file.read_to_string(&mut code).unwrap();
-    let parsed_lines = parse_lines(arg_input_file.to_string(), code);
+    let parsed_lines = parse_file(command_line.input.clone(), code, command_line.undefined);
 
     if let Ok(parsed_lines) = parsed_lines {
-        let module = Module::from_lines(parsed_lines);
+        let module = Module::from_lines(parsed_lines).unwrap_or_else(|e| {
+            eprintln!("{}", e);
+            exit(1);
+        });
 
-        module.write_to_file(Path::new(arg_output_file), ObjectFileType::MachO).expect("Couldn't write module");
+        module.write_to_file(Path::new(&command_line.output), command_line.format).expect("Couldn't write module");
     } else {
         exit(1);
     }
@@ -0,0 +1,172 @@
+// aron (c) Nikolas Wipper 2022
+
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+
+use crate::parse::helpers::{get_next, is_register, parse_integer, Specifier, SymbolTable};
+use crate::parse::lexer::{Token, OPERATORS};
+use crate::parse::ParseError;
+use std::slice::Iter;
+
+/// What an expression evaluates to while parsing
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Value {
+    Constant(i128),
+    /// A symbol plus a constant, which is up to the linker. The specifier is the `@PLT` in `foo@PLT`.
+    Symbol(String, i128, Option<Specifier>),
+    /// `end - start` plus a constant, which is known once the section both symbols are in is laid out
+    Difference(String, String, i128),
+}
+
+use Value::*;
+
+/// Binding strength of binary operators, higher binds tighter. These are GNU as' levels, so unlike in C, shifts bind
+/// like multiplication and the bitwise operators bind tighter than addition.
+fn precedence(op: &str) -> Option<u8> {
+    match op {
+        "==" | "!=" | "<>" | "<" | ">" | "<=" | ">=" => Some(1),
+        "+" | "-" => Some(2),
+        "|" | "^" | "&" => Some(3),
+        "*" | "/" | "%" | "<<" | ">>" => Some(4),
+        _ => None,
+    }
+}
+
+const ADDITIVE: u8 = 2;
+
+/// Like GNU as, true comparisons are all ones
+fn truth(condition: bool) -> i128 {
+    if condition {
+        -1
+    } else {
+        0
+    }
+}
+
+fn checked(value: Option<i128>) -> Result<i128, ParseError> {
+    value.ok_or(ParseError::NumberOutOfRange)
+}
+
+fn fold(op: &str, lhs: i128, rhs: i128) -> Result<i128, ParseError> {
+    match op {
+        "+" => checked(lhs.checked_add(rhs)),
+        "-" => checked(lhs.checked_sub(rhs)),
+        "*" => checked(lhs.checked_mul(rhs)),
+        "/" | "%" if rhs == 0 => Err(ParseError::DivisionByZero),
+        "/" => checked(lhs.checked_div(rhs)),
+        "%" => checked(lhs.checked_rem(rhs)),
+        "<<" => {
+            // Don't let bits fall off the top silently
+            let shift = u32::try_from(rhs).map_err(|_| ParseError::NumberOutOfRange)?;
+            checked(lhs.checked_shl(shift).filter(|shifted| shifted >> shift == lhs))
+        }
+        ">>" => checked(u32::try_from(rhs).ok().map(|shift| lhs >> shift.min(127))),
+        "&" => Ok(lhs & rhs),
+        "^" => Ok(lhs ^ rhs),
+        "|" => Ok(lhs | rhs),
+        "==" => Ok(truth(lhs == rhs)),
+        "!=" | "<>" => Ok(truth(lhs != rhs)),
+        "<" => Ok(truth(lhs < rhs)),
+        ">" => Ok(truth(lhs > rhs)),
+        "<=" => Ok(truth(lhs <= rhs)),
+        ">=" => Ok(truth(lhs >= rhs)),
+        _ => unreachable!("Unknown operator {}", op),
+    }
+}
+
+impl Value {
+    /// Applies a binary operator. Symbols only survive addition and subtraction of constants, and subtraction of
+    /// another symbol.
+    pub fn apply(self, op: &str, rhs: Value) -> Result<Value, ParseError> {
+        match (op, self, rhs) {
+            (op, Constant(lhs), Constant(rhs)) => Ok(Constant(fold(op, lhs, rhs)?)),
+            ("+", Symbol(symbol, offset, specifier), Constant(c))
+            | ("+", Constant(c), Symbol(symbol, offset, specifier)) => {
+                Ok(Symbol(symbol, checked(offset.checked_add(c))?, specifier))
+            }
+            ("+", Difference(end, start, offset), Constant(c)) | ("+", Constant(c), Difference(end, start, offset)) => {
+                Ok(Difference(end, start, checked(offset.checked_add(c))?))
+            }
+            ("-", Symbol(symbol, offset, specifier), Constant(c)) => {
+                Ok(Symbol(symbol, checked(offset.checked_sub(c))?, specifier))
+            }
+            ("-", Difference(end, start, offset), Constant(c)) => {
+                Ok(Difference(end, start, checked(offset.checked_sub(c))?))
+            }
+            ("-", Symbol(end, end_offset, None), Symbol(start, start_offset, None)) => {
+                Ok(Difference(end, start, checked(end_offset.checked_sub(start_offset))?))
+            }
+            _ => Err(ParseError::InvalidOperand),
+        }
+    }
+}
+
+fn parse_primary(token: &Token, symbols: &SymbolTable) -> Result<Value, ParseError> {
+    if let Some(num) = parse_integer(token) {
+        return Ok(Constant(num?));
+    }
+
+    let name = token.as_str();
+    if let Some(constant) = symbols.get(name) {
+        Ok(Constant(constant as i128))
+    } else if name == "." || OPERATORS.contains(&name) || is_register(name) {
+        Err(ParseError::InvalidOperand)
+    } else if let Some((symbol, specifier)) = name.split_once('@').filter(|(symbol, _)| !symbol.is_empty()) {
+        // Anything else after an @ is part of the name, like symbol versions
+        match specifier.parse() {
+            Ok(specifier) => Ok(Symbol(symbol.to_string(), 0, Some(specifier))),
+            Err(_) => Ok(Symbol(name.to_string(), 0, None)),
+        }
+    } else {
+        Ok(Symbol(name.to_string(), 0, None))
+    }
+}
+
+fn parse_unary(iter: &mut Iter<Token>, symbols: &SymbolTable) -> Result<Value, (usize, ParseError)> {
+    let next = get_next(iter)?.clone();
+
+    let value = match next.as_str() {
+        "(" => {
+            let value = parse_expression(iter, symbols)?;
+            if get_next(iter)? != ")" {
+                return Err((iter.count(), ParseError::InvalidOperand));
+            }
+            return Ok(value);
+        }
+        "+" => return parse_unary(iter, symbols),
+        op @ ("-" | "~" | "!") => match (op, parse_unary(iter, symbols)?) {
+            ("-", Constant(c)) => Constant(-c),
+            ("~", Constant(c)) => Constant(!c),
+            ("!", Constant(c)) => Constant((c == 0) as i128),
+            _ => return Err((iter.count(), ParseError::InvalidOperand)),
+        },
+        _ => parse_primary(&next, symbols).map_err(|e| (iter.clone().count(), e))?,
+    };
+
+    Ok(value)
+}
+
+fn parse_binary(iter: &mut Iter<Token>, symbols: &SymbolTable, min: u8) -> Result<Value, (usize, ParseError)> {
+    let mut lhs = parse_unary(iter, symbols)?;
+
+    while let Some(op_precedence) = iter.clone().next().and_then(|op| precedence(op.as_str())).filter(|p| *p >= min) {
+        let op = iter.next().unwrap();
+        let at = iter.clone().count();
+
+        let rhs = parse_binary(iter, symbols, op_precedence + 1)?;
+        lhs = lhs.apply(op.as_str(), rhs).map_err(|e| (at, e))?;
+    }
+
+    Ok(lhs)
+}
+
+/// Parses an expression, up to the first token that can't continue it, like `,` or `]`
+pub fn parse_expression(iter: &mut Iter<Token>, symbols: &SymbolTable) -> Result<Value, (usize, ParseError)> {
+    parse_binary(iter, symbols, 1)
+}
+
+/// Parses a single summand, so that memory operands can add registers in between
+pub fn parse_term(iter: &mut Iter<Token>, symbols: &SymbolTable) -> Result<Value, (usize, ParseError)> {
+    parse_binary(iter, symbols, ADDITIVE + 1)
+}
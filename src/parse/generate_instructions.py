@@ -15,8 +15,12 @@ import csv
 class Operand:
     def __init__(self, op: str):
         self.raw = op
+        # EVEX decorations, like the {k1}{z} in zmm1{k1}{z}, which are parsed after the operand itself
+        self.decorations = []
         if self.raw:
             self.raw = self.raw.lower()
+            self.decorations = re.findall(r"\{(\w+)}", self.raw)
+            self.raw = self.raw.split("{")[0]
 
     def is_imm(self) -> bool:
         return self.raw and self.raw.startswith("imm")
@@ -25,6 +29,15 @@ class Operand:
         if self.raw:
             return int(self.raw[3:])
 
+    def get_size(self):
+        if self.is_specific_operand():
+            return {"al": 8, "ax": 16, "eax": 32, "rax": 64}.get(self.raw)
+        elif self.is_unspecific_reg():
+            return self.get_reg_size()
+        elif self.is_unspecific_rm():
+            return self.get_rm_size()
+        return None
+
     def is_rel(self):
         return self.raw and self.raw.startswith("rel")
 
@@ -54,13 +67,1222 @@ class Operand:
     def is_m(self) -> bool:
         return self.raw and self.raw == "m"
 
+    def is_moffs(self) -> bool:
+        return self.raw and self.raw.startswith("moffs")
+
+    def is_sized_m(self) -> bool:
+        return self.raw and re.fullmatch(r"m\d+", self.raw) is not None
+
+    def get_m_size(self) -> int:
+        if self.raw:
+            return int(self.raw[1:])
+
+    def is_vector(self) -> bool:
+        return self.raw and re.fullmatch(r"[xyz]mm\d", self.raw) is not None
+
+    def is_vector_rm(self) -> bool:
+        return self.raw and re.fullmatch(r"[xyz]mm\d/m\d+(/m\d+bcst)?", self.raw) is not None
+
+    def get_vector_kind(self) -> str:
+        """xmm, ymm or zmm, which is also the name of the matching helper"""
+        if self.raw:
+            return self.raw[:3]
+
+    def get_vector_size(self) -> int:
+        return {"xmm": 128, "ymm": 256, "zmm": 512}[self.get_vector_kind()]
+
+    def get_vector_rm_size(self) -> int:
+        if self.raw:
+            return int(self.raw.split("/m")[1])
+
+    def get_broadcast_size(self):
+        """The size of the elements that can be broadcast, like 32 for zmm3/m512/m32bcst"""
+        if self.raw and self.raw.endswith("bcst"):
+            return int(self.raw.split("/m")[2][:-4])
+
+    def is_vsib(self) -> bool:
+        """A memory operand with a vector of indices, like vm32x, which are 32-bit indices in an xmm register"""
+        return self.raw and re.fullmatch(r"vm(32|64)[xy]", self.raw) is not None
+
+    def get_vsib_index_size(self) -> int:
+        if self.raw:
+            return {"x": 128, "y": 256}[self.raw[-1]]
+
+    def is_mask(self) -> bool:
+        return self.raw and re.fullmatch(r"k\d", self.raw) is not None
+
+    def is_mask_rm(self) -> bool:
+        return self.raw and re.fullmatch(r"k\d/m\d+", self.raw) is not None
+
+    def get_mask_rm_size(self) -> int:
+        if self.raw:
+            return int(self.raw.split("/m")[1])
+
+    def is_masked(self) -> bool:
+        return any(re.fullmatch(r"k\d", decoration) for decoration in self.decorations)
+
+    def get_memory_size(self):
+        """The size of the memory operand, if the operand can be one"""
+        if self.is_vector_rm():
+            return self.get_vector_rm_size()
+        elif self.is_sized_m():
+            return self.get_m_size()
+        elif self.is_unspecific_rm():
+            return self.get_rm_size()
+
 
 class InstructionTemplate:
-    def __init__(self, opcode, name, op1, op2):
+    def __init__(self, opcode, name, op1, op2, op3=None, op4=None):
         self.opcode = opcode
         self.name = name.lower()
         self.op1 = Operand(op1)
         self.op2 = Operand(op2)
+        self.op3 = Operand(op3)
+        self.op4 = Operand(op4)
+
+    def operands(self):
+        return [self.op1, self.op2, self.op3, self.op4]
+
+    def is_evex(self) -> bool:
+        return self.opcode.startswith("EVEX")
+
+    def get_vvvv_operand(self):
+        """The position of the register encoded in VEX.vvvv, if there is one. Like the manual, the first register is in
+        ModRM.reg and the last one, or the memory operand, in ModRM.rm, so vvvv is the one in between. Instructions
+        that use ModRM.reg as part of the opcode keep their destination in vvvv instead."""
+        if not self.opcode.split(".")[0] in ["VEX", "EVEX"]:
+            return None
+
+        registers = [position for position, op in enumerate(self.operands()) if op.is_vector() or op.is_mask()]
+        has_rm = any(op.is_vector_rm() or op.is_mask_rm() or op.is_unspecific_rm() or op.is_m() or op.is_sized_m()
+                     or op.is_vsib() for op in self.operands())
+
+        if re.search(r"/\d", self.opcode):
+            return registers[0] if registers else None
+        if len(registers) == 3 or (has_rm and len(registers) == 2):
+            return registers[1]
+        return None
+
+    def get_is4_operand(self):
+        """The position of the register encoded in the upper four bits of an immediate, like the last one of
+        vblendvps"""
+        if "/is4" in self.opcode:
+            return 3
+        return None
+
+    def get_vsib_element_size(self) -> int:
+        """Gathers load elements of the size selected by VEX.W, which is also the size of their memory operand"""
+        return 64 if ".W1" in self.opcode else 32
+
+    def is_sign_extended(self, imm: Operand) -> bool:
+        """Whether the CPU sign-extends the immediate to a wider operand, like in `add rax, imm32`"""
+        if self.name == "push":
+            return imm.get_imm_size() != 16
+
+        other = self.op2 if imm == self.op1 else self.op1
+        size = other.get_size() if other.raw else None
+
+        return self.name in ["adc", "add", "and", "cmp", "imul", "mov", "or", "sbb", "sub", "test", "xor"] \
+            and size is not None and size > imm.get_imm_size()
+
+    def get_operand_size(self):
+        """The size of the operation, which takes an operand-size prefix if it's 16 bits"""
+        if self.name in ["cbw", "cwd", "cmpsw", "insw", "lodsw", "movsw", "outsw", "scasw", "stosw"]:
+            return 16
+        # These only ever work on 16 bits
+        if self.name in ["fnstsw", "fstsw", "lldt", "lmsw", "ltr", "verr", "verw"]:
+            return None
+        # crc32 always accumulates into a 32 or 64-bit register, the prefix selects the size of the source
+        if self.name == "crc32":
+            return self.op2.get_size()
+        if self.name == "pushw":
+            return self.op1.get_imm_size()
+
+        # The port in dx doesn't count, the data in ax does
+        sizes = [op.get_size() for op in [self.op1, self.op2] if op.raw and op.raw != "dx"]
+        return next((size for size in sizes if size), None)
+
+    def get_address_size(self):
+        """The size of implicit addresses, only jecxz uses 32 bits"""
+        return 32 if self.name == "jecxz" else None
+
+
+def sse_instructions():
+    """SSE and SSE2, which are listed here instead of letting every xmm form of the table through, most of which are
+    later extensions"""
+    instructions = []
+
+    # Scalar loads come before the stores, so moves between registers use the same form as other assemblers
+    for prefix, suffix, m in [("", "PS", "m128"), ("66 ", "PD", "m128"), ("F3 ", "SS", "m32"), ("F2 ", "SD", "m64")]:
+        for opcode, name in [("58", "ADD"), ("59", "MUL"), ("5C", "SUB"), ("5D", "MIN"), ("5E", "DIV"), ("5F", "MAX"),
+                             ("51", "SQRT")]:
+            instructions.append(InstructionTemplate(f"{prefix}0F {opcode} /r", name + suffix, "xmm1", f"xmm2/{m}"))
+        instructions.append(InstructionTemplate(f"{prefix}0F C2 /r ib", "CMP" + suffix, "xmm1", f"xmm2/{m}", "imm8"))
+
+    for prefix, suffix, m in [("", "PS", "m128"), ("F3 ", "SS", "m32")]:
+        instructions += [
+            InstructionTemplate(f"{prefix}0F 53 /r", "RCP" + suffix, "xmm1", f"xmm2/{m}"),
+            InstructionTemplate(f"{prefix}0F 52 /r", "RSQRT" + suffix, "xmm1", f"xmm2/{m}"),
+        ]
+
+    for prefix, suffix in [("", "PS"), ("66 ", "PD")]:
+        for opcode, name in [("54", "AND"), ("55", "ANDN"), ("56", "OR"), ("57", "XOR"), ("14", "UNPCKL"),
+                             ("15", "UNPCKH")]:
+            instructions.append(InstructionTemplate(f"{prefix}0F {opcode} /r", name + suffix, "xmm1", "xmm2/m128"))
+        instructions.append(InstructionTemplate(f"{prefix}0F C6 /r ib", "SHUF" + suffix, "xmm1", "xmm2/m128", "imm8"))
+
+    for prefix, suffix, m in [("", "S", "m32"), ("66 ", "D", "m64")]:
+        instructions += [
+            InstructionTemplate(f"{prefix}0F 2F /r", "COMIS" + suffix, "xmm1", f"xmm2/{m}"),
+            InstructionTemplate(f"{prefix}0F 2E /r", "UCOMIS" + suffix, "xmm1", f"xmm2/{m}"),
+        ]
+
+    for prefix, name, m in [("F3 ", "MOVSS", "m32"), ("F2 ", "MOVSD", "m64"), ("", "MOVUPS", "m128"),
+                            ("66 ", "MOVUPD", "m128")]:
+        instructions += [
+            InstructionTemplate(f"{prefix}0F 10 /r", name, "xmm1", f"xmm2/{m}"),
+            InstructionTemplate(f"{prefix}0F 11 /r", name, f"xmm2/{m}", "xmm1"),
+        ]
+
+    for prefix, name in [("", "MOVAPS"), ("66 ", "MOVAPD")]:
+        instructions += [
+            InstructionTemplate(f"{prefix}0F 28 /r", name, "xmm1", "xmm2/m128"),
+            InstructionTemplate(f"{prefix}0F 29 /r", name, "xmm2/m128", "xmm1"),
+        ]
+
+    for prefix, name in [("66 ", "MOVDQA"), ("F3 ", "MOVDQU")]:
+        instructions += [
+            InstructionTemplate(f"{prefix}0F 6F /r", name, "xmm1", "xmm2/m128"),
+            InstructionTemplate(f"{prefix}0F 7F /r", name, "xmm2/m128", "xmm1"),
+        ]
+
+    for prefix, suffix in [("", "PS"), ("66 ", "PD")]:
+        instructions += [
+            InstructionTemplate(f"{prefix}0F 12 /r", "MOVL" + suffix, "xmm1", "m64"),
+            InstructionTemplate(f"{prefix}0F 13 /r", "MOVL" + suffix, "m64", "xmm1"),
+            InstructionTemplate(f"{prefix}0F 16 /r", "MOVH" + suffix, "xmm1", "m64"),
+            InstructionTemplate(f"{prefix}0F 17 /r", "MOVH" + suffix, "m64", "xmm1"),
+            InstructionTemplate(f"{prefix}0F 50 /r", "MOVMSK" + suffix, "r32", "xmm2"),
+            InstructionTemplate(f"{prefix}0F 2B /r", "MOVNT" + suffix, "m128", "xmm1"),
+        ]
+
+    instructions += [
+        InstructionTemplate("0F 12 /r", "MOVHLPS", "xmm1", "xmm2"),
+        InstructionTemplate("0F 16 /r", "MOVLHPS", "xmm1", "xmm2"),
+        InstructionTemplate("66 0F E7 /r", "MOVNTDQ", "m128", "xmm1"),
+        InstructionTemplate("66 0F 6E /r", "MOVD", "xmm1", "r/m32"),
+        InstructionTemplate("66 0F 7E /r", "MOVD", "r/m32", "xmm1"),
+        InstructionTemplate("F3 0F 7E /r", "MOVQ", "xmm1", "xmm2/m64"),
+        InstructionTemplate("66 0F D6 /r", "MOVQ", "xmm2/m64", "xmm1"),
+        InstructionTemplate("66 REX.W 0F 6E /r", "MOVQ", "xmm1", "r/m64"),
+        InstructionTemplate("66 REX.W 0F 7E /r", "MOVQ", "r/m64", "xmm1"),
+        InstructionTemplate("66 0F D7 /r", "PMOVMSKB", "r32", "xmm2"),
+        InstructionTemplate("66 0F C5 /r ib", "PEXTRW", "r32", "xmm2", "imm8"),
+        InstructionTemplate("66 0F 70 /r ib", "PSHUFD", "xmm1", "xmm2/m128", "imm8"),
+        InstructionTemplate("F2 0F 70 /r ib", "PSHUFLW", "xmm1", "xmm2/m128", "imm8"),
+        InstructionTemplate("F3 0F 70 /r ib", "PSHUFHW", "xmm1", "xmm2/m128", "imm8"),
+        InstructionTemplate("0F AE /2", "LDMXCSR", "m32", None),
+        InstructionTemplate("0F AE /3", "STMXCSR", "m32", None),
+        InstructionTemplate("0F AE E8", "LFENCE", None, None),
+        InstructionTemplate("0F AE F0", "MFENCE", None, None),
+        InstructionTemplate("0F AE F8", "SFENCE", None, None),
+    ]
+
+    for prefix, suffix, m in [("F3 ", "SS", "m32"), ("F2 ", "SD", "m64")]:
+        instructions += [
+            InstructionTemplate(f"{prefix}0F 2A /r", "CVTSI2" + suffix, "xmm1", "r/m32"),
+            InstructionTemplate(f"{prefix}REX.W 0F 2A /r", "CVTSI2" + suffix, "xmm1", "r/m64"),
+            InstructionTemplate(f"{prefix}0F 2D /r", f"CVT{suffix}2SI", "r32", f"xmm2/{m}"),
+            InstructionTemplate(f"{prefix}REX.W 0F 2D /r", f"CVT{suffix}2SI", "r64", f"xmm2/{m}"),
+            InstructionTemplate(f"{prefix}0F 2C /r", f"CVTT{suffix}2SI", "r32", f"xmm2/{m}"),
+            InstructionTemplate(f"{prefix}REX.W 0F 2C /r", f"CVTT{suffix}2SI", "r64", f"xmm2/{m}"),
+        ]
+
+    instructions += [
+        InstructionTemplate("F3 0F 5A /r", "CVTSS2SD", "xmm1", "xmm2/m32"),
+        InstructionTemplate("F2 0F 5A /r", "CVTSD2SS", "xmm1", "xmm2/m64"),
+        InstructionTemplate("0F 5A /r", "CVTPS2PD", "xmm1", "xmm2/m64"),
+        InstructionTemplate("66 0F 5A /r", "CVTPD2PS", "xmm1", "xmm2/m128"),
+        InstructionTemplate("0F 5B /r", "CVTDQ2PS", "xmm1", "xmm2/m128"),
+        InstructionTemplate("66 0F 5B /r", "CVTPS2DQ", "xmm1", "xmm2/m128"),
+        InstructionTemplate("F3 0F 5B /r", "CVTTPS2DQ", "xmm1", "xmm2/m128"),
+        InstructionTemplate("F3 0F E6 /r", "CVTDQ2PD", "xmm1", "xmm2/m64"),
+        InstructionTemplate("F2 0F E6 /r", "CVTPD2DQ", "xmm1", "xmm2/m128"),
+        InstructionTemplate("66 0F E6 /r", "CVTTPD2DQ", "xmm1", "xmm2/m128"),
+    ]
+
+    integer = [("FC", "PADDB"), ("FD", "PADDW"), ("FE", "PADDD"), ("D4", "PADDQ"), ("F8", "PSUBB"), ("F9", "PSUBW"),
+               ("FA", "PSUBD"), ("FB", "PSUBQ"), ("EC", "PADDSB"), ("ED", "PADDSW"), ("DC", "PADDUSB"),
+               ("DD", "PADDUSW"), ("E8", "PSUBSB"), ("E9", "PSUBSW"), ("D8", "PSUBUSB"), ("D9", "PSUBUSW"),
+               ("D5", "PMULLW"), ("E5", "PMULHW"), ("E4", "PMULHUW"), ("F4", "PMULUDQ"), ("F5", "PMADDWD"),
+               ("F6", "PSADBW"), ("E0", "PAVGB"), ("E3", "PAVGW"), ("DA", "PMINUB"), ("DE", "PMAXUB"),
+               ("EA", "PMINSW"), ("EE", "PMAXSW"), ("DB", "PAND"), ("DF", "PANDN"), ("EB", "POR"), ("EF", "PXOR"),
+               ("74", "PCMPEQB"), ("75", "PCMPEQW"), ("76", "PCMPEQD"), ("64", "PCMPGTB"), ("65", "PCMPGTW"),
+               ("66", "PCMPGTD"), ("63", "PACKSSWB"), ("6B", "PACKSSDW"), ("67", "PACKUSWB"), ("60", "PUNPCKLBW"),
+               ("61", "PUNPCKLWD"), ("62", "PUNPCKLDQ"), ("6C", "PUNPCKLQDQ"), ("68", "PUNPCKHBW"),
+               ("69", "PUNPCKHWD"), ("6A", "PUNPCKHDQ"), ("6D", "PUNPCKHQDQ"), ("F1", "PSLLW"), ("F2", "PSLLD"),
+               ("F3", "PSLLQ"), ("D1", "PSRLW"), ("D2", "PSRLD"), ("D3", "PSRLQ"), ("E1", "PSRAW"), ("E2", "PSRAD")]
+    for opcode, name in integer:
+        instructions.append(InstructionTemplate(f"66 0F {opcode} /r", name, "xmm1", "xmm2/m128"))
+
+    shifts = [("71 /6", "PSLLW"), ("71 /2", "PSRLW"), ("71 /4", "PSRAW"), ("72 /6", "PSLLD"), ("72 /2", "PSRLD"),
+              ("72 /4", "PSRAD"), ("73 /6", "PSLLQ"), ("73 /2", "PSRLQ"), ("73 /7", "PSLLDQ"), ("73 /3", "PSRLDQ")]
+    for opcode, name in shifts:
+        instructions.append(InstructionTemplate(f"66 0F {opcode} ib", name, "xmm1", "imm8"))
+
+    return instructions
+
+
+def avx_instructions():
+    """AVX, AVX2, FMA and F16C, which are all VEX encoded. The 128-bit forms come first, so they're picked for xmm
+    operands."""
+    instructions = []
+    vectors = [("128", "xmm", "m128"), ("256", "ymm", "m256")]
+
+    def vex(length, prefix, escape, w, rest):
+        fields = ["VEX", length] + ([prefix] if prefix else []) + [escape, w]
+        return ".".join(fields) + " " + rest
+
+    for prefix, suffix in [("", "PS"), ("66", "PD")]:
+        for length, v, m in vectors:
+            for opcode, name in [("58", "ADD"), ("59", "MUL"), ("5C", "SUB"), ("5D", "MIN"), ("5E", "DIV"),
+                                 ("5F", "MAX"), ("54", "AND"), ("55", "ANDN"), ("56", "OR"), ("57", "XOR"),
+                                 ("14", "UNPCKL"), ("15", "UNPCKH")]:
+                instructions.append(InstructionTemplate(vex(length, prefix, "0F", "WIG", f"{opcode} /r"),
+                                                        f"V{name}{suffix}", f"{v}1", f"{v}2", f"{v}3/{m}"))
+            instructions += [
+                InstructionTemplate(vex(length, prefix, "0F", "WIG", "51 /r"), "VSQRT" + suffix, f"{v}1", f"{v}2/{m}"),
+                InstructionTemplate(vex(length, prefix, "0F", "WIG", "C2 /r ib"), "VCMP" + suffix, f"{v}1", f"{v}2",
+                                    f"{v}3/{m}", "imm8"),
+                InstructionTemplate(vex(length, prefix, "0F", "WIG", "C6 /r ib"), "VSHUF" + suffix, f"{v}1", f"{v}2",
+                                    f"{v}3/{m}", "imm8"),
+                InstructionTemplate(vex(length, prefix, "0F", "WIG", "10 /r"), "VMOVU" + suffix, f"{v}1", f"{v}2/{m}"),
+                InstructionTemplate(vex(length, prefix, "0F", "WIG", "11 /r"), "VMOVU" + suffix, f"{v}2/{m}", f"{v}1"),
+                InstructionTemplate(vex(length, prefix, "0F", "WIG", "28 /r"), "VMOVA" + suffix, f"{v}1", f"{v}2/{m}"),
+                InstructionTemplate(vex(length, prefix, "0F", "WIG", "29 /r"), "VMOVA" + suffix, f"{v}2/{m}", f"{v}1"),
+                InstructionTemplate(vex(length, prefix, "0F", "WIG", "50 /r"), "VMOVMSK" + suffix, "r32", f"{v}2"),
+                InstructionTemplate(vex(length, prefix, "0F", "WIG", "2B /r"), "VMOVNT" + suffix, m, f"{v}1"),
+            ]
+            if suffix == "PS":
+                instructions += [
+                    InstructionTemplate(vex(length, "", "0F", "WIG", "53 /r"), "VRCPPS", f"{v}1", f"{v}2/{m}"),
+                    InstructionTemplate(vex(length, "", "0F", "WIG", "52 /r"), "VRSQRTPS", f"{v}1", f"{v}2/{m}"),
+                ]
+
+    for length, v, m in vectors:
+        for prefix, opcode, name in [("F2", "7C", "VHADDPS"), ("66", "7C", "VHADDPD"), ("F2", "7D", "VHSUBPS"),
+                                     ("66", "7D", "VHSUBPD"), ("F2", "D0", "VADDSUBPS"), ("66", "D0", "VADDSUBPD")]:
+            instructions.append(InstructionTemplate(vex(length, prefix, "0F", "WIG", f"{opcode} /r"), name, f"{v}1",
+                                                    f"{v}2", f"{v}3/{m}"))
+        instructions += [
+            InstructionTemplate(vex(length, "F3", "0F", "WIG", "16 /r"), "VMOVSHDUP", f"{v}1", f"{v}2/{m}"),
+            InstructionTemplate(vex(length, "F3", "0F", "WIG", "12 /r"), "VMOVSLDUP", f"{v}1", f"{v}2/{m}"),
+            InstructionTemplate(vex(length, "F2", "0F", "WIG", "12 /r"), "VMOVDDUP", f"{v}1",
+                                "xmm2/m64" if v == "xmm" else "ymm2/m256"),
+        ]
+
+    for prefix, suffix, m in [("F3", "SS", "m32"), ("F2", "SD", "m64")]:
+        for opcode, name in [("58", "ADD"), ("59", "MUL"), ("5C", "SUB"), ("5D", "MIN"), ("5E", "DIV"), ("5F", "MAX"),
+                             ("51", "SQRT")]:
+            instructions.append(InstructionTemplate(vex("LIG", prefix, "0F", "WIG", f"{opcode} /r"), f"V{name}{suffix}",
+                                                    "xmm1", "xmm2", f"xmm3/{m}"))
+        instructions += [
+            InstructionTemplate(vex("LIG", prefix, "0F", "WIG", "C2 /r ib"), "VCMP" + suffix, "xmm1", "xmm2",
+                                f"xmm3/{m}", "imm8"),
+            InstructionTemplate(vex("LIG", prefix, "0F", "WIG", "10 /r"), "VMOV" + suffix, "xmm1", "xmm2", "xmm3"),
+            InstructionTemplate(vex("LIG", prefix, "0F", "WIG", "10 /r"), "VMOV" + suffix, "xmm1", m),
+            InstructionTemplate(vex("LIG", prefix, "0F", "WIG", "11 /r"), "VMOV" + suffix, m, "xmm1"),
+            InstructionTemplate(vex("LIG", prefix, "0F", "W0", "2A /r"), "VCVTSI2" + suffix, "xmm1", "xmm2", "r/m32"),
+            InstructionTemplate(vex("LIG", prefix, "0F", "W1", "2A /r"), "VCVTSI2" + suffix, "xmm1", "xmm2", "r/m64"),
+            InstructionTemplate(vex("LIG", prefix, "0F", "W0", "2D /r"), f"VCVT{suffix}2SI", "r32", f"xmm1/{m}"),
+            InstructionTemplate(vex("LIG", prefix, "0F", "W1", "2D /r"), f"VCVT{suffix}2SI", "r64", f"xmm1/{m}"),
+            InstructionTemplate(vex("LIG", prefix, "0F", "W0", "2C /r"), f"VCVTT{suffix}2SI", "r32", f"xmm1/{m}"),
+            InstructionTemplate(vex("LIG", prefix, "0F", "W1", "2C /r"), f"VCVTT{suffix}2SI", "r64", f"xmm1/{m}"),
+        ]
+
+    instructions += [
+        InstructionTemplate(vex("LIG", "F3", "0F", "WIG", "53 /r"), "VRCPSS", "xmm1", "xmm2", "xmm3/m32"),
+        InstructionTemplate(vex("LIG", "F3", "0F", "WIG", "52 /r"), "VRSQRTSS", "xmm1", "xmm2", "xmm3/m32"),
+        InstructionTemplate(vex("LIG", "F3", "0F", "WIG", "5A /r"), "VCVTSS2SD", "xmm1", "xmm2", "xmm3/m32"),
+        InstructionTemplate(vex("LIG", "F2", "0F", "WIG", "5A /r"), "VCVTSD2SS", "xmm1", "xmm2", "xmm3/m64"),
+    ]
+
+    for prefix, suffix, m in [("", "S", "m32"), ("66", "D", "m64")]:
+        instructions += [
+            InstructionTemplate(vex("LIG", prefix, "0F", "WIG", "2F /r"), "VCOMIS" + suffix, "xmm1", f"xmm2/{m}"),
+            InstructionTemplate(vex("LIG", prefix, "0F", "WIG", "2E /r"), "VUCOMIS" + suffix, "xmm1", f"xmm2/{m}"),
+        ]
+
+    # The conversions between 32 and 64-bit elements have a source or destination of half the width
+    for length, v, m in vectors:
+        half_m = "m64" if v == "xmm" else "m128"
+        instructions += [
+            InstructionTemplate(vex(length, "", "0F", "WIG", "5A /r"), "VCVTPS2PD", f"{v}1", f"xmm2/{half_m}"),
+            InstructionTemplate(vex(length, "66", "0F", "WIG", "5A /r"), "VCVTPD2PS", "xmm1", f"{v}2/{m}"),
+            InstructionTemplate(vex(length, "", "0F", "WIG", "5B /r"), "VCVTDQ2PS", f"{v}1", f"{v}2/{m}"),
+            InstructionTemplate(vex(length, "66", "0F", "WIG", "5B /r"), "VCVTPS2DQ", f"{v}1", f"{v}2/{m}"),
+            InstructionTemplate(vex(length, "F3", "0F", "WIG", "5B /r"), "VCVTTPS2DQ", f"{v}1", f"{v}2/{m}"),
+            InstructionTemplate(vex(length, "F3", "0F", "WIG", "E6 /r"), "VCVTDQ2PD", f"{v}1", f"xmm2/{half_m}"),
+            InstructionTemplate(vex(length, "F2", "0F", "WIG", "E6 /r"), "VCVTPD2DQ", "xmm1", f"{v}2/{m}"),
+            InstructionTemplate(vex(length, "66", "0F", "WIG", "E6 /r"), "VCVTTPD2DQ", "xmm1", f"{v}2/{m}"),
+        ]
+
+    for prefix, suffix in [("", "PS"), ("66", "PD")]:
+        instructions += [
+            InstructionTemplate(vex("128", prefix, "0F", "WIG", "12 /r"), "VMOVL" + suffix, "xmm1", "xmm2", "m64"),
+            InstructionTemplate(vex("128", prefix, "0F", "WIG", "13 /r"), "VMOVL" + suffix, "m64", "xmm1"),
+            InstructionTemplate(vex("128", prefix, "0F", "WIG", "16 /r"), "VMOVH" + suffix, "xmm1", "xmm2", "m64"),
+            InstructionTemplate(vex("128", prefix, "0F", "WIG", "17 /r"), "VMOVH" + suffix, "m64", "xmm1"),
+        ]
+
+    instructions += [
+        InstructionTemplate(vex("128", "", "0F", "WIG", "12 /r"), "VMOVHLPS", "xmm1", "xmm2", "xmm3"),
+        InstructionTemplate(vex("128", "", "0F", "WIG", "16 /r"), "VMOVLHPS", "xmm1", "xmm2", "xmm3"),
+        InstructionTemplate(vex("128", "66", "0F", "W0", "6E /r"), "VMOVD", "xmm1", "r/m32"),
+        InstructionTemplate(vex("128", "66", "0F", "W0", "7E /r"), "VMOVD", "r/m32", "xmm1"),
+        InstructionTemplate(vex("128", "F3", "0F", "WIG", "7E /r"), "VMOVQ", "xmm1", "xmm2/m64"),
+        InstructionTemplate(vex("128", "66", "0F", "WIG", "D6 /r"), "VMOVQ", "xmm2/m64", "xmm1"),
+        InstructionTemplate(vex("128", "66", "0F", "W1", "6E /r"), "VMOVQ", "xmm1", "r/m64"),
+        InstructionTemplate(vex("128", "66", "0F", "W1", "7E /r"), "VMOVQ", "r/m64", "xmm1"),
+        InstructionTemplate(vex("128", "66", "0F", "W0", "C5 /r ib"), "VPEXTRW", "r32", "xmm2", "imm8"),
+        InstructionTemplate(vex("LZ", "", "0F", "WIG", "AE /2"), "VLDMXCSR", "m32", None),
+        InstructionTemplate(vex("LZ", "", "0F", "WIG", "AE /3"), "VSTMXCSR", "m32", None),
+        InstructionTemplate(vex("128", "", "0F", "WIG", "77"), "VZEROUPPER", None, None),
+        InstructionTemplate(vex("256", "", "0F", "WIG", "77"), "VZEROALL", None, None),
+    ]
+
+    # The integer instructions were extended to 256 bits by AVX2
+    integer = [("FC", "VPADDB"), ("FD", "VPADDW"), ("FE", "VPADDD"), ("D4", "VPADDQ"), ("F8", "VPSUBB"),
+               ("F9", "VPSUBW"), ("FA", "VPSUBD"), ("FB", "VPSUBQ"), ("EC", "VPADDSB"), ("ED", "VPADDSW"),
+               ("DC", "VPADDUSB"), ("DD", "VPADDUSW"), ("E8", "VPSUBSB"), ("E9", "VPSUBSW"), ("D8", "VPSUBUSB"),
+               ("D9", "VPSUBUSW"), ("D5", "VPMULLW"), ("E5", "VPMULHW"), ("E4", "VPMULHUW"), ("F4", "VPMULUDQ"),
+               ("F5", "VPMADDWD"), ("F6", "VPSADBW"), ("E0", "VPAVGB"), ("E3", "VPAVGW"), ("DA", "VPMINUB"),
+               ("DE", "VPMAXUB"), ("EA", "VPMINSW"), ("EE", "VPMAXSW"), ("DB", "VPAND"), ("DF", "VPANDN"),
+               ("EB", "VPOR"), ("EF", "VPXOR"), ("74", "VPCMPEQB"), ("75", "VPCMPEQW"), ("76", "VPCMPEQD"),
+               ("64", "VPCMPGTB"), ("65", "VPCMPGTW"), ("66", "VPCMPGTD"), ("63", "VPACKSSWB"), ("6B", "VPACKSSDW"),
+               ("67", "VPACKUSWB"), ("60", "VPUNPCKLBW"), ("61", "VPUNPCKLWD"), ("62", "VPUNPCKLDQ"),
+               ("6C", "VPUNPCKLQDQ"), ("68", "VPUNPCKHBW"), ("69", "VPUNPCKHWD"), ("6A", "VPUNPCKHDQ"),
+               ("6D", "VPUNPCKHQDQ")]
+    ssse3 = [("00", "VPSHUFB"), ("01", "VPHADDW"), ("02", "VPHADDD"), ("04", "VPMADDUBSW"), ("05", "VPHSUBW"),
+             ("06", "VPHSUBD"), ("0B", "VPMULHRSW"), ("28", "VPMULDQ"), ("29", "VPCMPEQQ"), ("2B", "VPACKUSDW"),
+             ("37", "VPCMPGTQ"), ("38", "VPMINSB"), ("39", "VPMINSD"), ("3A", "VPMINUW"), ("3B", "VPMINUD"),
+             ("3C", "VPMAXSB"), ("3D", "VPMAXSD"), ("3E", "VPMAXUW"), ("3F", "VPMAXUD"), ("40", "VPMULLD")]
+    shifts = [("71 /6", "VPSLLW"), ("71 /2", "VPSRLW"), ("71 /4", "VPSRAW"), ("72 /6", "VPSLLD"), ("72 /2", "VPSRLD"),
+              ("72 /4", "VPSRAD"), ("73 /6", "VPSLLQ"), ("73 /2", "VPSRLQ"), ("73 /7", "VPSLLDQ"),
+              ("73 /3", "VPSRLDQ")]
+    # Shifting by a register always takes the count from an xmm register
+    counts = [("F1", "VPSLLW"), ("F2", "VPSLLD"), ("F3", "VPSLLQ"), ("D1", "VPSRLW"), ("D2", "VPSRLD"),
+              ("D3", "VPSRLQ"), ("E1", "VPSRAW"), ("E2", "VPSRAD")]
+    extends = [("20", "VPMOVSXBW", 64), ("21", "VPMOVSXBD", 32), ("22", "VPMOVSXBQ", 16), ("23", "VPMOVSXWD", 64),
+               ("24", "VPMOVSXWQ", 32), ("25", "VPMOVSXDQ", 64), ("30", "VPMOVZXBW", 64), ("31", "VPMOVZXBD", 32),
+               ("32", "VPMOVZXBQ", 16), ("33", "VPMOVZXWD", 64), ("34", "VPMOVZXWQ", 32), ("35", "VPMOVZXDQ", 64)]
+
+    for length, v, m in vectors:
+        for opcode, name in integer:
+            instructions.append(InstructionTemplate(vex(length, "66", "0F", "WIG", f"{opcode} /r"), name, f"{v}1",
+                                                    f"{v}2", f"{v}3/{m}"))
+        for opcode, name in ssse3:
+            instructions.append(InstructionTemplate(vex(length, "66", "0F38", "WIG", f"{opcode} /r"), name, f"{v}1",
+                                                    f"{v}2", f"{v}3/{m}"))
+        for opcode, name in counts:
+            instructions.append(InstructionTemplate(vex(length, "66", "0F", "WIG", f"{opcode} /r"), name, f"{v}1",
+                                                    f"{v}2", "xmm3/m128"))
+        for opcode, name in shifts:
+            instructions.append(InstructionTemplate(vex(length, "66", "0F", "WIG", f"{opcode} ib"), name, f"{v}1",
+                                                    f"{v}2", "imm8"))
+        for opcode, name, size in extends:
+            instructions.append(InstructionTemplate(vex(length, "66", "0F38", "WIG", f"{opcode} /r"), name, f"{v}1",
+                                                    f"xmm2/m{size if v == 'xmm' else size * 2}"))
+        for opcode, name in [("1C", "VPABSB"), ("1D", "VPABSW"), ("1E", "VPABSD"), ("17", "VPTEST")]:
+            instructions.append(InstructionTemplate(vex(length, "66", "0F38", "WIG", f"{opcode} /r"), name, f"{v}1",
+                                                    f"{v}2/{m}"))
+        for opcode, name in [("0E", "VTESTPS"), ("0F", "VTESTPD")]:
+            instructions.append(InstructionTemplate(vex(length, "66", "0F38", "W0", f"{opcode} /r"), name, f"{v}1",
+                                                    f"{v}2/{m}"))
+        for opcode, name in [("0F", "VPALIGNR"), ("0C", "VBLENDPS"), ("0D", "VBLENDPD"), ("0E", "VPBLENDW"),
+                             ("40", "VDPPS")]:
+            instructions.append(InstructionTemplate(vex(length, "66", "0F3A", "WIG", f"{opcode} /r ib"), name,
+                                                    f"{v}1", f"{v}2", f"{v}3/{m}", "imm8"))
+        for opcode, name in [("4A", "VBLENDVPS"), ("4B", "VBLENDVPD"), ("4C", "VPBLENDVB")]:
+            instructions.append(InstructionTemplate(vex(length, "66", "0F3A", "W0", f"{opcode} /r /is4"), name,
+                                                    f"{v}1", f"{v}2", f"{v}3/{m}", f"{v}4"))
+        for opcode, name in [("08", "VROUNDPS"), ("09", "VROUNDPD")]:
+            instructions.append(InstructionTemplate(vex(length, "66", "0F3A", "WIG", f"{opcode} /r ib"), name,
+                                                    f"{v}1", f"{v}2/{m}", "imm8"))
+        instructions += [
+            InstructionTemplate(vex(length, "66", "0F", "WIG", "6F /r"), "VMOVDQA", f"{v}1", f"{v}2/{m}"),
+            InstructionTemplate(vex(length, "66", "0F", "WIG", "7F /r"), "VMOVDQA", f"{v}2/{m}", f"{v}1"),
+            InstructionTemplate(vex(length, "F3", "0F", "WIG", "6F /r"), "VMOVDQU", f"{v}1", f"{v}2/{m}"),
+            InstructionTemplate(vex(length, "F3", "0F", "WIG", "7F /r"), "VMOVDQU", f"{v}2/{m}", f"{v}1"),
+            InstructionTemplate(vex(length, "66", "0F", "WIG", "E7 /r"), "VMOVNTDQ", m, f"{v}1"),
+            InstructionTemplate(vex(length, "66", "0F38", "WIG", "2A /r"), "VMOVNTDQA", f"{v}1", m),
+            InstructionTemplate(vex(length, "66", "0F", "WIG", "D7 /r"), "VPMOVMSKB", "r32", f"{v}2"),
+            InstructionTemplate(vex(length, "66", "0F", "WIG", "70 /r ib"), "VPSHUFD", f"{v}1", f"{v}2/{m}", "imm8"),
+            InstructionTemplate(vex(length, "F2", "0F", "WIG", "70 /r ib"), "VPSHUFLW", f"{v}1", f"{v}2/{m}", "imm8"),
+            InstructionTemplate(vex(length, "F3", "0F", "WIG", "70 /r ib"), "VPSHUFHW", f"{v}1", f"{v}2/{m}", "imm8"),
+            InstructionTemplate(vex(length, "66", "0F38", "W0", "0C /r"), "VPERMILPS", f"{v}1", f"{v}2", f"{v}3/{m}"),
+            InstructionTemplate(vex(length, "66", "0F38", "W0", "0D /r"), "VPERMILPD", f"{v}1", f"{v}2", f"{v}3/{m}"),
+            InstructionTemplate(vex(length, "66", "0F3A", "W0", "04 /r ib"), "VPERMILPS", f"{v}1", f"{v}2/{m}",
+                                "imm8"),
+            InstructionTemplate(vex(length, "66", "0F3A", "W0", "05 /r ib"), "VPERMILPD", f"{v}1", f"{v}2/{m}",
+                                "imm8"),
+            InstructionTemplate(vex(length, "66", "0F3A", "W0", "02 /r ib"), "VPBLENDD", f"{v}1", f"{v}2",
+                                f"{v}3/{m}", "imm8"),
+            InstructionTemplate(vex(length, "66", "0F38", "W0", "47 /r"), "VPSLLVD", f"{v}1", f"{v}2", f"{v}3/{m}"),
+            InstructionTemplate(vex(length, "66", "0F38", "W1", "47 /r"), "VPSLLVQ", f"{v}1", f"{v}2", f"{v}3/{m}"),
+            InstructionTemplate(vex(length, "66", "0F38", "W0", "45 /r"), "VPSRLVD", f"{v}1", f"{v}2", f"{v}3/{m}"),
+            InstructionTemplate(vex(length, "66", "0F38", "W1", "45 /r"), "VPSRLVQ", f"{v}1", f"{v}2", f"{v}3/{m}"),
+            InstructionTemplate(vex(length, "66", "0F38", "W0", "46 /r"), "VPSRAVD", f"{v}1", f"{v}2", f"{v}3/{m}"),
+            InstructionTemplate(vex(length, "66", "0F38", "W0", "18 /r"), "VBROADCASTSS", f"{v}1", "xmm2/m32"),
+            InstructionTemplate(vex(length, "66", "0F38", "W0", "78 /r"), "VPBROADCASTB", f"{v}1", "xmm2/m8"),
+            InstructionTemplate(vex(length, "66", "0F38", "W0", "79 /r"), "VPBROADCASTW", f"{v}1", "xmm2/m16"),
+            InstructionTemplate(vex(length, "66", "0F38", "W0", "58 /r"), "VPBROADCASTD", f"{v}1", "xmm2/m32"),
+            InstructionTemplate(vex(length, "66", "0F38", "W0", "59 /r"), "VPBROADCASTQ", f"{v}1", "xmm2/m64"),
+        ]
+
+    instructions += [
+        InstructionTemplate(vex("128", "66", "0F3A", "WIG", "41 /r ib"), "VDPPD", "xmm1", "xmm2", "xmm3/m128", "imm8"),
+        InstructionTemplate(vex("LIG", "66", "0F3A", "WIG", "0A /r ib"), "VROUNDSS", "xmm1", "xmm2", "xmm3/m32",
+                            "imm8"),
+        InstructionTemplate(vex("LIG", "66", "0F3A", "WIG", "0B /r ib"), "VROUNDSD", "xmm1", "xmm2", "xmm3/m64",
+                            "imm8"),
+        InstructionTemplate(vex("256", "66", "0F38", "W0", "19 /r"), "VBROADCASTSD", "ymm1", "xmm2/m64"),
+        InstructionTemplate(vex("256", "66", "0F38", "W0", "1A /r"), "VBROADCASTF128", "ymm1", "m128"),
+        InstructionTemplate(vex("256", "66", "0F38", "W0", "5A /r"), "VBROADCASTI128", "ymm1", "m128"),
+        InstructionTemplate(vex("256", "66", "0F3A", "W0", "18 /r ib"), "VINSERTF128", "ymm1", "ymm2", "xmm3/m128",
+                            "imm8"),
+        InstructionTemplate(vex("256", "66", "0F3A", "W0", "38 /r ib"), "VINSERTI128", "ymm1", "ymm2", "xmm3/m128",
+                            "imm8"),
+        InstructionTemplate(vex("256", "66", "0F3A", "W0", "19 /r ib"), "VEXTRACTF128", "xmm1/m128", "ymm2", "imm8"),
+        InstructionTemplate(vex("256", "66", "0F3A", "W0", "39 /r ib"), "VEXTRACTI128", "xmm1/m128", "ymm2", "imm8"),
+        InstructionTemplate(vex("256", "66", "0F3A", "W0", "06 /r ib"), "VPERM2F128", "ymm1", "ymm2", "ymm3/m256",
+                            "imm8"),
+        InstructionTemplate(vex("256", "66", "0F3A", "W0", "46 /r ib"), "VPERM2I128", "ymm1", "ymm2", "ymm3/m256",
+                            "imm8"),
+        InstructionTemplate(vex("256", "66", "0F38", "W0", "36 /r"), "VPERMD", "ymm1", "ymm2", "ymm3/m256"),
+        InstructionTemplate(vex("256", "66", "0F38", "W0", "16 /r"), "VPERMPS", "ymm1", "ymm2", "ymm3/m256"),
+        InstructionTemplate(vex("256", "66", "0F3A", "W1", "00 /r ib"), "VPERMQ", "ymm1", "ymm2/m256", "imm8"),
+        InstructionTemplate(vex("256", "66", "0F3A", "W1", "01 /r ib"), "VPERMPD", "ymm1", "ymm2/m256", "imm8"),
+    ]
+
+    # AVX2 gathers, where the vector of indices can be wider or narrower than the destination, depending on the sizes of
+    # the indices and elements. The mask has the size of the destination.
+    gathers = [("90", "VPGATHERDD", "W0", "xmm", "vm32x", "ymm", "vm32y"),
+               ("91", "VPGATHERQD", "W0", "xmm", "vm64x", "xmm", "vm64y"),
+               ("90", "VPGATHERDQ", "W1", "xmm", "vm32x", "ymm", "vm32x"),
+               ("91", "VPGATHERQQ", "W1", "xmm", "vm64x", "ymm", "vm64y"),
+               ("92", "VGATHERDPS", "W0", "xmm", "vm32x", "ymm", "vm32y"),
+               ("93", "VGATHERQPS", "W0", "xmm", "vm64x", "xmm", "vm64y"),
+               ("92", "VGATHERDPD", "W1", "xmm", "vm32x", "ymm", "vm32x"),
+               ("93", "VGATHERQPD", "W1", "xmm", "vm64x", "ymm", "vm64y")]
+    for opcode, name, w, v128, vsib128, v256, vsib256 in gathers:
+        for length, v, vsib in [("128", v128, vsib128), ("256", v256, vsib256)]:
+            instructions.append(InstructionTemplate(vex(length, "66", "0F38", w, f"{opcode} /r"), name, f"{v}1", vsib,
+                                                    f"{v}2"))
+
+    # FMA, where the digits say which operands are multiplied and which one is added, like 231 for ymm2 * ymm3 + ymm1
+    fma = [("VFMADD", 0x98), ("VFMSUB", 0x9A), ("VFNMADD", 0x9C), ("VFNMSUB", 0x9E), ("VFMADDSUB", 0x96),
+           ("VFMSUBADD", 0x97)]
+    for name, base in fma:
+        for order, offset in [("132", 0x00), ("213", 0x10), ("231", 0x20)]:
+            for suffix, w in [("PS", "W0"), ("PD", "W1")]:
+                for length, v, m in vectors:
+                    instructions.append(InstructionTemplate(vex(length, "66", "0F38", w, f"{base + offset:02X} /r"),
+                                                            f"{name}{order}{suffix}", f"{v}1", f"{v}2", f"{v}3/{m}"))
+            # The alternating forms have no scalar version
+            if name not in ["VFMADDSUB", "VFMSUBADD"]:
+                for suffix, w, m in [("SS", "W0", "m32"), ("SD", "W1", "m64")]:
+                    instructions.append(InstructionTemplate(vex("LIG", "66", "0F38", w, f"{base + offset + 1:02X} /r"),
+                                                            f"{name}{order}{suffix}", "xmm1", "xmm2", f"xmm3/{m}"))
+
+    # F16C
+    instructions += [
+        InstructionTemplate(vex("128", "66", "0F38", "W0", "13 /r"), "VCVTPH2PS", "xmm1", "xmm2/m64"),
+        InstructionTemplate(vex("256", "66", "0F38", "W0", "13 /r"), "VCVTPH2PS", "ymm1", "xmm2/m128"),
+        InstructionTemplate(vex("128", "66", "0F3A", "W0", "1D /r ib"), "VCVTPS2PH", "xmm1/m64", "xmm2", "imm8"),
+        InstructionTemplate(vex("256", "66", "0F3A", "W0", "1D /r ib"), "VCVTPS2PH", "xmm1/m128", "ymm2", "imm8"),
+    ]
+
+    return instructions
+
+
+def avx512_instructions():
+    """The AVX-512 foundation, VL, BW and DQ subsets, which are EVEX encoded, and the opmask instructions, which use
+    VEX. Rounding control and exception suppression only exist for the 512-bit and scalar forms."""
+    instructions = []
+    vectors = [("128", "xmm", "m128"), ("256", "ymm", "m256"), ("512", "zmm", "m512")]
+
+    def evex(length, prefix, escape, w, rest):
+        fields = ["EVEX", length] + ([prefix] if prefix else []) + [escape, w]
+        return ".".join(fields) + " " + rest
+
+    def vex(length, prefix, escape, w, rest):
+        fields = ["VEX", length] + ([prefix] if prefix else []) + [escape, w]
+        return ".".join(fields) + " " + rest
+
+    def half(v):
+        """The register and memory operand with half the width, like for conversions from 32 to 64-bit elements"""
+        return {"xmm": ("xmm", "m64"), "ymm": ("xmm", "m128"), "zmm": ("ymm", "m256")}[v]
+
+    for prefix, suffix, w, e in [("", "PS", "W0", 32), ("66", "PD", "W1", 64)]:
+        for length, v, m in vectors:
+            er = "{er}" if v == "zmm" else ""
+            sae = "{sae}" if v == "zmm" else ""
+            dest = f"{v}1{{k1}}{{z}}"
+            source = f"{v}3/{m}/m{e}bcst"
+            for opcode, name, decoration in [("58", "ADD", er), ("59", "MUL", er), ("5C", "SUB", er),
+                                             ("5E", "DIV", er), ("5D", "MIN", sae), ("5F", "MAX", sae),
+                                             ("54", "AND", ""), ("55", "ANDN", ""), ("56", "OR", ""), ("57", "XOR", ""),
+                                             ("14", "UNPCKL", ""), ("15", "UNPCKH", "")]:
+                instructions.append(InstructionTemplate(evex(length, prefix, "0F", w, f"{opcode} /r"),
+                                                        f"V{name}{suffix}", dest, f"{v}2", source + decoration))
+            instructions += [
+                InstructionTemplate(evex(length, prefix, "0F", w, "51 /r"), "VSQRT" + suffix, dest,
+                                    f"{v}2/{m}/m{e}bcst{er}"),
+                InstructionTemplate(evex(length, prefix, "0F", w, "C2 /r ib"), "VCMP" + suffix, "k1{k2}", f"{v}2",
+                                    source + sae, "imm8"),
+                InstructionTemplate(evex(length, prefix, "0F", w, "C6 /r ib"), "VSHUF" + suffix, dest, f"{v}2",
+                                    source, "imm8"),
+                InstructionTemplate(evex(length, prefix, "0F", w, "10 /r"), "VMOVU" + suffix, dest, f"{v}2/{m}"),
+                InstructionTemplate(evex(length, prefix, "0F", w, "11 /r"), "VMOVU" + suffix, f"{v}2/{m}{{k1}}{{z}}",
+                                    f"{v}1"),
+                InstructionTemplate(evex(length, prefix, "0F", w, "28 /r"), "VMOVA" + suffix, dest, f"{v}2/{m}"),
+                InstructionTemplate(evex(length, prefix, "0F", w, "29 /r"), "VMOVA" + suffix, f"{v}2/{m}{{k1}}{{z}}",
+                                    f"{v}1"),
+                InstructionTemplate(evex(length, prefix, "0F", w, "2B /r"), "VMOVNT" + suffix, m, f"{v}1"),
+                InstructionTemplate(evex(length, "66", "0F38", w, "4C /r"), "VRCP14" + suffix, dest,
+                                    f"{v}2/{m}/m{e}bcst"),
+                InstructionTemplate(evex(length, "66", "0F38", w, "4E /r"), "VRSQRT14" + suffix, dest,
+                                    f"{v}2/{m}/m{e}bcst"),
+                InstructionTemplate(evex(length, "66", "0F38", w, "42 /r"), "VGETEXP" + suffix, dest,
+                                    f"{v}2/{m}/m{e}bcst{sae}"),
+                InstructionTemplate(evex(length, "66", "0F38", w, "2C /r"), "VSCALEF" + suffix, dest, f"{v}2",
+                                    source + er),
+                InstructionTemplate(evex(length, "66", "0F38", w, "65 /r"), "VBLENDM" + suffix, dest, f"{v}2", source),
+                InstructionTemplate(evex(length, "66", "0F38", w, "77 /r"), "VPERMI2" + suffix, dest, f"{v}2", source),
+                InstructionTemplate(evex(length, "66", "0F38", w, "7F /r"), "VPERMT2" + suffix, dest, f"{v}2", source),
+                InstructionTemplate(evex(length, "66", "0F38", w, "0C /r" if e == 32 else "0D /r"),
+                                    "VPERMIL" + suffix, dest, f"{v}2", source),
+                InstructionTemplate(evex(length, "66", "0F3A", w, "04 /r ib" if e == 32 else "05 /r ib"),
+                                    "VPERMIL" + suffix, dest, f"{v}2/{m}/m{e}bcst", "imm8"),
+                InstructionTemplate(evex(length, "66", "0F3A", w, "08 /r ib" if e == 32 else "09 /r ib"),
+                                    "VRNDSCALE" + suffix, dest, f"{v}2/{m}/m{e}bcst{sae}", "imm8"),
+                InstructionTemplate(evex(length, "66", "0F3A", w, "26 /r ib"), "VGETMANT" + suffix, dest,
+                                    f"{v}2/{m}/m{e}bcst{sae}", "imm8"),
+                InstructionTemplate(evex(length, "66", "0F3A", w, "50 /r ib"), "VRANGE" + suffix, dest, f"{v}2",
+                                    source + sae, "imm8"),
+                InstructionTemplate(evex(length, "66", "0F3A", w, "56 /r ib"), "VREDUCE" + suffix, dest,
+                                    f"{v}2/{m}/m{e}bcst{sae}", "imm8"),
+                InstructionTemplate(evex(length, "66", "0F3A", w, "66 /r ib"), "VFPCLASS" + suffix, "k1{k2}",
+                                    f"{v}2/{m}/m{e}bcst", "imm8"),
+            ]
+            # The permutes across lanes have no 128-bit form
+            if v != "xmm":
+                instructions += [
+                    InstructionTemplate(evex(length, "66", "0F38", w, "16 /r"), "VPERM" + suffix, dest, f"{v}2",
+                                        source),
+                    InstructionTemplate(evex(length, "66", "0F3A", w, "23 /r ib"),
+                                        "VSHUFF32X4" if e == 32 else "VSHUFF64X2", dest, f"{v}2", source, "imm8"),
+                    InstructionTemplate(evex(length, "66", "0F3A", w, "43 /r ib"),
+                                        "VSHUFI32X4" if e == 32 else "VSHUFI64X2", dest, f"{v}2", source, "imm8"),
+                ]
+                if suffix == "PD":
+                    instructions.append(InstructionTemplate(evex(length, "66", "0F3A", "W1", "01 /r ib"), "VPERMPD",
+                                                            dest, f"{v}2/{m}/m64bcst", "imm8"))
+
+    for length, v, m in vectors:
+        dest = f"{v}1{{k1}}{{z}}"
+        instructions += [
+            InstructionTemplate(evex(length, "F3", "0F", "W0", "16 /r"), "VMOVSHDUP", dest, f"{v}2/{m}"),
+            InstructionTemplate(evex(length, "F3", "0F", "W0", "12 /r"), "VMOVSLDUP", dest, f"{v}2/{m}"),
+            InstructionTemplate(evex(length, "F2", "0F", "W1", "12 /r"), "VMOVDDUP", dest,
+                                "xmm2/m64" if v == "xmm" else f"{v}2/{m}"),
+        ]
+
+    for prefix, suffix, w, m in [("F3", "SS", "W0", "m32"), ("F2", "SD", "W1", "m64")]:
+        dest = "xmm1{k1}{z}"
+        for opcode, name, decoration in [("58", "ADD", "{er}"), ("59", "MUL", "{er}"), ("5C", "SUB", "{er}"),
+                                         ("5E", "DIV", "{er}"), ("5D", "MIN", "{sae}"), ("5F", "MAX", "{sae}"),
+                                         ("51", "SQRT", "{er}")]:
+            instructions.append(InstructionTemplate(evex("LIG", prefix, "0F", w, f"{opcode} /r"), f"V{name}{suffix}",
+                                                    dest, "xmm2", f"xmm3/{m}{decoration}"))
+        other = "SD" if suffix == "SS" else "SS"
+        instructions += [
+            InstructionTemplate(evex("LIG", prefix, "0F", w, "C2 /r ib"), "VCMP" + suffix, "k1{k2}", "xmm2",
+                                f"xmm3/{m}{{sae}}", "imm8"),
+            InstructionTemplate(evex("LIG", prefix, "0F", w, "10 /r"), "VMOV" + suffix, dest, "xmm2", "xmm3"),
+            InstructionTemplate(evex("LIG", prefix, "0F", w, "10 /r"), "VMOV" + suffix, dest, m),
+            InstructionTemplate(evex("LIG", prefix, "0F", w, "11 /r"), "VMOV" + suffix, f"{m}{{k1}}", "xmm1"),
+            InstructionTemplate(evex("LIG", prefix, "0F", w, "5A /r"), f"VCVT{suffix}2{other}", dest, "xmm2",
+                                f"xmm3/{m}{{sae}}" if suffix == "SS" else f"xmm3/{m}{{er}}"),
+            # Converting a 32-bit integer to a double is always exact, so it can't be rounded
+            InstructionTemplate(evex("LIG", prefix, "0F", "W0", "2A /r"), "VCVTSI2" + suffix, "xmm1", "xmm2",
+                                "r/m32{er}" if suffix == "SS" else "r/m32"),
+            InstructionTemplate(evex("LIG", prefix, "0F", "W1", "2A /r"), "VCVTSI2" + suffix, "xmm1", "xmm2",
+                                "r/m64{er}"),
+            InstructionTemplate(evex("LIG", prefix, "0F", "W0", "7B /r"), "VCVTUSI2" + suffix, "xmm1", "xmm2",
+                                "r/m32{er}" if suffix == "SS" else "r/m32"),
+            InstructionTemplate(evex("LIG", prefix, "0F", "W1", "7B /r"), "VCVTUSI2" + suffix, "xmm1", "xmm2",
+                                "r/m64{er}"),
+            InstructionTemplate(evex("LIG", prefix, "0F", "W0", "2D /r"), f"VCVT{suffix}2SI", "r32",
+                                f"xmm1/{m}{{er}}"),
+            InstructionTemplate(evex("LIG", prefix, "0F", "W1", "2D /r"), f"VCVT{suffix}2SI", "r64",
+                                f"xmm1/{m}{{er}}"),
+            InstructionTemplate(evex("LIG", prefix, "0F", "W0", "2C /r"), f"VCVTT{suffix}2SI", "r32",
+                                f"xmm1/{m}{{sae}}"),
+            InstructionTemplate(evex("LIG", prefix, "0F", "W1", "2C /r"), f"VCVTT{suffix}2SI", "r64",
+                                f"xmm1/{m}{{sae}}"),
+            InstructionTemplate(evex("LIG", prefix, "0F", "W0", "79 /r"), f"VCVT{suffix}2USI", "r32",
+                                f"xmm1/{m}{{er}}"),
+            InstructionTemplate(evex("LIG", prefix, "0F", "W1", "79 /r"), f"VCVT{suffix}2USI", "r64",
+                                f"xmm1/{m}{{er}}"),
+            InstructionTemplate(evex("LIG", prefix, "0F", "W0", "78 /r"), f"VCVTT{suffix}2USI", "r32",
+                                f"xmm1/{m}{{sae}}"),
+            InstructionTemplate(evex("LIG", prefix, "0F", "W1", "78 /r"), f"VCVTT{suffix}2USI", "r64",
+                                f"xmm1/{m}{{sae}}"),
+            InstructionTemplate(evex("LIG", "" if suffix == "SS" else "66", "0F", w, "2F /r"), "VCOMIS" + suffix[1],
+                                "xmm1", f"xmm2/{m}{{sae}}"),
+            InstructionTemplate(evex("LIG", "" if suffix == "SS" else "66", "0F", w, "2E /r"), "VUCOMIS" + suffix[1],
+                                "xmm1", f"xmm2/{m}{{sae}}"),
+        ]
+        for opcode, name, decoration in [("4D", "VRCP14", ""), ("4F", "VRSQRT14", ""), ("2D", "VSCALEF", "{er}"),
+                                         ("43", "VGETEXP", "{sae}")]:
+            instructions.append(InstructionTemplate(evex("LIG", "66", "0F38", w, f"{opcode} /r"), name + suffix, dest,
+                                                    "xmm2", f"xmm3/{m}{decoration}"))
+        for opcode, name in [("0A", "VRNDSCALE"), ("27", "VGETMANT"), ("51", "VRANGE"), ("57", "VREDUCE")]:
+            if name == "VRNDSCALE" and suffix == "SD":
+                opcode = "0B"
+            instructions.append(InstructionTemplate(evex("LIG", "66", "0F3A", w, f"{opcode} /r ib"), name + suffix,
+                                                    dest, "xmm2", f"xmm3/{m}{{sae}}", "imm8"))
+        instructions.append(InstructionTemplate(evex("LIG", "66", "0F3A", w, "67 /r ib"), "VFPCLASS" + suffix,
+                                                "k1{k2}", f"xmm2/{m}", "imm8"))
+
+    for length, v, m in vectors:
+        er = "{er}" if v == "zmm" else ""
+        sae = "{sae}" if v == "zmm" else ""
+        half_v, half_m = half(v)
+        # The conversions between 32 and 64-bit elements have a source or destination of half the width
+        wide_to_wide = [("0F", "", "W0", "5B", "VCVTDQ2PS", er), ("0F", "66", "W0", "5B", "VCVTPS2DQ", er),
+                        ("0F", "F3", "W0", "5B", "VCVTTPS2DQ", sae), ("0F", "", "W0", "79", "VCVTPS2UDQ", er),
+                        ("0F", "", "W0", "78", "VCVTTPS2UDQ", sae), ("0F", "F2", "W0", "7A", "VCVTUDQ2PS", er),
+                        ("0F", "F3", "W1", "E6", "VCVTQQ2PD", er), ("0F", "66", "W1", "7B", "VCVTPD2QQ", er),
+                        ("0F", "66", "W1", "7A", "VCVTTPD2QQ", sae), ("0F", "F3", "W1", "7A", "VCVTUQQ2PD", er),
+                        ("0F", "66", "W1", "79", "VCVTPD2UQQ", er), ("0F", "66", "W1", "78", "VCVTTPD2UQQ", sae)]
+        narrowing = [("0F", "66", "W1", "5A", "VCVTPD2PS", er), ("0F", "F2", "W1", "E6", "VCVTPD2DQ", er),
+                     ("0F", "66", "W1", "E6", "VCVTTPD2DQ", sae), ("0F", "", "W1", "79", "VCVTPD2UDQ", er),
+                     ("0F", "", "W1", "78", "VCVTTPD2UDQ", sae), ("0F", "", "W1", "5B", "VCVTQQ2PS", er),
+                     ("0F", "F2", "W1", "7A", "VCVTUQQ2PS", er)]
+        widening = [("0F", "", "W0", "5A", "VCVTPS2PD", sae), ("0F", "F3", "W0", "E6", "VCVTDQ2PD", ""),
+                    ("0F", "F3", "W0", "7A", "VCVTUDQ2PD", ""), ("0F", "66", "W0", "7B", "VCVTPS2QQ", er),
+                    ("0F", "66", "W0", "7A", "VCVTTPS2QQ", sae), ("0F", "66", "W0", "79", "VCVTPS2UQQ", er),
+                    ("0F", "66", "W0", "78", "VCVTTPS2UQQ", sae)]
+        for escape, prefix, w, opcode, name, decoration in wide_to_wide:
+            e = 32 if w == "W0" else 64
+            instructions.append(InstructionTemplate(evex(length, prefix, escape, w, f"{opcode} /r"), name,
+                                                    f"{v}1{{k1}}{{z}}", f"{v}2/{m}/m{e}bcst{decoration}"))
+        for escape, prefix, w, opcode, name, decoration in narrowing:
+            instructions.append(InstructionTemplate(evex(length, prefix, escape, w, f"{opcode} /r"), name,
+                                                    f"{half_v}1{{k1}}{{z}}", f"{v}2/{m}/m64bcst{decoration}"))
+        for escape, prefix, w, opcode, name, decoration in widening:
+            instructions.append(InstructionTemplate(evex(length, prefix, escape, w, f"{opcode} /r"), name,
+                                                    f"{v}1{{k1}}{{z}}", f"{half_v}2/{half_m}/m32bcst{decoration}"))
+        instructions += [
+            InstructionTemplate(evex(length, "66", "0F38", "W0", "13 /r"), "VCVTPH2PS", f"{v}1{{k1}}{{z}}",
+                                f"{half_v}2/{half_m}{sae}"),
+            InstructionTemplate(evex(length, "66", "0F3A", "W0", "1D /r ib"), "VCVTPS2PH",
+                                f"{half_v}1/{half_m}{{k1}}{{z}}", f"{v}2{sae}", "imm8"),
+        ]
+
+    fma = [("VFMADD", 0x98), ("VFMSUB", 0x9A), ("VFNMADD", 0x9C), ("VFNMSUB", 0x9E), ("VFMADDSUB", 0x96),
+           ("VFMSUBADD", 0x97)]
+    for name, base in fma:
+        for order, offset in [("132", 0x00), ("213", 0x10), ("231", 0x20)]:
+            for suffix, w, e in [("PS", "W0", 32), ("PD", "W1", 64)]:
+                for length, v, m in vectors:
+                    er = "{er}" if v == "zmm" else ""
+                    instructions.append(InstructionTemplate(evex(length, "66", "0F38", w, f"{base + offset:02X} /r"),
+                                                            f"{name}{order}{suffix}", f"{v}1{{k1}}{{z}}", f"{v}2",
+                                                            f"{v}3/{m}/m{e}bcst{er}"))
+            if name not in ["VFMADDSUB", "VFMSUBADD"]:
+                for suffix, w, m in [("SS", "W0", "m32"), ("SD", "W1", "m64")]:
+                    instructions.append(InstructionTemplate(evex("LIG", "66", "0F38", w,
+                                                                 f"{base + offset + 1:02X} /r"),
+                                                            f"{name}{order}{suffix}", "xmm1{k1}{z}", "xmm2",
+                                                            f"xmm3/{m}{{er}}"))
+
+    # Instructions on doublewords and quadwords can broadcast their elements, the ones on bytes and words can't
+    dwords = [("0F", "FE", "VPADDD", "W0"), ("0F", "D4", "VPADDQ", "W1"), ("0F", "FA", "VPSUBD", "W0"),
+              ("0F", "FB", "VPSUBQ", "W1"), ("0F", "F4", "VPMULUDQ", "W1"), ("0F38", "28", "VPMULDQ", "W1"),
+              ("0F38", "40", "VPMULLD", "W0"), ("0F38", "40", "VPMULLQ", "W1"), ("0F", "DB", "VPANDD", "W0"),
+              ("0F", "DB", "VPANDQ", "W1"), ("0F", "DF", "VPANDND", "W0"), ("0F", "DF", "VPANDNQ", "W1"),
+              ("0F", "EB", "VPORD", "W0"), ("0F", "EB", "VPORQ", "W1"), ("0F", "EF", "VPXORD", "W0"),
+              ("0F", "EF", "VPXORQ", "W1"), ("0F38", "3D", "VPMAXSD", "W0"), ("0F38", "3D", "VPMAXSQ", "W1"),
+              ("0F38", "3F", "VPMAXUD", "W0"), ("0F38", "3F", "VPMAXUQ", "W1"), ("0F38", "39", "VPMINSD", "W0"),
+              ("0F38", "39", "VPMINSQ", "W1"), ("0F38", "3B", "VPMINUD", "W0"), ("0F38", "3B", "VPMINUQ", "W1"),
+              ("0F38", "47", "VPSLLVD", "W0"), ("0F38", "47", "VPSLLVQ", "W1"), ("0F38", "45", "VPSRLVD", "W0"),
+              ("0F38", "45", "VPSRLVQ", "W1"), ("0F38", "46", "VPSRAVD", "W0"), ("0F38", "46", "VPSRAVQ", "W1"),
+              ("0F38", "15", "VPROLVD", "W0"), ("0F38", "15", "VPROLVQ", "W1"), ("0F38", "14", "VPRORVD", "W0"),
+              ("0F38", "14", "VPRORVQ", "W1"), ("0F38", "76", "VPERMI2D", "W0"), ("0F38", "76", "VPERMI2Q", "W1"),
+              ("0F38", "7E", "VPERMT2D", "W0"), ("0F38", "7E", "VPERMT2Q", "W1"), ("0F38", "64", "VPBLENDMD", "W0"),
+              ("0F38", "64", "VPBLENDMQ", "W1"), ("0F", "62", "VPUNPCKLDQ", "W0"), ("0F", "6A", "VPUNPCKHDQ", "W0"),
+              ("0F", "6C", "VPUNPCKLQDQ", "W1"), ("0F", "6D", "VPUNPCKHQDQ", "W1"), ("0F", "6B", "VPACKSSDW", "W0"),
+              ("0F38", "2B", "VPACKUSDW", "W0")]
+    dword_compares = [("", "0F", "76", "VPCMPEQD", "W0"), ("", "0F", "66", "VPCMPGTD", "W0"),
+                      ("", "0F38", "29", "VPCMPEQQ", "W1"), ("", "0F38", "37", "VPCMPGTQ", "W1"),
+                      ("66", "0F38", "27", "VPTESTMD", "W0"), ("66", "0F38", "27", "VPTESTMQ", "W1"),
+                      ("F3", "0F38", "27", "VPTESTNMD", "W0"), ("F3", "0F38", "27", "VPTESTNMQ", "W1")]
+    dword_immediates = [("1F", "VPCMPD", "W0", "k1{k2}"), ("1E", "VPCMPUD", "W0", "k1{k2}"),
+                        ("1F", "VPCMPQ", "W1", "k1{k2}"), ("1E", "VPCMPUQ", "W1", "k1{k2}"),
+                        ("25", "VPTERNLOGD", "W0", None), ("25", "VPTERNLOGQ", "W1", None),
+                        ("03", "VALIGND", "W0", None), ("03", "VALIGNQ", "W1", None)]
+    dword_shifts = [("72 /6", "VPSLLD", "W0"), ("72 /2", "VPSRLD", "W0"), ("72 /4", "VPSRAD", "W0"),
+                    ("73 /6", "VPSLLQ", "W1"), ("73 /2", "VPSRLQ", "W1"), ("72 /4", "VPSRAQ", "W1"),
+                    ("72 /1", "VPROLD", "W0"), ("72 /1", "VPROLQ", "W1"), ("72 /0", "VPRORD", "W0"),
+                    ("72 /0", "VPRORQ", "W1")]
+    counts = [("F1", "VPSLLW", "W0"), ("F2", "VPSLLD", "W0"), ("F3", "VPSLLQ", "W1"), ("D1", "VPSRLW", "W0"),
+              ("D2", "VPSRLD", "W0"), ("D3", "VPSRLQ", "W1"), ("E1", "VPSRAW", "W0"), ("E2", "VPSRAD", "W0"),
+              ("E2", "VPSRAQ", "W1")]
+    words = [("0F", "FC", "VPADDB", "W0"), ("0F", "FD", "VPADDW", "W0"), ("0F", "F8", "VPSUBB", "W0"),
+             ("0F", "F9", "VPSUBW", "W0"), ("0F", "EC", "VPADDSB", "W0"), ("0F", "ED", "VPADDSW", "W0"),
+             ("0F", "DC", "VPADDUSB", "W0"), ("0F", "DD", "VPADDUSW", "W0"), ("0F", "E8", "VPSUBSB", "W0"),
+             ("0F", "E9", "VPSUBSW", "W0"), ("0F", "D8", "VPSUBUSB", "W0"), ("0F", "D9", "VPSUBUSW", "W0"),
+             ("0F", "D5", "VPMULLW", "W0"), ("0F", "E5", "VPMULHW", "W0"), ("0F", "E4", "VPMULHUW", "W0"),
+             ("0F", "F5", "VPMADDWD", "W0"), ("0F", "E0", "VPAVGB", "W0"), ("0F", "E3", "VPAVGW", "W0"),
+             ("0F", "DA", "VPMINUB", "W0"), ("0F", "DE", "VPMAXUB", "W0"), ("0F", "EA", "VPMINSW", "W0"),
+             ("0F", "EE", "VPMAXSW", "W0"), ("0F", "63", "VPACKSSWB", "W0"), ("0F", "67", "VPACKUSWB", "W0"),
+             ("0F", "60", "VPUNPCKLBW", "W0"), ("0F", "61", "VPUNPCKLWD", "W0"), ("0F", "68", "VPUNPCKHBW", "W0"),
+             ("0F", "69", "VPUNPCKHWD", "W0"), ("0F38", "00", "VPSHUFB", "W0"), ("0F38", "04", "VPMADDUBSW", "W0"),
+             ("0F38", "0B", "VPMULHRSW", "W0"), ("0F38", "38", "VPMINSB", "W0"), ("0F38", "3C", "VPMAXSB", "W0"),
+             ("0F38", "3A", "VPMINUW", "W0"), ("0F38", "3E", "VPMAXUW", "W0"), ("0F38", "12", "VPSLLVW", "W1"),
+             ("0F38", "10", "VPSRLVW", "W1"), ("0F38", "11", "VPSRAVW", "W1"), ("0F38", "75", "VPERMI2W", "W1"),
+             ("0F38", "7D", "VPERMT2W", "W1"), ("0F38", "66", "VPBLENDMB", "W0"), ("0F38", "66", "VPBLENDMW", "W1")]
+    word_compares = [("", "0F", "74", "VPCMPEQB", "W0"), ("", "0F", "75", "VPCMPEQW", "W0"),
+                     ("", "0F", "64", "VPCMPGTB", "W0"), ("", "0F", "65", "VPCMPGTW", "W0"),
+                     ("66", "0F38", "26", "VPTESTMB", "W0"), ("66", "0F38", "26", "VPTESTMW", "W1"),
+                     ("F3", "0F38", "26", "VPTESTNMB", "W0"), ("F3", "0F38", "26", "VPTESTNMW", "W1")]
+    # The down conversions store the lower half, quarter or eighth of each element, like the upconversions load it
+    narrows = [("30", "VPMOVWB", 2), ("20", "VPMOVSWB", 2), ("10", "VPMOVUSWB", 2), ("31", "VPMOVDB", 4),
+               ("21", "VPMOVSDB", 4), ("11", "VPMOVUSDB", 4), ("32", "VPMOVQB", 8), ("22", "VPMOVSQB", 8),
+               ("12", "VPMOVUSQB", 8), ("33", "VPMOVDW", 2), ("23", "VPMOVSDW", 2), ("13", "VPMOVUSDW", 2),
+               ("34", "VPMOVQW", 4), ("24", "VPMOVSQW", 4), ("14", "VPMOVUSQW", 4), ("35", "VPMOVQD", 2),
+               ("25", "VPMOVSQD", 2), ("15", "VPMOVUSQD", 2)]
+    extends = [("20", "VPMOVSXBW", 2), ("21", "VPMOVSXBD", 4), ("22", "VPMOVSXBQ", 8), ("23", "VPMOVSXWD", 2),
+               ("24", "VPMOVSXWQ", 4), ("25", "VPMOVSXDQ", 2), ("30", "VPMOVZXBW", 2), ("31", "VPMOVZXBD", 4),
+               ("32", "VPMOVZXBQ", 8), ("33", "VPMOVZXWD", 2), ("34", "VPMOVZXWQ", 4), ("35", "VPMOVZXDQ", 2)]
+
+    for length, v, m in vectors:
+        dest = f"{v}1{{k1}}{{z}}"
+        size = int(m[1:])
+        for escape, opcode, name, w in dwords:
+            e = 32 if w == "W0" else 64
+            instructions.append(InstructionTemplate(evex(length, "66", escape, w, f"{opcode} /r"), name, dest,
+                                                    f"{v}2", f"{v}3/{m}/m{e}bcst"))
+        for prefix, escape, opcode, name, w in dword_compares:
+            e = 32 if w == "W0" else 64
+            instructions.append(InstructionTemplate(evex(length, prefix or "66", escape, w, f"{opcode} /r"), name,
+                                                    "k1{k2}", f"{v}2", f"{v}3/{m}/m{e}bcst"))
+        for opcode, name, w, other in dword_immediates:
+            e = 32 if w == "W0" else 64
+            instructions.append(InstructionTemplate(evex(length, "66", "0F3A", w, f"{opcode} /r ib"), name,
+                                                    other or dest, f"{v}2", f"{v}3/{m}/m{e}bcst", "imm8"))
+        for opcode, name, w in dword_shifts:
+            e = 32 if w == "W0" else 64
+            instructions.append(InstructionTemplate(evex(length, "66", "0F", w, f"{opcode} ib"), name, dest,
+                                                    f"{v}2/{m}/m{e}bcst", "imm8"))
+        for opcode, name, w in counts:
+            instructions.append(InstructionTemplate(evex(length, "66", "0F", w, f"{opcode} /r"), name, dest, f"{v}2",
+                                                    "xmm3/m128"))
+        for escape, opcode, name, w in words:
+            instructions.append(InstructionTemplate(evex(length, "66", escape, w, f"{opcode} /r"), name, dest,
+                                                    f"{v}2", f"{v}3/{m}"))
+        for prefix, escape, opcode, name, w in word_compares:
+            instructions.append(InstructionTemplate(evex(length, prefix or "66", escape, w, f"{opcode} /r"), name,
+                                                    "k1{k2}", f"{v}2", f"{v}3/{m}"))
+        for opcode, name, w in [("3F", "VPCMPB", "W0"), ("3E", "VPCMPUB", "W0"), ("3F", "VPCMPW", "W1"),
+                                ("3E", "VPCMPUW", "W1")]:
+            instructions.append(InstructionTemplate(evex(length, "66", "0F3A", w, f"{opcode} /r ib"), name,
+                                                    "k1{k2}", f"{v}2", f"{v}3/{m}", "imm8"))
+        for opcode, name in [("71 /6", "VPSLLW"), ("71 /2", "VPSRLW"), ("71 /4", "VPSRAW")]:
+            instructions.append(InstructionTemplate(evex(length, "66", "0F", "W0", f"{opcode} ib"), name, dest,
+                                                    f"{v}2/{m}", "imm8"))
+        for opcode, name in [("73 /7", "VPSLLDQ"), ("73 /3", "VPSRLDQ")]:
+            instructions.append(InstructionTemplate(evex(length, "66", "0F", "W0", f"{opcode} ib"), name, f"{v}1",
+                                                    f"{v}2/{m}", "imm8"))
+        for opcode, name, ratio in narrows:
+            narrow_v = "ymm" if size // ratio == 256 else "xmm"
+            instructions.append(InstructionTemplate(evex(length, "F3", "0F38", "W0", f"{opcode} /r"), name,
+                                                    f"{narrow_v}1/m{size // ratio}{{k1}}{{z}}", f"{v}2"))
+        for opcode, name, ratio in extends:
+            narrow_v = "ymm" if size // ratio == 256 else "xmm"
+            instructions.append(InstructionTemplate(evex(length, "66", "0F38", "W0", f"{opcode} /r"), name, dest,
+                                                    f"{narrow_v}2/m{size // ratio}"))
+        for opcode, name, w in [("28", "VPMOVM2B", "W0"), ("28", "VPMOVM2W", "W1"), ("38", "VPMOVM2D", "W0"),
+                                ("38", "VPMOVM2Q", "W1")]:
+            instructions.append(InstructionTemplate(evex(length, "F3", "0F38", w, f"{opcode} /r"), name, f"{v}1",
+                                                    "k1"))
+        for opcode, name, w in [("29", "VPMOVB2M", "W0"), ("29", "VPMOVW2M", "W1"), ("39", "VPMOVD2M", "W0"),
+                                ("39", "VPMOVQ2M", "W1")]:
+            instructions.append(InstructionTemplate(evex(length, "F3", "0F38", w, f"{opcode} /r"), name, "k1",
+                                                    f"{v}1"))
+        for prefix, name, w in [("66", "VMOVDQA32", "W0"), ("66", "VMOVDQA64", "W1"), ("F3", "VMOVDQU32", "W0"),
+                                ("F3", "VMOVDQU64", "W1"), ("F2", "VMOVDQU8", "W0"), ("F2", "VMOVDQU16", "W1")]:
+            instructions += [
+                InstructionTemplate(evex(length, prefix, "0F", w, "6F /r"), name, dest, f"{v}2/{m}"),
+                InstructionTemplate(evex(length, prefix, "0F", w, "7F /r"), name, f"{v}2/{m}{{k1}}{{z}}", f"{v}1"),
+            ]
+        for opcode, name in [("1C", "VPABSB"), ("1D", "VPABSW")]:
+            instructions.append(InstructionTemplate(evex(length, "66", "0F38", "W0", f"{opcode} /r"), name, dest,
+                                                    f"{v}2/{m}"))
+        instructions += [
+            InstructionTemplate(evex(length, "66", "0F38", "W0", "1E /r"), "VPABSD", dest, f"{v}2/{m}/m32bcst"),
+            InstructionTemplate(evex(length, "66", "0F38", "W1", "1F /r"), "VPABSQ", dest, f"{v}2/{m}/m64bcst"),
+            InstructionTemplate(evex(length, "66", "0F", "W0", "70 /r ib"), "VPSHUFD", dest, f"{v}2/{m}/m32bcst",
+                                "imm8"),
+            InstructionTemplate(evex(length, "F2", "0F", "W0", "70 /r ib"), "VPSHUFLW", dest, f"{v}2/{m}", "imm8"),
+            InstructionTemplate(evex(length, "F3", "0F", "W0", "70 /r ib"), "VPSHUFHW", dest, f"{v}2/{m}", "imm8"),
+            InstructionTemplate(evex(length, "66", "0F3A", "W0", "0F /r ib"), "VPALIGNR", dest, f"{v}2", f"{v}3/{m}",
+                                "imm8"),
+            InstructionTemplate(evex(length, "66", "0F", "W0", "F6 /r"), "VPSADBW", f"{v}1", f"{v}2", f"{v}3/{m}"),
+            InstructionTemplate(evex(length, "66", "0F", "W0", "E7 /r"), "VMOVNTDQ", m, f"{v}1"),
+            InstructionTemplate(evex(length, "66", "0F38", "W0", "2A /r"), "VMOVNTDQA", f"{v}1", m),
+            InstructionTemplate(evex(length, "66", "0F38", "W0", "18 /r"), "VBROADCASTSS", dest, "xmm2/m32"),
+            InstructionTemplate(evex(length, "66", "0F38", "W0", "78 /r"), "VPBROADCASTB", dest, "xmm2/m8"),
+            InstructionTemplate(evex(length, "66", "0F38", "W0", "79 /r"), "VPBROADCASTW", dest, "xmm2/m16"),
+            InstructionTemplate(evex(length, "66", "0F38", "W0", "58 /r"), "VPBROADCASTD", dest, "xmm2/m32"),
+            InstructionTemplate(evex(length, "66", "0F38", "W1", "59 /r"), "VPBROADCASTQ", dest, "xmm2/m64"),
+            InstructionTemplate(evex(length, "66", "0F38", "W0", "7A /r"), "VPBROADCASTB", dest, "r32"),
+            InstructionTemplate(evex(length, "66", "0F38", "W0", "7B /r"), "VPBROADCASTW", dest, "r32"),
+            InstructionTemplate(evex(length, "66", "0F38", "W0", "7C /r"), "VPBROADCASTD", dest, "r32"),
+            InstructionTemplate(evex(length, "66", "0F38", "W1", "7C /r"), "VPBROADCASTQ", dest, "r64"),
+            InstructionTemplate(evex(length, "66", "0F38", "W0", "59 /r"), "VBROADCASTI32X2", dest, "xmm2/m64"),
+        ]
+        if v != "xmm":
+            instructions += [
+                InstructionTemplate(evex(length, "66", "0F38", "W0", "36 /r"), "VPERMD", dest, f"{v}2",
+                                    f"{v}3/{m}/m32bcst"),
+                InstructionTemplate(evex(length, "66", "0F38", "W1", "36 /r"), "VPERMQ", dest, f"{v}2",
+                                    f"{v}3/{m}/m64bcst"),
+                InstructionTemplate(evex(length, "66", "0F3A", "W1", "00 /r ib"), "VPERMQ", dest, f"{v}2/{m}/m64bcst",
+                                    "imm8"),
+                InstructionTemplate(evex(length, "66", "0F38", "W1", "8D /r"), "VPERMW", dest, f"{v}2", f"{v}3/{m}"),
+                InstructionTemplate(evex(length, "66", "0F38", "W1", "19 /r"), "VBROADCASTSD", dest, "xmm2/m64"),
+                InstructionTemplate(evex(length, "66", "0F38", "W0", "19 /r"), "VBROADCASTF32X2", dest, "xmm2/m64"),
+            ]
+        else:
+            instructions.append(InstructionTemplate(evex(length, "66", "0F38", "W1", "8D /r"), "VPERMW", dest,
+                                                    f"{v}2", f"{v}3/{m}"))
+
+    # Inserting and extracting parts of 128 or 256 bits
+    for length, v, m in vectors[1:]:
+        dest = f"{v}1{{k1}}{{z}}"
+        parts = [("18", "19", "1A", "F32X4", "W0", "xmm", "m128"), ("38", "39", "5A", "I32X4", "W0", "xmm", "m128"),
+                 ("18", "19", "1A", "F64X2", "W1", "xmm", "m128"), ("38", "39", "5A", "I64X2", "W1", "xmm", "m128")]
+        if v == "zmm":
+            parts += [("1A", "1B", "1B", "F64X4", "W1", "ymm", "m256"),
+                      ("3A", "3B", "5B", "I64X4", "W1", "ymm", "m256"),
+                      ("1A", "1B", "1B", "F32X8", "W0", "ymm", "m256"),
+                      ("3A", "3B", "5B", "I32X8", "W0", "ymm", "m256")]
+        for insert, extract, broadcast, suffix, w, part_v, part_m in parts:
+            instructions += [
+                InstructionTemplate(evex(length, "66", "0F3A", w, f"{insert} /r ib"), "VINSERT" + suffix, dest,
+                                    f"{v}2", f"{part_v}3/{part_m}", "imm8"),
+                InstructionTemplate(evex(length, "66", "0F3A", w, f"{extract} /r ib"), "VEXTRACT" + suffix,
+                                    f"{part_v}1/{part_m}{{k1}}{{z}}", f"{v}2", "imm8"),
+                InstructionTemplate(evex(length, "66", "0F38", w, f"{broadcast} /r"), "VBROADCAST" + suffix, dest,
+                                    part_m),
+            ]
+
+    # Only needed for xmm16 to xmm31. Unlike with VEX, other assemblers load and store quadwords in memory with the
+    # forms for general purpose registers
+    instructions += [
+        InstructionTemplate(evex("128", "66", "0F", "W0", "6E /r"), "VMOVD", "xmm1", "r/m32"),
+        InstructionTemplate(evex("128", "66", "0F", "W0", "7E /r"), "VMOVD", "r/m32", "xmm1"),
+        InstructionTemplate(evex("128", "66", "0F", "W1", "6E /r"), "VMOVQ", "xmm1", "r/m64"),
+        InstructionTemplate(evex("128", "66", "0F", "W1", "7E /r"), "VMOVQ", "r/m64", "xmm1"),
+        InstructionTemplate(evex("128", "F3", "0F", "W1", "7E /r"), "VMOVQ", "xmm1", "xmm2/m64"),
+        InstructionTemplate(evex("128", "66", "0F", "W1", "D6 /r"), "VMOVQ", "xmm2/m64", "xmm1"),
+    ]
+
+    # The opmask instructions are VEX encoded, where the suffix decides on the prefix and VEX.W
+    for suffix, prefix, w, gpr_prefix, gpr in [("B", "66", "W0", "66", "r32"), ("W", "", "W0", "", "r32"),
+                                               ("D", "66", "W1", "F2", "r32"), ("Q", "", "W1", "F2", "r64")]:
+        size = {"B": 8, "W": 16, "D": 32, "Q": 64}[suffix]
+        for opcode, name in [("41", "KAND"), ("42", "KANDN"), ("45", "KOR"), ("46", "KXNOR"), ("47", "KXOR"),
+                             ("4A", "KADD")]:
+            instructions.append(InstructionTemplate(vex("L1", prefix, "0F", w, f"{opcode} /r"), name + suffix, "k1",
+                                                    "k2", "k3"))
+        instructions += [
+            InstructionTemplate(vex("L0", prefix, "0F", w, "44 /r"), "KNOT" + suffix, "k1", "k2"),
+            InstructionTemplate(vex("L0", prefix, "0F", w, "98 /r"), "KORTEST" + suffix, "k1", "k2"),
+            InstructionTemplate(vex("L0", prefix, "0F", w, "99 /r"), "KTEST" + suffix, "k1", "k2"),
+            InstructionTemplate(vex("L0", prefix, "0F", w, "90 /r"), "KMOV" + suffix, "k1", f"k2/m{size}"),
+            InstructionTemplate(vex("L0", prefix, "0F", w, "91 /r"), "KMOV" + suffix, f"m{size}", "k1"),
+            InstructionTemplate(vex("L0", gpr_prefix, "0F", "W1" if gpr == "r64" else "W0", "92 /r"), "KMOV" + suffix,
+                                "k1", gpr),
+            InstructionTemplate(vex("L0", gpr_prefix, "0F", "W1" if gpr == "r64" else "W0", "93 /r"), "KMOV" + suffix,
+                                gpr, "k1"),
+        ]
+
+    for opcode, name, w in [("30", "KSHIFTRB", "W0"), ("30", "KSHIFTRW", "W1"), ("31", "KSHIFTRD", "W0"),
+                            ("31", "KSHIFTRQ", "W1"), ("32", "KSHIFTLB", "W0"), ("32", "KSHIFTLW", "W1"),
+                            ("33", "KSHIFTLD", "W0"), ("33", "KSHIFTLQ", "W1")]:
+        instructions.append(InstructionTemplate(vex("L0", "66", "0F3A", w, f"{opcode} /r ib"), name, "k1", "k2",
+                                                "imm8"))
+    instructions += [
+        InstructionTemplate(vex("L1", "66", "0F", "W0", "4B /r"), "KUNPCKBW", "k1", "k2", "k3"),
+        InstructionTemplate(vex("L1", "", "0F", "W0", "4B /r"), "KUNPCKWD", "k1", "k2", "k3"),
+        InstructionTemplate(vex("L1", "", "0F", "W1", "4B /r"), "KUNPCKDQ", "k1", "k2", "k3"),
+    ]
+
+    return instructions
+
+
+def register_parser(op, evex):
+    """The call parsing a vector or opmask register, where EVEX can also use the registers from 16 to 31"""
+    if op.is_mask():
+        return "is_mask_register(&mut iter)"
+    elif evex:
+        return f"is_evex_vector(&mut iter, {op.get_vector_size()})"
+    return f"is_{op.get_vector_kind()}(&mut iter)"
+
+
+def print_instruction(instruction, number, types_header):
+    """Prints the function matching one instruction and returns its name"""
+    # Only operands that may be written with constants look them up
+    operands = [op for op in instruction.operands() if op.raw and not op.is_specific_operand()]
+    uses_symbols = any(op.is_imm() or op.is_moffs() or op.is_rel() or op.is_unspecific_rm() or op.is_m()
+                       or op.is_sized_m() or op.is_vector_rm() or op.is_mask_rm() or op.is_vsib() for op in operands)
+    symbols = "symbols" if uses_symbols else "_symbols"
+
+    print(f"""
+fn matches_{instruction.name}{number}(tokens: &[Token], {symbols}: &SymbolTable) -> Result<Instruction, (usize, ParseError)> {{
+    let mut iter = tokens.iter();
+    
+    if get_next(&mut iter)? != \"{instruction.name}\" {{ return Err((iter.count(), ParseError::InvalidInstruction)); }}""",
+          file=types_header)
+
+    rm = False
+    reg = False
+    imm = []
+    rel = None
+    vvvv = instruction.get_vvvv_operand()
+    is4 = instruction.get_is4_operand()
+    evex = instruction.is_evex()
+
+    for position, op in enumerate(instruction.operands()):
+        if not op.raw:
+            continue
+
+        if position > 0:
+            print(
+                "    if get_next(&mut iter)? != \",\" { return Err((iter.count(), ParseError::InvalidOperand)); }",
+                file=types_header)
+
+        if op.is_imm():
+            func = "is_signed_imm_of_size" if instruction.is_sign_extended(op) else "is_imm_of_size"
+            print(f"    let imm{len(imm) + 1} = {func}(&mut iter, {op.get_imm_size()}, symbols)?;",
+                  file=types_header)
+            imm.append((op, f"imm{len(imm) + 1}"))
+        elif op.is_moffs():
+            print(f"    let imm{len(imm) + 1} = is_moffs(&mut iter, symbols)?;", file=types_header)
+            imm.append((op, f"imm{len(imm) + 1}"))
+        elif op.is_rel():
+            print(f"    let rel = is_rel_of_size(&mut iter, {op.get_rel_size()}, symbols)?;", file=types_header)
+            rel = op
+        elif op.is_specific_operand():
+            print(
+                f"    if get_next(&mut iter)? != \"{op.raw}\" {{ return Err((iter.count(), ParseError::InvalidOperand)); }}",
+                file=types_header)
+        elif op.is_unspecific_reg() and reg:
+            # The vector or opmask register is in ModRM.reg, like in `kmovw k1, eax`
+            print(f"    let rm = Rm::Register(is_reg_of_size(&mut iter, {op.get_reg_size()})?);", file=types_header)
+            rm = True
+        elif op.is_unspecific_reg():
+            print(f"    let reg = is_reg_of_size(&mut iter, {op.get_reg_size()})?;", file=types_header)
+            reg = True
+        elif op.is_unspecific_rm():
+            print(f"    let rm = is_rm_of_size(&mut iter, {op.get_rm_size()}, symbols)?;", file=types_header)
+            rm = True
+        elif op.is_m():
+            print(f"    let rm = is_m_of_size(&mut iter, 0, symbols)?;", file=types_header)
+            rm = True
+        elif op.is_sized_m():
+            print(f"    let rm = is_m_of_size(&mut iter, {op.get_m_size()}, symbols)?;", file=types_header)
+            rm = True
+        elif op.is_vector() and position == is4:
+            print(f"    let imm{len(imm) + 1} = is4({register_parser(op, evex)}?);", file=types_header)
+            imm.append((op, f"imm{len(imm) + 1}"))
+        elif (op.is_vector() or op.is_mask()) and position == vvvv:
+            print(f"    let vvvv = {register_parser(op, evex)}?;", file=types_header)
+        elif (op.is_vector() or op.is_mask()) and not reg:
+            print(f"    let reg = {register_parser(op, evex)}?;", file=types_header)
+            reg = True
+        elif op.is_vector() or op.is_mask():
+            # The other register is in ModRM.reg, like in `pmovmskb r32, xmm2`
+            print(f"    let rm = Rm::Register({register_parser(op, evex)}?);", file=types_header)
+            rm = True
+        elif op.is_vector_rm() and evex:
+            broadcast = op.get_broadcast_size()
+            print(f"    let (rm, {'broadcast' if broadcast else '_'}) = is_evex_vector_or_m_of_size(&mut iter, "
+                  f"{op.get_vector_size()}, {op.get_vector_rm_size()}, "
+                  f"{f'Some({broadcast})' if broadcast else 'None'}, symbols)?;", file=types_header)
+            rm = True
+        elif op.is_vector_rm():
+            print(f"    let rm = is_{op.get_vector_kind()}_or_m_of_size(&mut iter, {op.get_vector_rm_size()}, "
+                  f"symbols)?;", file=types_header)
+            rm = True
+        elif op.is_mask_rm():
+            print(f"    let rm = is_mask_or_m_of_size(&mut iter, {op.get_mask_rm_size()}, symbols)?;",
+                  file=types_header)
+            rm = True
+        elif op.is_vsib():
+            print(f"    let rm = is_vsib_of_size(&mut iter, {instruction.get_vsib_element_size()}, "
+                  f"{op.get_vsib_index_size()}, symbols)?;", file=types_header)
+            rm = True
+        else:
+            raise RuntimeError("Unsupported op type '" + op.raw + "'")
+
+        if op.is_masked():
+            # Stores can be masked, but not zeroed
+            zeroing = "false"
+            if "z" in op.decorations:
+                zeroing = "matches!(rm, Rm::Register(_))" if op.is_vector_rm() else "true"
+            print(f"    let (mask, zeroing) = is_mask(&mut iter, {zeroing})?;", file=types_header)
+        if "er" in op.decorations or "sae" in op.decorations:
+            sae = "true" if "sae" in op.decorations else "false"
+            print(f"    let rounding = is_rounding(&mut iter, &rm, {sae})?;", file=types_header)
+
+    print(
+        f"""    if iter.next().is_some() {{ return Err((iter.count(), ParseError::ExtraneousTokenAfterInstruction)); }}""",
+        file=types_header)
+
+    # Normalize opcode
+    opcode = instruction.opcode \
+        .replace("REX +", "REX") \
+        .replace("REX.W +", "REX.W") \
+        .replace(" +r", "+r") \
+        .replace("/", " /") \
+        .replace("  ", " ")
+
+    fill_reg = None
+
+    parts = opcode.split(" ")
+
+    for index, p in enumerate(parts):
+        # /is4 is an immediate, not part of ModRM
+        if p.startswith("/") and p != "/is4":
+            fill_reg = parts.pop(index)[1:]
+
+    if not reg:
+        if fill_reg and fill_reg != "r":
+            reg = fill_reg
+        else:
+            reg = "0"
+    else:
+        reg = "reg"
+
+    if not rm:
+        rm = "0"
+    else:
+        rm = "rm.0"
+
+    wrote_rel = False
+
+    opcode_array = []
+    imm_count = 1
+
+    imms = []
+
+    for part in parts:
+        if part.startswith("EVEX"):
+            # Like EVEX.512.66.0F38.W0, where LIG and WIG are encoded as 0, like VEX
+            fields = part.split(".")
+            length = {"256": 1, "512": 2}.get(fields[1], 0)
+            prefix = next((f"0x{field}" for field in fields if field in ["66", "F2", "F3"]), "0")
+            escape = {"0F": "0x0F", "0F38": "0x38", "0F3A": "0x3A"}[next(f for f in fields if f.startswith("0F"))]
+            w = "true" if fields[-1] == "W1" else "false"
+            register = "Some(vvvv)" if vvvv is not None else "None"
+            decorations = [d for op in instruction.operands() for d in op.decorations]
+            masked = any(op.is_masked() for op in instruction.operands())
+            rounding = "rounding" if "er" in decorations or "sae" in decorations else "None"
+            # disp8*N, where N is the size of the memory access
+            memory = next((op for op in instruction.operands() if op.get_memory_size()), None)
+            scale = str(memory.get_memory_size() // 8) if memory else "1"
+            if memory and memory.get_broadcast_size():
+                scale = f"if broadcast {{ {memory.get_broadcast_size() // 8} }} else {{ {scale} }}"
+            fields = {"length": length, "prefix": prefix, "map": escape, "wide": w, "register": register,
+                      "mask": "mask" if masked else "None", "zeroing": "zeroing" if masked else "false",
+                      "broadcast": "broadcast" if memory and memory.get_broadcast_size() else "false",
+                      "rounding": rounding, "scale": scale}
+            fields = ", ".join(name if value == name else f"{name}: {value}" for name, value in fields.items())
+            opcode_array.append(f"Opcode::Evex(Evex{{{fields}}})")
+        elif part.startswith("VEX"):
+            # Like VEX.256.66.0F38.W0, where the mandatory prefix is optional and LIG, LZ and WIG are encoded as 0
+            fields = part.split(".")
+            long = "true" if fields[1] in ["256", "L1"] else "false"
+            prefix = next((f"0x{field}" for field in fields if field in ["66", "F2", "F3"]), "0")
+            escape = {"0F": "0x0F", "0F38": "0x38", "0F3A": "0x3A"}[next(f for f in fields if f.startswith("0F"))]
+            w = "true" if fields[-1] == "W1" else "false"
+            register = "Some(vvvv)" if vvvv is not None else "None"
+            opcode_array.append(
+                f"Opcode::Vex{{long: {long}, prefix: {prefix}, map: {escape}, wide: {w}, register: {register}}}")
+        elif part.startswith("REX"):
+            w = "true" if part.endswith(".W") else "false"
+            opcode_array.append(f"Opcode::Rex{{wide: {w}}}")
+        elif part.startswith("i") or part == "/is4":
+            i = imm.pop(0)
+            imms.append(f"Some({i[1]})")
+            imm_count += 1
+        elif part.startswith("c"):
+            imms.append(f"Some(rel)")
+            wrote_rel = True
+
+            imm_count += 1
+        elif "+r" in part:
+            opcode_array.append(f"Opcode::AddRegister(0x{part.split('+')[0]}, reg)")
+        else:
+            opcode_array.append(f"Opcode::Byte(0x{part})")
+
+    instr_opcode = "vec![" + (", ".join(opcode_array)) + "]"
+    instr_reg = "None"
+    instr_rm = "None"
+
+    # Operands without a part in the opcode, like moffs, follow the opcode
+    for i in imm:
+        imms.append(f"Some({i[1]})")
+
+    if not wrote_rel and rel:
+        imms.append(f"Some(rel)")
+
+    if len(imms) > 2:
+        raise "Too many immediates"
+
+    while len(imms) < 2:
+        imms.append("None")
+
+    instr_imms = ", ".join(imms)
+
+    if rm == "rm.0":
+        # Todo: as i32 is a dirty hack
+        instr_reg = f"Some(Register::try_from({reg} as i32).unwrap())"
+        instr_rm = "Some(rm)"
+    elif reg == "reg" and fill_reg:
+        instr_reg = f"Some(Register::try_from({fill_reg}).unwrap())"
+        instr_rm = "Some(Rm::Register(reg))"
+
+    sizes = ""
+    if instruction.get_operand_size() == 16:
+        sizes += ".with_operand_size(Size::Word)"
+    if instruction.get_address_size() == 32:
+        sizes += ".with_address_size(Size::DWord)"
+
+    print(f"""
+    Ok(Instruction::new("{instruction.name}".to_string(), {instr_opcode}, {instr_reg}, {instr_rm}, {instr_imms}){sizes})""",
+          file=types_header)
+
+    print("}", file=types_header)
+
+    return f"matches_{instruction.name}{number}"
 
 
 def main():
@@ -97,6 +1319,15 @@ def main():
         }
 
         sp = re.split(" , | ,|, | |,", instr)
+
+        # Pushes are 64 bits wide by default, so the shorter 16-bit form may only be picked if it's asked for
+        if sp[0] == "PUSH" and sp[1:] == ["imm16"]:
+            sp[0] = "PUSHW"
+
+        # The one-byte inc/dec forms are REX prefixes in 64-bit mode
+        if sp[0] in ["INC", "DEC"] and len(sp) == 2 and sp[1] in ["r16", "r32"]:
+            continue
+
         if len(sp) > 3:
             continue
         elif len(sp) == 3:
@@ -113,177 +1344,64 @@ def main():
 
             instructions.append(InstructionTemplate(opc, sp[0], None, None))
 
+    # movabs isn't in the table, it's the assembler's name for the forms with a full 64-bit immediate or address
+    instructions += [
+        InstructionTemplate("REX.W + B8+rd io", "MOVABS", "r64", "imm64"),
+        InstructionTemplate("A0", "MOVABS", "AL", "moffs8"),
+        InstructionTemplate("A1", "MOVABS", "AX", "moffs16"),
+        InstructionTemplate("A1", "MOVABS", "EAX", "moffs32"),
+        InstructionTemplate("REX.W + A1", "MOVABS", "RAX", "moffs64"),
+        InstructionTemplate("A2", "MOVABS", "moffs8", "AL"),
+        InstructionTemplate("A3", "MOVABS", "moffs16", "AX"),
+        InstructionTemplate("A3", "MOVABS", "moffs32", "EAX"),
+        InstructionTemplate("REX.W + A3", "MOVABS", "moffs64", "RAX"),
+    ]
+
+    instructions += sse_instructions()
+    instructions += avx_instructions()
+    instructions += avx512_instructions()
+
     print("""// aron (c) Nikolas Wipper 2022
 
 /* This Source Code Form is subject to the terms of the Mozilla Public
  * License, v. 2.0. If a copy of the MPL was not distributed with this
  * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
 
-use crate::instructions::{Instruction, Opcode, Mod, Register};
+#![allow(clippy::unnecessary_cast, clippy::type_complexity)]
+
+use crate::instructions::{Evex, Instruction, Opcode, Register, Size};
 use crate::parse::lexer::Token;
 use crate::parse::ParseError;
 use crate::parse::helpers::*;""", file=types_header)
     funcs = []
 
     for instruction in instructions:
-        print(f"""
-fn matches_{instruction.name}{len(funcs) + 1}(tokens: &Vec<Token>) -> Result<Instruction, (usize, ParseError)> {{
-    let mut iter = tokens.iter();
-    
-    if get_next(&mut iter)? != \"{instruction.name}\" {{ return Err((iter.count(), ParseError::InvalidInstruction)); }}""",
-              file=types_header)
-
-        funcs.append(f"matches_{instruction.name}{len(funcs) + 1}")
-
-        rm = False
-        reg = False
-        imm = []
-        rel = None
-
-        for op in [instruction.op1, instruction.op2]:
-            if not op.raw:
-                continue
-
-            if op == instruction.op2:
-                print(
-                    "    if get_next(&mut iter)? != \",\" { return Err((iter.count(), ParseError::InvalidOperand)); }",
-                    file=types_header)
-
-            if op.is_imm():
-                print(f"    let imm{len(imm) + 1} = is_imm_of_size(&mut iter, {op.get_imm_size()})?;",
-                      file=types_header)
-                imm.append((op, f"imm{len(imm) + 1}"))
-            elif op.is_rel():
-                print(f"    let rel = is_rel_of_size(&mut iter, {op.get_rel_size()})?;", file=types_header)
-                rel = op
-            elif op.is_specific_operand():
-                print(
-                    f"    if get_next(&mut iter)? != \"{op.raw}\" {{ return Err((iter.count(), ParseError::InvalidOperand)); }}",
-                    file=types_header)
-            elif op.is_unspecific_reg():
-                print(f"    let reg = is_reg_of_size(&mut iter, {op.get_reg_size()})?;", file=types_header)
-                reg = True
-            elif op.is_unspecific_rm():
-                print(f"    let rm = is_rm_of_size(&mut iter, {op.get_rm_size()})?;", file=types_header)
-                rm = True
-            elif op.is_m():
-                print(f"    let rm = is_m_of_size(&mut iter, 0)?;", file=types_header)
-                rm = True
-            else:
-                raise RuntimeError("Unsupported op type '" + op.raw + "'")
-
-        print(
-            f"""    if iter.next().is_some() {{ return Err((iter.count(), ParseError::ExtraneousTokenAfterInstruction)); }}""",
-            file=types_header)
-
-        # Normalize opcode
-        opcode = instruction.opcode \
-            .replace("REX +", "REX") \
-            .replace("REX.W +", "REX.W") \
-            .replace(" +r", "+r") \
-            .replace("/", " /") \
-            .replace("  ", " ")
-
-        fill_reg = None
-
-        parts = opcode.split(" ")
-
-        for index, p in enumerate(parts):
-            if p.startswith("/"):
-                fill_reg = parts.pop(index)[1:]
-
-        if not reg:
-            if fill_reg and fill_reg != "r":
-                reg = fill_reg
-            else:
-                reg = "0"
-        else:
-            reg = "reg"
-
-        if not rm:
-            rm = "0"
-        else:
-            rm = "rm.0"
-
-        wrote_rel = False
-
-        opcode_array = []
-        imm_count = 1
-
-        imms = []
-
-        for part in parts:
-            if part.startswith("REX"):
-                w = "true" if part.endswith(".W") else "false"
-                opcode_array.append(f"Opcode::Rex{{wide: {w}}}")
-            elif part.startswith("i"):
-                i = imm.pop(0)
-                imms.append(f"Some({i[1]})")
-                imm_count += 1
-            elif part.startswith("c"):
-                imms.append(f"Some(rel)")
-                wrote_rel = True
-
-                imm_count += 1
-            else:
-                part = part.replace("rb", "reg as u8").replace("rw", "reg as u8").replace("rd", "reg as u8")
-
-                opcode_array.append(f"Opcode::Byte(0x{part})")
-
-        instr_mod = "get_mod_from_rm(&rm)" if rm == "rm.0" else "Mod::NoDereference"
-        instr_opcode = "vec![" + (", ".join(opcode_array)) + "]"
-        instr_offset = "rm.2" if rm == "rm.0" else "None"
-        instr_reg = "None"
-        instr_rm = "None"
-
-        if not wrote_rel and rel:
-            imms.append(f"Some(rel)")
-
-        if len(imms) > 2:
-            raise "Too many immediates"
-
-        while len(imms) < 2:
-            imms.append("None")
-
-        instr_imms = ", ".join(imms)
-
-        if rm == "rm.0":
-            # Todo: as i32 is a dirty hack
-            instr_reg = f"Some(Register::try_from({reg} as i32).unwrap())"
-            instr_rm = "Some(rm.0)"
-            instr_offset = "rm.2"
-        elif reg == "reg" and fill_reg:
-            instr_reg = f"Some(Register::try_from({fill_reg}).unwrap())"
-            instr_rm = "Some(reg)"
-            instr_mod = "Mod::NoDereference"
-
-        print(f"""
-    Ok(Instruction::new("{instruction.name}".to_string(), {instr_mod}, {instr_opcode}, {instr_offset}, {instr_reg}, {instr_rm}, {instr_imms}))""",
-              file=types_header)
-
-        print("}", file=types_header)
+        funcs.append(print_instruction(instruction, len(funcs) + 1, types_header))
 
     func_list = ", ".join(funcs)
 
     print(f"""
-const MATCH_FUNCTIONS: [fn(&Vec<Token>) -> Result<Instruction, (usize, ParseError)>; {len(funcs)}] = [{func_list}];
+static MATCH_FUNCTIONS: [fn(&[Token], &SymbolTable) -> Result<Instruction, (usize, ParseError)>; {len(funcs)}] = [{func_list}];
 
-pub fn matches(tokens: &Vec<Token>) -> Result<Instruction, (usize, ParseError)> {{
+pub fn matches(tokens: &[Token], symbols: &SymbolTable) -> Result<Instruction, (usize, ParseError)> {{
     let mut i: Option<Instruction> = None;
-    let mut l: usize = usize::MAX;
+    let mut l: (bool, usize) = (true, usize::MAX);
     let mut err: (usize, ParseError) = (tokens.len() - 1, ParseError::InvalidInstruction);
     
     for func in MATCH_FUNCTIONS {{
-        let instr = func(tokens);
+        let instr = func(tokens, symbols);
         if let Ok(instr) = instr {{
-            let le = instr.encode().get_bytes().len();
+            // Like other assemblers, EVEX is only used if there's no other encoding, even if it would be shorter
+            let le = (instr.is_evex(), instr.encode().get_bytes().len());
             if i.is_none() || le < l {{
                 i = Some(instr);
                 l = le;
             }}
         }} else {{
             let instr_err = instr.unwrap_err();
-            if instr_err.0 < err.0 {{
+            // Prefer specific errors, like malformed numbers, over generic ones at the same token
+            let generic = matches!(err.1, ParseError::InvalidOperand | ParseError::InvalidInstruction);
+            if instr_err.0 < err.0 || (instr_err.0 == err.0 && generic) {{
                 err = instr_err;
             }}
         }}
@@ -4,10 +4,11 @@
  * License, v. 2.0. If a copy of the MPL was not distributed with this
  * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
 
-use crate::instructions::{Mod, Register, Size};
+use crate::instructions::{Register, Rounding, Segment, Size};
+use crate::parse::expression::{parse_expression, parse_term, Value};
 use crate::parse::lexer::Token;
 use crate::parse::ParseError;
-use std::mem::size_of;
+use std::collections::HashMap;
 use std::slice::Iter;
 use std::str::FromStr;
 
@@ -18,30 +19,168 @@ pub enum Relativity {
     RipRelative,
 }
 
-#[derive(Debug)]
+/// Relocation specifiers like the `@PLT` in `call foo@PLT`, which ask the linker for something other than the
+/// symbol's address
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Specifier {
+    Plt,
+    GotPcRel,
+    GotOff,
+    TpOff,
+    GotTpOff,
+    TlsGd,
+    TlsLd,
+    DtpOff,
+    Tlvp,
+}
+
+#[derive(Clone, Debug)]
 pub enum ImmediateType {
-    Integer(i32),
-    Reference(String),
+    Integer(i64),
+    /// A symbol plus an addend
+    Reference(String, i64),
+    /// The distance from the second symbol to the first plus an offset, filled in once the section is laid out
+    Difference(String, String, i64),
 }
 
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub struct Immediate {
     pub rel: Relativity,
     pub typ: ImmediateType,
     pub size: Size,
+    /// Whether the CPU sign-extends the value to a wider operand, which decides between R_X86_64_32 and R_X86_64_32S
+    pub sign_extended: bool,
+    pub specifier: Option<Specifier>,
+}
+
+#[derive(Clone, Debug)]
+pub struct Memory {
+    pub base: Option<Register>,
+    pub index: Option<Register>,
+    pub scale: u8,
+    pub displacement: Option<Immediate>,
+    /// 64 bits, or 32 if the registers are, like in `[eax + ecx*4]`
+    pub address_size: Size,
+    pub segment: Option<Segment>,
+}
+
+/// Constants defined by `.set`, `.equ`, `=` or `.equiv`, which are folded into operands while parsing instead of
+/// becoming relocations
+#[derive(Debug, Default)]
+pub struct SymbolTable {
+    constants: HashMap<String, i64>,
+}
+
+/// A register or memory operand, i.e. whatever goes into the ModRM (and SIB) byte's r/m part
+#[derive(Clone, Debug)]
+pub enum Rm {
+    Register(Register),
+    Memory(Memory),
 }
 
 use ImmediateType::*;
 use Relativity::*;
-use crate::instructions::Register::Rip;
+use crate::instructions::Register::{Rip, Sp};
+
+impl Memory {
+    fn set_index(&mut self, index: Register, scale: u8) -> Result<(), ()> {
+        // rsp can't be encoded as an index, and rip isn't a general purpose register
+        if self.index.is_some() || index == Sp || index == Rip || ![1, 2, 4, 8].contains(&scale) {
+            return Err(());
+        }
+
+        self.index = Some(index);
+        self.scale = scale;
+        Ok(())
+    }
+}
+
+impl Specifier {
+    /// Whether the specifier refers to a thread-local variable
+    pub fn is_tls(self) -> bool {
+        !matches!(self, Specifier::Plt | Specifier::GotPcRel | Specifier::GotOff)
+    }
+}
+
+impl From<Specifier> for &'static str {
+    fn from(specifier: Specifier) -> Self {
+        match specifier {
+            Specifier::Plt => "PLT",
+            Specifier::GotPcRel => "GOTPCREL",
+            Specifier::GotOff => "GOTOFF",
+            Specifier::TpOff => "TPOFF",
+            Specifier::GotTpOff => "GOTTPOFF",
+            Specifier::TlsGd => "TLSGD",
+            Specifier::TlsLd => "TLSLD",
+            Specifier::DtpOff => "DTPOFF",
+            Specifier::Tlvp => "TLVP",
+        }
+    }
+}
+
+impl FromStr for Specifier {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_uppercase().as_str() {
+            "PLT" => Ok(Specifier::Plt),
+            "GOTPCREL" => Ok(Specifier::GotPcRel),
+            "GOTOFF" => Ok(Specifier::GotOff),
+            "TPOFF" => Ok(Specifier::TpOff),
+            "GOTTPOFF" => Ok(Specifier::GotTpOff),
+            "TLSGD" => Ok(Specifier::TlsGd),
+            "TLSLD" => Ok(Specifier::TlsLd),
+            "DTPOFF" => Ok(Specifier::DtpOff),
+            "TLVP" => Ok(Specifier::Tlvp),
+            _ => Err(()),
+        }
+    }
+}
+
+impl SymbolTable {
+    pub fn new() -> Self {
+        SymbolTable { constants: HashMap::new() }
+    }
+
+    pub fn get(&self, name: &str) -> Option<i64> {
+        self.constants.get(name).copied()
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.constants.contains_key(name)
+    }
+
+    pub fn set(&mut self, name: String, value: i64) {
+        self.constants.insert(name, value);
+    }
+}
 
 impl Immediate {
-    pub fn integer(int: i32, rel: Relativity, size: Size) -> Immediate {
-        Immediate { rel, typ: Integer(int), size }
+    pub fn integer(int: i64, rel: Relativity, size: Size) -> Immediate {
+        Immediate { rel, typ: Integer(int), size, sign_extended: false, specifier: None }
     }
 
-    pub fn reference(to: String, rel: Relativity, size: Size) -> Immediate {
-        Immediate { rel, typ: Reference(to), size }
+    pub fn reference(
+        to: String,
+        addend: i64,
+        specifier: Option<Specifier>,
+        rel: Relativity,
+        size: Size,
+    ) -> Immediate {
+        Immediate { rel, typ: Reference(to, addend), size, sign_extended: false, specifier }
+    }
+
+    pub fn difference(end: String, start: String, offset: i64, size: Size) -> Immediate {
+        Immediate { rel: Absolute, typ: Difference(end, start, offset), size, sign_extended: false, specifier: None }
+    }
+
+    /// The symbols the value refers to
+    pub fn symbols(&self) -> Vec<String> {
+        match &self.typ {
+            Integer(_) => Vec::new(),
+            Reference(to, _) => vec![to.clone()],
+            Difference(end, start, _) => vec![end.clone(), start.clone()],
+        }
     }
 }
 
@@ -53,62 +192,175 @@ pub fn get_next<'a>(iter: &'a mut Iter<Token>) -> Result<&'a Token, (usize, Pars
     Ok(next.unwrap())
 }
 
-pub fn get_mod_from_rm(rm: &(Register, Mod, Option<Immediate>)) -> Mod {
-    if let Some(off) = &rm.2 {
-        if off.rel == RipRelative {
-            Mod::NoOffset
-        } else {
-            match off.typ {
-                Integer(i) => {
-                    if i < 128 && i > -128 {
-                        Mod::Offset8Bit
-                    } else {
-                        Mod::Offset32Bit
-                    }
-                }
-                Reference(_) => Mod::Offset32Bit,
-            }
-        }
+/// Parses an integer literal: decimal, `0x`/`0o`/`0b` prefixed, NASM-style `h` suffixed hexadecimal or a character
+/// literal like `'A'`, with optional `_` digit separators. Returns `None` if the token isn't a number at all, i.e. it
+/// should be treated as a symbol.
+pub fn parse_integer(token: &Token) -> Option<Result<i128, ParseError>> {
+    let raw = token.as_str();
+
+    if let Some(literal) = raw.strip_prefix('\'') {
+        return Some(parse_character(literal));
+    }
+
+    if !raw.starts_with(|c: char| c.is_ascii_digit()) {
+        return None;
+    }
+
+    let raw = raw.to_ascii_lowercase();
+    let (digits, radix) = if let Some(digits) = raw.strip_prefix("0x") {
+        (digits, 16)
+    } else if let Some(digits) = raw.strip_suffix('h') {
+        (digits, 16)
+    } else if let Some(digits) = raw.strip_prefix("0b") {
+        (digits, 2)
+    } else if let Some(digits) = raw.strip_prefix("0o") {
+        (digits, 8)
     } else {
-        rm.1
+        (raw.as_str(), 10)
+    };
+
+    let digits = digits.replace('_', "");
+    if digits.is_empty() || !digits.chars().all(|c| c.is_digit(radix)) {
+        return Some(Err(ParseError::InvalidNumber));
     }
+
+    Some(u64::from_str_radix(&digits, radix).map(i128::from).map_err(|_| ParseError::NumberOutOfRange))
 }
 
-pub fn is_imm_of_size(iter: &mut Iter<Token>, size: usize) -> Result<Immediate, (usize, ParseError)> {
+fn parse_character(literal: &str) -> Result<i128, ParseError> {
+    let mut chars = literal.strip_suffix('\'').ok_or(ParseError::InvalidNumber)?.chars();
+
+    let c = match chars.next() {
+        Some('\\') => match chars.next().ok_or(ParseError::InvalidNumber)? {
+            '0' => '\0',
+            'a' => '\x07',
+            'b' => '\x08',
+            't' => '\t',
+            'n' => '\n',
+            'v' => '\x0B',
+            'f' => '\x0C',
+            'r' => '\r',
+            'e' => '\x1B',
+            c @ ('\\' | '\'' | '"' | '?') => c,
+            _ => return Err(ParseError::InvalidNumber),
+        },
+        Some(c) => c,
+        None => return Err(ParseError::InvalidNumber),
+    };
+
+    if chars.next().is_some() {
+        return Err(ParseError::InvalidNumber);
+    }
+
+    Ok(c as i128)
+}
+
+fn get_addend(addend: i128) -> Result<i64, ParseError> {
+    i64::try_from(addend).map_err(|_| ParseError::NumberOutOfRange)
+}
+
+fn parse_imm(
+    iter: &mut Iter<Token>,
+    size: usize,
+    sign_extended: bool,
+    symbols: &SymbolTable,
+) -> Result<Immediate, (usize, ParseError)> {
     if is_reg_of_size(&mut iter.clone(), 0).is_ok() { return Err((iter.count() - 1, ParseError::InvalidOperand)); }
 
-    let next = get_next(iter)?;
-    let (neg, num) = if next == "-" {
-        let next = get_next(iter)?;
+    let value = parse_expression(iter, symbols)?;
 
-        (-1, next.parse::<isize>())
-    } else {
-        let r = next.parse::<isize>();
+    // Symbols aren't known yet, so they can't be checked against the range of a short sign-extended immediate. This
+    // makes sure a wider form is picked instead.
+    if sign_extended && size == 8 && !matches!(value, Value::Constant(_)) {
+        return Err((iter.count(), ParseError::InvalidOperand));
+    }
 
-        if r.is_err() {
-            return Ok(Immediate::reference(next.clone_string(), Absolute, Size::try_from(size).unwrap()));
+    let num = match value {
+        Value::Constant(num) => num,
+        Value::Symbol(symbol, addend, specifier) => {
+            let addend = get_addend(addend).map_err(|e| (iter.clone().count(), e))?;
+            let reference = Immediate::reference(symbol, addend, specifier, Absolute, Size::try_from(size).unwrap());
+            return Ok(Immediate { sign_extended, ..reference });
+        }
+        Value::Difference(end, start, offset) => {
+            let offset = get_addend(offset).map_err(|e| (iter.clone().count(), e))?;
+            return Ok(Immediate::difference(end, start, offset, Size::try_from(size).unwrap()));
         }
-
-        (1, r)
     };
-    let num = num.unwrap();
 
-    if (size_of::<isize>() * 8 - num.leading_zeros() as usize) <= size - 1 {
-        Ok(Immediate::integer(num as i32 * neg, Absolute, Size::try_from(size).unwrap()))
-    } else {
-        Err((iter.count(), ParseError::InvalidOperand))
+    // Immediates that are sign-extended to a wider operand have to be in the signed range, everything else may also be
+    // written as an unsigned number, e.g. `mov al, 0xFF`
+    let bits = size as u32;
+    let min = -(1i128 << (bits - 1));
+    let max = if sign_extended { (1i128 << (bits - 1)) - 1 } else { (1i128 << bits) - 1 };
+
+    if !(min..=max).contains(&num) {
+        return Err((iter.count(), ParseError::NumberOutOfRange));
     }
+
+    // Store the value as a signed number of the immediate's size, so it can be written without further checks
+    let num = match size {
+        8 => num as i8 as i64,
+        16 => num as i16 as i64,
+        32 => num as i32 as i64,
+        _ => num as i64,
+    };
+
+    Ok(Immediate::integer(num, Absolute, Size::try_from(size).unwrap()))
+}
+
+pub fn is_imm_of_size(
+    iter: &mut Iter<Token>,
+    size: usize,
+    symbols: &SymbolTable,
+) -> Result<Immediate, (usize, ParseError)> {
+    parse_imm(iter, size, false, symbols)
+}
+
+/// Like [is_imm_of_size], but for immediates the CPU sign-extends to a wider operand, like in `add rax, imm32`
+pub fn is_signed_imm_of_size(
+    iter: &mut Iter<Token>,
+    size: usize,
+    symbols: &SymbolTable,
+) -> Result<Immediate, (usize, ParseError)> {
+    parse_imm(iter, size, true, symbols)
 }
 
-pub fn is_rel_of_size(iter: &mut Iter<Token>, size: usize) -> Result<Immediate, (usize, ParseError)> {
-    // Todo: rel16/rel8
-    if ![32usize, 64usize].contains(&size) {
+/// A 64-bit absolute address in brackets, as used by `movabs al, [0x1122334455667788]`
+pub fn is_moffs(iter: &mut Iter<Token>, symbols: &SymbolTable) -> Result<Immediate, (usize, ParseError)> {
+    if get_next(iter)? != "[" {
         return Err((iter.count(), ParseError::InvalidOperand));
     }
 
-    let next = get_next(iter)?;
+    let address = is_imm_of_size(iter, 64, symbols)?;
+
+    if get_next(iter)? != "]" {
+        return Err((iter.count(), ParseError::InvalidOperand));
+    }
+
+    Ok(address)
+}
+
+pub fn is_rel_of_size(
+    iter: &mut Iter<Token>,
+    size: usize,
+    symbols: &SymbolTable,
+) -> Result<Immediate, (usize, ParseError)> {
+    // rel16 truncates rip to 16 bits in long mode, so we never want it. rel8 forms are relaxed by the assembler, if
+    // the target turns out to be too far away.
+    if ![8usize, 32usize, 64usize].contains(&size) {
+        return Err((iter.count(), ParseError::InvalidOperand));
+    }
 
-    return Ok(Immediate::reference(next.clone_string(), Relative, Size::try_from(size).unwrap()));
+    // Todo: jumps to absolute addresses
+    match parse_expression(iter, symbols)? {
+        // Branches can go through the PLT, but not load anything from the GOT
+        Value::Symbol(to, addend, specifier @ (None | Some(Specifier::Plt))) => {
+            let addend = get_addend(addend).map_err(|e| (iter.clone().count(), e))?;
+            Ok(Immediate::reference(to, addend, specifier, Relative, Size::try_from(size).unwrap()))
+        }
+        _ => Err((iter.count(), ParseError::InvalidOperand)),
+    }
 }
 
 const REGS_8_BIT: [&str; 20] = [
@@ -129,15 +381,52 @@ const REGS_64_BIT: [&str; 17] = [
     "rax", "rbx", "rcx", "rdx", "rsi", "rdi", "rsp", "rbp", "r8", "r9", "r10", "r11", "r12", "r13", "r14", "r15", "rip",
 ];
 
+pub const REGS_XMM: [&str; 16] = [
+    "xmm0", "xmm1", "xmm2", "xmm3", "xmm4", "xmm5", "xmm6", "xmm7", "xmm8", "xmm9", "xmm10", "xmm11", "xmm12", "xmm13",
+    "xmm14", "xmm15",
+];
+
+pub const REGS_YMM: [&str; 16] = [
+    "ymm0", "ymm1", "ymm2", "ymm3", "ymm4", "ymm5", "ymm6", "ymm7", "ymm8", "ymm9", "ymm10", "ymm11", "ymm12", "ymm13",
+    "ymm14", "ymm15",
+];
+
+/// The registers above 15 can only be used with EVEX
+pub const REGS_XMM_EVEX: [&str; 16] = [
+    "xmm16", "xmm17", "xmm18", "xmm19", "xmm20", "xmm21", "xmm22", "xmm23", "xmm24", "xmm25", "xmm26", "xmm27", "xmm28",
+    "xmm29", "xmm30", "xmm31",
+];
+
+pub const REGS_YMM_EVEX: [&str; 16] = [
+    "ymm16", "ymm17", "ymm18", "ymm19", "ymm20", "ymm21", "ymm22", "ymm23", "ymm24", "ymm25", "ymm26", "ymm27", "ymm28",
+    "ymm29", "ymm30", "ymm31",
+];
+
+pub const REGS_ZMM: [&str; 32] = [
+    "zmm0", "zmm1", "zmm2", "zmm3", "zmm4", "zmm5", "zmm6", "zmm7", "zmm8", "zmm9", "zmm10", "zmm11", "zmm12", "zmm13",
+    "zmm14", "zmm15", "zmm16", "zmm17", "zmm18", "zmm19", "zmm20", "zmm21", "zmm22", "zmm23", "zmm24", "zmm25", "zmm26",
+    "zmm27", "zmm28", "zmm29", "zmm30", "zmm31",
+];
+
+pub const REGS_MASK: [&str; 8] = ["k0", "k1", "k2", "k3", "k4", "k5", "k6", "k7"];
+
+pub fn is_register(name: &str) -> bool {
+    REGS_8_BIT.contains(&name)
+        || REGS_16_BIT.contains(&name)
+        || REGS_32_BIT.contains(&name)
+        || REGS_64_BIT.contains(&name)
+        || REGS_XMM.contains(&name)
+        || REGS_YMM.contains(&name)
+        || REGS_XMM_EVEX.contains(&name)
+        || REGS_YMM_EVEX.contains(&name)
+        || REGS_ZMM.contains(&name)
+        || REGS_MASK.contains(&name)
+}
+
 pub fn is_reg_of_size(iter: &mut Iter<Token>, size: usize) -> Result<Register, (usize, ParseError)> {
     let reg = get_next(iter)?;
     let works = match size {
-        0 => {
-            REGS_8_BIT.contains(&reg.as_str())
-                || REGS_16_BIT.contains(&reg.as_str())
-                || REGS_32_BIT.contains(&reg.as_str())
-                || REGS_64_BIT.contains(&reg.as_str())
-        }
+        0 => is_register(reg.as_str()),
         8 => REGS_8_BIT.contains(&reg.as_str()),
         16 => REGS_16_BIT.contains(&reg.as_str()),
         32 => REGS_32_BIT.contains(&reg.as_str()),
@@ -151,80 +440,353 @@ pub fn is_reg_of_size(iter: &mut Iter<Token>, size: usize) -> Result<Register, (
     }
 }
 
-pub fn is_rm_of_size(
+pub fn is_xmm(iter: &mut Iter<Token>) -> Result<Register, (usize, ParseError)> {
+    let reg = get_next(iter)?;
+    if REGS_XMM.contains(&reg.as_str()) {
+        Ok(Register::from_str(reg.as_str()).unwrap())
+    } else {
+        Err((iter.count(), ParseError::InvalidOperand))
+    }
+}
+
+/// An SSE register or memory operand, like in `addsd xmm0, qword ptr [rax]`
+pub fn is_xmm_or_m_of_size(
     iter: &mut Iter<Token>,
     size: usize,
-) -> Result<(Register, Mod, Option<Immediate>), (usize, ParseError)> {
+    symbols: &SymbolTable,
+) -> Result<Rm, (usize, ParseError)> {
+    if let Ok(reg) = is_xmm(&mut iter.clone()) {
+        iter.next();
+        Ok(Rm::Register(reg))
+    } else {
+        is_m_of_size(iter, size, symbols)
+    }
+}
+
+pub fn is_ymm(iter: &mut Iter<Token>) -> Result<Register, (usize, ParseError)> {
+    let reg = get_next(iter)?;
+    if REGS_YMM.contains(&reg.as_str()) {
+        Ok(Register::from_str(reg.as_str()).unwrap())
+    } else {
+        Err((iter.count(), ParseError::InvalidOperand))
+    }
+}
+
+/// An AVX register or memory operand, like in `vaddps ymm0, ymm1, ymmword ptr [rax]`
+pub fn is_ymm_or_m_of_size(
+    iter: &mut Iter<Token>,
+    size: usize,
+    symbols: &SymbolTable,
+) -> Result<Rm, (usize, ParseError)> {
+    if let Ok(reg) = is_ymm(&mut iter.clone()) {
+        iter.next();
+        Ok(Rm::Register(reg))
+    } else {
+        is_m_of_size(iter, size, symbols)
+    }
+}
+
+/// A vector register of an instruction with an EVEX prefix, which can also use registers 16 to 31
+pub fn is_evex_vector(iter: &mut Iter<Token>, size: usize) -> Result<Register, (usize, ParseError)> {
+    let reg = get_next(iter)?;
+    let works = match size {
+        128 => REGS_XMM.contains(&reg.as_str()) || REGS_XMM_EVEX.contains(&reg.as_str()),
+        256 => REGS_YMM.contains(&reg.as_str()) || REGS_YMM_EVEX.contains(&reg.as_str()),
+        512 => REGS_ZMM.contains(&reg.as_str()),
+        _ => panic!("Invalid size"),
+    };
+    if works {
+        Ok(Register::from_str(reg.as_str()).unwrap())
+    } else {
+        Err((iter.count(), ParseError::InvalidOperand))
+    }
+}
+
+/// A vector register or memory operand of an instruction with an EVEX prefix. If the instruction can broadcast
+/// elements of `broadcast` bits, the memory operand may also be one of them, like in `dword ptr [rax]{1to16}`.
+/// Returns whether it is.
+pub fn is_evex_vector_or_m_of_size(
+    iter: &mut Iter<Token>,
+    size: usize,
+    m_size: usize,
+    broadcast: Option<usize>,
+    symbols: &SymbolTable,
+) -> Result<(Rm, bool), (usize, ParseError)> {
+    if let Ok(reg) = is_evex_vector(&mut iter.clone(), size) {
+        iter.next();
+        return Ok((Rm::Register(reg), false));
+    }
+
+    if let Some(element) = broadcast {
+        let mut attempt = iter.clone();
+        let decoration = format!("{{1to{}}}", m_size / element);
+        if let Ok(rm) = is_m_of_size(&mut attempt, element, symbols) {
+            if attempt.next().is_some_and(|token| *token == decoration) {
+                *iter = attempt;
+                return Ok((rm, true));
+            }
+        }
+    }
+
+    Ok((is_m_of_size(iter, m_size, symbols)?, false))
+}
+
+pub fn is_mask_register(iter: &mut Iter<Token>) -> Result<Register, (usize, ParseError)> {
+    let reg = get_next(iter)?;
+    if REGS_MASK.contains(&reg.as_str()) {
+        Ok(Register::from_str(reg.as_str()).unwrap())
+    } else {
+        Err((iter.count(), ParseError::InvalidOperand))
+    }
+}
+
+/// An opmask register or memory operand, like in `kmovw k1, word ptr [rax]`
+pub fn is_mask_or_m_of_size(
+    iter: &mut Iter<Token>,
+    size: usize,
+    symbols: &SymbolTable,
+) -> Result<Rm, (usize, ParseError)> {
+    if let Ok(reg) = is_mask_register(&mut iter.clone()) {
+        iter.next();
+        Ok(Rm::Register(reg))
+    } else {
+        is_m_of_size(iter, size, symbols)
+    }
+}
+
+/// The optional `{k1}` and `{z}` after the destination. k0 can't be used, as it means no masking.
+pub fn is_mask(iter: &mut Iter<Token>, zeroing: bool) -> Result<(Option<Register>, bool), (usize, ParseError)> {
+    let mut mask = None;
+    if let Some(name) = iter.clone().next().and_then(|token| token.as_str().strip_prefix('{')) {
+        if let Some(reg) = name.strip_suffix('}').filter(|name| REGS_MASK.contains(name)) {
+            iter.next();
+            if reg == "k0" {
+                return Err((iter.count(), ParseError::InvalidDecoration));
+            }
+            mask = Register::from_str(reg).ok();
+        }
+    }
+
+    if iter.clone().next().is_some_and(|token| token == "{z}") {
+        iter.next();
+        if !zeroing || mask.is_none() {
+            return Err((iter.count(), ParseError::InvalidDecoration));
+        }
+        return Ok((mask, true));
+    }
+
+    Ok((mask, false))
+}
+
+/// The optional rounding mode after the last register, like in `vaddps zmm0, zmm1, zmm2, {rn-sae}`. Only operations
+/// on registers can change it, and some only allow `{sae}`.
+pub fn is_rounding(iter: &mut Iter<Token>, rm: &Rm, sae: bool) -> Result<Option<Rounding>, (usize, ParseError)> {
+    let mut attempt = iter.clone();
+    if attempt.clone().next().is_some_and(|token| token == ",") {
+        attempt.next();
+    }
+
+    let rounding = match attempt.next() {
+        Some(token) => token.as_str().strip_prefix('{').and_then(|name| name.strip_suffix('}')),
+        None => None,
+    };
+    let rounding = match rounding.map(Rounding::from_str) {
+        Some(Ok(rounding)) => rounding,
+        _ => return Ok(None),
+    };
+
+    *iter = attempt;
+    if matches!(rm, Rm::Memory(_)) || (sae && rounding != Rounding::Sae) || (!sae && rounding == Rounding::Sae) {
+        return Err((iter.count(), ParseError::InvalidDecoration));
+    }
+    Ok(Some(rounding))
+}
+
+/// The register in the upper four bits of an immediate, like the last operand of `vblendvps`
+pub fn is4(reg: Register) -> Immediate {
+    Immediate::integer(((reg as u8 & 0b1111) << 4) as i8 as i64, Absolute, Size::Byte)
+}
+
+/// A base or index register, which may be 64 or 32 bits wide. With a vector of indices, the index may also be a
+/// vector register of that size, which has no address size.
+fn is_address_register(
+    iter: &mut Iter<Token>,
+    vector: Option<usize>,
+) -> Result<(Register, Option<Size>), (usize, ParseError)> {
+    if let Ok(reg) = is_reg_of_size(&mut iter.clone(), 64) {
+        iter.next();
+        return Ok((reg, Some(Size::QWord)));
+    }
+    let index = match vector {
+        Some(128) => is_xmm(&mut iter.clone()),
+        Some(256) => is_ymm(&mut iter.clone()),
+        _ => Err((0, ParseError::InvalidOperand)),
+    };
+    if let Ok(reg) = index {
+        iter.next();
+        return Ok((reg, None));
+    }
+
+    Ok((is_reg_of_size(iter, 32)?, Some(Size::DWord)))
+}
+
+/// The scale of an index register, which has to be a literal or a constant
+fn get_scale(token: &Token, symbols: &SymbolTable) -> Option<u8> {
+    let scale = match parse_integer(token) {
+        Some(scale) => scale.ok()?,
+        None => symbols.get(token.as_str())? as i128,
+    };
+
+    u8::try_from(scale).ok()
+}
+
+pub fn is_rm_of_size(iter: &mut Iter<Token>, size: usize, symbols: &SymbolTable) -> Result<Rm, (usize, ParseError)> {
     let reg_res = is_reg_of_size(&mut iter.clone(), size);
     if let Ok(reg_res) = reg_res {
         iter.next();
-        Ok((reg_res, Mod::NoDereference, None))
+        Ok(Rm::Register(reg_res))
     } else {
-        is_m_of_size(iter, size)
+        is_m_of_size(iter, size, symbols)
     }
 }
 
-pub fn is_m_of_size(iter: &mut Iter<Token>,
-                    mut size: usize,
-) -> Result<(Register, Mod, Option<Immediate>), (usize, ParseError)> {
-    let next = get_next(iter)?;
-    if next != "[" {
+pub fn is_m_of_size(iter: &mut Iter<Token>, size: usize, symbols: &SymbolTable) -> Result<Rm, (usize, ParseError)> {
+    parse_memory(iter, size, None, symbols)
+}
+
+/// A memory operand with a vector of indices, like the `[rax + xmm1*4]` of a gather. `size` is the size of an element.
+pub fn is_vsib_of_size(
+    iter: &mut Iter<Token>,
+    size: usize,
+    index_size: usize,
+    symbols: &SymbolTable,
+) -> Result<Rm, (usize, ParseError)> {
+    parse_memory(iter, size, Some(index_size), symbols)
+}
+
+/// A memory operand, whose index has to be a vector register of size `vector` if that's given
+fn parse_memory(
+    iter: &mut Iter<Token>,
+    size: usize,
+    vector: Option<usize>,
+    symbols: &SymbolTable,
+) -> Result<Rm, (usize, ParseError)> {
+    let mut next = get_next(iter)?;
+    if ["byte", "word", "dword", "qword", "xmmword", "ymmword", "zmmword"].contains(&next.as_str()) {
         if match size {
             8 => next != "byte",
             16 => next != "word",
             32 => next != "dword",
             64 => next != "qword",
-            0 => !["bytes", "word", "dword", "qword"].contains(&next.as_str()),
+            128 => next != "xmmword",
+            256 => next != "ymmword",
+            512 => next != "zmmword",
+            0 => false,
             _ => panic!("Invalid size"),
         } {
             return Err((iter.count(), ParseError::InvalidOperand));
         }
 
-        if size == 0 {
-            // fixme: hack
-            size = Size::try_from(next.clone_string()).unwrap() as usize;
-        }
-
         if get_next(iter)? != "ptr" {
             return Err((iter.count(), ParseError::InvalidOperand));
         }
-        if get_next(iter)? != "[" {
+        next = get_next(iter)?;
+    }
+
+    // A segment override, like in `fs:[0x28]`
+    let segment = Segment::from_str(next.as_str()).ok();
+    if segment.is_some() {
+        if get_next(iter)? != ":" {
             return Err((iter.count(), ParseError::InvalidOperand));
         }
-    } else {
-        // rip relative addressing
-        if size == 0 { size = 32 };
-    };
-    let reg_res = is_reg_of_size(iter, 0)?;
-
-    let rel = if reg_res == Rip {
-        RipRelative
-    } else {
-        Relative
-    };
-
-    let next = get_next(iter)?;
+        next = get_next(iter)?;
+    }
 
-    let mod_byte: Mod;
-    let mut off: Option<Immediate> = None;
+    if next != "[" {
+        return Err((iter.count(), ParseError::InvalidOperand));
+    }
 
-    if ["+", "-"].contains(&next.as_str()) {
-        let neg = if next.as_str() == "-" { -1 } else { 1 };
-        let off_res = is_imm_of_size(iter, 32)?;
-        off = match off_res.typ {
-            Integer(i) => Some(Immediate::integer(i * neg, rel, Size::try_from(size).unwrap())),
-            Reference(s) => Some(Immediate::reference(s, rel, Size::try_from(size).unwrap())),
-        };
+    let mut memory =
+        Memory { base: None, index: None, scale: 1, displacement: None, address_size: Size::QWord, segment };
+    let mut address_size = None;
+    let mut displacement = Value::Constant(0);
+    let mut negative = false;
+    let mut vector_index = false;
+
+    loop {
+        let mut lookahead = iter.clone();
+        lookahead.next();
+        let scaled_register =
+            lookahead.next().is_some_and(|t| t == "*") && is_address_register(&mut lookahead, vector).is_ok();
+
+        if let Ok((reg, size)) = is_address_register(&mut iter.clone(), vector) {
+            iter.next();
+            // All registers in an address have to be the same size, apart from a vector of indices
+            if negative || size.is_some_and(|size| *address_size.get_or_insert(size) != size) {
+                return Err((iter.count(), ParseError::InvalidOperand));
+            }
+            vector_index |= size.is_none();
+
+            if iter.clone().next().is_some_and(|t| t == "*") {
+                iter.next();
+                let scale =
+                    get_scale(get_next(iter)?, symbols).ok_or((iter.clone().count(), ParseError::InvalidOperand))?;
+                memory.set_index(reg, scale).map_err(|_| (iter.clone().count(), ParseError::InvalidOperand))?;
+            } else if memory.base.is_none() && size.is_some() {
+                memory.base = Some(reg);
+            } else {
+                memory.set_index(reg, 1).map_err(|_| (iter.clone().count(), ParseError::InvalidOperand))?;
+            }
+        } else if scaled_register {
+            let scale = get_scale(get_next(iter)?, symbols);
+            iter.next();
+            let (reg, size) = is_address_register(iter, vector)?;
+            if negative || scale.is_none() || size.is_some_and(|size| *address_size.get_or_insert(size) != size) {
+                return Err((iter.count(), ParseError::InvalidOperand));
+            }
+            vector_index |= size.is_none();
+            memory.set_index(reg, scale.unwrap()).map_err(|_| (iter.clone().count(), ParseError::InvalidOperand))?;
+        } else {
+            let term = parse_term(iter, symbols)?;
+            let op = if negative { "-" } else { "+" };
+            displacement = displacement.apply(op, term).map_err(|e| (iter.clone().count(), e))?;
+        }
 
-        if get_next(iter)? != "]" {
-            return Err((iter.count(), ParseError::InvalidOperand));
+        let next = get_next(iter)?;
+        match next.as_str() {
+            "+" => negative = false,
+            "-" => negative = true,
+            "]" => break,
+            _ => return Err((iter.count(), ParseError::InvalidOperand)),
         }
+    }
 
-        mod_byte = Mod::Offset32Bit;
-    } else if next != "]" {
+    if (memory.base == Some(Rip) && memory.index.is_some()) || vector.is_some() != vector_index {
         return Err((iter.count(), ParseError::InvalidOperand));
-    } else {
-        mod_byte = Mod::NoOffset;
     }
-    return Ok((reg_res, mod_byte, off));
+    memory.address_size = address_size.unwrap_or(Size::QWord);
+
+    let rel = if memory.base == Some(Rip) { RipRelative } else { Absolute };
+
+    memory.displacement = match displacement {
+        Value::Constant(0) if memory.base != Some(Rip) => None,
+        Value::Constant(displacement) => {
+            let displacement =
+                i32::try_from(displacement).map_err(|_| (iter.clone().count(), ParseError::NumberOutOfRange))?;
+            Some(Immediate::integer(displacement as i64, rel, Size::DWord))
+        }
+        Value::Symbol(symbol, addend, specifier) => {
+            let addend = get_addend(addend).map_err(|e| (iter.clone().count(), e))?;
+            // Displacements are always sign-extended to the address size
+            let reference = Immediate::reference(symbol, addend, specifier, rel, Size::DWord);
+            Some(Immediate { sign_extended: true, ..reference })
+        }
+        Value::Difference(end, start, offset) if rel == Absolute => {
+            let offset = get_addend(offset).map_err(|e| (iter.clone().count(), e))?;
+            Some(Immediate::difference(end, start, offset, Size::DWord))
+        }
+        Value::Difference(..) => return Err((iter.count(), ParseError::InvalidOperand)),
+    };
+
+    Ok(Rm::Memory(memory))
 }
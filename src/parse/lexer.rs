@@ -27,7 +27,10 @@ pub struct Token {
     pos: LexPosition
 }
 
-pub(crate) const OPERATORS: &[&str] = &[":", ",", "+", "-", "[", "]"];
+pub(crate) const OPERATORS: &[&str] = &[
+    ":", ",", "+", "-", "*", "/", "%", "[", "]", "(", ")", "=", "~", "!", "&", "|", "^", "<<", ">>", "==", "!=", "<>",
+    "<", ">", "<=", ">=",
+];
 
 pub struct Lexer {
     code: String,
@@ -74,9 +77,9 @@ impl Token {
     }
 }
 
-impl Into<String> for Token {
-    fn into(self) -> String {
-        self.raw
+impl From<Token> for String {
+    fn from(token: Token) -> Self {
+        token.raw
     }
 }
 
@@ -84,48 +87,31 @@ impl PartialEq<String> for Token {
     fn eq(&self, other: &String) -> bool {
         self.raw.eq(other)
     }
-
-    fn ne(&self, other: &String) -> bool {
-        self.raw.ne(other)
-    }
 }
 
 impl PartialEq<str> for Token {
     fn eq(&self, other: &str) -> bool {
         self.raw.eq(other)
     }
-
-    fn ne(&self, other: &str) -> bool {
-        self.raw.ne(other)
-    }
 }
 
 impl PartialEq<&str> for Token {
     fn eq(&self, other: &&str) -> bool {
         self.raw.eq(other)
     }
-
-    fn ne(&self, other: &&str) -> bool {
-        self.raw.ne(other)
-    }
 }
 
 impl Lexer {
     pub fn new(code: String) -> Lexer {
-        let char = code.chars().next();
-        let c = if char.is_none() {
-            Err(ParseError::UnexpectedEOF)
-        } else {
-            Ok(char.unwrap())
-        };
+        let c = code.chars().next().ok_or(ParseError::UnexpectedEOF);
         Lexer { code, c, pos: LexPosition::new() }
     }
 
     fn read_char(&mut self) -> &Result<char, ParseError> {
         self.c = self.peek_char();
-        if self.c.is_ok() {
+        if let Ok(c) = self.c {
             self.pos.pos += 1;
-            if self.c.as_ref().unwrap() == &'\n' {
+            if c == '\n' {
                 self.pos.line += 1;
                 self.pos.char = 0;
             } else {
@@ -137,12 +123,7 @@ impl Lexer {
 
     fn peek_char(&mut self) -> Result<char, ParseError> {
         if self.pos.pos < self.code.len() {
-            let char = self.code[self.pos.pos+1..].chars().next();
-            if char.is_none() {
-                Err(ParseError::UnexpectedEOF)
-            } else {
-                Ok(char.unwrap())
-            }
+            self.code[self.pos.pos+1..].chars().next().ok_or(ParseError::UnexpectedEOF)
         } else {
             Err(ParseError::UnexpectedEOF)
         }
@@ -177,14 +158,42 @@ impl Lexer {
         if self.get_char_or('\0') == '\0' {
             return Err(ParseError::UnexpectedLB);
         } else if self.get_char() == '"' || self.get_char() == '\'' {
+            let quote = self.get_char();
+            // Character literals keep their quotes, so they can be told apart from symbols
+            let keep_quotes = quote == '\'';
+            if keep_quotes {
+                token.push(quote);
+            }
+
+            let mut escaped = false;
+            loop {
+                self.read_char();
+                let char = self.get_char_or('\0');
+                if char == '\0' || char == '\n' {
+                    break;
+                }
+                if char == quote && !escaped {
+                    if keep_quotes {
+                        token.push(quote);
+                    }
+                    self.read_char();
+                    break;
+                }
+                escaped = char == '\\' && !escaped;
+                token.push(char);
+            }
+        } else if self.get_char() == '{' {
+            // Decorations like {k1}, {z} and {rn-sae} are single tokens, even right after an operand
+            token.push('{');
             loop {
                 self.read_char();
-                if self.get_char_or('\0') == '\0' || self.get_char_or('\0') == '\n' {
+                let char = self.get_char_or('\0');
+                if char == '\0' || char == '\n' {
                     break;
                 }
-                token.push(self.get_char_or('\0'));
-                if token.chars().last().unwrap() == '\'' || token.chars().last().unwrap() == '"' {
-                    token.pop();
+                token.push(char);
+                if char == '}' {
+                    self.read_char();
                     break;
                 }
             }
@@ -192,7 +201,7 @@ impl Lexer {
             let mut op = false;
             loop {
                 let char = self.get_char();
-                if STOPPERS.contains(char) {
+                if STOPPERS.contains(char) || (char == '{' && !token.is_empty()) {
                     if token.is_empty() {
                         token = char.to_string();
                         self.read_char();
@@ -5,13 +5,19 @@
  * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
 
 mod encodings;
+mod expression;
 pub mod helpers;
 mod lexer;
 pub mod parser;
+pub mod symbols;
+#[cfg(test)]
 mod tests;
 
 use crate::instructions::Instruction;
+use crate::parse::helpers::Immediate;
 use crate::parse::lexer::Token;
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
 
 #[derive(Debug, Clone)]
 pub enum ParseError {
@@ -22,6 +28,18 @@ pub enum ParseError {
     InvalidDirective,
     ExtraneousTokenBeforeLabel(Token),
     ExtraneousTokenAfterInstruction,
+    InvalidNumber,
+    NumberOutOfRange,
+    SymbolAlreadyDefined,
+    DivisionByZero,
+    UndefinedSymbol,
+    UndefinedGlobal,
+    InvalidPrefix,
+    DuplicatePrefix,
+    HighByteRegister,
+    InvalidDecoration,
+    InvalidEscape,
+    UnsupportedSectionFlag(char),
 }
 
 impl ParseError {
@@ -34,24 +52,50 @@ impl ParseError {
             ParseError::InvalidDirective => 5,
             ParseError::ExtraneousTokenBeforeLabel(_) => 6,
             ParseError::ExtraneousTokenAfterInstruction => 7,
+            ParseError::InvalidNumber => 8,
+            ParseError::NumberOutOfRange => 9,
+            ParseError::SymbolAlreadyDefined => 10,
+            ParseError::DivisionByZero => 11,
+            ParseError::UndefinedSymbol => 12,
+            ParseError::UndefinedGlobal => 13,
+            ParseError::InvalidPrefix => 14,
+            ParseError::DuplicatePrefix => 15,
+            ParseError::HighByteRegister => 16,
+            ParseError::InvalidDecoration => 17,
+            ParseError::InvalidEscape => 18,
+            ParseError::UnsupportedSectionFlag(_) => 19,
         }
     }
 }
 
-impl ToString for ParseError {
-    fn to_string(&self) -> String {
+impl Display for ParseError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         match self {
-            ParseError::UnexpectedEOF => "Unexpected end of file".to_string(),
-            ParseError::UnexpectedLB => "Unexpected end of line".to_string(),
-            ParseError::InvalidInstruction => "Invalid instruction".to_string(),
-            ParseError::InvalidOperand => "Invalid operand".to_string(),
-            ParseError::InvalidDirective => "Invalid directive".to_string(),
-            ParseError::ExtraneousTokenBeforeLabel(_) => "Extraneous token before label".to_string(),
-            ParseError::ExtraneousTokenAfterInstruction => "Extraneous token after instruction".to_string(),
+            ParseError::UnexpectedEOF => f.write_str("Unexpected end of file"),
+            ParseError::UnexpectedLB => f.write_str("Unexpected end of line"),
+            ParseError::InvalidInstruction => f.write_str("Invalid instruction"),
+            ParseError::InvalidOperand => f.write_str("Invalid operand"),
+            ParseError::InvalidDirective => f.write_str("Invalid directive"),
+            ParseError::ExtraneousTokenBeforeLabel(_) => f.write_str("Extraneous token before label"),
+            ParseError::ExtraneousTokenAfterInstruction => f.write_str("Extraneous token after instruction"),
+            ParseError::InvalidNumber => f.write_str("Invalid number"),
+            ParseError::NumberOutOfRange => f.write_str("Number out of range"),
+            ParseError::SymbolAlreadyDefined => f.write_str("Symbol already defined"),
+            ParseError::DivisionByZero => f.write_str("Division by zero"),
+            ParseError::UndefinedSymbol => f.write_str("Undefined symbol"),
+            ParseError::UndefinedGlobal => f.write_str("Global symbol is never defined"),
+            ParseError::InvalidPrefix => f.write_str("Prefix can't be used with this instruction"),
+            ParseError::DuplicatePrefix => f.write_str("Prefix of the same kind used twice"),
+            ParseError::HighByteRegister => f.write_str("ah, ch, dh and bh can't be used with a REX prefix"),
+            ParseError::InvalidDecoration => f.write_str("Decoration can't be used with this operand"),
+            ParseError::InvalidEscape => f.write_str("Invalid escape sequence"),
+            ParseError::UnsupportedSectionFlag(flag) => write!(f, "Section flag '{}' isn't supported", flag),
         }
     }
 }
 
+// Most lines are instructions, so boxing them wouldn't save any memory
+#[allow(clippy::large_enum_variant)]
 #[derive(Debug)]
 pub enum Line {
     Directive(Directive),
@@ -59,15 +103,238 @@ pub enum Line {
     Instruction(Instruction),
 }
 
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub enum Visibility {
+    #[default]
+    Default,
+    Protected,
+    Hidden,
+    Internal,
+}
+
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub enum SymbolType {
+    #[default]
+    NoType,
+    Function,
+    Object,
+    Tls,
+}
+
+/// The operand of `.size`, usually the distance from the symbol to the current location or a label behind it
+#[derive(Debug)]
+pub enum SymbolSize {
+    Constant(u64),
+    /// `end - start`, where an end of `None` stands for the current location, i.e. `.`
+    Difference(Option<String>, String),
+}
+
+/// The flags of an ELF section, as given by a flag string like `"aMS"`
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub struct ElfFlags {
+    pub alloc: bool,
+    pub write: bool,
+    pub exec: bool,
+    pub merge: bool,
+    pub strings: bool,
+    pub tls: bool,
+    pub retain: bool,
+    pub exclude: bool,
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum ElfSectionType {
+    ProgBits,
+    NoBits,
+    Note,
+    InitArray,
+    FiniArray,
+    PreinitArray,
+    Unwind,
+}
+
+/// What a Mach-O section holds, which tells the linker how to treat its contents
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum MachOSectionType {
+    Regular,
+    Zerofill,
+    CStringLiterals,
+    FourByteLiterals,
+    EightByteLiterals,
+    SixteenByteLiterals,
+    LiteralPointers,
+    NonLazySymbolPointers,
+    LazySymbolPointers,
+    ModInitFuncs,
+    ModTermFuncs,
+    Coalesced,
+    Interposing,
+    ThreadLocalRegular,
+    ThreadLocalZerofill,
+    ThreadLocalVariables,
+    ThreadLocalVariablePointers,
+    ThreadLocalInitFunctionPointers,
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum MachOAttribute {
+    PureInstructions,
+    NoToc,
+    StripStaticSyms,
+    NoDeadStrip,
+    LiveSupport,
+    SelfModifyingCode,
+    Debug,
+}
+
+/// Everything `.section` says about a section besides its name. Sections named without any are guessed from their
+/// name.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub enum SectionOptions {
+    #[default]
+    None,
+    /// Like the `"aMS",@progbits,1` in `.section .rodata.str1.1,"aMS",@progbits,1`
+    Elf {
+        flags: ElfFlags,
+        typ: Option<ElfSectionType>,
+        entity_size: Option<u64>,
+        /// The signature of the COMDAT group the `G` flag puts the section in
+        group: Option<String>,
+    },
+    /// Like the `regular,pure_instructions` in `.section __TEXT,__text,regular,pure_instructions`
+    MachO {
+        typ: MachOSectionType,
+        attributes: Vec<MachOAttribute>,
+    },
+}
+
 #[derive(Debug)]
 pub enum Directive {
-    Asciz(String),
+    Align {
+        alignment: usize,
+        fill: Option<u8>,
+        max_skip: Option<usize>,
+    },
+    Ascii(Vec<u8>),
+    Asciz(Vec<u8>),
+    #[allow(dead_code)]
     BuildVersion(BuildVersion),
+    /// A common symbol, whose storage is allocated by the linker and shared with other definitions of the same name
+    Common {
+        name: String,
+        size: u64,
+        alignment: u64,
+    },
+    /// Values of the same size, written one after another, like `.long 1, 2, label`
+    Data(Vec<Immediate>),
+    Fill {
+        repeat: usize,
+        size: usize,
+        value: i64,
+    },
+    /// Declares a symbol that's defined elsewhere, which all undefined symbols are anyway
+    Extern(String),
     Global(String),
-    Section(String),
+    Local(String),
+    /// Mach-O's way of saying global, but hidden
+    PrivateExtern(String),
+    /// Switches to another section, like `.section` or the `.text` shorthand, which may also name a subsection
+    Section {
+        name: String,
+        options: SectionOptions,
+        subsection: usize,
+    },
+    /// Like `Section`, but remembers the current section for `.popsection`
+    PushSection {
+        name: String,
+        options: SectionOptions,
+        subsection: usize,
+    },
+    PopSection,
+    /// Switches back to the section that was current before the last switch
+    Previous,
+    /// Switches to another subsection of the current section
+    Subsection(usize),
+    /// A constant, assigned by `.set`, `.equ`, `.equiv` or `=`
+    Set(String, i64),
+    Size(String, SymbolSize),
+    Type(String, SymbolType),
+    Visibility(String, Visibility),
+    Weak(String),
+    /// Reserves zeroed storage for a symbol in an uninitialized section, like `.lcomm` or `.zerofill`, which may also
+    /// only create the section
+    Zerofill {
+        section: String,
+        name: Option<String>,
+        size: usize,
+        alignment: usize,
+    },
     Unknown,
 }
 
+impl FromStr for ElfSectionType {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "progbits" => Ok(ElfSectionType::ProgBits),
+            "nobits" => Ok(ElfSectionType::NoBits),
+            "note" => Ok(ElfSectionType::Note),
+            "init_array" => Ok(ElfSectionType::InitArray),
+            "fini_array" => Ok(ElfSectionType::FiniArray),
+            "preinit_array" => Ok(ElfSectionType::PreinitArray),
+            "unwind" => Ok(ElfSectionType::Unwind),
+            _ => Err(()),
+        }
+    }
+}
+
+impl FromStr for MachOSectionType {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "regular" => Ok(MachOSectionType::Regular),
+            "zerofill" => Ok(MachOSectionType::Zerofill),
+            "cstring_literals" => Ok(MachOSectionType::CStringLiterals),
+            "4byte_literals" => Ok(MachOSectionType::FourByteLiterals),
+            "8byte_literals" => Ok(MachOSectionType::EightByteLiterals),
+            "16byte_literals" => Ok(MachOSectionType::SixteenByteLiterals),
+            "literal_pointers" => Ok(MachOSectionType::LiteralPointers),
+            "non_lazy_symbol_pointers" => Ok(MachOSectionType::NonLazySymbolPointers),
+            "lazy_symbol_pointers" => Ok(MachOSectionType::LazySymbolPointers),
+            "mod_init_funcs" => Ok(MachOSectionType::ModInitFuncs),
+            "mod_term_funcs" => Ok(MachOSectionType::ModTermFuncs),
+            "coalesced" => Ok(MachOSectionType::Coalesced),
+            "interposing" => Ok(MachOSectionType::Interposing),
+            "thread_local_regular" => Ok(MachOSectionType::ThreadLocalRegular),
+            "thread_local_zerofill" => Ok(MachOSectionType::ThreadLocalZerofill),
+            "thread_local_variables" => Ok(MachOSectionType::ThreadLocalVariables),
+            "thread_local_variable_pointers" => Ok(MachOSectionType::ThreadLocalVariablePointers),
+            "thread_local_init_function_pointers" => Ok(MachOSectionType::ThreadLocalInitFunctionPointers),
+            _ => Err(()),
+        }
+    }
+}
+
+impl FromStr for MachOAttribute {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "pure_instructions" => Ok(MachOAttribute::PureInstructions),
+            "no_toc" => Ok(MachOAttribute::NoToc),
+            "strip_static_syms" => Ok(MachOAttribute::StripStaticSyms),
+            "no_dead_strip" => Ok(MachOAttribute::NoDeadStrip),
+            "live_support" => Ok(MachOAttribute::LiveSupport),
+            "self_modifying_code" => Ok(MachOAttribute::SelfModifyingCode),
+            "debug" => Ok(MachOAttribute::Debug),
+            _ => Err(()),
+        }
+    }
+}
+
+#[allow(dead_code)]
 #[derive(Debug)]
 pub enum BuildVersion {
     MacOS { major: u16, minor: u16 },
@@ -4,27 +4,371 @@
  * License, v. 2.0. If a copy of the MPL was not distributed with this
  * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
 
+use crate::instructions::Prefix;
 use crate::parse::encodings::matches;
-use crate::parse::helpers::get_next;
+use crate::parse::helpers::{get_next, is_imm_of_size, parse_integer, ImmediateType, SymbolTable};
 use crate::parse::lexer::{Lexer, Token};
-use crate::parse::{BuildVersion, Directive, Line, ParseError};
+use crate::parse::symbols::{resolve_symbols, UndefinedSymbols};
+use crate::parse::{
+    BuildVersion, Directive, ElfFlags, Line, ParseError, SectionOptions, SymbolSize, SymbolType, Visibility,
+};
 use ariadne::{Label, Report, ReportKind, Source};
+use std::slice::Iter;
+use std::str::FromStr;
 
-fn sanitize_string(mut string: String) -> String {
-    string = string.replace("\\a", "\x07");
-    string = string.replace("\\b", "\x08");
-    string = string.replace("\\t", "\x09");
-    string = string.replace("\\n", "\x0A");
-    string = string.replace("\\v", "\x0B");
-    string = string.replace("\\f", "\x0C");
-    string = string.replace("\\r", "\x0D");
-    string = string.replace("\\e", "\x1B");
-    string = string.replace("\\?", "\x3F");
+/// Replaces the escape sequences in a string literal. Like in GNU as, octal and hexadecimal escapes only keep the
+/// lowest byte of their value, which is why this returns bytes rather than a string.
+fn sanitize_string(string: &str) -> Result<Vec<u8>, ParseError> {
+    let mut bytes = Vec::with_capacity(string.len());
+    let mut chars = string.chars().peekable();
 
-    string
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            bytes.extend(c.encode_utf8(&mut [0; 4]).as_bytes());
+            continue;
+        }
+
+        let byte = match chars.next().ok_or(ParseError::InvalidEscape)? {
+            'a' => 0x07,
+            'b' => 0x08,
+            't' => 0x09,
+            'n' => 0x0A,
+            'v' => 0x0B,
+            'f' => 0x0C,
+            'r' => 0x0D,
+            'e' => 0x1B,
+            c @ ('\\' | '"' | '\'' | '?') => c as u8,
+            // Up to three octal digits
+            c @ '0'..='7' => {
+                let mut value = c.to_digit(8).unwrap();
+                for _ in 0..2 {
+                    match chars.peek().and_then(|c| c.to_digit(8)) {
+                        Some(digit) => value = value * 8 + digit,
+                        None => break,
+                    }
+                    chars.next();
+                }
+                value as u8
+            }
+            // As many hexadecimal digits as there are
+            'x' | 'X' => {
+                let mut value = None;
+                while let Some(digit) = chars.peek().and_then(|c| c.to_digit(16)) {
+                    value = Some(value.unwrap_or(0u8).wrapping_mul(16).wrapping_add(digit as u8));
+                    chars.next();
+                }
+                value.ok_or(ParseError::InvalidEscape)?
+            }
+            _ => return Err(ParseError::InvalidEscape),
+        };
+        bytes.push(byte);
+    }
+
+    Ok(bytes)
+}
+
+/// Parses a comma separated list, that goes until the end of the line
+fn get_list<T>(
+    iter: &mut Iter<Token>,
+    mut parse: impl FnMut(&mut Iter<Token>) -> Result<T, (usize, ParseError)>,
+) -> Result<Vec<T>, (usize, ParseError)> {
+    let mut values = vec![parse(iter)?];
+
+    while let Some(next) = iter.next() {
+        if next != "," {
+            return Err((iter.count(), ParseError::InvalidDirective));
+        }
+        values.push(parse(iter)?);
+    }
+
+    Ok(values)
+}
+
+fn get_string(iter: &mut Iter<Token>) -> Result<Vec<u8>, (usize, ParseError)> {
+    let string = get_next(iter)?;
+    sanitize_string(string.as_str()).map_err(|e| (iter.count(), e))
+}
+
+fn get_integer(iter: &mut Iter<Token>, symbols: &SymbolTable) -> Result<i64, (usize, ParseError)> {
+    match is_imm_of_size(iter, 64, symbols)?.typ {
+        ImmediateType::Integer(i) => Ok(i),
+        // Symbols aren't known while parsing
+        ImmediateType::Reference(..) | ImmediateType::Difference(..) => {
+            Err((iter.count(), ParseError::InvalidDirective))
+        }
+    }
+}
+
+fn get_count(iter: &mut Iter<Token>, symbols: &SymbolTable) -> Result<usize, (usize, ParseError)> {
+    let count = get_integer(iter, symbols)?;
+    usize::try_from(count).map_err(|_| (iter.count(), ParseError::NumberOutOfRange))
+}
+
+/// Parses the optional operands following the required ones, e.g. the fill value of `.space 16, 0x90`
+fn get_optional<T>(
+    iter: &mut Iter<Token>,
+    parse: impl FnOnce(&mut Iter<Token>) -> Result<T, (usize, ParseError)>,
+) -> Result<Option<T>, (usize, ParseError)> {
+    match iter.next() {
+        None => Ok(None),
+        Some(next) if next == "," => Ok(Some(parse(iter)?)),
+        Some(_) => Err((iter.count(), ParseError::InvalidDirective)),
+    }
+}
+
+fn expect_end(iter: &mut Iter<Token>) -> Result<(), (usize, ParseError)> {
+    if iter.next().is_some() {
+        return Err((iter.count(), ParseError::InvalidDirective));
+    }
+
+    Ok(())
+}
+
+/// Parses a single symbol name, that makes up the rest of the line
+fn get_symbol(iter: &mut Iter<Token>) -> Result<String, (usize, ParseError)> {
+    let symbol = get_next(iter)?.clone_string();
+    expect_end(iter)?;
+
+    Ok(symbol)
+}
+
+fn get_symbol_type(iter: &mut Iter<Token>) -> Result<SymbolType, (usize, ParseError)> {
+    let mut typ = get_next(iter)?.clone();
+    // % is an operator, so %function is lexed as two tokens
+    if typ == "%" {
+        typ = get_next(iter)?.clone();
+    }
+
+    // GNU as accepts @function, %function, "function" and STT_FUNC
+    let name = typ.as_str().trim_start_matches('@').trim_start_matches("STT_").to_ascii_lowercase();
+    match name.as_str() {
+        "function" | "func" => Ok(SymbolType::Function),
+        "object" => Ok(SymbolType::Object),
+        "tls_object" | "tls" => Ok(SymbolType::Tls),
+        "notype" => Ok(SymbolType::NoType),
+        _ => Err((iter.count(), ParseError::InvalidDirective)),
+    }
+}
+
+fn get_symbol_size(iter: &mut Iter<Token>, symbols: &SymbolTable) -> Result<SymbolSize, (usize, ParseError)> {
+    let mut constant = iter.clone();
+    if let Ok(size) = get_count(&mut constant, symbols) {
+        *iter = constant;
+        return Ok(SymbolSize::Constant(size as u64));
+    }
+
+    let end = get_next(iter)?;
+    let end = if end == "." { None } else { Some(end.clone_string()) };
+
+    if get_next(iter)? != "-" {
+        return Err((iter.count(), ParseError::InvalidDirective));
+    }
+
+    Ok(SymbolSize::Difference(end, get_next(iter)?.clone_string()))
+}
+
+/// Parses a byte for padding, which may be written signed or unsigned
+fn get_byte(iter: &mut Iter<Token>, symbols: &SymbolTable) -> Result<u8, (usize, ParseError)> {
+    let byte = get_integer(iter, symbols)?;
+    if !(i8::MIN as i64..=u8::MAX as i64).contains(&byte) {
+        return Err((iter.count(), ParseError::NumberOutOfRange));
+    }
+
+    Ok(byte as u8)
+}
+
+fn expect_comma(iter: &mut Iter<Token>) -> Result<(), (usize, ParseError)> {
+    if get_next(iter)? != "," {
+        return Err((iter.count(), ParseError::InvalidDirective));
+    }
+
+    Ok(())
+}
+
+/// Parses the `size[, alignment]` of `.comm` and `.lcomm`. Like for `.align`, the alignment is in bytes. Without one,
+/// GNU as aligns to the largest power of two that's no larger than the symbol, up to 16 bytes.
+fn get_common(iter: &mut Iter<Token>, symbols: &SymbolTable) -> Result<(usize, usize), (usize, ParseError)> {
+    let size = get_count(iter, symbols)?;
+    let default = if size == 0 { 1 } else { 1 << size.ilog2().min(4) };
+    let alignment = get_optional(iter, |iter| get_count(iter, symbols))?.unwrap_or(default);
+
+    if !alignment.is_power_of_two() {
+        return Err((iter.count(), ParseError::InvalidDirective));
+    }
+    expect_end(iter)?;
+
+    Ok((size, alignment))
+}
+
+/// Parses a section name, up to the next comma. Names like `.note.GNU-stack` are split up by the lexer, so this glues
+/// them back together.
+fn get_section_name(iter: &mut Iter<Token>) -> Result<String, (usize, ParseError)> {
+    let mut name = get_next(iter)?.clone_string();
+    if name == "," {
+        return Err((iter.count(), ParseError::InvalidDirective));
+    }
+
+    while let Some(next) = iter.clone().next().filter(|next| *next != ",") {
+        name.push_str(next.as_str());
+        iter.next();
+    }
+
+    Ok(name)
+}
+
+/// The characters GNU as accepts in ELF section flag strings
+const ELF_FLAGS: &str = "awxMSGTRoe?";
+
+/// Parses the `"flags"[, @type[, entity size][, group, comdat]]` of an ELF `.section`
+fn get_elf_section_options(
+    iter: &mut Iter<Token>,
+    symbols: &SymbolTable,
+) -> Result<SectionOptions, (usize, ParseError)> {
+    let mut flags = ElfFlags::default();
+    let mut grouped = false;
+    for c in get_next(iter)?.as_str().chars() {
+        match c {
+            'a' => flags.alloc = true,
+            'w' => flags.write = true,
+            'x' => flags.exec = true,
+            'M' => flags.merge = true,
+            'S' => flags.strings = true,
+            'G' => grouped = true,
+            'T' => flags.tls = true,
+            'R' => flags.retain = true,
+            'e' => flags.exclude = true,
+            // Linked sections and groups inherited from the previous section
+            'o' | '?' => return Err((iter.count(), ParseError::UnsupportedSectionFlag(c))),
+            _ => return Err((iter.count(), ParseError::InvalidDirective)),
+        }
+    }
+
+    let typ = get_optional(iter, |iter| {
+        let mut typ = get_next(iter)?.clone();
+        // Like in .type, % is an operator, so %progbits is lexed as two tokens
+        if typ == "%" {
+            typ = get_next(iter)?.clone();
+        }
+
+        typ.as_str().trim_start_matches('@').parse().map_err(|_| (iter.count(), ParseError::InvalidDirective))
+    })?;
+
+    // Mergeable sections are made up of entities of the same size, which has to be given
+    let entity_size = if flags.merge || !grouped { get_optional(iter, |iter| get_count(iter, symbols))? } else { None };
+    if flags.merge && entity_size.is_none() {
+        return Err((iter.count(), ParseError::InvalidDirective));
+    }
+
+    let group = if grouped {
+        expect_comma(iter)?;
+        let signature = get_next(iter)?.clone_string();
+
+        // Only COMDAT groups can be written, plain section groups can't
+        match get_optional(iter, |iter| Ok(get_next(iter)?.clone()))? {
+            Some(linkage) if linkage == "comdat" => Some(signature),
+            Some(_) => return Err((iter.count(), ParseError::InvalidDirective)),
+            None => return Err((iter.count(), ParseError::UnsupportedSectionFlag('G'))),
+        }
+    } else {
+        None
+    };
+    expect_end(iter)?;
+
+    Ok(SectionOptions::Elf { flags, typ, entity_size: entity_size.map(|size| size as u64), group })
+}
+
+/// Parses the `type[, attribute+attribute...]` of a Mach-O `.section`
+fn get_macho_section_options(iter: &mut Iter<Token>) -> Result<SectionOptions, (usize, ParseError)> {
+    let typ = get_next(iter)?.parse().map_err(|_| (iter.clone().count(), ParseError::InvalidDirective))?;
+
+    let mut attributes = Vec::new();
+    if iter.clone().next().is_some() {
+        expect_comma(iter)?;
+
+        loop {
+            let attribute =
+                get_next(iter)?.parse().map_err(|_| (iter.clone().count(), ParseError::InvalidDirective))?;
+            attributes.push(attribute);
+
+            match iter.clone().next() {
+                Some(next) if next == "+" => iter.next(),
+                _ => break,
+            };
+        }
+    }
+
+    Ok(SectionOptions::MachO { typ, attributes })
 }
 
-fn parse_directive(tokens: &Vec<Token>) -> Result<Line, (usize, ParseError)> {
+/// Parses the `name[, options]` of `.section`. `.pushsection` may also give a subsection number right behind the
+/// name, which is then returned instead of 0.
+fn get_section(
+    iter: &mut Iter<Token>,
+    symbols: &SymbolTable,
+    subsection: bool,
+) -> Result<(String, SectionOptions, usize), (usize, ParseError)> {
+    let name = get_section_name(iter)?;
+    if iter.clone().next().is_none() {
+        return Ok((name, SectionOptions::None, 0));
+    }
+    expect_comma(iter)?;
+
+    let mut number = 0;
+    if subsection && iter.clone().next().is_some_and(|next| next.as_str().starts_with(|c: char| c.is_ascii_digit())) {
+        number = get_count(iter, symbols)?;
+        if iter.clone().next().is_none() {
+            return Ok((name, SectionOptions::None, number));
+        }
+        expect_comma(iter)?;
+    }
+
+    // Flag strings lose their quotes in the lexer, but they're never valid Mach-O section names either
+    let next = iter.clone().next();
+    if next.is_some_and(|next| next.as_str().chars().all(|c| ELF_FLAGS.contains(c))) {
+        let options = get_elf_section_options(iter, symbols)?;
+        Ok((name, options, number))
+    } else {
+        let name = format!("{},{}", name, get_section_name(iter)?);
+        let options = get_optional(iter, get_macho_section_options)?.unwrap_or_default();
+        expect_end(iter)?;
+
+        Ok((name, options, number))
+    }
+}
+
+/// Parses the `NAME, value` following `.set` and its siblings, or a `NAME = value` line, and defines the constant
+fn parse_assignment(
+    tokens: &[Token],
+    symbols: &mut SymbolTable,
+    redefinable: bool,
+) -> Result<Line, (usize, ParseError)> {
+    let mut iter = tokens.iter();
+
+    let name = get_next(&mut iter)?;
+    let is_number = parse_integer(name).is_some();
+    let name = name.clone_string();
+
+    if is_number {
+        return Err((iter.count(), ParseError::InvalidDirective));
+    }
+    if !redefinable && symbols.contains(&name) {
+        return Err((iter.count(), ParseError::SymbolAlreadyDefined));
+    }
+
+    let separator = get_next(&mut iter)?;
+    if separator != "," && separator != "=" {
+        return Err((iter.count(), ParseError::InvalidDirective));
+    }
+
+    let value = get_integer(&mut iter, symbols)?;
+    expect_end(&mut iter)?;
+
+    symbols.set(name.clone(), value);
+
+    Ok(Line::Directive(Directive::Set(name, value)))
+}
+
+fn parse_directive(tokens: &[Token], symbols: &mut SymbolTable) -> Result<Line, (usize, ParseError)> {
     if tokens.is_empty() {
         Err((0, ParseError::UnexpectedLB))
     } else {
@@ -35,10 +379,74 @@ fn parse_directive(tokens: &Vec<Token>) -> Result<Line, (usize, ParseError)> {
         }
 
         match first.unwrap().as_str() {
-            ".asciz" => {
-                let string = get_next(&mut iter)?.clone_string();
+            ".ascii" => {
+                let strings = get_list(&mut iter, get_string)?;
+
+                Ok(Line::Directive(Directive::Ascii(strings.concat())))
+            }
+            ".asciz" | ".string" => {
+                // The last terminator is added when the string is written
+                let strings = get_list(&mut iter, get_string)?;
+
+                Ok(Line::Directive(Directive::Asciz(strings.join(&0))))
+            }
+            ".byte" | ".short" | ".word" | ".2byte" | ".long" | ".int" | ".4byte" | ".quad" | ".8byte" => {
+                let size = match first.unwrap().as_str() {
+                    ".byte" => 8,
+                    ".short" | ".word" | ".2byte" => 16,
+                    ".long" | ".int" | ".4byte" => 32,
+                    _ => 64,
+                };
 
-                Ok(Line::Directive(Directive::Asciz(sanitize_string(string))))
+                Ok(Line::Directive(Directive::Data(get_list(&mut iter, |iter| is_imm_of_size(iter, size, symbols))?)))
+            }
+            // Like GNU as on x86, .align takes the alignment in bytes, not as a power of two
+            ".align" | ".balign" | ".p2align" => {
+                let alignment = get_count(&mut iter, symbols)?;
+                let alignment = if first.unwrap() == ".p2align" {
+                    if alignment >= 32 {
+                        return Err((iter.count(), ParseError::NumberOutOfRange));
+                    }
+                    1 << alignment
+                } else {
+                    alignment
+                };
+
+                if !alignment.is_power_of_two() {
+                    return Err((iter.count(), ParseError::InvalidDirective));
+                }
+
+                // The fill value may be left out, to only give a maximum, e.g. `.p2align 4,,10`
+                let fill = get_optional(&mut iter, |iter| match iter.clone().next() {
+                    Some(next) if next == "," => Ok(None),
+                    _ => Ok(Some(get_byte(iter, symbols)?)),
+                })?
+                .flatten();
+                let max_skip = get_optional(&mut iter, |iter| get_count(iter, symbols))?;
+
+                expect_end(&mut iter)?;
+
+                Ok(Line::Directive(Directive::Align { alignment, fill, max_skip }))
+            }
+            ".zero" | ".space" | ".skip" => {
+                let repeat = get_count(&mut iter, symbols)?;
+                let value = get_optional(&mut iter, |iter| get_integer(iter, symbols))?.unwrap_or(0);
+
+                expect_end(&mut iter)?;
+
+                Ok(Line::Directive(Directive::Fill { repeat, size: 1, value }))
+            }
+            ".fill" => {
+                let repeat = get_count(&mut iter, symbols)?;
+                let size = get_optional(&mut iter, |iter| get_count(iter, symbols))?.unwrap_or(1);
+                let value = get_optional(&mut iter, |iter| get_integer(iter, symbols))?.unwrap_or(0);
+
+                if size > 8 {
+                    return Err((iter.count(), ParseError::NumberOutOfRange));
+                }
+                expect_end(&mut iter)?;
+
+                Ok(Line::Directive(Directive::Fill { repeat, size, value }))
             }
             ".build_version" => {
                 let os = iter.next();
@@ -73,22 +481,106 @@ fn parse_directive(tokens: &Vec<Token>) -> Result<Line, (usize, ParseError)> {
                     Err((iter.count(), ParseError::UnexpectedLB))
                 }
             }
-            ".globl" => Ok(Line::Directive(Directive::Global(get_next(&mut iter)?.clone_string()))),
-            ".section" => {
+            ".comm" | ".lcomm" => {
+                let name = get_next(&mut iter)?.clone_string();
+                expect_comma(&mut iter)?;
+                let (size, alignment) = get_common(&mut iter, symbols)?;
+
+                let directive = if first.unwrap() == ".comm" {
+                    Directive::Common { name, size: size as u64, alignment: alignment as u64 }
+                } else {
+                    Directive::Zerofill { section: ".bss".to_string(), name: Some(name), size, alignment }
+                };
+
+                Ok(Line::Directive(directive))
+            }
+            // .zerofill segment, section[, symbol, size[, alignment]] with the alignment as a power of two
+            ".zerofill" => {
                 let segment = get_next(&mut iter)?.clone_string();
+                expect_comma(&mut iter)?;
+                let section = format!("{},{}", segment, get_next(&mut iter)?.as_str());
 
-                let next = iter.next();
-                if let Some(next) = next {
-                    if next != "," {
-                        return Err((iter.count(), ParseError::InvalidDirective));
+                let symbol = get_optional(&mut iter, |iter| {
+                    let name = get_next(iter)?.clone_string();
+                    expect_comma(iter)?;
+                    let size = get_count(iter, symbols)?;
+                    let alignment = get_optional(iter, |iter| get_count(iter, symbols))?.unwrap_or(0);
+
+                    if alignment >= 32 {
+                        return Err((iter.count(), ParseError::NumberOutOfRange));
                     }
 
-                    let section = get_next(&mut iter)?.clone_string();
+                    Ok((name, size, 1 << alignment))
+                })?;
+                expect_end(&mut iter)?;
 
-                    Ok(Line::Directive(Directive::Section(format!("{},{}", segment, section))))
-                } else {
-                    Ok(Line::Directive(Directive::Section(segment)))
+                let directive = match symbol {
+                    Some((name, size, alignment)) => Directive::Zerofill { section, name: Some(name), size, alignment },
+                    None => Directive::Zerofill { section, name: None, size: 0, alignment: 1 },
+                };
+
+                Ok(Line::Directive(directive))
+            }
+            ".text" | ".data" | ".bss" | ".rodata" => {
+                let name = first.unwrap().clone_string();
+                let subsection = if iter.clone().next().is_some() { get_count(&mut iter, symbols)? } else { 0 };
+                expect_end(&mut iter)?;
+
+                Ok(Line::Directive(Directive::Section { name, options: SectionOptions::None, subsection }))
+            }
+            ".extern" => Ok(Line::Directive(Directive::Extern(get_symbol(&mut iter)?))),
+            ".globl" | ".global" => Ok(Line::Directive(Directive::Global(get_symbol(&mut iter)?))),
+            ".local" => Ok(Line::Directive(Directive::Local(get_symbol(&mut iter)?))),
+            ".private_extern" => Ok(Line::Directive(Directive::PrivateExtern(get_symbol(&mut iter)?))),
+            // Mach-O's .weak_definition is the same as a weak definition on ELF
+            ".weak" | ".weak_definition" => Ok(Line::Directive(Directive::Weak(get_symbol(&mut iter)?))),
+            ".hidden" | ".protected" | ".internal" => {
+                let visibility = match first.unwrap().as_str() {
+                    ".hidden" => Visibility::Hidden,
+                    ".protected" => Visibility::Protected,
+                    _ => Visibility::Internal,
+                };
+
+                Ok(Line::Directive(Directive::Visibility(get_symbol(&mut iter)?, visibility)))
+            }
+            ".type" | ".size" => {
+                let symbol = get_next(&mut iter)?.clone_string();
+
+                if get_next(&mut iter)? != "," {
+                    return Err((iter.count(), ParseError::InvalidDirective));
                 }
+
+                let directive = if first.unwrap() == ".type" {
+                    Directive::Type(symbol, get_symbol_type(&mut iter)?)
+                } else {
+                    Directive::Size(symbol, get_symbol_size(&mut iter, symbols)?)
+                };
+                expect_end(&mut iter)?;
+
+                Ok(Line::Directive(directive))
+            }
+            // .equiv refuses to redefine a constant, the others just assign a new value
+            ".set" | ".equ" | ".equiv" => parse_assignment(&tokens[1..], symbols, first.unwrap() != ".equiv"),
+            ".section" => {
+                let (name, options, subsection) = get_section(&mut iter, symbols, false)?;
+                Ok(Line::Directive(Directive::Section { name, options, subsection }))
+            }
+            ".pushsection" => {
+                let (name, options, subsection) = get_section(&mut iter, symbols, true)?;
+                Ok(Line::Directive(Directive::PushSection { name, options, subsection }))
+            }
+            ".popsection" | ".previous" => {
+                expect_end(&mut iter)?;
+
+                let directive =
+                    if first.unwrap() == ".popsection" { Directive::PopSection } else { Directive::Previous };
+                Ok(Line::Directive(directive))
+            }
+            ".subsection" => {
+                let subsection = get_count(&mut iter, symbols)?;
+                expect_end(&mut iter)?;
+
+                Ok(Line::Directive(Directive::Subsection(subsection)))
             }
             // Todo: parse other important directives like section and alignment indicators
             _ => Ok(Line::Directive(Directive::Unknown)),
@@ -96,38 +588,62 @@ fn parse_directive(tokens: &Vec<Token>) -> Result<Line, (usize, ParseError)> {
     }
 }
 
-fn parse_label(tokens: &Vec<Token>) -> Result<Line, (usize, ParseError)> {
-    if tokens.len() == 1 {
-        Err((0, ParseError::UnexpectedLB))
-    } else {
-        let label = tokens.get(tokens.len() - 2).unwrap().clone();
-        if tokens.len() > 2 {
-            Err((tokens.len() - 1, ParseError::ExtraneousTokenBeforeLabel(label)))
-        } else {
-            Ok(Line::Label(tokens.first().unwrap().clone_string()))
-        }
+fn parse_label(tokens: &[Token]) -> Result<Line, (usize, ParseError)> {
+    match tokens {
+        [label, _] => Ok(Line::Label(label.clone_string())),
+        [.., label, _] => Err((tokens.len() - 1, ParseError::ExtraneousTokenBeforeLabel(label.clone()))),
+        _ => Err((0, ParseError::UnexpectedLB)),
     }
 }
 
-fn parse_instruction(tokens: &Vec<Token>) -> Result<Line, (usize, ParseError)> {
-    // todo: instruction prefix (rep, lock)
-    Ok(Line::Instruction(matches(tokens)?))
+fn parse_instruction(tokens: &[Token], symbols: &SymbolTable) -> Result<Line, (usize, ParseError)> {
+    let (_, prefixable) = tokens.split_last().ok_or((0, ParseError::UnexpectedEOF))?;
+
+    // Some prefixes, like lock, can also stand on their own, so the last token is always part of the instruction
+    let prefixes: Vec<Prefix> = prefixable.iter().map_while(|token| Prefix::from_str(token.as_str()).ok()).collect();
+
+    let mnemonic = prefixes.len();
+    let instruction = matches(&tokens[mnemonic..], symbols)?;
+    let instruction = instruction.with_prefixes(prefixes).map_err(|(i, error)| (tokens.len() - i - 1, error))?;
+    instruction.check_registers().map_err(|error| {
+        // Point at the mnemonic, should the register be spelled in a way that isn't found
+        let i = tokens.iter().position(|token| ["ah", "ch", "dh", "bh"].contains(&token.as_str())).unwrap_or(mnemonic);
+        (tokens.len() - i - 1, error)
+    })?;
+
+    Ok(Line::Instruction(instruction))
 }
 
-fn parse_line(tokens: &Vec<Token>) -> Result<Line, (usize, ParseError)> {
-    if tokens.first().unwrap().as_str().starts_with('.') {
-        parse_directive(tokens)
-    } else if tokens.last().unwrap() == ":" {
+pub fn parse_line(tokens: &[Token], symbols: &mut SymbolTable) -> Result<Line, (usize, ParseError)> {
+    let (Some(first), Some(last)) = (tokens.first(), tokens.last()) else {
+        return Err((0, ParseError::UnexpectedEOF));
+    };
+
+    // Check for labels first, so ELF local labels (.L...) aren't mistaken for directives
+    if last == ":" {
         parse_label(tokens)
+    } else if tokens.get(1).is_some_and(|t| t == "=") {
+        parse_assignment(tokens, symbols, true)
+    } else if first.as_str().starts_with('.') {
+        parse_directive(tokens, symbols)
     } else {
-        parse_instruction(tokens)
+        parse_instruction(tokens, symbols)
     }
 }
 
+/// Parses a whole file, leaving undefined symbols to the linker
+#[cfg(test)]
 pub fn parse_lines(file_name: String, code: String) -> Result<Vec<Line>, ()> {
+    parse_file(file_name, code, UndefinedSymbols::default())
+}
+
+/// Parses a whole file and checks the symbols in it, reporting all errors along the way
+pub fn parse_file(file_name: String, code: String, undefined: UndefinedSymbols) -> Result<Vec<Line>, ()> {
     let mut lexer = Lexer::new(code.clone());
 
     let mut vec = Vec::new();
+    let mut line_tokens = Vec::new();
+    let mut symbols = SymbolTable::new();
     let mut is_ok = true;
 
     'outer_parser: loop {
@@ -148,10 +664,11 @@ pub fn parse_lines(file_name: String, code: String) -> Result<Vec<Line>, ()> {
         }
 
         if !tokens.is_empty() {
-            let res = parse_line(&tokens);
+            let res = parse_line(&tokens, &mut symbols);
 
             if let Ok(res) = res {
                 vec.push(res);
+                line_tokens.push(tokens);
             } else if let Err((i, e)) = res {
                 let tok = tokens.remove(tokens.len() - i - 1);
 
@@ -163,14 +680,11 @@ pub fn parse_lines(file_name: String, code: String) -> Result<Vec<Line>, ()> {
                             .with_message(format!("'{}' here", tok.as_str())),
                     );
 
-                match e {
-                    ParseError::ExtraneousTokenBeforeLabel(label) => {
-                        builder = builder.with_label(
-                            Label::new((file_name.clone(), label.get_range()))
-                                .with_message(format!("To label '{}' here", label.as_str())),
-                        );
-                    }
-                    _ => {}
+                if let ParseError::ExtraneousTokenBeforeLabel(label) = e {
+                    builder = builder.with_label(
+                        Label::new((file_name.clone(), label.get_range()))
+                            .with_message(format!("To label '{}' here", label.as_str())),
+                    );
                 }
 
                 builder.finish().eprint((file_name.clone(), Source::from(code.clone()))).unwrap();
@@ -180,7 +694,8 @@ pub fn parse_lines(file_name: String, code: String) -> Result<Vec<Line>, ()> {
         }
     }
 
-    if is_ok {
+    // Symbols can be used before they're defined, so they can only be checked once everything is parsed
+    if is_ok && resolve_symbols(&file_name, &code, &vec, &line_tokens, undefined) {
         Ok(vec)
     } else {
         Err(())
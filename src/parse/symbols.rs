@@ -0,0 +1,175 @@
+// aron (c) Nikolas Wipper 2022
+
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+
+use crate::parse::helpers::Immediate;
+use crate::parse::lexer::Token;
+use crate::parse::{Directive, Line, ParseError};
+use ariadne::{Label, Report, ReportKind, Source};
+use indexmap::IndexMap;
+use std::collections::HashSet;
+use std::str::FromStr;
+
+/// What to do about references to symbols that aren't defined in the file. They're left to the linker by default,
+/// like all assemblers do, but are more often than not typos.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub enum UndefinedSymbols {
+    #[default]
+    Allow,
+    Warn,
+    Error,
+}
+
+impl From<UndefinedSymbols> for &'static str {
+    fn from(undefined: UndefinedSymbols) -> Self {
+        match undefined {
+            UndefinedSymbols::Allow => "allow",
+            UndefinedSymbols::Warn => "warn",
+            UndefinedSymbols::Error => "error",
+        }
+    }
+}
+
+impl FromStr for UndefinedSymbols {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "allow" => Ok(UndefinedSymbols::Allow),
+            "warn" => Ok(UndefinedSymbols::Warn),
+            "error" => Ok(UndefinedSymbols::Error),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Prints the diagnostics of one file, remembering whether any of them were errors
+struct Diagnostics<'a> {
+    file_name: &'a str,
+    code: &'a str,
+    is_ok: bool,
+}
+
+impl<'a> Diagnostics<'a> {
+    fn report(&mut self, kind: ReportKind, error: ParseError, labels: Vec<(&Token, String)>) {
+        let file_name = self.file_name.to_string();
+        let mut builder = Report::build(kind, file_name.clone(), labels[0].0.get_pos().pos)
+            .with_code(error.to_code())
+            .with_message(error.to_string());
+
+        for (token, message) in labels {
+            builder = builder.with_label(Label::new((file_name.clone(), token.get_range())).with_message(message));
+        }
+
+        builder.finish().eprint((file_name, Source::from(self.code))).unwrap();
+
+        if kind == ReportKind::Error {
+            self.is_ok = false;
+        }
+    }
+}
+
+/// The token naming `name` in a line, which might also carry a specifier like `@PLT`
+fn find_token<'a>(tokens: &'a [Token], name: &str) -> &'a Token {
+    tokens
+        .iter()
+        .find(|token| token.as_str() == name || token.as_str().strip_prefix(name).is_some_and(|s| s.starts_with('@')))
+        .unwrap_or(&tokens[0])
+}
+
+/// The symbols a line refers to, as opposed to the ones it defines or declares
+fn references(line: &Line) -> Vec<String> {
+    match line {
+        Line::Instruction(instruction) => instruction.symbols(),
+        Line::Directive(Directive::Data(values)) => values.iter().flat_map(Immediate::symbols).collect(),
+        _ => Vec::new(),
+    }
+}
+
+/// Checks the symbols of a parsed file. Symbols defined twice and `.globl` symbols that are never defined are
+/// reported, as are references to undefined symbols, if asked to. Returns whether there were any errors.
+pub fn resolve_symbols(
+    file_name: &str,
+    code: &str,
+    lines: &[Line],
+    tokens: &[Vec<Token>],
+    undefined: UndefinedSymbols,
+) -> bool {
+    let mut diagnostics = Diagnostics { file_name, code, is_ok: true };
+
+    let mut definitions: IndexMap<&str, &Token> = IndexMap::new();
+    // Common symbols and constants may be mentioned more than once, so they aren't checked for duplicates
+    let mut defined = HashSet::new();
+    let mut externs = HashSet::new();
+    let mut globals = Vec::new();
+    let mut referenced = Vec::new();
+
+    for (line, tokens) in lines.iter().zip(tokens) {
+        let definition = match line {
+            Line::Label(name) => Some(name),
+            Line::Directive(Directive::Zerofill { name: Some(name), .. }) => Some(name),
+            Line::Directive(Directive::Common { name, .. } | Directive::Set(name, _)) => {
+                defined.insert(name.as_str());
+                None
+            }
+            Line::Directive(Directive::Extern(name)) => {
+                externs.insert(name.as_str());
+                None
+            }
+            Line::Directive(Directive::Global(name)) => {
+                globals.push((name.as_str(), find_token(tokens, name)));
+                None
+            }
+            _ => None,
+        };
+
+        if let Some(name) = definition {
+            let token = find_token(tokens, name);
+            if let Some(first) = definitions.get(name.as_str()) {
+                let labels = vec![
+                    (*first, format!("'{}' is first defined here", name)),
+                    (token, "and defined again here".to_string()),
+                ];
+                diagnostics.report(ReportKind::Error, ParseError::SymbolAlreadyDefined, labels);
+            } else {
+                definitions.insert(name, token);
+            }
+        }
+
+        for name in references(line) {
+            referenced.push((name.clone(), find_token(tokens, &name)));
+        }
+    }
+
+    let is_defined = |name: &str| definitions.contains_key(name) || defined.contains(name);
+
+    // Undefined globals are always worth a warning, so they're only treated differently if they should be errors
+    let kind = match undefined {
+        UndefinedSymbols::Allow | UndefinedSymbols::Warn => ReportKind::Warning,
+        UndefinedSymbols::Error => ReportKind::Error,
+    };
+
+    for (name, token) in globals {
+        if !is_defined(name) {
+            let labels = vec![(token, format!("'{}' is declared global here, but never defined", name))];
+            diagnostics.report(kind, ParseError::UndefinedGlobal, labels);
+        }
+    }
+
+    if undefined == UndefinedSymbols::Allow {
+        return diagnostics.is_ok;
+    }
+
+    // Only the first reference to each symbol is reported
+    let mut reported = HashSet::new();
+    for (name, token) in referenced {
+        if !is_defined(&name) && !externs.contains(name.as_str()) && reported.insert(name.clone()) {
+            let labels = vec![(token, format!("'{}' is referenced here", name))];
+            diagnostics.report(kind, ParseError::UndefinedSymbol, labels);
+        }
+    }
+
+    diagnostics.is_ok
+}
@@ -4,43 +4,970 @@
  * License, v. 2.0. If a copy of the MPL was not distributed with this
  * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
 
-#[cfg(test)]
-mod tests {
-    use crate::parse::encodings::matches;
-    use crate::parse::lexer::{Lexer, Token};
-
-    #[test]
-    fn test_lexer() {
-        let mut lexer = Lexer::new("# test comment\n.test_directive test_dir_arg\na_label:\nsome other stuff 0x00, 22".to_string());
-
-        assert_eq!(lexer.read().unwrap().as_str(), "\n");
-        assert_eq!(lexer.read().unwrap().as_str(), ".test_directive");
-        assert_eq!(lexer.read().unwrap().as_str(), "test_dir_arg");
-        assert_eq!(lexer.read().unwrap().as_str(), "\n");
-        assert_eq!(lexer.read().unwrap().as_str(), "a_label");
-        assert_eq!(lexer.read().unwrap().as_str(), ":");
-        assert_eq!(lexer.read().unwrap().as_str(), "\n");
-        assert_eq!(lexer.read().unwrap().as_str(), "some");
-        assert_eq!(lexer.read().unwrap().as_str(), "other");
-        assert_eq!(lexer.read().unwrap().as_str(), "stuff");
-        assert_eq!(lexer.read().unwrap().as_str(), "0x00");
-        assert_eq!(lexer.read().unwrap().as_str(), ",");
-        assert_eq!(lexer.read().unwrap().as_str(), "22");
+use crate::instructions::Register;
+use crate::parse::encodings::matches;
+use crate::parse::expression::{parse_expression, Value};
+use crate::parse::helpers::{parse_integer, SymbolTable};
+use crate::parse::lexer::{Lexer, Token};
+use crate::parse::parser::{parse_file, parse_line, parse_lines};
+use crate::parse::symbols::UndefinedSymbols;
+use crate::parse::{Line, ParseError};
+
+#[test]
+fn test_lexer() {
+    let mut lexer = Lexer::new("# test comment\n.test_directive test_dir_arg\na_label:\nsome other stuff 0x00, 22".to_string());
+
+    assert_eq!(lexer.read().unwrap().as_str(), "\n");
+    assert_eq!(lexer.read().unwrap().as_str(), ".test_directive");
+    assert_eq!(lexer.read().unwrap().as_str(), "test_dir_arg");
+    assert_eq!(lexer.read().unwrap().as_str(), "\n");
+    assert_eq!(lexer.read().unwrap().as_str(), "a_label");
+    assert_eq!(lexer.read().unwrap().as_str(), ":");
+    assert_eq!(lexer.read().unwrap().as_str(), "\n");
+    assert_eq!(lexer.read().unwrap().as_str(), "some");
+    assert_eq!(lexer.read().unwrap().as_str(), "other");
+    assert_eq!(lexer.read().unwrap().as_str(), "stuff");
+    assert_eq!(lexer.read().unwrap().as_str(), "0x00");
+    assert_eq!(lexer.read().unwrap().as_str(), ",");
+    assert_eq!(lexer.read().unwrap().as_str(), "22");
+}
+
+#[test]
+fn test_assembler() {
+    let instr = matches(&[Token::new("push"), Token::new("rbp")], &SymbolTable::new());
+
+    assert!(instr.is_ok());
+    let instr = instr.unwrap();
+    assert_eq!(instr.encode().get_bytes(), &vec![0x55u8]);
+
+    let instr = matches(&[Token::new("rdseed"), Token::new("eax")], &SymbolTable::new());
+
+    assert!(instr.is_ok());
+    let instr = instr.unwrap();
+    assert_eq!(instr.encode().get_bytes(), &vec![0x0F, 0xC7, 0xF8]);
+}
+
+fn lex(line: &str) -> Vec<Token> {
+    let mut lexer = Lexer::new(line.to_string());
+    let mut tokens = Vec::new();
+
+    while let Ok(token) = lexer.read() {
+        tokens.push(token);
     }
 
-    #[test]
-    fn test_assembler() {
-        let instr = matches(&vec![Token::new("push"), Token::new("rbp")]);
+    tokens
+}
+
+fn encode(line: &str) -> Vec<u8> {
+    matches(&lex(line), &SymbolTable::new()).unwrap().encode().get_bytes().clone()
+}
+
+#[test]
+fn test_sib() {
+    assert_eq!(encode("mov rax, [rax + rcx*8 + 16]"), vec![0x48, 0x8B, 0x44, 0xC8, 0x10]);
+    assert_eq!(encode("mov rax, [4*rcx + rax]"), vec![0x48, 0x8B, 0x04, 0x88]);
+    assert_eq!(encode("mov rax, [rsp]"), vec![0x48, 0x8B, 0x04, 0x24]);
+    assert_eq!(encode("mov rax, [rsp + 8]"), vec![0x48, 0x8B, 0x44, 0x24, 0x08]);
+    assert_eq!(encode("mov rax, [r12 + 8]"), vec![0x49, 0x8B, 0x44, 0x24, 0x08]);
+    assert_eq!(encode("mov rax, [rbp]"), vec![0x48, 0x8B, 0x45, 0x00]);
+    assert_eq!(encode("mov rax, [r13]"), vec![0x49, 0x8B, 0x45, 0x00]);
+    assert_eq!(encode("mov rax, [rax + r9*2]"), vec![0x4A, 0x8B, 0x04, 0x48]);
+    assert_eq!(encode("mov rax, [rcx*4]"), vec![0x48, 0x8B, 0x04, 0x8D, 0x00, 0x00, 0x00, 0x00]);
+    assert_eq!(encode("mov rax, [16]"), vec![0x48, 0x8B, 0x04, 0x25, 0x10, 0x00, 0x00, 0x00]);
+    assert_eq!(encode("mov rax, [rbx - 1024]"), vec![0x48, 0x8B, 0x83, 0x00, 0xFC, 0xFF, 0xFF]);
+    assert_eq!(encode("lea rax, [rip + sym]"), vec![0x48, 0x8D, 0x05, 0x00, 0x00, 0x00, 0x00]);
+}
+
+#[test]
+fn test_invalid_sib() {
+    assert!(matches(&lex("mov rax, [rax + rsp*2]"), &SymbolTable::new()).is_err());
+    assert!(matches(&lex("mov rax, [rax + rcx*3]"), &SymbolTable::new()).is_err());
+    assert!(matches(&lex("mov rax, [rip + rcx*2]"), &SymbolTable::new()).is_err());
+}
+
+#[test]
+fn test_rex_registers() {
+    assert_eq!(encode("mov rax, r8"), vec![0x4C, 0x89, 0xC0]);
+    assert_eq!(encode("mov r8, rax"), vec![0x49, 0x89, 0xC0]);
+    assert_eq!(encode("add r9d, 1"), vec![0x41, 0x83, 0xC1, 0x01]);
+}
+
+#[test]
+fn test_number_literals() {
+    let int = |raw: &str| parse_integer(&Token::new(raw)).unwrap().unwrap();
 
-        assert!(instr.is_ok());
-        let instr = instr.unwrap();
-        assert_eq!(instr.encode().get_bytes(), &vec![0x55u8]);
+    assert_eq!(int("42"), 42);
+    assert_eq!(int("1_000_000"), 1_000_000);
+    assert_eq!(int("0x1F"), 0x1F);
+    assert_eq!(int("0XdeadBEEF"), 0xDEADBEEF);
+    assert_eq!(int("0xFFFF_FFFF"), 0xFFFF_FFFF);
+    assert_eq!(int("0FFh"), 0xFF);
+    assert_eq!(int("10H"), 0x10);
+    assert_eq!(int("0b1010"), 0b1010);
+    assert_eq!(int("0b1111_0000"), 0xF0);
+    assert_eq!(int("0o17"), 0o17);
+    assert_eq!(int("'A'"), 0x41);
+    assert_eq!(int("' '"), 0x20);
+    assert_eq!(int("'\\n'"), 0x0A);
+    assert_eq!(int("'\\''"), 0x27);
+    assert_eq!(int("'\\\\'"), 0x5C);
+    assert_eq!(int("0xFFFFFFFFFFFFFFFF"), u64::MAX as i128);
 
-        let instr = matches(&vec![Token::new("rdseed"), Token::new("eax")]);
+    assert!(parse_integer(&Token::new("symbol")).is_none());
+    assert!(parse_integer(&Token::new("_start")).is_none());
 
-        assert!(instr.is_ok());
-        let instr = instr.unwrap();
-        assert_eq!(instr.encode().get_bytes(), &vec![0x0F, 0xC7, 0xF8]);
+    for invalid in ["0x", "0xG", "12a", "0b102", "0o8", "1_0x", "''", "'AB'", "'\\q'"] {
+        assert!(matches!(parse_integer(&Token::new(invalid)), Some(Err(ParseError::InvalidNumber))), "{}", invalid);
     }
+    assert!(matches!(parse_integer(&Token::new("0x1_0000_0000_0000_0000")), Some(Err(ParseError::NumberOutOfRange))));
+}
+
+#[test]
+fn test_char_literal_lexing() {
+    let tokens = lex("mov al, 'a'\n.asciz \"say \\\"hi\\\"\"");
+    let tokens: Vec<&str> = tokens.iter().map(|t| t.as_str()).collect();
+
+    assert_eq!(tokens, ["mov", "al", ",", "'a'", "\n", ".asciz", "say \\\"hi\\\""]);
+}
+
+#[test]
+fn test_decoration_lexing() {
+    let tokens = lex("vaddps zmm0{k1}{z}, zmm1, dword ptr [rax]{1to16}, {rn-sae}");
+    let tokens: Vec<&str> = tokens.iter().map(|t| t.as_str()).collect();
+
+    assert_eq!(
+        tokens,
+        ["vaddps", "zmm0", "{k1}", "{z}", ",", "zmm1", ",", "dword", "ptr", "[", "rax", "]", "{1to16}", ",", "{rn-sae}"]
+    );
+}
+
+#[test]
+fn test_immediates() {
+    assert_eq!(encode("mov eax, 0x10"), vec![0xB8, 0x10, 0x00, 0x00, 0x00]);
+    assert_eq!(encode("mov eax, 0xFFFFFFFF"), vec![0xB8, 0xFF, 0xFF, 0xFF, 0xFF]);
+    assert_eq!(encode("mov eax, -1"), vec![0xB8, 0xFF, 0xFF, 0xFF, 0xFF]);
+    assert_eq!(encode("mov al, 'A'"), vec![0xB0, 0x41]);
+    assert_eq!(encode("mov al, 0FFh"), vec![0xB0, 0xFF]);
+    assert_eq!(encode("mov al, -128"), vec![0xB0, 0x80]);
+    assert_eq!(encode("mov ecx, 0b1111_1111_1111_1111"), vec![0xB9, 0xFF, 0xFF, 0x00, 0x00]);
+    assert_eq!(encode("mov cl, 0o377"), vec![0xB1, 0xFF]);
+    assert_eq!(encode("mov byte ptr [rax], 0xFF"), vec![0xC6, 0x00, 0xFF]);
+
+    // Sign-extended immediates only use the short form if the value survives the extension
+    assert_eq!(encode("add eax, 0x7F"), vec![0x83, 0xC0, 0x7F]);
+    assert_eq!(encode("add ecx, 0xFF"), vec![0x81, 0xC1, 0xFF, 0x00, 0x00, 0x00]);
+    assert_eq!(encode("add ecx, -128"), vec![0x83, 0xC1, 0x80]);
+    assert_eq!(encode("and rcx, -16"), vec![0x48, 0x83, 0xE1, 0xF0]);
+    assert_eq!(encode("shl ecx, 0xFF"), vec![0xC1, 0xE1, 0xFF]);
+}
+
+#[test]
+fn test_immediate_errors() {
+    let error = |line: &str| matches(&lex(line), &SymbolTable::new()).unwrap_err().1;
+
+    assert!(matches!(error("mov eax, 0x1G"), ParseError::InvalidNumber));
+    assert!(matches!(error("mov eax, 12abc"), ParseError::InvalidNumber));
+    assert!(matches!(error("mov eax, [rax + 0b2]"), ParseError::InvalidNumber));
+
+    assert!(matches!(error("mov al, 256"), ParseError::NumberOutOfRange));
+    assert!(matches!(error("mov al, -129"), ParseError::NumberOutOfRange));
+    assert!(matches!(error("mov ax, 0x10000"), ParseError::NumberOutOfRange));
+    assert!(matches!(error("mov ax, -32769"), ParseError::NumberOutOfRange));
+    assert!(matches!(error("mov eax, 0x100000000"), ParseError::NumberOutOfRange));
+    assert!(matches!(error("mov eax, -0x80000001"), ParseError::NumberOutOfRange));
+    assert!(matches!(error("and rcx, 0xFFFFFFFF"), ParseError::NumberOutOfRange));
+    assert!(matches!(error("mov eax, [rax + 0x80000000]"), ParseError::NumberOutOfRange));
+}
+
+#[test]
+fn test_64_bit_immediates() {
+    assert_eq!(
+        encode("mov rax, 0x123456789abcdef0"),
+        vec![0x48, 0xB8, 0xF0, 0xDE, 0xBC, 0x9A, 0x78, 0x56, 0x34, 0x12]
+    );
+    assert_eq!(
+        encode("mov r10, 0xFFFFFFFFFFFFFFFF"),
+        vec![0x49, 0xBA, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF]
+    );
+    assert_eq!(encode("mov rax, 0xFFFFFFFF"), vec![0x48, 0xB8, 0xFF, 0xFF, 0xFF, 0xFF, 0x00, 0x00, 0x00, 0x00]);
+
+    // Values that survive sign extension use the shorter imm32 form
+    assert_eq!(encode("mov rax, -1"), vec![0x48, 0xC7, 0xC0, 0xFF, 0xFF, 0xFF, 0xFF]);
+    assert_eq!(encode("mov rcx, 0x7FFFFFFF"), vec![0x48, 0xC7, 0xC1, 0xFF, 0xFF, 0xFF, 0x7F]);
+
+    assert_eq!(encode("movabs rax, 1"), vec![0x48, 0xB8, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00]);
+    assert_eq!(encode("movabs r15, -2"), vec![0x49, 0xBF, 0xFE, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF]);
+    assert_eq!(
+        encode("movabs al, [0x1122334455667788]"),
+        vec![0xA0, 0x88, 0x77, 0x66, 0x55, 0x44, 0x33, 0x22, 0x11]
+    );
+    assert_eq!(
+        encode("movabs eax, [0x1122334455667788]"),
+        vec![0xA1, 0x88, 0x77, 0x66, 0x55, 0x44, 0x33, 0x22, 0x11]
+    );
+    assert_eq!(
+        encode("movabs rax, [0x1122334455667788]"),
+        vec![0x48, 0xA1, 0x88, 0x77, 0x66, 0x55, 0x44, 0x33, 0x22, 0x11]
+    );
+    assert_eq!(
+        encode("movabs [0x1122334455667788], rax"),
+        vec![0x48, 0xA3, 0x88, 0x77, 0x66, 0x55, 0x44, 0x33, 0x22, 0x11]
+    );
+
+    let error = matches(&lex("mov rax, 0x10000000000000000"), &SymbolTable::new()).unwrap_err().1;
+    assert!(matches!(error, ParseError::NumberOutOfRange));
+}
+
+#[test]
+fn test_register_in_opcode() {
+    assert_eq!(encode("push r12"), vec![0x41, 0x54]);
+    assert_eq!(encode("pop r15"), vec![0x41, 0x5F]);
+    assert_eq!(encode("mov r9d, 1"), vec![0x41, 0xB9, 0x01, 0x00, 0x00, 0x00]);
+    assert_eq!(encode("bswap r8"), vec![0x49, 0x0F, 0xC8]);
+    assert_eq!(encode("inc eax"), vec![0xFF, 0xC0]);
+    assert_eq!(encode("dec ecx"), vec![0xFF, 0xC9]);
+}
+
+#[test]
+fn test_constants() {
+    let mut symbols = SymbolTable::new();
+    symbols.set("SIZE".to_string(), 0x20);
+    symbols.set("OFFSET".to_string(), 8);
+    let encode = |line: &str| matches(&lex(line), &symbols).unwrap().encode().get_bytes().clone();
+
+    assert_eq!(encode("mov eax, SIZE"), [0xB8, 0x20, 0, 0, 0]);
+    assert_eq!(encode("add rsp, SIZE"), [0x48, 0x83, 0xC4, 0x20]);
+    assert_eq!(encode("mov rax, [rbp - OFFSET]"), [0x48, 0x8B, 0x45, 0xF8]);
+    assert_eq!(encode("mov rax, [rbx + OFFSET + 4]"), [0x48, 0x8B, 0x43, 0x0C]);
+
+    // Constants still have to fit their operand
+    symbols.set("BIG".to_string(), 0x100);
+    assert!(matches(&lex("mov al, BIG"), &symbols).is_err());
+}
+
+fn evaluate(expression: &str) -> Result<Value, ParseError> {
+    let mut symbols = SymbolTable::new();
+    symbols.set("LOCALS".to_string(), 24);
+
+    let tokens = lex(expression);
+    let mut iter = tokens.iter();
+    let value = parse_expression(&mut iter, &symbols).map_err(|e| e.1)?;
+    assert!(iter.next().is_none(), "'{}' wasn't parsed completely", expression);
+
+    Ok(value)
 }
 
+#[test]
+fn test_expressions() {
+    let constant = |expression: &str| match evaluate(expression) {
+        Ok(Value::Constant(c)) => c,
+        value => panic!("'{}' evaluated to {:?}", expression, value),
+    };
+
+    assert_eq!(constant("4*8+1"), 33);
+    assert_eq!(constant("1 + 2 * 3 - 4"), 3);
+    assert_eq!(constant("(1 + 2) * 3"), 9);
+    assert_eq!(constant("-(LOCALS + 8)"), -32);
+    assert_eq!(constant("17 / 5 + 17 % 5"), 5);
+    assert_eq!(constant("1 << 4 | 0x3"), 0x13);
+    assert_eq!(constant("0xF0 & 0x3C ^ 0x01"), 0x31);
+    assert_eq!(constant("~0"), -1);
+    assert_eq!(constant("!0 + !5"), 1);
+    assert_eq!(constant("256 >> 4"), 16);
+    assert_eq!(constant("3 < 4"), -1);
+    assert_eq!(constant("3 >= 4"), 0);
+    assert_eq!(constant("LOCALS == 24"), -1);
+    assert_eq!(constant("1 != 1"), 0);
+
+    // GNU as' precedence differs from C's
+    assert_eq!(constant("1 | 1 + 1"), 2);
+    assert_eq!(constant("1 < 0 + 2"), -1);
+    assert_eq!(constant("2 + 3 << 1"), 8);
+    assert_eq!(constant("1 | 2 & 0"), 0);
+    assert_eq!(constant("2 < 3 == 0"), 0);
+
+    assert_eq!(evaluate("table + 16 - 8").unwrap(), Value::Symbol("table".to_string(), 8, None));
+    assert_eq!(evaluate("end - start").unwrap(), Value::Difference("end".to_string(), "start".to_string(), 0));
+
+    assert!(matches!(evaluate("1 / 0"), Err(ParseError::DivisionByZero)));
+    assert!(matches!(evaluate("1 << 200"), Err(ParseError::NumberOutOfRange)));
+    assert!(matches!(evaluate("a + b"), Err(ParseError::InvalidOperand)));
+    assert!(matches!(evaluate("2 * sym"), Err(ParseError::InvalidOperand)));
+    assert!(matches!(evaluate("(1 + 2"), Err(ParseError::UnexpectedLB)));
+}
+
+#[test]
+fn test_expression_operands() {
+    let mut symbols = SymbolTable::new();
+    symbols.set("LOCALS".to_string(), 24);
+    symbols.set("SCALE".to_string(), 8);
+    let encode = |line: &str| matches(&lex(line), &symbols).unwrap().encode().get_bytes().clone();
+
+    assert_eq!(encode("mov eax, 4*8+1"), [0xB8, 0x21, 0, 0, 0]);
+    assert_eq!(encode("add rsp, LOCALS * 2"), [0x48, 0x83, 0xC4, 0x30]);
+    assert_eq!(encode("mov rax, [rbp - (LOCALS+8)]"), [0x48, 0x8B, 0x45, 0xE0]);
+    assert_eq!(encode("mov rax, [rbx + rcx*SCALE + 2*4]"), [0x48, 0x8B, 0x44, 0xCB, 0x08]);
+    assert_eq!(encode("mov rax, [rbx + 2*rcx]"), [0x48, 0x8B, 0x04, 0x4B]);
+
+    // Differences are only known after layout, so they never use the short sign-extended forms
+    assert_eq!(encode("add eax, end - start"), [0x05, 0, 0, 0, 0]);
+
+    assert!(matches(&lex("mov eax, (1 << 40)"), &symbols).is_err());
+    assert!(matches(&lex("mov rax, [rbx + rcx + rdx]"), &symbols).is_err());
+}
+
+#[test]
+fn test_symbol_resolution() {
+    let parse = |code: &str, undefined| parse_file("test.s".to_string(), code.to_string(), undefined).is_ok();
+
+    assert!(!parse("a:\nret\na:\n", UndefinedSymbols::Allow));
+    assert!(!parse("a:\n.lcomm a, 4\n", UndefinedSymbols::Allow));
+    assert!(parse(".comm a, 4\n.comm a, 8\n", UndefinedSymbols::Allow));
+
+    // Globals that are never defined are warned about, unless undefined symbols are errors
+    assert!(parse(".globl main\n", UndefinedSymbols::Allow));
+    assert!(parse(".globl main\n", UndefinedSymbols::Warn));
+    assert!(!parse(".globl main\n", UndefinedSymbols::Error));
+
+    let code = ".extern puts\ncall puts@PLT\njmp later\n.quad later - main\nmain:\nlater:\n";
+    assert!(parse(code, UndefinedSymbols::Error));
+    assert!(parse("call puts\n", UndefinedSymbols::Warn));
+    assert!(!parse("call puts\n", UndefinedSymbols::Error));
+    assert!(!parse(".long missing - main\nmain:\n", UndefinedSymbols::Error));
+    assert!(!parse("mov eax, [rip + missing]\n", UndefinedSymbols::Error));
+    assert!(!parse("main:\nmov dword ptr [rip + main], missing\n", UndefinedSymbols::Error));
+    assert!(parse("main:\nmov dword ptr [rip + main], main\n", UndefinedSymbols::Error));
+
+    assert_eq!("warn".parse(), Ok(UndefinedSymbols::Warn));
+    assert!("ignore".parse::<UndefinedSymbols>().is_err());
+}
+
+#[test]
+fn test_operand_size_prefix() {
+    let symbols = SymbolTable::new();
+    let encode = |line: &str| matches(&lex(line), &symbols).unwrap().encode().get_bytes().clone();
+
+    // Every 16-bit form in encodings.rs, checked against GNU as
+    let forms: [(&str, &[u8]); 157] = [
+        ("adc ax, 0x1234", &[0x66, 0x15, 0x34, 0x12]),
+        ("adc word ptr [rax], 0x1234", &[0x66, 0x81, 0x10, 0x34, 0x12]),
+        ("adc word ptr [rax], -5", &[0x66, 0x83, 0x10, 0xFB]),
+        ("adc word ptr [rax], cx", &[0x66, 0x11, 0x08]),
+        ("adc cx, word ptr [rax]", &[0x66, 0x13, 0x08]),
+        ("add ax, 0x1234", &[0x66, 0x05, 0x34, 0x12]),
+        ("add word ptr [rax], 0x1234", &[0x66, 0x81, 0x00, 0x34, 0x12]),
+        ("add word ptr [rax], -5", &[0x66, 0x83, 0x00, 0xFB]),
+        ("add word ptr [rax], cx", &[0x66, 0x01, 0x08]),
+        ("add cx, word ptr [rax]", &[0x66, 0x03, 0x08]),
+        ("and ax, 0x1234", &[0x66, 0x25, 0x34, 0x12]),
+        ("and word ptr [rax], 0x1234", &[0x66, 0x81, 0x20, 0x34, 0x12]),
+        ("and word ptr [rax], -5", &[0x66, 0x83, 0x20, 0xFB]),
+        ("and word ptr [rax], cx", &[0x66, 0x21, 0x08]),
+        ("and cx, word ptr [rax]", &[0x66, 0x23, 0x08]),
+        ("bsf cx, word ptr [rax]", &[0x66, 0x0F, 0xBC, 0x08]),
+        ("bsr cx, word ptr [rax]", &[0x66, 0x0F, 0xBD, 0x08]),
+        ("bt word ptr [rax], cx", &[0x66, 0x0F, 0xA3, 0x08]),
+        ("bt word ptr [rax], 3", &[0x66, 0x0F, 0xBA, 0x20, 0x03]),
+        ("btc word ptr [rax], cx", &[0x66, 0x0F, 0xBB, 0x08]),
+        ("btc word ptr [rax], 3", &[0x66, 0x0F, 0xBA, 0x38, 0x03]),
+        ("btr word ptr [rax], cx", &[0x66, 0x0F, 0xB3, 0x08]),
+        ("btr word ptr [rax], 3", &[0x66, 0x0F, 0xBA, 0x30, 0x03]),
+        ("bts word ptr [rax], cx", &[0x66, 0x0F, 0xAB, 0x08]),
+        ("bts word ptr [rax], 3", &[0x66, 0x0F, 0xBA, 0x28, 0x03]),
+        ("call word ptr [rax]", &[0x66, 0xFF, 0x10]),
+        ("cbw", &[0x66, 0x98]),
+        ("cmova cx, word ptr [rax]", &[0x66, 0x0F, 0x47, 0x08]),
+        ("cmovae cx, word ptr [rax]", &[0x66, 0x0F, 0x43, 0x08]),
+        ("cmovb cx, word ptr [rax]", &[0x66, 0x0F, 0x42, 0x08]),
+        ("cmovbe cx, word ptr [rax]", &[0x66, 0x0F, 0x46, 0x08]),
+        ("cmovc cx, word ptr [rax]", &[0x66, 0x0F, 0x42, 0x08]),
+        ("cmove cx, word ptr [rax]", &[0x66, 0x0F, 0x44, 0x08]),
+        ("cmovg cx, word ptr [rax]", &[0x66, 0x0F, 0x4F, 0x08]),
+        ("cmovge cx, word ptr [rax]", &[0x66, 0x0F, 0x4D, 0x08]),
+        ("cmovl cx, word ptr [rax]", &[0x66, 0x0F, 0x4C, 0x08]),
+        ("cmovle cx, word ptr [rax]", &[0x66, 0x0F, 0x4E, 0x08]),
+        ("cmovna cx, word ptr [rax]", &[0x66, 0x0F, 0x46, 0x08]),
+        ("cmovnae cx, word ptr [rax]", &[0x66, 0x0F, 0x42, 0x08]),
+        ("cmovnb cx, word ptr [rax]", &[0x66, 0x0F, 0x43, 0x08]),
+        ("cmovnbe cx, word ptr [rax]", &[0x66, 0x0F, 0x47, 0x08]),
+        ("cmovnc cx, word ptr [rax]", &[0x66, 0x0F, 0x43, 0x08]),
+        ("cmovne cx, word ptr [rax]", &[0x66, 0x0F, 0x45, 0x08]),
+        ("cmovng cx, word ptr [rax]", &[0x66, 0x0F, 0x4E, 0x08]),
+        ("cmovnge cx, word ptr [rax]", &[0x66, 0x0F, 0x4C, 0x08]),
+        ("cmovnl cx, word ptr [rax]", &[0x66, 0x0F, 0x4D, 0x08]),
+        ("cmovnle cx, word ptr [rax]", &[0x66, 0x0F, 0x4F, 0x08]),
+        ("cmovno cx, word ptr [rax]", &[0x66, 0x0F, 0x41, 0x08]),
+        ("cmovnp cx, word ptr [rax]", &[0x66, 0x0F, 0x4B, 0x08]),
+        ("cmovns cx, word ptr [rax]", &[0x66, 0x0F, 0x49, 0x08]),
+        ("cmovnz cx, word ptr [rax]", &[0x66, 0x0F, 0x45, 0x08]),
+        ("cmovo cx, word ptr [rax]", &[0x66, 0x0F, 0x40, 0x08]),
+        ("cmovp cx, word ptr [rax]", &[0x66, 0x0F, 0x4A, 0x08]),
+        ("cmovpe cx, word ptr [rax]", &[0x66, 0x0F, 0x4A, 0x08]),
+        ("cmp ax, 0x1234", &[0x66, 0x3D, 0x34, 0x12]),
+        ("cmp word ptr [rax], 0x1234", &[0x66, 0x81, 0x38, 0x34, 0x12]),
+        ("cmp word ptr [rax], -5", &[0x66, 0x83, 0x38, 0xFB]),
+        ("cmp word ptr [rax], cx", &[0x66, 0x39, 0x08]),
+        ("cmp cx, word ptr [rax]", &[0x66, 0x3B, 0x08]),
+        ("cmpsw", &[0x66, 0xA7]),
+        ("cmpxchg word ptr [rax], cx", &[0x66, 0x0F, 0xB1, 0x08]),
+        ("crc32 ecx, word ptr [rax]", &[0x66, 0xF2, 0x0F, 0x38, 0xF1, 0x08]),
+        ("cwd", &[0x66, 0x99]),
+        ("dec bx", &[0x66, 0xFF, 0xCB]),
+        ("div bx", &[0x66, 0xF7, 0xF3]),
+        ("idiv bx", &[0x66, 0xF7, 0xFB]),
+        ("imul bx", &[0x66, 0xF7, 0xEB]),
+        ("imul cx, word ptr [rax]", &[0x66, 0x0F, 0xAF, 0x08]),
+        ("in ax, 3", &[0x66, 0xE5, 0x03]),
+        ("in ax, dx", &[0x66, 0xED]),
+        ("inc bx", &[0x66, 0xFF, 0xC3]),
+        ("insw", &[0x66, 0x6D]),
+        ("jmp word ptr [rax]", &[0x66, 0xFF, 0x20]),
+        ("lea cx, [rax]", &[0x66, 0x8D, 0x08]),
+        ("lodsw", &[0x66, 0xAD]),
+        ("lzcnt cx, word ptr [rax]", &[0x66, 0xF3, 0x0F, 0xBD, 0x08]),
+        ("mov word ptr [rax], cx", &[0x66, 0x89, 0x08]),
+        ("mov cx, word ptr [rax]", &[0x66, 0x8B, 0x08]),
+        ("mov cx, 0x1234", &[0x66, 0xB9, 0x34, 0x12]),
+        ("mov word ptr [rax], 0x1234", &[0x66, 0xC7, 0x00, 0x34, 0x12]),
+        ("movsw", &[0x66, 0xA5]),
+        ("movsx cx, byte ptr [rax]", &[0x66, 0x0F, 0xBE, 0x08]),
+        ("movzx cx, byte ptr [rax]", &[0x66, 0x0F, 0xB6, 0x08]),
+        ("mul bx", &[0x66, 0xF7, 0xE3]),
+        ("neg bx", &[0x66, 0xF7, 0xDB]),
+        ("not bx", &[0x66, 0xF7, 0xD3]),
+        ("or ax, 0x1234", &[0x66, 0x0D, 0x34, 0x12]),
+        ("or word ptr [rax], 0x1234", &[0x66, 0x81, 0x08, 0x34, 0x12]),
+        ("or word ptr [rax], -5", &[0x66, 0x83, 0x08, 0xFB]),
+        ("or word ptr [rax], cx", &[0x66, 0x09, 0x08]),
+        ("or cx, word ptr [rax]", &[0x66, 0x0B, 0x08]),
+        ("out 3, ax", &[0x66, 0xE7, 0x03]),
+        ("out dx, ax", &[0x66, 0xEF]),
+        ("outsw", &[0x66, 0x6F]),
+        ("pop word ptr [rax]", &[0x66, 0x8F, 0x00]),
+        ("pop cx", &[0x66, 0x59]),
+        ("popcnt cx, word ptr [rax]", &[0x66, 0xF3, 0x0F, 0xB8, 0x08]),
+        ("push word ptr [rax]", &[0x66, 0xFF, 0x30]),
+        ("push cx", &[0x66, 0x51]),
+        ("pushw 0x1234", &[0x66, 0x68, 0x34, 0x12]),
+        ("rcl word ptr [rax], 1", &[0x66, 0xD1, 0x10]),
+        ("rcl word ptr [rax], cl", &[0x66, 0xD3, 0x10]),
+        ("rcl word ptr [rax], 3", &[0x66, 0xC1, 0x10, 0x03]),
+        ("rcr word ptr [rax], 1", &[0x66, 0xD1, 0x18]),
+        ("rcr word ptr [rax], cl", &[0x66, 0xD3, 0x18]),
+        ("rcr word ptr [rax], 3", &[0x66, 0xC1, 0x18, 0x03]),
+        ("rol word ptr [rax], 1", &[0x66, 0xD1, 0x00]),
+        ("rol word ptr [rax], cl", &[0x66, 0xD3, 0x00]),
+        ("rol word ptr [rax], 3", &[0x66, 0xC1, 0x00, 0x03]),
+        ("ror word ptr [rax], 1", &[0x66, 0xD1, 0x08]),
+        ("ror word ptr [rax], cl", &[0x66, 0xD3, 0x08]),
+        ("ror word ptr [rax], 3", &[0x66, 0xC1, 0x08, 0x03]),
+        ("rdrand cx", &[0x66, 0x0F, 0xC7, 0xF1]),
+        ("rdseed cx", &[0x66, 0x0F, 0xC7, 0xF9]),
+        ("sal word ptr [rax], 1", &[0x66, 0xD1, 0x20]),
+        ("sal word ptr [rax], cl", &[0x66, 0xD3, 0x20]),
+        ("sal word ptr [rax], 3", &[0x66, 0xC1, 0x20, 0x03]),
+        ("sar word ptr [rax], 1", &[0x66, 0xD1, 0x38]),
+        ("sar word ptr [rax], cl", &[0x66, 0xD3, 0x38]),
+        ("sar word ptr [rax], 3", &[0x66, 0xC1, 0x38, 0x03]),
+        ("shl word ptr [rax], 1", &[0x66, 0xD1, 0x20]),
+        ("shl word ptr [rax], cl", &[0x66, 0xD3, 0x20]),
+        ("shl word ptr [rax], 3", &[0x66, 0xC1, 0x20, 0x03]),
+        ("shr word ptr [rax], 1", &[0x66, 0xD1, 0x28]),
+        ("shr word ptr [rax], cl", &[0x66, 0xD3, 0x28]),
+        ("shr word ptr [rax], 3", &[0x66, 0xC1, 0x28, 0x03]),
+        ("sbb ax, 0x1234", &[0x66, 0x1D, 0x34, 0x12]),
+        ("sbb word ptr [rax], 0x1234", &[0x66, 0x81, 0x18, 0x34, 0x12]),
+        ("sbb word ptr [rax], -5", &[0x66, 0x83, 0x18, 0xFB]),
+        ("sbb word ptr [rax], cx", &[0x66, 0x19, 0x08]),
+        ("sbb cx, word ptr [rax]", &[0x66, 0x1B, 0x08]),
+        ("scasw", &[0x66, 0xAF]),
+        ("sldt bx", &[0x66, 0x0F, 0x00, 0xC3]),
+        ("smsw bx", &[0x66, 0x0F, 0x01, 0xE3]),
+        ("stosw", &[0x66, 0xAB]),
+        ("str bx", &[0x66, 0x0F, 0x00, 0xCB]),
+        ("sub ax, 0x1234", &[0x66, 0x2D, 0x34, 0x12]),
+        ("sub word ptr [rax], 0x1234", &[0x66, 0x81, 0x28, 0x34, 0x12]),
+        ("sub word ptr [rax], -5", &[0x66, 0x83, 0x28, 0xFB]),
+        ("sub word ptr [rax], cx", &[0x66, 0x29, 0x08]),
+        ("sub cx, word ptr [rax]", &[0x66, 0x2B, 0x08]),
+        ("test ax, 0x1234", &[0x66, 0xA9, 0x34, 0x12]),
+        ("test word ptr [rax], 0x1234", &[0x66, 0xF7, 0x00, 0x34, 0x12]),
+        ("test word ptr [rax], cx", &[0x66, 0x85, 0x08]),
+        ("tzcnt cx, word ptr [rax]", &[0x66, 0xF3, 0x0F, 0xBC, 0x08]),
+        ("xadd word ptr [rax], cx", &[0x66, 0x0F, 0xC1, 0x08]),
+        ("xchg ax, cx", &[0x66, 0x91]),
+        ("xchg cx, ax", &[0x66, 0x91]),
+        ("xchg word ptr [rax], cx", &[0x66, 0x87, 0x08]),
+        ("xchg cx, word ptr [rax]", &[0x66, 0x87, 0x08]),
+        ("xor ax, 0x1234", &[0x66, 0x35, 0x34, 0x12]),
+        ("xor word ptr [rax], 0x1234", &[0x66, 0x81, 0x30, 0x34, 0x12]),
+        ("xor word ptr [rax], -5", &[0x66, 0x83, 0x30, 0xFB]),
+        ("xor word ptr [rax], cx", &[0x66, 0x31, 0x08]),
+        ("xor cx, word ptr [rax]", &[0x66, 0x33, 0x08]),
+        ("movabs ax, [0x1122334455667788]", &[0x66, 0xA1, 0x88, 0x77, 0x66, 0x55, 0x44, 0x33, 0x22, 0x11]),
+        ("movabs [0x1122334455667788], ax", &[0x66, 0xA3, 0x88, 0x77, 0x66, 0x55, 0x44, 0x33, 0x22, 0x11]),
+    ];
+
+    for (line, bytes) in forms {
+        assert_eq!(encode(line), bytes, "{}", line);
+    }
+
+    // Word operands that are part of the instruction rather than its operand size don't switch it
+    assert_eq!(encode("movzx eax, word ptr [rax]"), [0x0F, 0xB7, 0x00]);
+    assert_eq!(encode("enter 8, 2"), [0xC8, 0x08, 0x00, 0x02]);
+    assert_eq!(encode("ret 8"), [0xC2, 0x08, 0x00]);
+    assert_eq!(encode("lldt ax"), [0x0F, 0x00, 0xD0]);
+    assert_eq!(encode("push 0x1234"), [0x68, 0x34, 0x12, 0x00, 0x00]);
+}
+
+#[test]
+fn test_address_size_prefix() {
+    let symbols = SymbolTable::new();
+    let encode = |line: &str| matches(&lex(line), &symbols).unwrap().encode().get_bytes().clone();
+
+    assert_eq!(encode("mov eax, [eax]"), [0x67, 0x8B, 0x00]);
+    assert_eq!(encode("lea rax, [eax+ecx*4]"), [0x67, 0x48, 0x8D, 0x04, 0x88]);
+    assert_eq!(encode("mov word ptr [ebx], 5"), [0x67, 0x66, 0xC7, 0x03, 0x05, 0x00]);
+    assert_eq!(encode("jecxz loop")[..2], [0x67, 0xE3]);
+
+    assert!(matches(&lex("mov eax, [rax+ecx]"), &symbols).is_err());
+}
+
+#[test]
+fn test_prefixes() {
+    let parse = |line: &str| parse_lines("test.s".to_string(), format!("{}\n", line));
+    let instruction = |line: &str| match parse(line).unwrap().remove(0) {
+        Line::Instruction(instruction) => instruction,
+        _ => panic!("'{}' isn't an instruction", line),
+    };
+    let encode = |line: &str| instruction(line).encode().get_bytes().clone();
+
+    // Checked against GNU as
+    assert_eq!(encode("lock add [rbx], rax"), [0xF0, 0x48, 0x01, 0x03]);
+    assert_eq!(encode("lock cmpxchg [rdi], rsi"), [0xF0, 0x48, 0x0F, 0xB1, 0x37]);
+    assert_eq!(encode("lock bts dword ptr [rax], 3"), [0xF0, 0x0F, 0xBA, 0x28, 0x03]);
+    assert_eq!(encode("lock add word ptr fs:[eax], 1"), [0x64, 0x67, 0x66, 0xF0, 0x83, 0x00, 0x01]);
+    assert_eq!(encode("rep movsb"), [0xF3, 0xA4]);
+    assert_eq!(encode("rep movsw"), [0x66, 0xF3, 0xA5]);
+    assert_eq!(encode("rep movsq"), [0xF3, 0x48, 0xA5]);
+    assert_eq!(encode("repe cmpsb"), [0xF3, 0xA6]);
+    assert_eq!(encode("repnz scasb"), [0xF2, 0xAE]);
+    assert_eq!(encode("rep ret"), [0xF3, 0xC3]);
+    assert_eq!(encode("xacquire lock add qword ptr [rax], 1"), [0xF2, 0xF0, 0x48, 0x83, 0x00, 0x01]);
+    assert_eq!(encode("lock xrelease add qword ptr [rax], 1"), [0xF3, 0xF0, 0x48, 0x83, 0x00, 0x01]);
+    assert_eq!(encode("xacquire xchg [rax], rbx"), [0xF2, 0x48, 0x87, 0x18]);
+    assert_eq!(encode("xrelease mov [rax], rbx"), [0xF3, 0x48, 0x89, 0x18]);
+    assert_eq!(encode("notrack call rax"), [0x3E, 0xFF, 0xD0]);
+    assert_eq!(encode("notrack jmp qword ptr [rax]"), [0x3E, 0xFF, 0x20]);
+    assert_eq!(encode("bnd notrack call rax"), [0x3E, 0xF2, 0xFF, 0xD0]);
+    assert_eq!(encode("bnd jmp qword ptr fs:[rax]"), [0x64, 0xF2, 0xFF, 0x20]);
+    assert_eq!(encode("bnd ret"), [0xF2, 0xC3]);
+
+    // Relaxed jumps keep their prefixes
+    let relaxed = instruction("bnd je target").relaxed().unwrap();
+    assert_eq!(relaxed.encode().get_bytes(), &[0xF2, 0x0F, 0x84, 0, 0, 0, 0]);
+
+    // lock on its own still works
+    assert_eq!(encode("lock"), [0xF0]);
+
+    assert!(parse("lock add rax, [rbx]").is_err());
+    assert!(parse("lock add rax, rbx").is_err());
+    assert!(parse("lock mov [rax], rbx").is_err());
+    assert!(parse("lock lock add [rax], rbx").is_err());
+    assert!(parse("rep repne movsb").is_err());
+    assert!(parse("rep add [rax], rbx").is_err());
+    assert!(parse("xacquire add qword ptr [rax], 1").is_err());
+    assert!(parse("xacquire mov [rax], rbx").is_err());
+    assert!(parse("notrack call target").is_err());
+    assert!(parse("notrack call qword ptr fs:[rax]").is_err());
+
+    // Prefixes and decorations without an instruction
+    let error = |line: &str| parse_line(&lex(line), &mut SymbolTable::new()).err().map(|(_, error)| error);
+    assert!(matches!(error("rep"), Some(ParseError::InvalidInstruction)));
+    assert!(matches!(error("lock rep"), Some(ParseError::InvalidInstruction)));
+    assert!(matches!(error("notrack {k1}"), Some(ParseError::InvalidInstruction)));
+    assert!(matches!(error("{z}"), Some(ParseError::InvalidInstruction)));
+    assert!(matches!(error(""), Some(ParseError::UnexpectedEOF)));
+    assert!(parse("rep").is_err());
+}
+
+#[test]
+fn test_segment_override() {
+    let symbols = SymbolTable::new();
+    let encode = |line: &str| matches(&lex(line), &symbols).unwrap().encode().get_bytes().clone();
+
+    assert_eq!(encode("mov rax, fs:[0x28]"), [0x64, 0x48, 0x8B, 0x04, 0x25, 0x28, 0x00, 0x00, 0x00]);
+    assert_eq!(encode("mov rax, qword ptr fs:[0x28]"), [0x64, 0x48, 0x8B, 0x04, 0x25, 0x28, 0x00, 0x00, 0x00]);
+    assert_eq!(encode("mov qword ptr gs:[rax+8], rcx"), [0x65, 0x48, 0x89, 0x48, 0x08]);
+    assert_eq!(encode("mov al, gs:[eax]"), [0x65, 0x67, 0x8A, 0x00]);
+    assert_eq!(encode("mov al, es:[rbp]"), [0x26, 0x8A, 0x45, 0x00]);
+
+    // Overrides with the segment that's used anyway are left out, like GNU as does
+    assert_eq!(encode("mov al, ds:[rax]"), [0x8A, 0x00]);
+    assert_eq!(encode("mov al, ss:[rsp]"), [0x8A, 0x04, 0x24]);
+    assert_eq!(encode("mov al, ds:[rbp]"), [0x3E, 0x8A, 0x45, 0x00]);
+
+    assert!(matches(&lex("mov rax, fs[0x28]"), &symbols).is_err());
+    assert!(matches(&lex("mov rax, [fs:0x28]"), &symbols).is_err());
+}
+
+#[test]
+fn test_byte_registers() {
+    let parse = |line: &str| parse_lines("test.s".to_string(), format!("{}\n", line));
+    let encode = |line: &str| match parse(line).unwrap().remove(0) {
+        Line::Instruction(instruction) => instruction.encode().get_bytes().clone(),
+        _ => panic!("'{}' isn't an instruction", line),
+    };
+
+    // Every 8-bit register as the register in the opcode, in ModRM.reg and in ModRM.r/m, checked against GNU as
+    let forms: [(&str, &[u8]); 60] = [
+        ("mov al, 1", &[0xB0, 0x01]),
+        ("add cl, al", &[0x00, 0xC1]),
+        ("add al, cl", &[0x00, 0xC8]),
+        ("mov ah, 1", &[0xB4, 0x01]),
+        ("add cl, ah", &[0x00, 0xE1]),
+        ("add ah, cl", &[0x00, 0xCC]),
+        ("mov bl, 1", &[0xB3, 0x01]),
+        ("add cl, bl", &[0x00, 0xD9]),
+        ("add bl, cl", &[0x00, 0xCB]),
+        ("mov bh, 1", &[0xB7, 0x01]),
+        ("add cl, bh", &[0x00, 0xF9]),
+        ("add bh, cl", &[0x00, 0xCF]),
+        ("mov cl, 1", &[0xB1, 0x01]),
+        ("add cl, cl", &[0x00, 0xC9]),
+        ("add cl, cl", &[0x00, 0xC9]),
+        ("mov ch, 1", &[0xB5, 0x01]),
+        ("add cl, ch", &[0x00, 0xE9]),
+        ("add ch, cl", &[0x00, 0xCD]),
+        ("mov dl, 1", &[0xB2, 0x01]),
+        ("add cl, dl", &[0x00, 0xD1]),
+        ("add dl, cl", &[0x00, 0xCA]),
+        ("mov dh, 1", &[0xB6, 0x01]),
+        ("add cl, dh", &[0x00, 0xF1]),
+        ("add dh, cl", &[0x00, 0xCE]),
+        ("mov sil, 1", &[0x40, 0xB6, 0x01]),
+        ("add cl, sil", &[0x40, 0x00, 0xF1]),
+        ("add sil, cl", &[0x40, 0x00, 0xCE]),
+        ("mov dil, 1", &[0x40, 0xB7, 0x01]),
+        ("add cl, dil", &[0x40, 0x00, 0xF9]),
+        ("add dil, cl", &[0x40, 0x00, 0xCF]),
+        ("mov spl, 1", &[0x40, 0xB4, 0x01]),
+        ("add cl, spl", &[0x40, 0x00, 0xE1]),
+        ("add spl, cl", &[0x40, 0x00, 0xCC]),
+        ("mov bpl, 1", &[0x40, 0xB5, 0x01]),
+        ("add cl, bpl", &[0x40, 0x00, 0xE9]),
+        ("add bpl, cl", &[0x40, 0x00, 0xCD]),
+        ("mov r8b, 1", &[0x41, 0xB0, 0x01]),
+        ("add cl, r8b", &[0x44, 0x00, 0xC1]),
+        ("add r8b, cl", &[0x41, 0x00, 0xC8]),
+        ("mov r9b, 1", &[0x41, 0xB1, 0x01]),
+        ("add cl, r9b", &[0x44, 0x00, 0xC9]),
+        ("add r9b, cl", &[0x41, 0x00, 0xC9]),
+        ("mov r10b, 1", &[0x41, 0xB2, 0x01]),
+        ("add cl, r10b", &[0x44, 0x00, 0xD1]),
+        ("add r10b, cl", &[0x41, 0x00, 0xCA]),
+        ("mov r11b, 1", &[0x41, 0xB3, 0x01]),
+        ("add cl, r11b", &[0x44, 0x00, 0xD9]),
+        ("add r11b, cl", &[0x41, 0x00, 0xCB]),
+        ("mov r12b, 1", &[0x41, 0xB4, 0x01]),
+        ("add cl, r12b", &[0x44, 0x00, 0xE1]),
+        ("add r12b, cl", &[0x41, 0x00, 0xCC]),
+        ("mov r13b, 1", &[0x41, 0xB5, 0x01]),
+        ("add cl, r13b", &[0x44, 0x00, 0xE9]),
+        ("add r13b, cl", &[0x41, 0x00, 0xCD]),
+        ("mov r14b, 1", &[0x41, 0xB6, 0x01]),
+        ("add cl, r14b", &[0x44, 0x00, 0xF1]),
+        ("add r14b, cl", &[0x41, 0x00, 0xCE]),
+        ("mov r15b, 1", &[0x41, 0xB7, 0x01]),
+        ("add cl, r15b", &[0x44, 0x00, 0xF9]),
+        ("add r15b, cl", &[0x41, 0x00, 0xCF]),
+    ];
+
+    for (line, bytes) in forms {
+        assert_eq!(encode(line), bytes, "{}", line);
+    }
+
+    // ah, ch, dh and bh are where spl, bpl, sil and dil would be without a REX prefix
+    for register in ["ah", "ch", "dh", "bh"] {
+        assert!(parse(&format!("mov {}, sil", register)).is_err());
+        assert!(parse(&format!("add r8b, {}", register)).is_err());
+        assert!(parse(&format!("mov {}, byte ptr [r8]", register)).is_err());
+        assert!(parse(&format!("movzx rax, {}", register)).is_err());
+    }
+    assert_eq!(encode("movzx eax, ah"), [0x0F, 0xB6, 0xC4]);
+    assert_eq!(encode("mov byte ptr [rax], bh"), [0x88, 0x38]);
+}
+
+#[test]
+fn test_register_numbers() {
+    for number in 0..16 {
+        assert_eq!(Register::try_from(number).map(|register| register as i32), Ok(number));
+    }
+    assert!(Register::try_from(16).is_err());
+    assert_eq!("ymm9".parse::<Register>().map(|register| register as i32 & 0b1111), Ok(9));
+    assert_eq!("zmm29".parse::<Register>().map(|register| register as i32 & 0b11111), Ok(29));
+    assert_eq!("xmm16".parse::<Register>().map(|register| register as i32 & 0b11111), Ok(16));
+    assert_eq!("k7".parse::<Register>().map(|register| register as i32 & 0b111), Ok(7));
+}
+
+#[test]
+fn test_sse() {
+    let parse = |line: &str| parse_lines("test.s".to_string(), format!("{}\n", line));
+    let encode = |line: &str| match parse(line).unwrap().remove(0) {
+        Line::Instruction(instruction) => instruction.encode().get_bytes().clone(),
+        _ => panic!("'{}' isn't an instruction", line),
+    };
+
+    // Checked against GNU as
+    assert_eq!(encode("addsd xmm0, xmm1"), [0xF2, 0x0F, 0x58, 0xC1]);
+    assert_eq!(encode("mulss xmm9, dword ptr [rax + 8]"), [0xF3, 0x44, 0x0F, 0x59, 0x48, 0x08]);
+    assert_eq!(encode("divpd xmm2, xmmword ptr [r9]"), [0x66, 0x41, 0x0F, 0x5E, 0x11]);
+    assert_eq!(encode("sqrtps xmm1, xmm12"), [0x41, 0x0F, 0x51, 0xCC]);
+    assert_eq!(encode("xorps xmm0, xmm0"), [0x0F, 0x57, 0xC0]);
+    assert_eq!(encode("ucomisd xmm0, qword ptr [rdi]"), [0x66, 0x0F, 0x2E, 0x07]);
+    assert_eq!(encode("cvtsi2sd xmm0, edi"), [0xF2, 0x0F, 0x2A, 0xC7]);
+    assert_eq!(encode("cvtsi2ss xmm2, rdi"), [0xF3, 0x48, 0x0F, 0x2A, 0xD7]);
+    assert_eq!(encode("cvttsd2si eax, xmm0"), [0xF2, 0x0F, 0x2C, 0xC0]);
+    assert_eq!(encode("cvttsd2si r9, xmm10"), [0xF2, 0x4D, 0x0F, 0x2C, 0xCA]);
+    assert_eq!(encode("cvtss2sd xmm0, xmm1"), [0xF3, 0x0F, 0x5A, 0xC1]);
+    assert_eq!(encode("pxor xmm12, xmm12"), [0x66, 0x45, 0x0F, 0xEF, 0xE4]);
+    assert_eq!(encode("punpcklqdq xmm0, xmm1"), [0x66, 0x0F, 0x6C, 0xC1]);
+    assert_eq!(encode("pshufd xmm1, xmm2, 0x1B"), [0x66, 0x0F, 0x70, 0xCA, 0x1B]);
+    assert_eq!(encode("shufps xmm1, xmmword ptr [rax], 3"), [0x0F, 0xC6, 0x08, 0x03]);
+    assert_eq!(encode("psrldq xmm9, 8"), [0x66, 0x41, 0x0F, 0x73, 0xD9, 0x08]);
+    assert_eq!(encode("pmovmskb eax, xmm12"), [0x66, 0x41, 0x0F, 0xD7, 0xC4]);
+    assert_eq!(encode("ldmxcsr dword ptr [rsp]"), [0x0F, 0xAE, 0x14, 0x24]);
+    assert_eq!(encode("mfence"), [0x0F, 0xAE, 0xF0]);
+
+    // Moves between registers use the load forms, like other assemblers do
+    assert_eq!(encode("movaps xmm0, xmm1"), [0x0F, 0x28, 0xC1]);
+    assert_eq!(encode("movaps xmmword ptr [rsp], xmm1"), [0x0F, 0x29, 0x0C, 0x24]);
+    assert_eq!(encode("movdqu xmm8, xmmword ptr [rsi]"), [0xF3, 0x44, 0x0F, 0x6F, 0x06]);
+    assert_eq!(encode("movd xmm0, eax"), [0x66, 0x0F, 0x6E, 0xC0]);
+    assert_eq!(encode("movq xmm0, rax"), [0x66, 0x48, 0x0F, 0x6E, 0xC0]);
+    assert_eq!(encode("movq rax, xmm0"), [0x66, 0x48, 0x0F, 0x7E, 0xC0]);
+    assert_eq!(encode("movq xmm0, xmm1"), [0xF3, 0x0F, 0x7E, 0xC1]);
+    assert_eq!(encode("movq xmm0, qword ptr [rax]"), [0xF3, 0x0F, 0x7E, 0x00]);
+    assert_eq!(encode("movq qword ptr [rax], xmm0"), [0x66, 0x0F, 0xD6, 0x00]);
+
+    // movsd and cmpsd are also string instructions, which don't take any operands
+    assert_eq!(encode("movsd xmm0, xmm1"), [0xF2, 0x0F, 0x10, 0xC1]);
+    assert_eq!(encode("movsd qword ptr [rax], xmm1"), [0xF2, 0x0F, 0x11, 0x08]);
+    assert_eq!(encode("movsd"), [0xA5]);
+    assert_eq!(encode("rep movsd"), [0xF3, 0xA5]);
+    assert_eq!(encode("cmpsd xmm0, xmm1, 2"), [0xF2, 0x0F, 0xC2, 0xC1, 0x02]);
+    assert_eq!(encode("cmpsd"), [0xA7]);
+    assert!(parse("rep movsd xmm0, xmm1").is_err());
+
+    assert!(parse("addsd xmm0, dword ptr [rax]").is_err());
+    assert!(parse("addps xmm0, qword ptr [rax]").is_err());
+    assert!(parse("addsd xmm16, xmm0").is_err());
+    assert!(parse("addsd xmm0, rax").is_err());
+}
+
+#[test]
+fn test_avx() {
+    let parse = |line: &str| parse_lines("test.s".to_string(), format!("{}\n", line));
+    let encode = |line: &str| match parse(line).unwrap().remove(0) {
+        Line::Instruction(instruction) => instruction.encode().get_bytes().clone(),
+        _ => panic!("'{}' isn't an instruction", line),
+    };
+
+    // Checked against GNU as. The two byte VEX prefix is used unless X, B, W or another map are needed.
+    assert_eq!(encode("vaddps ymm0, ymm1, ymm2"), [0xC5, 0xF4, 0x58, 0xC2]);
+    assert_eq!(encode("vaddps xmm0, xmm1, xmm2"), [0xC5, 0xF0, 0x58, 0xC2]);
+    assert_eq!(encode("vaddsd xmm0, xmm1, qword ptr [rax]"), [0xC5, 0xF3, 0x58, 0x00]);
+    assert_eq!(encode("vaddps ymm8, ymm9, ymm10"), [0xC4, 0x41, 0x34, 0x58, 0xC2]);
+    assert_eq!(encode("vaddps ymm0, ymm1, ymm10"), [0xC4, 0xC1, 0x74, 0x58, 0xC2]);
+    assert_eq!(
+        encode("vmulpd ymm1, ymm14, ymmword ptr [r9 + rcx * 4 + 16]"),
+        [0xC4, 0xC1, 0x0D, 0x59, 0x4C, 0x89, 0x10]
+    );
+    assert_eq!(encode("vxorps xmm0, xmm0, xmm0"), [0xC5, 0xF8, 0x57, 0xC0]);
+    assert_eq!(encode("vsqrtpd ymm2, ymm3"), [0xC5, 0xFD, 0x51, 0xD3]);
+    assert_eq!(encode("vcmpps ymm0, ymm1, ymm2, 1"), [0xC5, 0xF4, 0xC2, 0xC2, 0x01]);
+    assert_eq!(encode("vshufps ymm0, ymm1, ymm2, 0x1B"), [0xC5, 0xF4, 0xC6, 0xC2, 0x1B]);
+    assert_eq!(encode("vcvtsi2sd xmm0, xmm0, rdi"), [0xC4, 0xE1, 0xFB, 0x2A, 0xC7]);
+    assert_eq!(encode("vcvttsd2si r9, xmm10"), [0xC4, 0x41, 0xFB, 0x2C, 0xCA]);
+    assert_eq!(encode("vcvtpd2ps xmm0, ymmword ptr [rax]"), [0xC5, 0xFD, 0x5A, 0x00]);
+    assert_eq!(encode("vcvtpd2ps xmm0, xmmword ptr [rax]"), [0xC5, 0xF9, 0x5A, 0x00]);
+    assert_eq!(encode("vldmxcsr dword ptr [rsp]"), [0xC5, 0xF8, 0xAE, 0x14, 0x24]);
+    assert_eq!(encode("vzeroupper"), [0xC5, 0xF8, 0x77]);
+    assert_eq!(encode("vzeroall"), [0xC5, 0xFC, 0x77]);
+
+    // Moves between registers use the store form if that avoids the three byte prefix
+    assert_eq!(encode("vmovups ymm0, ymmword ptr [rdi]"), [0xC5, 0xFC, 0x10, 0x07]);
+    assert_eq!(encode("vmovups ymmword ptr [rdi + 32], ymm9"), [0xC5, 0x7C, 0x11, 0x4F, 0x20]);
+    assert_eq!(encode("vmovaps ymm1, ymm8"), [0xC5, 0x7C, 0x29, 0xC1]);
+    assert_eq!(encode("vmovdqu ymm3, ymmword ptr [rsi]"), [0xC5, 0xFE, 0x6F, 0x1E]);
+    assert_eq!(encode("vmovss xmm1, dword ptr [rsi]"), [0xC5, 0xFA, 0x10, 0x0E]);
+    assert_eq!(encode("vmovss xmm0, xmm1, xmm2"), [0xC5, 0xF2, 0x10, 0xC2]);
+    assert_eq!(encode("vmovd eax, xmm0"), [0xC5, 0xF9, 0x7E, 0xC0]);
+    assert_eq!(encode("vmovq xmm0, rax"), [0xC4, 0xE1, 0xF9, 0x6E, 0xC0]);
+
+    // AVX2
+    assert_eq!(encode("vpaddd ymm0, ymm1, ymm2"), [0xC5, 0xF5, 0xFE, 0xC2]);
+    assert_eq!(encode("vpxor xmm12, xmm12, xmm12"), [0xC4, 0x41, 0x19, 0xEF, 0xE4]);
+    assert_eq!(encode("vpshufb ymm0, ymm1, ymm2"), [0xC4, 0xE2, 0x75, 0x00, 0xC2]);
+    assert_eq!(encode("vpsllw ymm0, ymm1, xmm2"), [0xC5, 0xF5, 0xF1, 0xC2]);
+    assert_eq!(encode("vpsrldq ymm9, ymm1, 8"), [0xC5, 0xB5, 0x73, 0xD9, 0x08]);
+    assert_eq!(encode("vpsrad xmm1, xmm12, 3"), [0xC4, 0xC1, 0x71, 0x72, 0xE4, 0x03]);
+    assert_eq!(encode("vpmovzxbw ymm0, xmmword ptr [rax]"), [0xC4, 0xE2, 0x7D, 0x30, 0x00]);
+    assert_eq!(encode("vpshufd ymm1, ymm2, 0x1B"), [0xC5, 0xFD, 0x70, 0xCA, 0x1B]);
+    assert_eq!(encode("vpmovmskb eax, ymm12"), [0xC4, 0xC1, 0x7D, 0xD7, 0xC4]);
+    assert_eq!(encode("vbroadcastss ymm0, dword ptr [rax]"), [0xC4, 0xE2, 0x7D, 0x18, 0x00]);
+    assert_eq!(encode("vbroadcastsd ymm1, xmm2"), [0xC4, 0xE2, 0x7D, 0x19, 0xCA]);
+    assert_eq!(encode("vpbroadcastd ymm0, xmm1"), [0xC4, 0xE2, 0x7D, 0x58, 0xC1]);
+    assert_eq!(encode("vpbroadcastb xmm3, byte ptr [rdi]"), [0xC4, 0xE2, 0x79, 0x78, 0x1F]);
+    assert_eq!(encode("vpbroadcastq ymm9, qword ptr [rsp]"), [0xC4, 0x62, 0x7D, 0x59, 0x0C, 0x24]);
+    assert_eq!(encode("vinsertf128 ymm0, ymm1, xmm2, 1"), [0xC4, 0xE3, 0x75, 0x18, 0xC2, 0x01]);
+    assert_eq!(encode("vextracti128 xmmword ptr [rax], ymm3, 1"), [0xC4, 0xE3, 0x7D, 0x39, 0x18, 0x01]);
+    assert_eq!(encode("vperm2i128 ymm0, ymm1, ymm2, 0x21"), [0xC4, 0xE3, 0x75, 0x46, 0xC2, 0x21]);
+    assert_eq!(encode("vperm2f128 ymm8, ymm9, ymmword ptr [r10], 0x31"), [0xC4, 0x43, 0x35, 0x06, 0x02, 0x31]);
+    assert_eq!(encode("vpermq ymm0, ymm1, 0xD8"), [0xC4, 0xE3, 0xFD, 0x00, 0xC1, 0xD8]);
+    assert_eq!(encode("vpermd ymm0, ymm1, ymm2"), [0xC4, 0xE2, 0x75, 0x36, 0xC2]);
+    assert_eq!(encode("vpermilps xmm0, xmm1, 0x55"), [0xC4, 0xE3, 0x79, 0x04, 0xC1, 0x55]);
+    assert_eq!(encode("vpsllvd ymm0, ymm1, ymm2"), [0xC4, 0xE2, 0x75, 0x47, 0xC2]);
+    assert_eq!(encode("vpsrlvq xmm0, xmm1, xmm2"), [0xC4, 0xE2, 0xF1, 0x45, 0xC2]);
+    assert_eq!(encode("vpblendd ymm0, ymm1, ymm2, 0xF0"), [0xC4, 0xE3, 0x75, 0x02, 0xC2, 0xF0]);
+    assert_eq!(encode("vptest ymm0, ymm1"), [0xC4, 0xE2, 0x7D, 0x17, 0xC1]);
+
+    // The blends selected by a fourth register keep it in the upper bits of an immediate
+    assert_eq!(encode("vblendvps xmm1, xmm2, xmm3, xmm4"), [0xC4, 0xE3, 0x69, 0x4A, 0xCB, 0x40]);
+    assert_eq!(encode("vblendvpd ymm9, ymm12, ymm13, ymm11"), [0xC4, 0x43, 0x1D, 0x4B, 0xCD, 0xB0]);
+    assert_eq!(
+        encode("vpblendvb xmm0, xmm8, xmmword ptr [r9 + rax * 2 + 0x40], xmm15"),
+        [0xC4, 0xC3, 0x39, 0x4C, 0x44, 0x41, 0x40, 0xF0]
+    );
+    assert_eq!(encode("vblendvps ymm1, ymm2, ymmword ptr [rax], ymm4"), [0xC4, 0xE3, 0x6D, 0x4A, 0x08, 0x40]);
+
+    // Gathers index memory with a vector register, whose size depends on the number and size of the indices
+    assert_eq!(encode("vgatherdps xmm1, dword ptr [rax + xmm2 * 4], xmm3"), [0xC4, 0xE2, 0x61, 0x92, 0x0C, 0x90]);
+    assert_eq!(encode("vgatherdps ymm1, dword ptr [rax + ymm2 * 4], ymm3"), [0xC4, 0xE2, 0x65, 0x92, 0x0C, 0x90]);
+    assert_eq!(encode("vgatherqps xmm1, dword ptr [rax + ymm2], xmm3"), [0xC4, 0xE2, 0x65, 0x93, 0x0C, 0x10]);
+    assert_eq!(
+        encode("vgatherdpd ymm10, qword ptr [r13 + xmm14 * 8 + 0x80], ymm9"),
+        [0xC4, 0x02, 0xB5, 0x92, 0x94, 0xF5, 0x80, 0x00, 0x00, 0x00]
+    );
+    assert_eq!(
+        encode("vgatherqpd xmm0, qword ptr [xmm5 * 2], xmm6"),
+        [0xC4, 0xE2, 0xC9, 0x93, 0x04, 0x6D, 0x00, 0x00, 0x00, 0x00]
+    );
+    assert_eq!(encode("vpgatherdd ymm4, dword ptr [ymm11 + r12], ymm7"), [0xC4, 0x82, 0x45, 0x90, 0x24, 0x1C]);
+    assert_eq!(
+        encode("vpgatherqd xmm2, dword ptr [ebx + xmm3 - 8], xmm5"),
+        [0x67, 0xC4, 0xE2, 0x51, 0x91, 0x54, 0x1B, 0xF8]
+    );
+    assert_eq!(encode("vpgatherdq ymm1, qword ptr [rsp + xmm4], ymm2"), [0xC4, 0xE2, 0xED, 0x90, 0x0C, 0x24]);
+    assert_eq!(encode("vpgatherqq ymm1, qword ptr [rbp + ymm4], ymm2"), [0xC4, 0xE2, 0xED, 0x91, 0x4C, 0x25, 0x00]);
+
+    // FMA and F16C
+    assert_eq!(encode("vfmadd231ps ymm0, ymm1, ymm2"), [0xC4, 0xE2, 0x75, 0xB8, 0xC2]);
+    assert_eq!(encode("vfmadd132pd ymm3, ymm2, ymm1"), [0xC4, 0xE2, 0xED, 0x98, 0xD9]);
+    assert_eq!(encode("vfmadd213ss xmm1, xmm0, dword ptr [r8]"), [0xC4, 0xC2, 0x79, 0xA9, 0x08]);
+    assert_eq!(encode("vfnmsub231sd xmm9, xmm10, xmm11"), [0xC4, 0x42, 0xA9, 0xBF, 0xCB]);
+    assert_eq!(encode("vfmaddsub132pd ymm0, ymm2, ymm1"), [0xC4, 0xE2, 0xED, 0x96, 0xC1]);
+    assert_eq!(encode("vfmsubadd231ps xmm0, xmm1, xmm2"), [0xC4, 0xE2, 0x71, 0xB7, 0xC2]);
+    assert_eq!(encode("vcvtph2ps ymm0, xmm1"), [0xC4, 0xE2, 0x7D, 0x13, 0xC1]);
+    assert_eq!(encode("vcvtps2ph xmm0, ymm1, 0"), [0xC4, 0xE3, 0x7D, 0x1D, 0xC8, 0x00]);
+    assert_eq!(encode("vcvtps2ph qword ptr [rdi], xmm1, 4"), [0xC4, 0xE3, 0x79, 0x1D, 0x0F, 0x04]);
+
+    assert!(parse("vaddps ymm0, xmm1, ymm2").is_err());
+    assert!(parse("vaddps xmm0, xmm1, ymmword ptr [rax]").is_err());
+    assert!(parse("vaddss ymm0, ymm1, ymm2").is_err());
+    assert!(parse("vperm2i128 xmm0, xmm1, xmm2, 1").is_err());
+    assert!(parse("vfmaddsub231ss xmm0, xmm1, xmm2").is_err());
+    assert!(parse("vhaddps ymm16, ymm0, ymm0").is_err());
+    assert!(parse("addps xmm16, xmm0").is_err());
+    assert!(parse("vblendvps ymm1, ymm2, ymm3, xmm4").is_err());
+    assert!(parse("vgatherdps xmm1, dword ptr [rax], xmm3").is_err());
+    assert!(parse("vgatherdps xmm1, dword ptr [rax + rbx * 4], xmm3").is_err());
+    assert!(parse("vgatherdps xmm1, dword ptr [rax + ymm2], xmm3").is_err());
+    assert!(parse("vgatherdpd xmm1, dword ptr [rax + xmm2], xmm3").is_err());
+    assert!(parse("vgatherdps xmm1, dword ptr [rip + xmm2], xmm3").is_err());
+    assert!(parse("vmovups xmm1, xmmword ptr [rax + xmm2]").is_err());
+}
+
+#[test]
+fn test_avx512() {
+    let parse = |line: &str| parse_lines("test.s".to_string(), format!("{}\n", line));
+    let encode = |line: &str| match parse(line).unwrap().remove(0) {
+        Line::Instruction(instruction) => instruction.encode().get_bytes().clone(),
+        _ => panic!("'{}' isn't an instruction", line),
+    };
+
+    // Checked against GNU as. EVEX is only used if VEX can't encode the instruction.
+    assert_eq!(encode("vaddps xmm0, xmm1, xmm2"), [0xC5, 0xF0, 0x58, 0xC2]);
+    assert_eq!(encode("vaddps zmm0, zmm1, zmm2"), [0x62, 0xF1, 0x74, 0x48, 0x58, 0xC2]);
+    assert_eq!(encode("vaddps xmm16, xmm17, xmm18"), [0x62, 0xA1, 0x74, 0x00, 0x58, 0xC2]);
+    assert_eq!(encode("vaddps ymm31, ymm1, ymm2"), [0x62, 0x61, 0x74, 0x28, 0x58, 0xFA]);
+    assert_eq!(encode("vpaddd zmm0, zmm16, zmm1"), [0x62, 0xF1, 0x7D, 0x40, 0xFE, 0xC1]);
+    assert_eq!(encode("vmovq xmm16, qword ptr [rax]"), [0x62, 0xE1, 0xFD, 0x08, 0x6E, 0x00]);
+
+    // Masking, zeroing and broadcasts
+    assert_eq!(encode("vaddps zmm0{k1}{z}, zmm1, zmm2"), [0x62, 0xF1, 0x74, 0xC9, 0x58, 0xC2]);
+    assert_eq!(encode("vaddps zmm0 {k1} {z}, zmm1, zmm2"), [0x62, 0xF1, 0x74, 0xC9, 0x58, 0xC2]);
+    assert_eq!(encode("vmovaps zmmword ptr [rax]{k1}, zmm0"), [0x62, 0xF1, 0x7C, 0x49, 0x29, 0x00]);
+    assert_eq!(encode("vaddss xmm0{k1}, xmm1, dword ptr [rax + 8]"), [0x62, 0xF1, 0x76, 0x09, 0x58, 0x40, 0x02]);
+    assert_eq!(encode("vaddps zmm0, zmm1, dword ptr [rax]{1to16}"), [0x62, 0xF1, 0x74, 0x58, 0x58, 0x00]);
+    assert_eq!(encode("vaddps ymm0, ymm1, dword ptr [rax]{1to8}"), [0x62, 0xF1, 0x74, 0x38, 0x58, 0x00]);
+    assert_eq!(
+        encode("vpaddq zmm0, zmm1, qword ptr [rax + 8]{1to8}"),
+        [0x62, 0xF1, 0xF5, 0x58, 0xD4, 0x40, 0x01]
+    );
+    assert_eq!(
+        encode("vpternlogd zmm0{k1}{z}, zmm1, dword ptr [rax + 4]{1to16}, 0xF0"),
+        [0x62, 0xF3, 0x75, 0xD9, 0x25, 0x40, 0x01, 0xF0]
+    );
+
+    // Rounding control and suppressed exceptions
+    assert_eq!(encode("vaddps zmm0, zmm1, zmm2, {rz-sae}"), [0x62, 0xF1, 0x74, 0x78, 0x58, 0xC2]);
+    assert_eq!(encode("vaddpd zmm0, zmm1, zmm2, {ru-sae}"), [0x62, 0xF1, 0xF5, 0x58, 0x58, 0xC2]);
+    assert_eq!(encode("vaddss xmm0, xmm1, xmm2, {rd-sae}"), [0x62, 0xF1, 0x76, 0x38, 0x58, 0xC2]);
+    assert_eq!(encode("vmaxps zmm0, zmm1, zmm2, {sae}"), [0x62, 0xF1, 0x74, 0x18, 0x5F, 0xC2]);
+    assert_eq!(encode("vcmpps k1{k2}, zmm0, zmm1, {sae}, 3"), [0x62, 0xF1, 0x7C, 0x1A, 0xC2, 0xC9, 0x03]);
+    assert_eq!(encode("vcvtpd2ps ymm0{k1}, zmm1, {rd-sae}"), [0x62, 0xF1, 0xFD, 0x39, 0x5A, 0xC1]);
+
+    // Displacements are scaled by the size of the memory access, or of the broadcast element
+    assert_eq!(encode("vaddps zmm0, zmm1, zmmword ptr [rax + 64]"), [0x62, 0xF1, 0x74, 0x48, 0x58, 0x40, 0x01]);
+    assert_eq!(
+        encode("vaddps zmm0, zmm1, zmmword ptr [rax + 68]"),
+        [0x62, 0xF1, 0x74, 0x48, 0x58, 0x80, 0x44, 0x00, 0x00, 0x00]
+    );
+    assert_eq!(
+        encode("vpaddd zmm0, zmm1, dword ptr [rax + 4]{1to16}"),
+        [0x62, 0xF1, 0x75, 0x58, 0xFE, 0x40, 0x01]
+    );
+    assert_eq!(encode("vcvtps2pd zmm0, ymmword ptr [rax + 32]"), [0x62, 0xF1, 0x7C, 0x48, 0x5A, 0x40, 0x01]);
+    assert_eq!(
+        encode("vmovdqu8 zmm0{k1}{z}, zmmword ptr [rsi - 8192]"),
+        [0x62, 0xF1, 0x7F, 0xC9, 0x6F, 0x46, 0x80]
+    );
+    assert_eq!(
+        encode("vextracti64x4 ymmword ptr [rax + 32]{k1}, zmm1, 1"),
+        [0x62, 0xF3, 0xFD, 0x49, 0x3B, 0x48, 0x01, 0x01]
+    );
+
+    // Opmask registers
+    assert_eq!(encode("vpcmpd k1{k2}, zmm0, zmm1, 4"), [0x62, 0xF3, 0x7D, 0x4A, 0x1F, 0xC9, 0x04]);
+    assert_eq!(encode("vpmovm2d zmm1, k3"), [0x62, 0xF2, 0x7E, 0x48, 0x38, 0xCB]);
+    assert_eq!(encode("vpmovdb xmmword ptr [rax]{k1}, zmm1"), [0x62, 0xF2, 0x7E, 0x49, 0x31, 0x08]);
+    assert_eq!(encode("vpbroadcastd zmm1{k1}{z}, eax"), [0x62, 0xF2, 0x7D, 0xC9, 0x7C, 0xC8]);
+    assert_eq!(encode("kmovw k1, eax"), [0xC5, 0xF8, 0x92, 0xC8]);
+    assert_eq!(encode("kandw k1, k2, k3"), [0xC5, 0xEC, 0x41, 0xCB]);
+    assert_eq!(encode("kmovq rax, k1"), [0xC4, 0xE1, 0xFB, 0x93, 0xC1]);
+    assert_eq!(encode("kshiftlw k1, k2, 3"), [0xC4, 0xE3, 0xF9, 0x32, 0xCA, 0x03]);
+
+    assert!(parse("vaddps zmm0{k0}, zmm1, zmm2").is_err());
+    assert!(parse("vaddps zmm0{z}, zmm1, zmm2").is_err());
+    assert!(parse("vmovaps zmmword ptr [rax]{k1}{z}, zmm0").is_err());
+    assert!(parse("vcmpps k1{z}, zmm0, zmm1, 3").is_err());
+    assert!(parse("kmovw k1{k2}, k3").is_err());
+    assert!(parse("vaddps zmm0, zmm1, zmmword ptr [rax], {rn-sae}").is_err());
+    assert!(parse("vaddps ymm0, ymm1, ymm2, {rn-sae}").is_err());
+    assert!(parse("vmaxps zmm0, zmm1, zmm2, {rn-sae}").is_err());
+    assert!(parse("vaddps zmm0, zmm1, zmm2, {sae}").is_err());
+    assert!(parse("vaddps zmm0, zmm1, dword ptr [rax]{1to8}").is_err());
+    assert!(parse("vpaddb zmm0, zmm1, dword ptr [rax]{1to16}").is_err());
+    assert!(parse("vaddss xmm0, xmm1, dword ptr [rax]{1to4}").is_err());
+}